@@ -0,0 +1,113 @@
+use crate::dynamic_firestore_client::WrappedService;
+use http::uri::PathAndQuery;
+use tonic::{client::Grpc, codec::ProstCodec, Code, Status};
+
+// The Admin API is served from the same endpoint as the data API, but the generated protos we
+// depend on predate its `GetDatabase` call, so the messages it needs are declared here.
+const GET_DATABASE_PATH: &str = "/google.firestore.admin.v1.FirestoreAdmin/GetDatabase";
+
+/// A client for the admin API. Like [crate::dynamic_firestore_client::SharedFirestoreClient],
+/// each request is made through its own clone rather than by locking a shared client.
+pub(crate) type SharedAdminClient = Grpc<WrappedService>;
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct GetDatabaseRequest {
+    #[prost(string, tag = "1")]
+    name: String,
+}
+
+/// The subset of `google.firestore.admin.v1.Database` that we read.
+#[derive(Clone, PartialEq, prost::Message)]
+struct DatabaseProto {
+    #[prost(string, tag = "1")]
+    name: String,
+    #[prost(string, tag = "9")]
+    location_id: String,
+    #[prost(int32, tag = "10")]
+    r#type: i32,
+    #[prost(int32, tag = "15")]
+    concurrency_mode: i32,
+}
+
+/// Whether a database is in Firestore Native mode or Datastore mode.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DatabaseType {
+    FirestoreNative,
+    DatastoreMode,
+    Unspecified,
+}
+
+impl DatabaseType {
+    fn from_proto(value: i32) -> Self {
+        match value {
+            1 => DatabaseType::FirestoreNative,
+            2 => DatabaseType::DatastoreMode,
+            _ => DatabaseType::Unspecified,
+        }
+    }
+}
+
+/// The concurrency control mode used by transactions in a database.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConcurrencyMode {
+    Optimistic,
+    Pessimistic,
+    OptimisticWithEntityGroups,
+    Unspecified,
+}
+
+impl ConcurrencyMode {
+    fn from_proto(value: i32) -> Self {
+        match value {
+            1 => ConcurrencyMode::Optimistic,
+            2 => ConcurrencyMode::Pessimistic,
+            3 => ConcurrencyMode::OptimisticWithEntityGroups,
+            _ => ConcurrencyMode::Unspecified,
+        }
+    }
+}
+
+/// Metadata about a Firestore database, as returned by [crate::Database::info].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DatabaseInfo {
+    /// The fully-qualified name of the database.
+    pub name: String,
+
+    /// The location of the database (e.g. `nam5` or `us-east1`).
+    pub location_id: String,
+
+    pub database_type: DatabaseType,
+
+    pub concurrency_mode: ConcurrencyMode,
+}
+
+/// Fetch metadata about the database with the given fully-qualified name.
+pub(crate) async fn get_database(
+    client: &SharedAdminClient,
+    name: &str,
+) -> Result<DatabaseInfo, Status> {
+    let mut client = client.clone();
+    client
+        .ready()
+        .await
+        .map_err(|e| Status::new(Code::Unknown, format!("Service was not ready: {}", e)))?;
+
+    let request = GetDatabaseRequest {
+        name: name.to_string(),
+    };
+    let database: DatabaseProto = client
+        .unary(
+            tonic::Request::new(request),
+            PathAndQuery::from_static(GET_DATABASE_PATH),
+            ProstCodec::default(),
+        )
+        .await?
+        .into_inner();
+
+    Ok(DatabaseInfo {
+        name: database.name,
+        location_id: database.location_id,
+        database_type: DatabaseType::from_proto(database.r#type),
+        concurrency_mode: ConcurrencyMode::from_proto(database.concurrency_mode),
+    })
+}
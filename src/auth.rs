@@ -0,0 +1,174 @@
+use google_authz::TokenSource;
+use http::header::{HeaderValue, AUTHORIZATION};
+use http::Request;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tower_service::Service;
+
+/// Tokens are refreshed this long before they expire, so that they do not expire in flight.
+const EXPIRY_MARGIN: Duration = Duration::from_secs(10);
+
+/// Number of times fetching a token is attempted before giving up.
+const MAX_TOKEN_ATTEMPTS: u8 = 5;
+
+type TokenFuture = Pin<Box<dyn Future<Output = Result<(HeaderValue, Instant), String>> + Send>>;
+
+#[derive(Debug)]
+struct CachedToken {
+    value: HeaderValue,
+    expiry: Instant,
+}
+
+impl CachedToken {
+    fn is_fresh(&self, now: Instant) -> bool {
+        self.expiry
+            .checked_duration_since(now)
+            .is_some_and(|remaining| remaining >= EXPIRY_MARGIN)
+    }
+}
+
+type SharedToken = Arc<Mutex<Option<CachedToken>>>;
+
+/// A handle for discarding the token cached by an [Authorize] service (and its clones), so
+/// that a fresh one is fetched for the next request.
+#[derive(Clone, Debug)]
+pub(crate) struct TokenRefresher {
+    token: SharedToken,
+}
+
+impl TokenRefresher {
+    pub(crate) fn invalidate(&self) {
+        self.token.lock().unwrap().take();
+    }
+
+    /// Construct a refresher holding a token that never expires, for use in unit tests.
+    #[cfg(test)]
+    pub(crate) fn test_refresher() -> Self {
+        let token = CachedToken {
+            value: HeaderValue::from_static("Bearer test"),
+            expiry: Instant::now() + Duration::from_secs(3600),
+        };
+        TokenRefresher {
+            token: Arc::new(Mutex::new(Some(token))),
+        }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn has_token(&self) -> bool {
+        self.token.lock().unwrap().is_some()
+    }
+}
+
+/// Adds an access token from a [TokenSource] to every request, fetching a new token when the
+/// cached one expires or is invalidated with a [TokenRefresher].
+///
+/// This plays the same role as `google_authz::AddAuthorization`, which does not allow its
+/// cached token to be discarded before it expires.
+pub(crate) struct Authorize<S> {
+    source: Arc<TokenSource>,
+    token: SharedToken,
+
+    /// The token fetch in progress, if any, with the number of the attempt.
+    fetch: Option<(u8, TokenFuture)>,
+
+    /// The token found by `poll_ready`, to be attached by the following `call`.
+    ready: Option<HeaderValue>,
+
+    service: S,
+}
+
+impl<S> Authorize<S> {
+    pub(crate) fn new(source: impl Into<TokenSource>, service: S) -> Self {
+        Authorize {
+            source: Arc::new(source.into()),
+            token: SharedToken::default(),
+            fetch: None,
+            ready: None,
+            service,
+        }
+    }
+
+    pub(crate) fn refresher(&self) -> TokenRefresher {
+        TokenRefresher {
+            token: self.token.clone(),
+        }
+    }
+}
+
+fn fetch_token(source: &TokenSource) -> TokenFuture {
+    let token = source.token();
+    Box::pin(async move {
+        let token = token.await.map_err(|e| e.to_string())?;
+        token.into_pairs().map_err(|e| e.to_string())
+    })
+}
+
+impl<S: Clone> Clone for Authorize<S> {
+    fn clone(&self) -> Self {
+        Authorize {
+            source: self.source.clone(),
+            token: self.token.clone(),
+            fetch: None,
+            ready: None,
+            service: self.service.clone(),
+        }
+    }
+}
+
+impl<S, B> Service<Request<B>> for Authorize<S>
+where
+    S: Service<Request<B>>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = S::Future;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        loop {
+            if let Some((attempt, fetch)) = &mut self.fetch {
+                match fetch.as_mut().poll(cx) {
+                    Poll::Pending => return Poll::Pending,
+                    Poll::Ready(Ok((value, expiry))) => {
+                        *self.token.lock().unwrap() = Some(CachedToken { value, expiry });
+                        self.fetch = None;
+                    }
+                    Poll::Ready(Err(_)) if *attempt < MAX_TOKEN_ATTEMPTS => {
+                        let attempt = *attempt + 1;
+                        self.fetch = Some((attempt, fetch_token(&self.source)));
+                        continue;
+                    }
+                    Poll::Ready(Err(error)) => {
+                        panic!("Could not fetch an access token: {}", error)
+                    }
+                }
+            }
+
+            let fresh = self
+                .token
+                .lock()
+                .unwrap()
+                .as_ref()
+                .filter(|token| token.is_fresh(Instant::now()))
+                .map(|token| token.value.clone());
+            match fresh {
+                Some(token) => {
+                    self.ready = Some(token);
+                    return self.service.poll_ready(cx);
+                }
+                None => self.fetch = Some((1, fetch_token(&self.source))),
+            }
+        }
+    }
+
+    fn call(&mut self, mut request: Request<B>) -> Self::Future {
+        let token = self
+            .ready
+            .take()
+            .expect("Authorize::call was called before poll_ready.");
+        request.headers_mut().insert(AUTHORIZATION, token);
+        self.service.call(request)
+    }
+}
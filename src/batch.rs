@@ -0,0 +1,231 @@
+use crate::collection::MAX_WRITES_PER_COMMIT;
+use crate::dynamic_firestore_client::SharedFirestoreClient;
+use crate::error::to_document;
+use crate::identifiers::{DocumentName, QualifyDocumentName};
+use crate::retry::{Idempotency, RetryPolicy};
+use crate::{Collection, WriteResult};
+use anyhow::Context;
+use firestore_serde::firestore::{
+    precondition::ConditionType, write::Operation, CommitRequest, Precondition, Write,
+};
+use serde::{de::DeserializeOwned, Serialize};
+
+/// Writes to documents in any number of collections, buffered locally and sent together
+/// when the batch is committed. Obtained from [crate::Database::batch].
+///
+/// Unlike a [crate::Transaction], a batch reads nothing, so it cannot be aborted by
+/// contention. Its writes are serialized with `to_document` like a transaction's, so bypass
+/// the validators and caches of the collections they are made to.
+pub struct WriteBatch {
+    db: SharedFirestoreClient,
+    database: String,
+    retry: RetryPolicy,
+    writes: Vec<Write>,
+}
+
+impl WriteBatch {
+    pub(crate) fn new(db: SharedFirestoreClient, database: String, retry: RetryPolicy) -> Self {
+        WriteBatch {
+            db,
+            database,
+            retry,
+            writes: Vec::new(),
+        }
+    }
+
+    /// Create a document with the given key in the given collection when the batch is
+    /// committed. The commit fails if the document already exists.
+    pub fn create<T>(
+        &mut self,
+        collection: &Collection<T>,
+        key: impl QualifyDocumentName,
+        ob: &T,
+    ) -> anyhow::Result<()>
+    where
+        T: Serialize + DeserializeOwned + Unpin,
+    {
+        let name = key.qualify(&collection.name())?;
+        self.write(name, Some(ob), Some(false))
+    }
+
+    /// Overwrite a document with the given key in the given collection when the batch is
+    /// committed, creating it if it does not exist.
+    pub fn upsert<T>(
+        &mut self,
+        collection: &Collection<T>,
+        key: impl QualifyDocumentName,
+        ob: &T,
+    ) -> anyhow::Result<()>
+    where
+        T: Serialize + DeserializeOwned + Unpin,
+    {
+        let name = key.qualify(&collection.name())?;
+        self.write(name, Some(ob), None)
+    }
+
+    /// Overwrite a document with the given key in the given collection when the batch is
+    /// committed. The commit fails if the document does not exist.
+    pub fn update<T>(
+        &mut self,
+        collection: &Collection<T>,
+        key: impl QualifyDocumentName,
+        ob: &T,
+    ) -> anyhow::Result<()>
+    where
+        T: Serialize + DeserializeOwned + Unpin,
+    {
+        let name = key.qualify(&collection.name())?;
+        self.write(name, Some(ob), Some(true))
+    }
+
+    /// Delete a document with the given key in the given collection when the batch is
+    /// committed. The commit fails if the document does not exist.
+    pub fn delete<T>(
+        &mut self,
+        collection: &Collection<T>,
+        key: impl QualifyDocumentName,
+    ) -> anyhow::Result<()>
+    where
+        T: Serialize + DeserializeOwned + Unpin,
+    {
+        let name = key.qualify(&collection.name())?;
+        self.write::<T>(name, None, Some(true))
+    }
+
+    /// Returns the number of writes in the batch.
+    pub fn len(&self) -> usize {
+        self.writes.len()
+    }
+
+    /// Returns `true` if the batch has no writes.
+    pub fn is_empty(&self) -> bool {
+        self.writes.is_empty()
+    }
+
+    /// Buffer a write of `ob` (or a delete, if `None`) to the given document, with an
+    /// optional existence precondition.
+    fn write<T>(
+        &mut self,
+        name: DocumentName,
+        ob: Option<&T>,
+        exists: Option<bool>,
+    ) -> anyhow::Result<()>
+    where
+        T: Serialize,
+    {
+        let operation = match ob {
+            Some(ob) => {
+                let mut document = to_document(ob)?;
+                document.name = name.name();
+                Operation::Update(document)
+            }
+            None => Operation::Delete(name.name()),
+        };
+
+        self.writes.push(Write {
+            operation: Some(operation),
+            current_document: exists.map(|exists| Precondition {
+                condition_type: Some(ConditionType::Exists(exists)),
+            }),
+            ..Write::default()
+        });
+        Ok(())
+    }
+
+    /// Commit the writes in the batch, returning a result for each, in the order they were
+    /// made. A delete's result carries the time of the commit it was applied in.
+    ///
+    /// Up to 500 writes are sent in a single commit, and applied atomically. Larger batches
+    /// are split into commits of 500, which are applied in order but not atomically: if one
+    /// fails, the commits before it remain applied, and the error reports how many writes
+    /// were.
+    pub async fn commit(self) -> anyhow::Result<Vec<WriteResult>> {
+        let total = self.writes.len();
+        let mut results = Vec::with_capacity(total);
+
+        for chunk in self.writes.chunks(MAX_WRITES_PER_COMMIT) {
+            let request = &CommitRequest {
+                database: self.database.clone(),
+                writes: chunk.to_vec(),
+                ..CommitRequest::default()
+            };
+            let db = &self.db;
+            // Not retried by default: writes may carry preconditions, which a retry of an
+            // applied commit would fail against its own writes.
+            let response = self
+                .retry
+                .run(Idempotency::NotIdempotent, || async move {
+                    db.clone().commit(request.clone()).await
+                })
+                .await
+                .with_context(|| {
+                    format!(
+                        "Batch failed after committing {} of {} writes.",
+                        results.len(),
+                        total
+                    )
+                })?
+                .into_inner();
+
+            let commit_time = WriteResult::new(response.commit_time)?;
+            results.extend(response.write_results.into_iter().map(
+                |result| match result.update_time {
+                    Some(update_time) => WriteResult { update_time },
+                    None => commit_time.clone(),
+                },
+            ));
+        }
+
+        Ok(results)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::dynamic_firestore_client::test_client;
+    use crate::CollectionName;
+    use serde::Deserialize;
+
+    #[derive(Serialize, Deserialize)]
+    struct Account {
+        balance: i64,
+    }
+
+    #[tokio::test]
+    async fn test_writes_across_collections() {
+        let accounts: Collection<Account> =
+            Collection::new(test_client(), CollectionName::new("my-project", "accounts"));
+        let archive: Collection<Account> =
+            Collection::new(test_client(), CollectionName::new("my-project", "archive"));
+        let mut batch = WriteBatch::new(
+            test_client(),
+            "projects/my-project/databases/(default)".to_string(),
+            RetryPolicy::never(),
+        );
+        assert!(batch.is_empty());
+
+        batch
+            .create(&archive, "a", &Account { balance: 10 })
+            .unwrap();
+        batch.delete(&accounts, "a").unwrap();
+        assert_eq!(2, batch.len());
+
+        let names: Vec<&str> = batch
+            .writes
+            .iter()
+            .map(|write| match write.operation.as_ref().unwrap() {
+                Operation::Update(document) => document.name.as_str(),
+                Operation::Delete(name) => name.as_str(),
+                _ => panic!("Unexpected operation."),
+            })
+            .collect();
+        assert_eq!(
+            vec![
+                "projects/my-project/databases/(default)/documents/archive/a",
+                "projects/my-project/databases/(default)/documents/accounts/a",
+            ],
+            names
+        );
+    }
+}
@@ -0,0 +1,124 @@
+use crate::identifiers::DocumentName;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+/// Configures the cache enabled by [crate::Collection::with_cache].
+#[derive(Clone, Copy, Debug)]
+pub struct CacheConfig {
+    /// The most documents kept at once. When the cache is full, the entry cached longest ago
+    /// is evicted to make room.
+    pub max_entries: usize,
+
+    /// How long an entry is served for after it is cached.
+    pub ttl: Duration,
+}
+
+/// Deserialized documents, keyed by name, with the time at which each was cached.
+pub(crate) struct DocumentCache<T> {
+    config: CacheConfig,
+    /// Clones a cached value, so that `T: Clone` is only required where the cache is enabled.
+    clone: fn(&T) -> T,
+    entries: Mutex<HashMap<DocumentName, (SystemTime, T)>>,
+}
+
+impl<T> DocumentCache<T> {
+    pub(crate) fn new(config: CacheConfig) -> Self
+    where
+        T: Clone,
+    {
+        DocumentCache {
+            config,
+            clone: T::clone,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the cached value of the given document, if it was cached less than the TTL ago.
+    pub(crate) fn get(&self, name: &DocumentName, now: SystemTime) -> Option<T> {
+        let mut entries = self.entries.lock().unwrap();
+        match entries.get(name) {
+            Some((cached_at, value)) if self.is_fresh(*cached_at, now) => Some((self.clone)(value)),
+            Some(_) => {
+                entries.remove(name);
+                None
+            }
+            None => None,
+        }
+    }
+
+    pub(crate) fn insert(&self, name: DocumentName, value: &T, now: SystemTime) {
+        if self.config.max_entries == 0 {
+            return;
+        }
+
+        let mut entries = self.entries.lock().unwrap();
+        if !entries.contains_key(&name) && entries.len() >= self.config.max_entries {
+            entries.retain(|_, (cached_at, _)| self.is_fresh(*cached_at, now));
+        }
+        while !entries.contains_key(&name) && entries.len() >= self.config.max_entries {
+            let oldest = entries
+                .iter()
+                .min_by_key(|(_, (cached_at, _))| *cached_at)
+                .map(|(name, _)| name.clone())
+                .expect("Expected a full cache to have an entry.");
+            entries.remove(&oldest);
+        }
+        entries.insert(name, (now, (self.clone)(value)));
+    }
+
+    /// Clones a value taken from the cache.
+    pub(crate) fn clone_value(&self, value: &T) -> T {
+        (self.clone)(value)
+    }
+
+    pub(crate) fn remove(&self, name: &DocumentName) {
+        self.entries.lock().unwrap().remove(name);
+    }
+
+    fn is_fresh(&self, cached_at: SystemTime, now: SystemTime) -> bool {
+        now.duration_since(cached_at)
+            .map_or(true, |age| age < self.config.ttl)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::CollectionName;
+
+    fn name(id: &str) -> DocumentName {
+        CollectionName::new("my-project", "things").document(id)
+    }
+
+    #[test]
+    fn test_document_cache() {
+        let cache = DocumentCache::new(CacheConfig {
+            max_entries: 2,
+            ttl: Duration::from_secs(10),
+        });
+        let start = SystemTime::UNIX_EPOCH;
+        let later = |secs| start + Duration::from_secs(secs);
+
+        cache.insert(name("a"), &1, start);
+        cache.insert(name("b"), &2, later(1));
+        assert_eq!(Some(1), cache.get(&name("a"), later(2)));
+
+        // The cache is full, so the entry cached longest ago is evicted.
+        cache.insert(name("c"), &3, later(2));
+        assert_eq!(None, cache.get(&name("a"), later(2)));
+        assert_eq!(Some(2), cache.get(&name("b"), later(2)));
+
+        // Replacing an entry does not evict another.
+        cache.insert(name("b"), &4, later(3));
+        assert_eq!(Some(4), cache.get(&name("b"), later(3)));
+        assert_eq!(Some(3), cache.get(&name("c"), later(3)));
+
+        cache.remove(&name("b"));
+        assert_eq!(None, cache.get(&name("b"), later(3)));
+
+        // Entries expire after the TTL.
+        assert_eq!(Some(3), cache.get(&name("c"), later(11)));
+        assert_eq!(None, cache.get(&name("c"), later(12)));
+    }
+}
@@ -0,0 +1,52 @@
+use crate::error::Cancelled;
+use std::future::Future;
+
+/// Run `operation` until it completes or `signal` does, whichever is first. If the signal
+/// completes first, the operation is dropped and a [Cancelled] error is returned.
+///
+/// Dropping an operation cancels any request it has in flight, so this frees Firestore
+/// resources as soon as the caller gives up, e.g. when the client of a web server
+/// disconnects. Any future can serve as the signal, such as the `cancelled()` future of a
+/// `tokio_util` `CancellationToken`, or a timeout.
+///
+/// A write which is cancelled may still have been applied, if Firestore received it before
+/// the request was dropped.
+///
+/// ```no_run
+/// # use tiny_firestore_odm::{cancellable, Collection};
+/// # async fn example(users: Collection<String>, disconnected: tokio::sync::oneshot::Receiver<()>) {
+/// match cancellable(users.get("ada"), disconnected).await {
+///     Ok(user) => println!("Found {}.", user),
+///     Err(error) => println!("Gave up: {}", error),
+/// }
+/// # }
+/// ```
+pub async fn cancellable<T, F, S>(operation: F, signal: S) -> anyhow::Result<T>
+where
+    F: Future<Output = anyhow::Result<T>>,
+    S: Future,
+{
+    tokio::select! {
+        // An operation which has completed returns its result, even if the signal has too.
+        biased;
+        result = operation => result,
+        _ = signal => Err(Cancelled.into()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_cancellable() {
+        let result = cancellable(async { Ok(5) }, std::future::pending::<()>()).await;
+        assert_eq!(5, result.unwrap());
+
+        let result: anyhow::Result<()> = cancellable(std::future::pending(), async {}).await;
+        assert_eq!(
+            Some(&Cancelled),
+            result.unwrap_err().downcast_ref::<Cancelled>()
+        );
+    }
+}
@@ -1,18 +1,250 @@
+use crate::auth::{Authorize, TokenRefresher};
+use crate::connection::{ConnectionMonitor, ConnectionState, MonitorConnection};
 use crate::dynamic_firestore_client::{DynamicFirestoreClient, WrappedService};
 use anyhow::Result;
 use firestore_serde::firestore::firestore_client::FirestoreClient;
 use googapis::CERTIFICATES;
-use google_authz::{AddAuthorization, Credentials, TokenSource};
+use google_authz::{Credentials, TokenSource};
 use http::Uri;
+use hyper::client::HttpConnector;
+use rustls::RootCertStore;
+use tokio::sync::watch;
+use tonic::client::Grpc;
 use tonic::transport::{Certificate, Channel, ClientTlsConfig};
 
 const FIRESTORE_API_DOMAIN: &str = "firestore.googleapis.com";
 
+/// The environment variable which, if set, points clients at a Firestore emulator at the
+/// given host and port (e.g. `localhost:8080`) instead of at Firestore.
+pub const EMULATOR_HOST_VAR: &str = "FIRESTORE_EMULATOR_HOST";
+
+/// The certificate authorities a client trusts to have signed Firestore's certificate.
+#[derive(Clone, Debug)]
+pub enum TlsRoots {
+    /// The certificates bundled with `googapis`. This is the default.
+    Bundled,
+
+    /// The certificates in the given PEM data, instead of the bundled ones, e.g. the
+    /// certificate of a proxy which intercepts TLS.
+    Pem(Vec<u8>),
+
+    /// The certificates trusted by the operating system, loaded with `rustls-native-certs`.
+    Native,
+}
+
+impl TlsRoots {
+    fn tls_config(&self) -> Result<ClientTlsConfig> {
+        let tls_config = ClientTlsConfig::new().domain_name(FIRESTORE_API_DOMAIN);
+        let root_store = match self {
+            TlsRoots::Bundled => {
+                return Ok(tls_config.ca_certificate(Certificate::from_pem(CERTIFICATES)))
+            }
+            TlsRoots::Pem(pem) => {
+                let mut root_store = RootCertStore::empty();
+                let (valid, _) = root_store
+                    .add_pem_file(&mut pem.as_slice())
+                    .map_err(|()| anyhow::anyhow!("Could not parse PEM certificates."))?;
+                if valid == 0 {
+                    return Err(anyhow::anyhow!(
+                        "Expected at least one valid certificate in PEM data."
+                    ));
+                }
+                root_store
+            }
+            // Certificates which fail to load are skipped, as long as some load.
+            TlsRoots::Native => match rustls_native_certs::load_native_certs() {
+                Ok(root_store) | Err((Some(root_store), _)) => root_store,
+                Err((None, error)) => {
+                    return Err(anyhow::Error::new(error)
+                        .context("Could not load the system's certificates."))
+                }
+            },
+        };
+
+        // Configured as tonic configures rustls itself, which it only does for a single
+        // certificate.
+        let mut config = rustls::ClientConfig::new();
+        config.set_protocols(&[b"h2".to_vec()]);
+        config.root_store = root_store;
+        Ok(tls_config.rustls_client_config(config))
+    }
+}
+
+/// Options controlling how a client communicates with Firestore.
+#[derive(Clone, Debug)]
+pub struct ClientOptions {
+    #[cfg(feature = "gzip")]
+    gzip: bool,
+    refresh_on_unauthenticated: bool,
+    tls_roots: TlsRoots,
+}
+
+impl Default for ClientOptions {
+    fn default() -> Self {
+        ClientOptions {
+            #[cfg(feature = "gzip")]
+            gzip: false,
+            refresh_on_unauthenticated: true,
+            tls_roots: TlsRoots::Bundled,
+        }
+    }
+}
+
+impl ClientOptions {
+    /// If `true`, compress requests with gzip and ask the server to compress responses.
+    /// Off by default.
+    ///
+    /// Compression trades CPU time on both ends for less data on the wire, which mostly pays
+    /// off for large documents and long listings over constrained networks.
+    ///
+    /// Only available with the `gzip` feature, which enables tonic's compression support.
+    #[cfg(feature = "gzip")]
+    pub fn with_gzip(self, gzip: bool) -> Self {
+        ClientOptions { gzip, ..self }
+    }
+
+    /// If `true`, a request made through a [crate::Collection] which fails with
+    /// `UNAUTHENTICATED` (e.g. because the token expired in flight, or because of clock skew)
+    /// is retried exactly once with a freshly-fetched token. On by default.
+    ///
+    /// This applies to every request retried by a [crate::RetryPolicy], regardless of the
+    /// policy's settings, since a request rejected as unauthenticated was not applied.
+    /// Streaming reads are not retried. Only databases constructed with
+    /// `Database::new_with_options` (or `Database::new`) can refresh their token.
+    pub fn with_refresh_on_unauthenticated(self, refresh_on_unauthenticated: bool) -> Self {
+        ClientOptions {
+            refresh_on_unauthenticated,
+            ..self
+        }
+    }
+
+    /// The certificate authorities to trust when connecting to Firestore. Defaults to
+    /// [TlsRoots::Bundled]; connections to the emulator do not use TLS.
+    pub fn with_tls_roots(self, tls_roots: TlsRoots) -> Self {
+        ClientOptions { tls_roots, ..self }
+    }
+
+    fn apply(&self, client: DynamicFirestoreClient) -> DynamicFirestoreClient {
+        #[cfg(feature = "gzip")]
+        if self.gzip {
+            return client.send_gzip().accept_gzip();
+        }
+        client
+    }
+}
+
 /// Construct a client from a given TokenSource.
 pub async fn get_client(source: impl Into<TokenSource>) -> Result<DynamicFirestoreClient> {
-    let tls_config = ClientTlsConfig::new()
-        .ca_certificate(Certificate::from_pem(CERTIFICATES))
-        .domain_name(FIRESTORE_API_DOMAIN);
+    get_client_with_options(source, &ClientOptions::default()).await
+}
+
+/// Construct a client from a given TokenSource, with the given options.
+///
+/// If the `FIRESTORE_EMULATOR_HOST` environment variable is set, the client connects to the
+/// emulator instead, and the token source is not used.
+pub async fn get_client_with_options(
+    source: impl Into<TokenSource>,
+    options: &ClientOptions,
+) -> Result<DynamicFirestoreClient> {
+    let (monitor, _) = ConnectionMonitor::new();
+    let client = match emulator_host() {
+        Some(host) => FirestoreClient::new(WrappedService::new(
+            get_emulator_channel(&host, monitor).await?,
+        )),
+        None => FirestoreClient::new(WrappedService::new(
+            get_authorized_channel(source, options, monitor).await?,
+        )),
+    };
+    Ok(options.apply(client))
+}
+
+/// Construct a client for the Firestore emulator at the given host and port (e.g.
+/// `localhost:8080`), which speaks plaintext gRPC and does not check credentials.
+pub async fn get_client_emulator(host: &str) -> Result<DynamicFirestoreClient> {
+    let (monitor, _) = ConnectionMonitor::new();
+    let channel = get_emulator_channel(host, monitor).await?;
+    Ok(FirestoreClient::new(WrappedService::new(channel)))
+}
+
+/// Returns the host of the emulator named by `FIRESTORE_EMULATOR_HOST`, if it is set.
+pub fn emulator_host() -> Option<String> {
+    std::env::var(EMULATOR_HOST_VAR)
+        .ok()
+        .filter(|host| !host.is_empty())
+}
+
+/// Clients sharing one connection and set of credentials, constructed by
+/// [get_data_and_admin_clients].
+pub(crate) struct Clients {
+    pub(crate) data: DynamicFirestoreClient,
+    pub(crate) admin: Grpc<WrappedService>,
+
+    /// Set if the options ask for tokens to be refreshed when a request is unauthenticated.
+    pub(crate) token_refresher: Option<TokenRefresher>,
+
+    /// Observes the state of the shared connection.
+    pub(crate) connection_state: watch::Receiver<ConnectionState>,
+}
+
+/// Construct a client for the data API and a client for the admin API, sharing one
+/// connection and set of credentials. If `FIRESTORE_EMULATOR_HOST` is set, they connect to the
+/// emulator instead.
+pub(crate) async fn get_data_and_admin_clients(
+    source: impl Into<TokenSource>,
+    options: &ClientOptions,
+) -> Result<Clients> {
+    if let Some(host) = emulator_host() {
+        return get_emulator_clients(&host, options).await;
+    }
+
+    let (monitor, connection_state) = ConnectionMonitor::new();
+    let channel = get_authorized_channel(source, options, monitor).await?;
+    let token_refresher = if options.refresh_on_unauthenticated {
+        Some(channel.refresher())
+    } else {
+        None
+    };
+
+    Ok(Clients {
+        admin: Grpc::new(WrappedService::new(channel.clone())),
+        data: options.apply(FirestoreClient::new(WrappedService::new(channel))),
+        token_refresher,
+        connection_state,
+    })
+}
+
+/// Construct clients for the data and admin APIs of the emulator at the given host, sharing
+/// one connection.
+pub(crate) async fn get_emulator_clients(host: &str, options: &ClientOptions) -> Result<Clients> {
+    let (monitor, connection_state) = ConnectionMonitor::new();
+    let channel = get_emulator_channel(host, monitor).await?;
+
+    Ok(Clients {
+        admin: Grpc::new(WrappedService::new(channel.clone())),
+        data: options.apply(FirestoreClient::new(WrappedService::new(channel))),
+        token_refresher: None,
+        connection_state,
+    })
+}
+
+async fn get_emulator_channel(host: &str, monitor: ConnectionMonitor) -> Result<Channel> {
+    let base_url = Uri::builder()
+        .scheme("http")
+        .authority(host)
+        .path_and_query("")
+        .build()?;
+
+    Ok(Channel::builder(base_url)
+        .connect_with_connector(MonitorConnection::new(http_connector(), monitor))
+        .await?)
+}
+
+async fn get_authorized_channel(
+    source: impl Into<TokenSource>,
+    options: &ClientOptions,
+    monitor: ConnectionMonitor,
+) -> Result<Authorize<Channel>> {
+    let tls_config = options.tls_roots.tls_config()?;
 
     let base_url = Uri::builder()
         .scheme("https")
@@ -22,17 +254,49 @@ pub async fn get_client(source: impl Into<TokenSource>) -> Result<DynamicFiresto
 
     let channel = Channel::builder(base_url)
         .tls_config(tls_config)?
-        .connect()
+        .connect_with_connector(MonitorConnection::new(http_connector(), monitor))
         .await?;
 
-    let authorized_channel = AddAuthorization::init_with(source, channel);
-
-    let client = FirestoreClient::new(WrappedService::new(authorized_channel));
+    Ok(Authorize::new(source, channel))
+}
 
-    Ok(client)
+/// Configured as tonic configures its own connector, which cannot be wrapped.
+fn http_connector() -> HttpConnector {
+    let mut http = HttpConnector::new();
+    http.enforce_http(false);
+    http.set_nodelay(true);
+    http
 }
 
-/// Construct a client using google-authz's default credential discovery process.
+/// Construct a client using google-authz's default credential discovery process, or for the
+/// emulator if `FIRESTORE_EMULATOR_HOST` is set (in which case no credentials are needed).
 pub async fn get_client_default() -> Result<DynamicFirestoreClient> {
-    get_client(Credentials::default().await).await
+    match emulator_host() {
+        Some(host) => get_client_emulator(&host).await,
+        None => get_client(Credentials::default().await).await,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_emulator_unreachable() {
+        // Find a port with nothing listening on it.
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let host = listener.local_addr().unwrap().to_string();
+        drop(listener);
+
+        assert!(get_client_emulator(&host).await.is_err());
+    }
+
+    #[test]
+    fn test_tls_roots() {
+        assert!(TlsRoots::Bundled.tls_config().is_ok());
+        assert!(TlsRoots::Pem(CERTIFICATES.to_vec()).tls_config().is_ok());
+        assert!(TlsRoots::Pem(b"not a certificate".to_vec())
+            .tls_config()
+            .is_err());
+    }
 }
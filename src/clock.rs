@@ -0,0 +1,93 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+/// A source of the current time, used for every timestamp computed on the client rather
+/// than set by the server.
+///
+/// Defaults to [SystemClock]; tests can substitute a [MockClock] with
+/// [crate::Database::with_clock] so that time-dependent logic is deterministic.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> SystemTime;
+}
+
+impl<C: Clock + ?Sized> Clock for Arc<C> {
+    fn now(&self) -> SystemTime {
+        (**self).now()
+    }
+}
+
+/// A [Clock] that reads the system time.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// A [Clock] that only moves when told to.
+///
+/// Clones share the same time, so a test can keep a clone to advance a clock it has handed
+/// to a [crate::Database].
+#[derive(Clone, Debug)]
+pub struct MockClock {
+    now: Arc<Mutex<SystemTime>>,
+}
+
+impl MockClock {
+    /// Construct a clock stopped at the given time.
+    pub fn new(now: SystemTime) -> Self {
+        MockClock {
+            now: Arc::new(Mutex::new(now)),
+        }
+    }
+
+    pub fn set(&self, now: SystemTime) {
+        *self.now.lock().unwrap() = now;
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        *self.now.lock().unwrap() += duration;
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> SystemTime {
+        *self.now.lock().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::dynamic_firestore_client::test_client;
+    use crate::{Database, Timestamp};
+
+    #[tokio::test]
+    async fn test_mock_clock() {
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000);
+        let clock = MockClock::new(start);
+        let db = Database::new_from_client(test_client(), "my-project").with_clock(clock.clone());
+
+        assert_eq!(
+            Timestamp {
+                seconds: 1_000,
+                nanos: 0
+            },
+            db.now()
+        );
+
+        clock.advance(Duration::from_millis(1_500));
+        assert_eq!(
+            Timestamp {
+                seconds: 1_001,
+                nanos: 500_000_000
+            },
+            db.now()
+        );
+
+        clock.set(start);
+        assert_eq!(start, clock.now());
+    }
+}
@@ -1,13 +1,153 @@
+use crate::cache::{CacheConfig, DocumentCache};
+use crate::clock::{Clock, SystemClock};
+use crate::counter::ShardedCounter;
 use crate::dynamic_firestore_client::SharedFirestoreClient;
-use crate::identifiers::{CollectionName, DocumentName, QualifyDocumentName};
-use crate::list_response::ListResponse;
+use crate::error::{
+    from_document, to_document, DeserializeError, FirestoreError, MissingDocuments, ValidationError,
+};
+use crate::identifiers::{CollectionName, DocumentName, QualifyDocumentName, MAX_PATH_DEPTH};
+use crate::list_response::{KeyChunks, ListResponse};
+use crate::offline::{ApplyOutcome, OfflineQueue};
+use crate::query::{CountCache, FieldOp, Query};
+use crate::retry::{Idempotency, RetryPolicy};
+use crate::transaction::Transaction;
+use crate::transforms::{to_array_value, transform, transform_fields, TransformResult, Transforms};
+use crate::{NamedDocument, Timestamp, WriteResult};
 use firestore_serde::firestore::{
-    precondition::ConditionType, CreateDocumentRequest, DeleteDocumentRequest, GetDocumentRequest,
-    Precondition, UpdateDocumentRequest,
+    batch_get_documents_response::Result as BatchGetResult,
+    document_transform::{field_transform::TransformType, FieldTransform},
+    get_document_request::ConsistencySelector,
+    precondition::ConditionType,
+    value::ValueType,
+    write::Operation,
+    BatchGetDocumentsRequest, CommitRequest, CreateDocumentRequest, Document, DocumentMask,
+    DocumentTransform, GetDocumentRequest, ListCollectionIdsRequest, ListDocumentsRequest,
+    ListDocumentsResponse, Precondition, UpdateDocumentRequest, Value, Write,
 };
+use prost::Message;
 use serde::{de::DeserializeOwned, Serialize};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::future::Future;
+use std::io::{ErrorKind, Read};
 use std::marker::PhantomData;
-use tonic::Code;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio_stream::StreamExt;
+use tonic::{Code, Status};
+
+/// The outcome of fetching several documents at once with [Collection::get_many].
+#[derive(Debug)]
+pub struct GetManyResult<T> {
+    /// Documents that were found and deserialized, in the order their keys were given.
+    pub found: Vec<NamedDocument<T>>,
+
+    /// Keys for which no document exists.
+    pub missing: Vec<DocumentName>,
+
+    /// Documents that exist but could not be deserialized into `T`.
+    pub errors: Vec<(DocumentName, DeserializeError)>,
+}
+
+/// Whether [Collection::upsert_returning_status] created a document or overwrote one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WriteOutcome {
+    /// No document existed with the key, so one was created.
+    Created,
+
+    /// An existing document was overwritten.
+    Updated,
+}
+
+/// How up to date the version of a document read by [Collection::get_with_consistency] must
+/// be.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum Consistency {
+    /// The latest version of the document, as read by `get`.
+    #[default]
+    Strong,
+
+    /// The version of the document as it was at the given time, which must be within the
+    /// past hour (or, with point-in-time recovery enabled, a whole minute within its
+    /// retention period).
+    ReadTime(Timestamp),
+
+    /// The version of the document as it was the given duration ago, per the collection's
+    /// clock. Reads at least ten seconds stale can be served by the nearest replica, so are
+    /// faster than strong reads.
+    Stale(Duration),
+}
+
+/// The outcome of checking every document in a collection against its type with
+/// [Collection::validate_schema].
+#[derive(Debug)]
+pub struct SchemaReport {
+    /// The number of documents that deserialized into the collection's type.
+    pub conforming: usize,
+
+    /// Documents that could not be deserialized, in the order they were listed.
+    pub non_conforming: Vec<(DocumentName, DeserializeError)>,
+}
+
+impl SchemaReport {
+    /// Returns `true` if every document deserialized.
+    pub fn is_valid(&self) -> bool {
+        self.non_conforming.is_empty()
+    }
+
+    /// Returns the number of documents checked.
+    pub fn total(&self) -> usize {
+        self.conforming + self.non_conforming.len()
+    }
+}
+
+/// Number of documents fetched per page when reading a whole collection, e.g. by
+/// [Collection::validate_schema] and [Collection::export_binary]. This is below the limit on
+/// writes per commit, so that [Collection::rename_field] can write a page in one commit.
+const SCAN_PAGE_SIZE: i32 = 300;
+
+/// Maximum number of writes Firestore accepts in a single commit.
+pub(crate) const MAX_WRITES_PER_COMMIT: usize = 500;
+
+/// Default limit on how many levels of subcollections `delete_recursive` will descend into.
+///
+/// This matches the maximum subcollection depth that Firestore allows.
+pub const DEFAULT_MAX_DELETE_DEPTH: usize = MAX_PATH_DEPTH;
+
+/// Field set to the server's time by [Collection::touch_many], so that each document's
+/// `update_time` advances. Types stored in a touched collection should ignore it, which
+/// serde does unless they deny unknown fields.
+pub const TOUCH_FIELD: &str = "_touched";
+
+/// A single write in a batch applied with [Collection::apply], addressed by key `K`.
+pub enum Op<K, T> {
+    /// Create a document, failing the batch if it already exists. A batch with a create is not
+    /// retried unless the retry policy forces it.
+    CreateIfAbsent(K, T),
+
+    /// Overwrite a document, failing the batch if it does not exist.
+    UpdateIfPresent(K, T),
+
+    /// Overwrite a document, creating it if it does not exist.
+    Upsert(K, T),
+
+    /// Delete a document, failing the batch if it does not exist.
+    Delete(K),
+
+    /// Apply transforms to a document, creating it if it does not exist. If any of them is
+    /// an increment, the batch is not retried unless the retry policy forces it.
+    Transform(K, Transforms),
+}
+
+type Validator<T> = Arc<dyn Fn(&T) -> Result<(), String> + Send + Sync>;
+
+/// The name of each document written by a batch, with the value written to it (or `None` if it
+/// was deleted or transformed).
+type Written<T> = Vec<(DocumentName, Option<T>)>;
+
+/// The field in which a collection with case-insensitive IDs stores each document's ID as it
+/// was given when the document was written.
+const ORIGINAL_ID_FIELD: &str = "_original_id";
 
 /// Represents a collection of documents in a Firestore database.
 ///
@@ -20,6 +160,21 @@ where
 {
     db: SharedFirestoreClient,
     name: CollectionName,
+    retry: RetryPolicy,
+    /// Checked against every document before it is written, if set.
+    validator: Option<Validator<T>>,
+    /// Whether document IDs are lowercased before use; see
+    /// [Collection::with_case_insensitive_ids].
+    case_insensitive_ids: bool,
+    /// Whether null fields are removed from documents before they are written; see
+    /// [Collection::with_skip_nulls].
+    skip_nulls: bool,
+    /// Documents read or written through this collection, if caching is enabled.
+    cache: Option<Arc<DocumentCache<T>>>,
+    /// Holds writes made with [Collection::apply_or_queue] while Firestore is unreachable.
+    offline_queue: Option<OfflineQueue>,
+    clock: Arc<dyn Clock>,
+    count_cache: CountCache,
     _ph: PhantomData<T>,
 }
 
@@ -32,7 +187,121 @@ where
         Collection {
             db,
             name,
-            _ph: PhantomData::default(),
+            retry: RetryPolicy::default(),
+            validator: None,
+            case_insensitive_ids: false,
+            skip_nulls: false,
+            cache: None,
+            offline_queue: None,
+            clock: Arc::new(SystemClock),
+            count_cache: CountCache::default(),
+            _ph: PhantomData,
+        }
+    }
+
+    /// Use the given policy to retry requests made through this collection that fail with a
+    /// transient error. See [RetryPolicy] for which operations are retried.
+    pub fn with_retry_policy(self, retry: RetryPolicy) -> Self {
+        let retry = retry.inherit_token_refresher(&self.retry);
+        Collection { retry, ..self }
+    }
+
+    /// Use the given clock to expire cached counts (see [Query::count_cached]). Collections
+    /// obtained from a [crate::Database] use its clock.
+    pub fn with_clock(self, clock: impl Clock + 'static) -> Self {
+        Collection {
+            clock: Arc::new(clock),
+            ..self
+        }
+    }
+
+    /// Hold writes made with [Collection::apply_or_queue] in the given queue while Firestore
+    /// is unreachable. Subcollections inherit it. Collections obtained from a
+    /// [crate::Database] use its queue, if it has one.
+    pub fn with_offline_queue(self, offline_queue: OfflineQueue) -> Self {
+        Collection {
+            offline_queue: Some(offline_queue),
+            ..self
+        }
+    }
+
+    /// Check every document written through this collection with `validator` before it is
+    /// serialized. If it returns an error, the write is not made and a [ValidationError]
+    /// carrying its message is returned instead.
+    ///
+    /// Applies to `create`, `create_with_key`, `try_create`, `upsert`, `update`,
+    /// `update_diff`, `apply` and `get_or_insert_with`. Subcollections do not inherit it.
+    pub fn with_validator(
+        self,
+        validator: impl Fn(&T) -> Result<(), String> + Send + Sync + 'static,
+    ) -> Self {
+        Collection {
+            validator: Some(Arc::new(validator)),
+            ..self
+        }
+    }
+
+    /// Treat document IDs in this collection as case-insensitive, so that e.g. `User1` and
+    /// `user1` refer to the same document.
+    ///
+    /// Firestore IDs are case-sensitive, so documents are stored under the lowercase form of
+    /// their ID, and the ID as given is kept in a field called `_original_id` (which
+    /// overwrites any field of `T` with the same name, and is ignored when deserializing
+    /// unless `T` has such a field). Every operation that takes a key lowercases it first.
+    ///
+    /// Documents written before this mode was enabled may be stored under an ID that is not
+    /// lowercase. So that they can still be found, `get`, `exists` and `get_or_insert_with`
+    /// fall back to the key exactly as given when no document exists under its lowercase
+    /// form, which costs an extra read for every miss. Other operations, including writes,
+    /// only use the lowercase form; to make old documents behave consistently, rewrite them
+    /// through this collection. Names returned by `list` and queries are the stored,
+    /// lowercase ones.
+    ///
+    /// Subcollections of this collection are nested beneath the lowercase ID of their parent,
+    /// but do not inherit the mode themselves.
+    pub fn with_case_insensitive_ids(self) -> Self {
+        Collection {
+            case_insensitive_ids: true,
+            ..self
+        }
+    }
+
+    /// If `true`, fields whose value serializes as null (such as `None` optionals) are left out
+    /// of documents written through this collection, rather than stored as explicit nulls.
+    /// Off by default.
+    ///
+    /// Nulls are removed from nested maps as well as the top level, but not from arrays, whose
+    /// elements are compared by value by array transforms (see [Collection::array_union]). When
+    /// reading, an absent field deserializes into `None` just as a null does, but the two
+    /// differ in queries: a filter on a field does not match documents that lack it. For
+    /// the same reason, a type that distinguishes a null field from an absent one (such as
+    /// `Option<Option<V>>` with a `deserialize_with` helper) cannot round-trip its null
+    /// state in this mode.
+    ///
+    /// Writes that replace a whole document remove a field that is now `None`. Writes with a
+    /// field mask, such as `update_diff`, do too: the field is in the mask but absent from the
+    /// document, so it is deleted. Writes made in a [crate::Transaction] are not affected.
+    /// Subcollections do not inherit the mode.
+    pub fn with_skip_nulls(self, skip_nulls: bool) -> Self {
+        Collection { skip_nulls, ..self }
+    }
+
+    /// Cache documents read or written through this collection, so that `get` and
+    /// `get_many` can return them without a request.
+    ///
+    /// Writes through this collection update or evict the entries they affect. Writes made
+    /// any other way, including through another `Collection` for the same collection, in a
+    /// transaction, or by another client, do not; until its entry expires after `config.ttl`,
+    /// a stale value may be returned. Only use the cache where that staleness is acceptable.
+    ///
+    /// Lists and queries are not served from the cache. Subcollections do not inherit it.
+    pub fn with_cache(self, config: CacheConfig) -> Self
+    where
+        T: Clone,
+    {
+        Collection {
+            cache: Some(Arc::new(DocumentCache::new(config))),
+            ..self
         }
     }
 
@@ -41,6 +310,60 @@ where
         ListResponse::new(self.name.clone(), self.db.clone())
     }
 
+    /// Returns a stream of the names of every document in this collection, in chunks of
+    /// `chunk_size` (the last chunk may be smaller).
+    ///
+    /// Documents are listed without their fields, one page per chunk, which is cheaper than
+    /// listing whole documents when only their keys are needed (e.g. to fan work out to
+    /// workers). Like [Collection::list], the stream ends early if a page cannot be fetched;
+    /// see its `take_error` method.
+    pub fn stream_keys_chunked(&self, chunk_size: usize) -> KeyChunks {
+        KeyChunks::new(self.name.clone(), self.db.clone(), chunk_size)
+    }
+
+    /// Returns a [Query] matching every document in this collection, which can be narrowed down
+    /// with filters.
+    pub fn query(&self) -> Query<T> {
+        Query::new(self.name.clone(), self.db.clone())
+            .with_retry_policy(self.retry.clone())
+            .with_cache(self.cache.clone())
+            .with_count_cache(self.count_cache.clone(), self.clock.clone())
+    }
+
+    /// Returns a document whose `field` is equal to `value`, or `None` if there is none. If
+    /// several match, which one is returned is unspecified, so this suits fields with unique
+    /// values (e.g. looking up a user by email address).
+    pub async fn find_one(
+        &self,
+        field: &str,
+        value: impl Serialize,
+    ) -> anyhow::Result<Option<NamedDocument<T>>> {
+        let mut query = self.query().where_eq(field, value).limit(1);
+        query.next().await.transpose()
+    }
+
+    /// Returns a [Query] matching the documents in this collection whose keys start with
+    /// `prefix` (e.g. every key starting with a date, for time-bucketed keys), which can be
+    /// narrowed down further.
+    ///
+    /// This is a range filter on document names, so on its own it is served without an index.
+    /// Like any range filter, it can be combined with range filters on other fields, but the
+    /// query then needs a composite index (see [Query::where_gt]).
+    pub fn list_prefix(&self, prefix: &str) -> Query<T> {
+        let prefix = self.normalize_id(prefix);
+        if prefix.is_empty() {
+            return self.query();
+        }
+
+        let query = self
+            .query()
+            .where_name(FieldOp::GreaterThanOrEqual, prefix.as_str());
+        match prefix_successor(&prefix) {
+            Some(end) => query.where_name(FieldOp::LessThan, end.as_str()),
+            None => query,
+        }
+    }
+
     pub fn name(&self) -> CollectionName {
         self.name.clone()
     }
@@ -51,142 +374,2698 @@ where
     {
         Collection {
             db: self.db.clone(),
-            name: self.name.subcollection(name, collection),
-            _ph: PhantomData::default(),
+            name: self
+                .name
+                .subcollection(&self.normalize_id(name), collection),
+            retry: self.retry.clone(),
+            validator: None,
+            case_insensitive_ids: false,
+            skip_nulls: false,
+            cache: None,
+            offline_queue: self.offline_queue.clone(),
+            clock: self.clock.clone(),
+            count_cache: CountCache::default(),
+            _ph: PhantomData,
         }
     }
 
+    /// Like `subcollection`, but first checks that the parent document exists, returning an
+    /// error if it does not. This costs a read, and catches typos in the parent's name that
+    /// would otherwise attach documents beneath a nonexistent parent.
+    ///
+    /// Firestore itself does not require a subcollection's parent to exist.
+    pub async fn subcollection_checked<S>(
+        &self,
+        name: &str,
+        collection: &str,
+    ) -> anyhow::Result<Collection<S>>
+    where
+        S: Serialize + DeserializeOwned + Unpin + 'static,
+    {
+        if !self.exists(name).await? {
+            return Err(anyhow::anyhow!(
+                "Cannot use subcollection {} of document {}, because the document does not exist.",
+                collection,
+                self.name.document(name).name()
+            ));
+        }
+        Ok(self.subcollection(name, collection))
+    }
+
+    /// Returns a [ShardedCounter] stored beneath the document with the given name, with its
+    /// shards in a subcollection called `shards`.
+    ///
+    /// The document itself does not need to exist. Returns an error if `num_shards` is zero.
+    pub fn sharded_counter(&self, name: &str, num_shards: usize) -> anyhow::Result<ShardedCounter> {
+        Ok(ShardedCounter::new(
+            self.db.clone(),
+            self.name.subcollection(&self.normalize_id(name), "shards"),
+            num_shards,
+        )?
+        .with_retry_policy(self.retry.clone()))
+    }
+
     /// Create the given document in this collection with the given key.
-    /// Returns an error if the key is already in use (if you intend to replace the
-    /// document in that case, use `upsert` instead.)
+    /// Returns [FirestoreError::AlreadyExists] if the key is already in use (if you intend to
+    /// replace the document in that case, use `upsert` instead.)
     pub async fn create_with_key(
         &self,
         ob: &T,
         key: impl QualifyDocumentName,
-    ) -> anyhow::Result<()> {
-        let mut document = firestore_serde::to_document(ob)?;
+    ) -> Result<WriteResult, FirestoreError> {
+        let document = self.create_document_with_key(ob, key).await?;
+        WriteResult::new(document.update_time).map_err(FirestoreError::Other)
+    }
 
-        document.name = key.qualify(&self.name)?.name();
-        self.db
-            .lock()
-            .await
-            .update_document(UpdateDocumentRequest {
-                document: Some(document),
+    /// Create the given document in this collection with the given key, and return it as
+    /// stored, including any fields set by the server.
+    ///
+    /// Like `create_with_key`, fails if the key is already in use; the error can be downcast
+    /// to [FirestoreError::AlreadyExists].
+    pub async fn create_with_key_returning(
+        &self,
+        ob: &T,
+        key: impl QualifyDocumentName,
+    ) -> anyhow::Result<NamedDocument<T>> {
+        let document = self.create_document_with_key(ob, key).await?;
+        let name = DocumentName::parse(&document.name)?;
+        Ok(NamedDocument::from_proto(name, document)?)
+    }
+
+    /// Write the given document with the given key, unless a stored document with the key has
+    /// a version at least as new, in which case [FirestoreError::Stale] is returned. Versions
+    /// are the values of the top-level field `version_field`, which must be an integer, double,
+    /// timestamp or string; a stored document without the field counts as older.
+    ///
+    /// This gives last-writer-wins-by-version semantics, for example for events that may
+    /// arrive out of order. Firestore preconditions can only compare the server's update
+    /// time, so the stored document is read and compared in a transaction, which fails with an
+    /// `ABORTED` status if the document changes before the write is committed; in that case,
+    /// the call should be retried.
+    pub async fn create_if_newer(
+        &self,
+        ob: &T,
+        key: impl QualifyDocumentName,
+        version_field: &str,
+    ) -> Result<WriteResult, FirestoreError> {
+        let (name, document) = self
+            .to_document_with_key(ob, key)
+            .map_err(FirestoreError::Other)?;
+        let version = document.fields.get(version_field).ok_or_else(|| {
+            FirestoreError::other(anyhow::anyhow!(
+                "Expected document to have version field {}.",
+                version_field
+            ))
+        })?;
+
+        let transaction =
+            Transaction::begin(self.db.clone(), self.name.database_name(), Vec::new())
+                .await
+                .map_err(FirestoreError::Other)?;
+        let checked = async {
+            let stored = transaction
+                .get_document(name.name())
+                .await
+                .map_err(FirestoreError::Other)?;
+            let stored_version = stored
+                .as_ref()
+                .and_then(|stored| stored.fields.get(version_field));
+
+            if let Some(stored_version) = stored_version {
+                let newer =
+                    compare_versions(version, stored_version).map(|o| o == Ordering::Greater);
+                match newer {
+                    Some(true) => (),
+                    Some(false) => return Err(FirestoreError::Stale(name.clone())),
+                    None => {
+                        return Err(FirestoreError::other(anyhow::anyhow!(
+                            "Cannot compare versions {:?} and {:?} of field {}.",
+                            version.value_type,
+                            stored_version.value_type,
+                            version_field
+                        )))
+                    }
+                }
+            }
+            Ok(())
+        }
+        .await;
+
+        if let Err(error) = checked {
+            // The error of the check is more useful than any from rolling back.
+            let _ = transaction.rollback().await;
+            return Err(error);
+        }
+
+        transaction.write_document(name.name(), Some(document), None);
+        let result = transaction.commit().await.map_err(FirestoreError::Other)?;
+        self.cache_insert(name, ob);
+        Ok(result)
+    }
+
+    /// Create a document with the given key, failing if it exists, and return the document
+    /// written.
+    async fn create_document_with_key(
+        &self,
+        ob: &T,
+        key: impl QualifyDocumentName,
+    ) -> Result<Document, FirestoreError> {
+        let (name, document) = self
+            .to_document_with_key(ob, key)
+            .map_err(FirestoreError::Other)?;
+
+        let request = UpdateDocumentRequest {
+            document: Some(document),
+            current_document: Some(Precondition {
+                condition_type: Some(ConditionType::Exists(false)),
+            }),
+            ..UpdateDocumentRequest::default()
+        };
+        // Not retried by default: if the first attempt succeeded but its response was lost,
+        // a retry would fail its precondition against our own write.
+        let result = self
+            .run(
+                Idempotency::NotIdempotent,
+                &request,
+                |db, request| async move { db.clone().update_document(request.clone()).await },
+            )
+            .await;
+
+        match result {
+            Ok(result) => {
+                self.cache_insert(name, ob);
+                Ok(result.into_inner())
+            }
+            Err(e) if e.code() == Code::AlreadyExists => Err(FirestoreError::AlreadyExists(name)),
+            Err(e) => Err(FirestoreError::other(e)),
+        }
+    }
+
+    /// Create the given document in this collection with the given key, applying
+    /// `transforms` (such as [Transforms::server_timestamp]) in the same atomic write, so
+    /// that the new document never exists without the fields they set. Returns the result
+    /// of each transform.
+    ///
+    /// Like `create_with_key`, returns [FirestoreError::AlreadyExists] if the key is already
+    /// in use, in which case neither the document nor the transforms are written.
+    pub async fn create_with_transforms(
+        &self,
+        ob: &T,
+        key: impl QualifyDocumentName,
+        transforms: Transforms,
+    ) -> Result<TransformResult, FirestoreError> {
+        let (name, document) = self
+            .to_document_with_key(ob, key)
+            .map_err(FirestoreError::Other)?;
+        let request = CommitRequest {
+            database: self.name.database_name(),
+            writes: vec![Write {
+                operation: Some(Operation::Update(document)),
+                update_transforms: transforms.into_field_transforms(),
                 current_document: Some(Precondition {
                     condition_type: Some(ConditionType::Exists(false)),
                 }),
-                ..UpdateDocumentRequest::default()
-            })
-            .await?;
-        Ok(())
+                ..Write::default()
+            }],
+            ..CommitRequest::default()
+        };
+        // Not retried by default: if the first attempt succeeded but its response was lost,
+        // a retry would fail its precondition against our own write.
+        let result = self
+            .run(
+                Idempotency::NotIdempotent,
+                &request,
+                |db, request| async move { db.clone().commit(request.clone()).await },
+            )
+            .await;
+
+        let result = match result {
+            Ok(result) => result.into_inner(),
+            Err(e) if e.code() == Code::AlreadyExists => {
+                return Err(FirestoreError::AlreadyExists(name))
+            }
+            Err(e) => return Err(FirestoreError::other(e)),
+        };
+        // The stored document differs from `ob` by the transformed fields, so is not cached.
+        self.cache_remove(&name);
+
+        let write_result = result
+            .write_results
+            .into_iter()
+            .next()
+            .ok_or_else(|| anyhow::anyhow!("Expected commit to return a write result."))
+            .map_err(FirestoreError::Other)?;
+        let update_time = write_result
+            .update_time
+            .or(result.commit_time)
+            .ok_or_else(|| anyhow::anyhow!("Expected write to return a time."))
+            .map_err(FirestoreError::Other)?;
+        Ok(TransformResult {
+            update_time,
+            values: write_result.transform_results,
+        })
     }
 
     /// Create the given document in this collection with the given key.
     /// Returns `true` if the document was created, or `false` if it already existed.
     pub async fn try_create(&self, ob: &T, key: impl QualifyDocumentName) -> anyhow::Result<bool> {
-        let mut document = firestore_serde::to_document(ob)?;
-        document.name = key.qualify(&self.name)?.name();
+        let (name, document) = self.to_document_with_key(ob, key)?;
+        let request = UpdateDocumentRequest {
+            document: Some(document),
+            current_document: Some(Precondition {
+                condition_type: Some(ConditionType::Exists(false)),
+            }),
+            ..UpdateDocumentRequest::default()
+        };
+        // Not retried by default: if the first attempt succeeded but its response was lost,
+        // a retry would fail its precondition against our own write.
         let result = self
-            .db
-            .lock()
-            .await
-            .update_document(UpdateDocumentRequest {
-                document: Some(document),
-                current_document: Some(Precondition {
-                    condition_type: Some(ConditionType::Exists(false)),
-                }),
-                ..UpdateDocumentRequest::default()
-            })
+            .run(
+                Idempotency::NotIdempotent,
+                &request,
+                |db, request| async move { db.clone().update_document(request.clone()).await },
+            )
             .await;
 
         match result {
-            Ok(_) => Ok(true),
+            Ok(_) => {
+                self.cache_insert(name, ob);
+                Ok(true)
+            }
             Err(e) if e.code() == Code::AlreadyExists => Ok(false),
             Err(e) => Err(e.into()),
         }
     }
 
+    /// Get the document with the given key, or if it does not exist, create it with the
+    /// value produced by awaiting `f()` and return that.
+    ///
+    /// `f` is only called if the document does not exist. If another writer creates the
+    /// document between our read and our create, their document is fetched and returned
+    /// instead, and the value produced by `f` is discarded.
+    pub async fn get_or_insert_with<F, Fut>(
+        &self,
+        key: impl QualifyDocumentName,
+        f: F,
+    ) -> anyhow::Result<T>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = T>,
+    {
+        let name = key.qualify(&self.name)?;
+        if let Some(ob) = self.get_if_exists(&name).await? {
+            return Ok(ob);
+        }
+
+        let ob = f().await;
+        if self.try_create(&ob, &name).await? {
+            return Ok(ob);
+        }
+
+        self.get(&name).await
+    }
+
+    /// Get the document with the given key, or `None` if it does not exist.
+    ///
+    /// Unlike `get`, a missing document is not an error; an error is still returned if the
+    /// document exists but cannot be deserialized into `T` (as a [DeserializeError]).
+    pub async fn get_if_exists(&self, key: impl QualifyDocumentName) -> anyhow::Result<Option<T>> {
+        for name in self.lookup_names(key)? {
+            if let Some(ob) = self.get_name_if_exists(&name).await? {
+                return Ok(Some(ob));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Get the document with the given name, or `None` if it does not exist.
+    async fn get_name_if_exists(&self, name: &DocumentName) -> anyhow::Result<Option<T>> {
+        if let Some(ob) = self.cached(name) {
+            return Ok(Some(ob));
+        }
+
+        let request = GetDocumentRequest {
+            name: name.name(),
+            ..GetDocumentRequest::default()
+        };
+        let result = self
+            .run(
+                Idempotency::Idempotent,
+                &request,
+                |db, request| async move { db.clone().get_document(request.clone()).await },
+            )
+            .await;
+
+        match result {
+            Ok(document) => {
+                let ob = from_document(document.into_inner())?;
+                self.cache_insert(name.clone(), &ob);
+                Ok(Some(ob))
+            }
+            Err(e) if e.code() == Code::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
     /// Add the given document to this collection, assigning it a new key at random.
     pub async fn create(&self, ob: &T) -> anyhow::Result<DocumentName> {
-        let document = firestore_serde::to_document(ob)?;
+        let document = self.to_document(ob)?;
+        let request = CreateDocumentRequest {
+            document: Some(document),
+            collection_id: self.name.leaf_name(),
+            parent: self.name.parent().name(),
+            ..CreateDocumentRequest::default()
+        };
         let result = self
-            .db
-            .lock()
-            .await
-            .create_document(CreateDocumentRequest {
-                document: Some(document),
-                collection_id: self.name.leaf_name(),
-                parent: self.name.parent().name(),
-                ..CreateDocumentRequest::default()
-            })
+            .run(
+                Idempotency::NotIdempotent,
+                &request,
+                |db, request| async move { db.clone().create_document(request.clone()).await },
+            )
             .await?
             .into_inner();
         Ok(DocumentName::parse(&result.name)?)
     }
 
     /// Overwrite the given document to this collection, creating a new document if one does not exist.
-    pub async fn upsert(&self, ob: &T, key: impl QualifyDocumentName) -> anyhow::Result<()> {
-        let mut document = firestore_serde::to_document(ob)?;
-        document.name = key.qualify(&self.name)?.name();
-        self.db
-            .lock()
-            .await
-            .update_document(UpdateDocumentRequest {
-                document: Some(document),
-                ..UpdateDocumentRequest::default()
-            })
-            .await?;
-        Ok(())
+    pub async fn upsert(
+        &self,
+        ob: &T,
+        key: impl QualifyDocumentName,
+    ) -> anyhow::Result<WriteResult> {
+        let result = self.upsert_document(ob, key).await?;
+        WriteResult::new(result.update_time)
+    }
+
+    /// Like `upsert`, but also reports whether the document was created or overwritten.
+    ///
+    /// This costs nothing extra: rather than reading the document before writing it (which
+    /// would take a transaction and an extra read), the outcome is determined from the
+    /// written document, which was created by this write if its creation time equals its
+    /// update time. If the write is retried after an attempt that succeeded without a
+    /// response, the retry reports [WriteOutcome::Updated] even though the document is new.
+    pub async fn upsert_returning_status(
+        &self,
+        ob: &T,
+        key: impl QualifyDocumentName,
+    ) -> anyhow::Result<WriteOutcome> {
+        let result = self.upsert_document(ob, key).await?;
+        Ok(write_outcome(&result))
+    }
+
+    /// Overwrite or create a document, returning it as written.
+    async fn upsert_document(
+        &self,
+        ob: &T,
+        key: impl QualifyDocumentName,
+    ) -> anyhow::Result<Document> {
+        let (name, document) = self.to_document_with_key(ob, key)?;
+        let request = UpdateDocumentRequest {
+            document: Some(document),
+            ..UpdateDocumentRequest::default()
+        };
+        let result = self
+            .run(
+                Idempotency::Idempotent,
+                &request,
+                |db, request| async move { db.clone().update_document(request.clone()).await },
+            )
+            .await?
+            .into_inner();
+        self.cache_insert(name, ob);
+        Ok(result)
     }
 
     /// Update the given document, returning an error if it does not exist.
-    pub async fn update(&self, ob: &T, key: impl QualifyDocumentName) -> anyhow::Result<()> {
-        let mut document = firestore_serde::to_document(ob)?;
-        document.name = key.qualify(&self.name)?.name();
-        self.db
-            .lock()
+    pub async fn update(
+        &self,
+        ob: &T,
+        key: impl QualifyDocumentName,
+    ) -> anyhow::Result<WriteResult> {
+        let (name, document) = self.to_document_with_key(ob, key)?;
+        let request = UpdateDocumentRequest {
+            document: Some(document),
+            current_document: Some(Precondition {
+                condition_type: Some(ConditionType::Exists(true)),
+            }),
+            ..UpdateDocumentRequest::default()
+        };
+        let result = self
+            .run(
+                Idempotency::Idempotent,
+                &request,
+                |db, request| async move { db.clone().update_document(request.clone()).await },
+            )
+            .await?
+            .into_inner();
+        self.cache_insert(name, ob);
+        WriteResult::new(result.update_time)
+    }
+
+    /// Update only the listed fields of the given document to their values in `ob`, leaving
+    /// its other fields untouched on the server, even those present in `ob`. Returns an error
+    /// if the document does not exist.
+    ///
+    /// Each of `fields` is a field path: a dotted path such as `address.city` names the
+    /// `city` field of the map in the `address` field, and updates it without touching the
+    /// other fields of `address`, whereas `address` replaces the whole map. A field name
+    /// which is not a simple identifier (letters, digits and `_`, not starting with a digit)
+    /// must be quoted with backticks, e.g. `` `first-name` ``. A listed field which `ob` does
+    /// not have is deleted from the document.
+    pub async fn update_fields(
+        &self,
+        key: impl QualifyDocumentName,
+        ob: &T,
+        fields: &[&str],
+    ) -> anyhow::Result<WriteResult> {
+        if fields.is_empty() {
+            return Err(anyhow::anyhow!("Expected at least one field to update."));
+        }
+
+        let (name, document) = self.to_document_with_key(ob, key)?;
+        let request = UpdateDocumentRequest {
+            document: Some(document),
+            update_mask: Some(DocumentMask {
+                field_paths: fields.iter().map(|field| field.to_string()).collect(),
+            }),
+            current_document: Some(Precondition {
+                condition_type: Some(ConditionType::Exists(true)),
+            }),
+            ..UpdateDocumentRequest::default()
+        };
+        let result = self
+            .run(
+                Idempotency::Idempotent,
+                &request,
+                |db, request| async move { db.clone().update_document(request.clone()).await },
+            )
+            .await?
+            .into_inner();
+        // The stored document may differ from `ob` in the fields not updated.
+        self.cache_remove(&name);
+        WriteResult::new(result.update_time)
+    }
+
+    /// Update the given document only if it has not been written since `update_time`, the
+    /// update time of the document as read (e.g. from [Collection::get_with_metadata] or a
+    /// previous write's [WriteResult]). Returns [FirestoreError::Stale] if it has been, or if
+    /// it does not exist.
+    ///
+    /// The precondition is sent exactly as given, so `update_time` should be passed on as
+    /// received from Firestore, without a round trip through a less precise type (such as
+    /// one with millisecond precision), which would make the precondition fail.
+    pub async fn update_if_unchanged(
+        &self,
+        ob: &T,
+        key: impl QualifyDocumentName,
+        update_time: &Timestamp,
+    ) -> Result<WriteResult, FirestoreError> {
+        let (name, document) = self
+            .to_document_with_key(ob, key)
+            .map_err(FirestoreError::Other)?;
+        let request = UpdateDocumentRequest {
+            document: Some(document),
+            current_document: Some(Precondition {
+                condition_type: Some(ConditionType::UpdateTime(update_time.clone())),
+            }),
+            ..UpdateDocumentRequest::default()
+        };
+        // Not retried by default, since a retry of a write which was applied would fail its
+        // precondition, and be reported as stale.
+        let result = self
+            .run(
+                Idempotency::NotIdempotent,
+                &request,
+                |db, request| async move { db.clone().update_document(request.clone()).await },
+            )
+            .await;
+
+        match result {
+            Ok(result) => {
+                self.cache_insert(name, ob);
+                WriteResult::new(result.into_inner().update_time).map_err(FirestoreError::Other)
+            }
+            Err(e) if e.code() == Code::FailedPrecondition => {
+                self.cache_remove(&name);
+                Err(FirestoreError::Stale(name))
+            }
+            Err(e) => Err(FirestoreError::other(e)),
+        }
+    }
+
+    /// Update only the fields of the given document which differ from what is stored,
+    /// returning `true` if a write was made or `false` if nothing had changed. Returns an
+    /// error if the document does not exist.
+    ///
+    /// Top-level fields are compared after serialization; fields absent from `ob` are
+    /// removed. The write is conditional on the document not having changed since it was
+    /// read, so a concurrent edit makes it fail rather than be clobbered.
+    pub async fn update_diff(&self, ob: &T, key: impl QualifyDocumentName) -> anyhow::Result<bool> {
+        let name = key.qualify(&self.name)?;
+        let request = GetDocumentRequest {
+            name: self.qualify(&name)?.name(),
+            ..GetDocumentRequest::default()
+        };
+        let current = self
+            .run(
+                Idempotency::Idempotent,
+                &request,
+                |db, request| async move { db.clone().get_document(request.clone()).await },
+            )
+            .await?
+            .into_inner();
+
+        let (name, document) = self.to_document_with_key(ob, &name)?;
+        let field_paths = changed_fields(&current, &document);
+        if field_paths.is_empty() {
+            self.cache_insert(name, ob);
+            return Ok(false);
+        }
+
+        let request = UpdateDocumentRequest {
+            document: Some(document),
+            update_mask: Some(DocumentMask { field_paths }),
+            current_document: Some(Precondition {
+                condition_type: current.update_time.map(ConditionType::UpdateTime),
+            }),
+            ..UpdateDocumentRequest::default()
+        };
+        // Not retried by default, since a retry of a write which was applied would fail its
+        // precondition.
+        self.run(
+            Idempotency::NotIdempotent,
+            &request,
+            |db, request| async move { db.clone().update_document(request.clone()).await },
+        )
+        .await?;
+        self.cache_insert(name, ob);
+
+        Ok(true)
+    }
+
+    /// Atomically add each of `elements` to the array field `field` of the given document,
+    /// unless an equal element is already present. If the field is not an array, it is
+    /// replaced by one containing `elements`; if the document does not exist, it is created.
+    ///
+    /// Elements are compared by value, after serialization. A struct is serialized as a map,
+    /// which is equal to another map only if both have exactly the same keys with equal
+    /// values (the order of keys does not matter). Fields serialized as `null`, such as
+    /// `None`s, count as keys too.
+    pub async fn array_union<V: Serialize>(
+        &self,
+        key: impl QualifyDocumentName,
+        field: &str,
+        elements: &[V],
+    ) -> anyhow::Result<WriteResult> {
+        let elements = to_array_value(elements)?;
+        self.transform(key, field, TransformType::AppendMissingElements(elements))
             .await
-            .update_document(UpdateDocumentRequest {
-                document: Some(document),
-                current_document: Some(Precondition {
-                    condition_type: Some(ConditionType::Exists(true)),
-                }),
-                ..UpdateDocumentRequest::default()
-            })
-            .await?;
-        Ok(())
     }
 
-    /// Get the document with a given key.
-    pub async fn get(&self, key: impl QualifyDocumentName) -> anyhow::Result<T> {
-        let document = self
-            .db
-            .lock()
+    /// Atomically remove every element equal to one of `elements` from the array field
+    /// `field` of the given document. If the field is not an array, it is replaced by an
+    /// empty array; if the document does not exist, it is created.
+    ///
+    /// Elements are compared by value, as in [Collection::array_union]: to remove a struct,
+    /// pass a value which serializes to exactly the same map as the element stored.
+    pub async fn array_remove<V: Serialize>(
+        &self,
+        key: impl QualifyDocumentName,
+        field: &str,
+        elements: &[V],
+    ) -> anyhow::Result<WriteResult> {
+        let elements = to_array_value(elements)?;
+        self.transform(key, field, TransformType::RemoveAllFromArray(elements))
             .await
-            .get_document(GetDocumentRequest {
-                name: key.qualify(&self.name)?.name(),
-                ..GetDocumentRequest::default()
-            })
+    }
+
+    /// Atomically remove each of `remove` from the array field `field` of the given document,
+    /// then add each of `add` that is not already present, as `array_remove` followed by
+    /// `array_union` would, but in a single commit, so that no other write is applied in
+    /// between. The document is created if it does not exist.
+    ///
+    /// Firestore allows only one transform of a field per write, so the removal and the
+    /// addition are sent as consecutive writes to the document in the same commit, and are
+    /// applied in that order. An element in both `remove` and `add` therefore ends up present,
+    /// moved to the end of the array.
+    pub async fn array_replace<V: Serialize>(
+        &self,
+        key: impl QualifyDocumentName,
+        field: &str,
+        remove: &[V],
+        add: &[V],
+    ) -> anyhow::Result<WriteResult> {
+        let name = self.qualify(key)?;
+        let write = |transform_type| Write {
+            operation: Some(Operation::Transform(DocumentTransform {
+                document: name.name(),
+                field_transforms: vec![FieldTransform {
+                    field_path: field.to_string(),
+                    transform_type: Some(transform_type),
+                }],
+            })),
+            ..Write::default()
+        };
+        let request = CommitRequest {
+            database: self.name.database_name(),
+            writes: vec![
+                write(TransformType::RemoveAllFromArray(to_array_value(remove)?)),
+                write(TransformType::AppendMissingElements(to_array_value(add)?)),
+            ],
+            ..CommitRequest::default()
+        };
+
+        // Replacing the same elements twice has the same effect as doing so once.
+        let result = self
+            .run(
+                Idempotency::Idempotent,
+                &request,
+                |db, request| async move { db.clone().commit(request.clone()).await },
+            )
             .await?
             .into_inner();
+        self.cache_remove(&name);
+        WriteResult::new(result.commit_time)
+    }
 
-        firestore_serde::from_document(document)
-            .map_err(|_| anyhow::anyhow!("Error deserializing."))
+    /// Atomically add `by` (which may be negative) to the integer field `field` of the given
+    /// document, returning the field's new value. A missing field is treated as zero, but
+    /// like `update`, this returns an error if the document does not exist.
+    ///
+    /// Firestore sustains roughly one write per second to any single document. Incrementing
+    /// the same document faster than that results in contention errors; for counters that
+    /// need a higher write rate, use a [ShardedCounter].
+    ///
+    /// Increments are not idempotent, so are only retried if the policy forces it. An error
+    /// is returned, after the increment is applied, if the field holds a value that is not an
+    /// integer.
+    pub async fn increment(
+        &self,
+        key: impl QualifyDocumentName,
+        field: &str,
+        by: i64,
+    ) -> anyhow::Result<i64> {
+        let value = self
+            .increment_existing(key, field, Transforms::new().increment(field, by))
+            .await?;
+        match value.value_type {
+            Some(ValueType::IntegerValue(value)) => Ok(value),
+            _ => Err(anyhow::anyhow!(
+                "Expected field {} to hold an integer, but got {:?}.",
+                field,
+                value
+            )),
+        }
     }
 
-    /// Delete the document with a given key.
-    pub async fn delete(&self, key: impl QualifyDocumentName) -> anyhow::Result<()> {
-        let name = key.qualify(&self.name)?.name();
-        self.db
-            .lock()
-            .await
-            .delete_document(DeleteDocumentRequest {
-                name,
+    /// Like `increment`, but adds a double, returning the field's new value. The field holds
+    /// a double afterwards, even if it held an integer before.
+    ///
+    /// The same write rate limit applies; see [ShardedCounter] for frequently-updated totals.
+    pub async fn increment_double(
+        &self,
+        key: impl QualifyDocumentName,
+        field: &str,
+        by: f64,
+    ) -> anyhow::Result<f64> {
+        let value = self
+            .increment_existing(key, field, Transforms::new().increment_double(field, by))
+            .await?;
+        match value.value_type {
+            Some(ValueType::DoubleValue(value)) => Ok(value),
+            _ => Err(anyhow::anyhow!(
+                "Expected field {} to hold a double, but got {:?}.",
+                field,
+                value
+            )),
+        }
+    }
+
+    /// Apply `increment`, a single increment of the field `field`, to the given document,
+    /// failing if the document does not exist, and return the field's new value.
+    async fn increment_existing(
+        &self,
+        key: impl QualifyDocumentName,
+        field: &str,
+        increment: Transforms,
+    ) -> anyhow::Result<Value> {
+        let name = self.qualify(key)?;
+        let request = CommitRequest {
+            database: self.name.database_name(),
+            writes: vec![Write {
+                operation: Some(Operation::Transform(DocumentTransform {
+                    document: name.name(),
+                    field_transforms: increment.into_field_transforms(),
+                })),
                 current_document: Some(Precondition {
                     condition_type: Some(ConditionType::Exists(true)),
                 }),
+                ..Write::default()
+            }],
+            ..CommitRequest::default()
+        };
+        let result = self
+            .run(
+                Idempotency::NotIdempotent,
+                &request,
+                |db, request| async move { db.clone().commit(request.clone()).await },
+            )
+            .await?
+            .into_inner();
+        self.cache_remove(&name);
+
+        result
+            .write_results
+            .into_iter()
+            .next()
+            .and_then(|result| result.transform_results.into_iter().next())
+            .ok_or_else(|| {
+                anyhow::anyhow!("Expected increment of {} to return the new value.", field)
             })
-            .await?;
-        Ok(())
+    }
+
+    async fn transform(
+        &self,
+        key: impl QualifyDocumentName,
+        field: &str,
+        transform_type: TransformType,
+    ) -> anyhow::Result<WriteResult> {
+        let name = self.qualify(key)?;
+        // Adding or removing the same elements twice has the same effect as doing so once.
+        let result = transform(
+            &self.db,
+            &self.retry,
+            Idempotency::Idempotent,
+            &name,
+            field,
+            transform_type,
+        )
+        .await?;
+        self.cache_remove(&name);
+        WriteResult::new(result.update_time)
+    }
+
+    /// Atomically apply every one of `transforms` to the given document in a single write,
+    /// creating the document if it does not exist. Returns the result of each transform.
+    ///
+    /// Retried only if none of the transforms is an increment, unless the policy forces it.
+    pub async fn apply_transforms(
+        &self,
+        key: impl QualifyDocumentName,
+        transforms: Transforms,
+    ) -> anyhow::Result<TransformResult> {
+        if transforms.is_empty() {
+            return Err(anyhow::anyhow!("Expected at least one transform to apply."));
+        }
+
+        let name = self.qualify(key)?;
+        let result = transform_fields(
+            &self.db,
+            &self.retry,
+            transforms.idempotency(),
+            &name,
+            transforms.into_field_transforms(),
+        )
+        .await?;
+        self.cache_remove(&name);
+
+        let update_time = result
+            .update_time
+            .ok_or_else(|| anyhow::anyhow!("Expected write to return a time."))?;
+        Ok(TransformResult {
+            update_time,
+            values: result.transform_results,
+        })
+    }
+
+    /// Get the document with a given key.
+    pub async fn get(&self, key: impl QualifyDocumentName) -> anyhow::Result<T> {
+        // The last name is fetched below, so that if it is also missing, its error is returned.
+        let mut names = self.lookup_names(key)?;
+        for name in &names {
+            if let Some(ob) = self.cached(name) {
+                return Ok(ob);
+            }
+        }
+
+        let name = names.pop().expect("Expected at least one name to look up.");
+        for name in &names {
+            if let Some(ob) = self.get_name_if_exists(name).await? {
+                return Ok(ob);
+            }
+        }
+
+        let request = GetDocumentRequest {
+            name: name.name(),
+            ..GetDocumentRequest::default()
+        };
+        let document = self
+            .run(
+                Idempotency::Idempotent,
+                &request,
+                |db, request| async move { db.clone().get_document(request.clone()).await },
+            )
+            .await?
+            .into_inner();
+
+        let ob = from_document(document)?;
+        self.cache_insert(name, &ob);
+        Ok(ob)
+    }
+
+    /// Get the document with a given key, along with its name and the times at which it was
+    /// created and last changed.
+    ///
+    /// Unlike `get`, this always reads the document from Firestore, since cached documents do
+    /// not carry their timestamps.
+    pub async fn get_with_metadata(
+        &self,
+        key: impl QualifyDocumentName,
+    ) -> anyhow::Result<NamedDocument<T>> {
+        let names = self.lookup_names(key)?;
+        let last = names.len() - 1;
+
+        for (i, name) in names.into_iter().enumerate() {
+            let request = GetDocumentRequest {
+                name: name.name(),
+                ..GetDocumentRequest::default()
+            };
+            let result = self
+                .run(
+                    Idempotency::Idempotent,
+                    &request,
+                    |db, request| async move { db.clone().get_document(request.clone()).await },
+                )
+                .await;
+
+            // If the last name is also missing, its error is returned.
+            match result {
+                Err(e) if e.code() == Code::NotFound && i < last => continue,
+                result => {
+                    let document = NamedDocument::from_proto(name.clone(), result?.into_inner())?;
+                    self.cache_insert(name, &document.value);
+                    return Ok(document);
+                }
+            }
+        }
+
+        unreachable!("Expected at least one name to look up.")
+    }
+
+    /// Get the document with a given key, read with the given [Consistency].
+    ///
+    /// Reads of past versions of a document always go to Firestore, since cached documents
+    /// are the latest version; a strong read is the same as `get`.
+    pub async fn get_with_consistency(
+        &self,
+        key: impl QualifyDocumentName,
+        consistency: Consistency,
+    ) -> anyhow::Result<T> {
+        let read_time = match consistency {
+            Consistency::Strong => return self.get(key).await,
+            Consistency::ReadTime(read_time) => read_time,
+            Consistency::Stale(staleness) => stale_read_time(self.clock.now(), staleness),
+        };
+
+        let names = self.lookup_names(key)?;
+        let last = names.len() - 1;
+
+        for (i, name) in names.into_iter().enumerate() {
+            let request = GetDocumentRequest {
+                name: name.name(),
+                consistency_selector: Some(ConsistencySelector::ReadTime(read_time.clone())),
+                ..GetDocumentRequest::default()
+            };
+            let result = self
+                .run(
+                    Idempotency::Idempotent,
+                    &request,
+                    |db, request| async move { db.clone().get_document(request.clone()).await },
+                )
+                .await;
+
+            // If the last name is also missing, its error is returned.
+            match result {
+                Err(e) if e.code() == Code::NotFound && i < last => continue,
+                result => return Ok(from_document(result?.into_inner())?),
+            }
+        }
+
+        unreachable!("Expected at least one name to look up.")
+    }
+
+    /// Get the version of the document with a given key as it was at the given time.
+    /// Equivalent to `get_with_consistency` with [Consistency::ReadTime].
+    pub async fn get_at(
+        &self,
+        key: impl QualifyDocumentName,
+        read_time: Timestamp,
+    ) -> anyhow::Result<T> {
+        self.get_with_consistency(key, Consistency::ReadTime(read_time))
+            .await
+    }
+
+    /// Returns `true` if a document with the given key exists, without fetching its fields.
+    pub async fn exists(&self, key: impl QualifyDocumentName) -> anyhow::Result<bool> {
+        for name in self.lookup_names(key)? {
+            if document_exists(&self.db, &self.retry, &name.name()).await? {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Get the documents with the given keys in a single round-trip.
+    ///
+    /// Keys that do not correspond to a document, and documents that cannot be deserialized
+    /// into `T`, do not cause the whole call to fail; they are reported separately in the
+    /// returned [GetManyResult].
+    pub async fn get_many(
+        &self,
+        keys: impl IntoIterator<Item = impl QualifyDocumentName>,
+    ) -> anyhow::Result<GetManyResult<T>> {
+        let (names, cached, documents) = self.fetch_many(keys).await?;
+
+        let mut result = GetManyResult {
+            found: Vec::new(),
+            missing: Vec::new(),
+            errors: Vec::new(),
+        };
+        for name in names {
+            match self.fetched_document(&name, &cached, &documents) {
+                Some(Ok(document)) => result.found.push(document),
+                Some(Err(e)) => result.errors.push((name, e)),
+                None => result.missing.push(name),
+            }
+        }
+
+        Ok(result)
+    }
+
+    /// Fetch the documents with the given keys which are not in the cache in a single
+    /// round-trip. Returns the qualified names of the keys, the cached values by name, and the
+    /// fetched documents by the string form of their names.
+    async fn fetch_many(
+        &self,
+        keys: impl IntoIterator<Item = impl QualifyDocumentName>,
+    ) -> anyhow::Result<(
+        Vec<DocumentName>,
+        HashMap<DocumentName, T>,
+        HashMap<String, Option<Document>>,
+    )> {
+        let mut names = Vec::new();
+        for key in keys {
+            names.push(self.qualify(key)?);
+        }
+
+        let mut cached = HashMap::new();
+        let mut uncached = Vec::new();
+        for name in &names {
+            match self.cached(name) {
+                Some(value) => {
+                    cached.insert(name.clone(), value);
+                }
+                None => uncached.push(name.clone()),
+            }
+        }
+        let documents =
+            batch_get(&self.db, &self.retry, &self.name.database_name(), &uncached).await?;
+
+        Ok((names, cached, documents))
+    }
+
+    /// Returns the document with the given name from the results of `fetch_many`, caching it
+    /// if it was fetched, or `None` if it does not exist.
+    fn fetched_document(
+        &self,
+        name: &DocumentName,
+        cached: &HashMap<DocumentName, T>,
+        documents: &HashMap<String, Option<Document>>,
+    ) -> Option<Result<NamedDocument<T>, DeserializeError>> {
+        // Values are cloned rather than taken, since a key may be requested more than once.
+        if let (Some(cache), Some(value)) = (&self.cache, cached.get(name)) {
+            return Some(Ok(NamedDocument::new(
+                name.clone(),
+                cache.clone_value(value),
+            )));
+        }
+
+        let document = documents.get(&name.name()).cloned().flatten()?;
+        let result = NamedDocument::from_proto(name.clone(), document);
+        if let Ok(document) = &result {
+            self.cache_insert(name.clone(), &document.value);
+        }
+        Some(result)
+    }
+
+    /// Get the documents with the given keys in a single round-trip, returning one entry per
+    /// key in the same order as the keys, which is `None` if the document does not exist.
+    ///
+    /// Unlike `get_many`, an error is returned if any document cannot be deserialized into `T`.
+    pub async fn get_many_ordered(
+        &self,
+        keys: impl IntoIterator<Item = impl QualifyDocumentName>,
+    ) -> anyhow::Result<Vec<Option<NamedDocument<T>>>> {
+        let (names, cached, documents) = self.fetch_many(keys).await?;
+
+        let mut result = Vec::with_capacity(names.len());
+        for name in &names {
+            result.push(
+                self.fetched_document(name, &cached, &documents)
+                    .transpose()?,
+            );
+        }
+
+        Ok(result)
+    }
+
+    /// Get the documents with the given keys in a single round-trip, returning them in the
+    /// same order as the keys.
+    ///
+    /// Unlike `get_many_ordered`, an error is returned if any of the documents does not exist.
+    /// It is a [MissingDocuments] error listing every one that does not, so that they can all
+    /// be reported at once.
+    pub async fn get_many_strict(
+        &self,
+        keys: impl IntoIterator<Item = impl QualifyDocumentName>,
+    ) -> anyhow::Result<Vec<NamedDocument<T>>> {
+        let mut names = Vec::new();
+        for key in keys {
+            names.push(self.qualify(key)?);
+        }
+        let documents = self.get_many_ordered(&names).await?;
+
+        let mut found = Vec::with_capacity(names.len());
+        let mut missing: Vec<DocumentName> = Vec::new();
+        for (name, document) in names.into_iter().zip(documents) {
+            match document {
+                Some(document) => found.push(document),
+                None if !missing.contains(&name) => missing.push(name),
+                None => (),
+            }
+        }
+
+        if missing.is_empty() {
+            Ok(found)
+        } else {
+            Err(MissingDocuments { names: missing }.into())
+        }
+    }
+
+    /// Get the documents with the given keys in a single round-trip without deserializing
+    /// them, returning one entry per key in the same order as the keys, with `None` if the
+    /// document does not exist.
+    ///
+    /// This is useful for documents that do not all have the shape of `T`; each can be
+    /// deserialized into a type of the caller's choosing with `firestore_serde::from_document`.
+    /// Since the cache holds deserialized values rather than documents, the documents are always
+    /// fetched; those that deserialize into `T` are then cached.
+    pub async fn get_many_raw(
+        &self,
+        keys: impl IntoIterator<Item = impl QualifyDocumentName>,
+    ) -> anyhow::Result<Vec<(DocumentName, Option<Document>)>> {
+        let mut names = Vec::new();
+        for key in keys {
+            names.push(self.qualify(key)?);
+        }
+        let documents =
+            batch_get(&self.db, &self.retry, &self.name.database_name(), &names).await?;
+
+        Ok(names
+            .into_iter()
+            .map(|name| {
+                let document = documents.get(&name.name()).cloned().flatten();
+                if let (Some(_), Some(document)) = (&self.cache, &document) {
+                    if let Ok(value) = from_document::<T>(document.clone()) {
+                        self.cache_insert(name.clone(), &value);
+                    }
+                }
+                (name, document)
+            })
+            .collect())
+    }
+
+    /// Atomically add each `delta` (which may be negative) to the numeric field `field` of the
+    /// document with key `key`, for every `(key, field, delta)` in `updates`, returning the new
+    /// value of each field in the same order. Documents and fields that do not exist are
+    /// treated as zero.
+    ///
+    /// The increments are sent in a single commit, so either all of them are applied or none
+    /// are, and a set of related counters (e.g. one per category) stays consistent. Increments
+    /// to different fields of the same document are combined into one write; at most 500
+    /// documents may be incremented at once, and each field may appear only once. Each
+    /// document is still limited to roughly one write per second (see [ShardedCounter]).
+    ///
+    /// Increments are not idempotent, so are only retried if the policy forces it. An error is
+    /// returned, after the increments are applied, if a field holds a value that is not an
+    /// integer.
+    pub async fn increment_many<K>(&self, updates: &[(K, &str, i64)]) -> anyhow::Result<Vec<i64>>
+    where
+        K: QualifyDocumentName,
+    {
+        if updates.is_empty() {
+            return Err(anyhow::anyhow!("Expected at least one increment to apply."));
+        }
+
+        // Each update's position among the writes, and among the transforms of its write.
+        let mut positions = Vec::with_capacity(updates.len());
+        let mut writes: Vec<(DocumentName, Transforms, usize)> = Vec::new();
+        for (key, field, delta) in updates {
+            let name = self.normalize(&key.qualify(&self.name)?);
+            let index = match writes.iter().position(|(existing, _, _)| *existing == name) {
+                Some(index) => index,
+                None => {
+                    writes.push((name, Transforms::new(), 0));
+                    writes.len() - 1
+                }
+            };
+            let (_, transforms, count) = &mut writes[index];
+            *transforms = std::mem::take(transforms).increment(field, *delta);
+            positions.push((index, *count));
+            *count += 1;
+        }
+
+        let ops: Vec<Op<DocumentName, T>> = writes
+            .into_iter()
+            .map(|(name, transforms, _)| Op::Transform(name, transforms))
+            .collect();
+        let (_, results) = self.commit_ops(ops).await?;
+
+        positions
+            .into_iter()
+            .zip(updates)
+            .map(|((write, transform), (_, field, _))| {
+                let value = results
+                    .get(write)
+                    .and_then(|result| result.values.get(transform))
+                    .ok_or_else(|| anyhow::anyhow!("Expected a result for each increment."))?;
+                match value.value_type {
+                    Some(ValueType::IntegerValue(value)) => Ok(value),
+                    _ => Err(anyhow::anyhow!(
+                        "Expected field {} to hold an integer, but got {:?}.",
+                        field,
+                        value
+                    )),
+                }
+            })
+            .collect()
+    }
+
+    /// Bump the `update_time` of each of the documents with the given keys, e.g. to invalidate
+    /// copies cached elsewhere, by setting their [TOUCH_FIELD] to the server's time. Returns
+    /// the number of documents touched.
+    ///
+    /// Up to 500 documents are touched in a single commit, atomically. More keys are split
+    /// into commits of 500, which are applied in order but not atomically: if one fails,
+    /// the commits before it remain applied. An error is returned, and none of the documents
+    /// in its commit are touched, if any of them does not exist.
+    pub async fn touch_many(
+        &self,
+        keys: impl IntoIterator<Item = impl QualifyDocumentName>,
+    ) -> anyhow::Result<usize> {
+        let mut names: Vec<DocumentName> = Vec::new();
+        for key in keys {
+            let name = self.qualify(key)?;
+            if !names.contains(&name) {
+                names.push(name);
+            }
+        }
+
+        for chunk in names.chunks(MAX_WRITES_PER_COMMIT) {
+            let writes = chunk
+                .iter()
+                .map(|name| Write {
+                    operation: Some(Operation::Transform(DocumentTransform {
+                        document: name.name(),
+                        field_transforms: Transforms::new()
+                            .server_timestamp(TOUCH_FIELD)
+                            .into_field_transforms(),
+                    })),
+                    current_document: Some(Precondition {
+                        condition_type: Some(ConditionType::Exists(true)),
+                    }),
+                    ..Write::default()
+                })
+                .collect();
+            let request = CommitRequest {
+                database: self.name.database_name(),
+                writes,
+                ..CommitRequest::default()
+            };
+            // Setting a field to the server's time again leaves the documents touched.
+            self.run(
+                Idempotency::Idempotent,
+                &request,
+                |db, request| async move { db.clone().commit(request.clone()).await },
+            )
+            .await?;
+            for name in chunk {
+                self.cache_remove(name);
+            }
+        }
+
+        Ok(names.len())
+    }
+
+    /// Get the document with the given key along with each document above it in its path,
+    /// without deserializing them, in a single round-trip. Documents are returned in path
+    /// order, starting from the top-level document and ending with the one requested; for a
+    /// document at `apps/phone/users/john`, that is `apps/phone` then `apps/phone/users/john`.
+    ///
+    /// Ancestors that do not exist (because they only exist as the parent of a subcollection)
+    /// are skipped. An error is returned if the requested document does not exist.
+    pub async fn get_with_ancestors(
+        &self,
+        key: impl QualifyDocumentName,
+    ) -> anyhow::Result<Vec<(DocumentName, Document)>> {
+        let name = self.qualify(key)?;
+        let mut names = name.ancestors();
+        names.push(name.clone());
+
+        let mut documents =
+            batch_get(&self.db, &self.retry, &self.name.database_name(), &names).await?;
+        if !matches!(documents.get(&name.name()), Some(Some(_))) {
+            return Err(anyhow::anyhow!("Document {} does not exist.", name.name()));
+        }
+
+        Ok(names
+            .into_iter()
+            .filter_map(|name| {
+                let document = documents.remove(&name.name()).flatten()?;
+                Some((name, document))
+            })
+            .collect())
+    }
+
+    /// Apply the given writes atomically: either every write succeeds, or (if any of their
+    /// preconditions fails) none are applied. Returns the time at which the writes took
+    /// effect.
+    ///
+    /// The writes are sent in a single commit, so at most 500 may be applied at once, and a
+    /// document may not be written more than once in the same batch. To learn the values
+    /// set by [Op::Transform]s, use `apply_with_results`.
+    pub async fn apply<K>(&self, ops: Vec<Op<K, T>>) -> anyhow::Result<WriteResult>
+    where
+        K: QualifyDocumentName,
+    {
+        let (commit_time, _) = self.commit_ops(ops).await?;
+        Ok(WriteResult {
+            update_time: commit_time,
+        })
+    }
+
+    /// Like `apply`, but returns a result for each op, in the same order as `ops`. The
+    /// result of an [Op::Transform] carries the value of each transformed field after the
+    /// commit (such as the new value of an incremented counter); the results of other ops
+    /// have no values.
+    pub async fn apply_with_results<K>(
+        &self,
+        ops: Vec<Op<K, T>>,
+    ) -> anyhow::Result<Vec<TransformResult>>
+    where
+        K: QualifyDocumentName,
+    {
+        let (_, results) = self.commit_ops(ops).await?;
+        Ok(results)
+    }
+
+    /// Apply the given writes atomically, like `apply`, or if Firestore cannot be reached,
+    /// hold them in this collection's [OfflineQueue] to be applied later. Returns an error if
+    /// the collection has no queue.
+    ///
+    /// Only writes which can safely be applied twice are queued: [Op::Upsert]s, and
+    /// [Op::Transform]s without an increment. If a batch containing any other op cannot be
+    /// sent, its error is returned as from `apply`. See [OfflineQueue] for the order in which
+    /// queued writes are applied.
+    ///
+    /// While writes are queued, reads do not reflect them. Cached copies of the documents they
+    /// write are discarded.
+    pub async fn apply_or_queue<K>(&self, ops: Vec<Op<K, T>>) -> anyhow::Result<ApplyOutcome>
+    where
+        K: QualifyDocumentName,
+    {
+        let offline_queue = self.offline_queue.as_ref().ok_or_else(|| {
+            anyhow::anyhow!("apply_or_queue requires the collection to have an offline queue.")
+        })?;
+
+        let (request, written, idempotency) = self.commit_request(ops)?;
+        match offline_queue
+            .commit_or_queue(&self.db, &self.retry, request, idempotency)
+            .await?
+        {
+            Some(result) => {
+                self.update_cache(written);
+                Ok(ApplyOutcome::Applied(WriteResult::new(result.commit_time)?))
+            }
+            None => {
+                for (name, _) in written {
+                    self.cache_remove(&name);
+                }
+                Ok(ApplyOutcome::Queued)
+            }
+        }
+    }
+
+    /// Commit the given ops in a single request, returning the commit time and the result of
+    /// each write.
+    async fn commit_ops<K>(
+        &self,
+        ops: Vec<Op<K, T>>,
+    ) -> anyhow::Result<(Timestamp, Vec<TransformResult>)>
+    where
+        K: QualifyDocumentName,
+    {
+        let (request, written, idempotency) = self.commit_request(ops)?;
+        let result = self
+            .run(idempotency, &request, |db, request| async move {
+                db.clone().commit(request.clone()).await
+            })
+            .await?
+            .into_inner();
+        self.update_cache(written);
+
+        let commit_time = result
+            .commit_time
+            .ok_or_else(|| anyhow::anyhow!("Expected write to return a time."))?;
+        // Write results are in the same order as the writes. Deletes have no update time, so
+        // the commit time stands in.
+        let results = result
+            .write_results
+            .into_iter()
+            .map(|write_result| TransformResult {
+                update_time: write_result
+                    .update_time
+                    .unwrap_or_else(|| commit_time.clone()),
+                values: write_result.transform_results,
+            })
+            .collect();
+        Ok((commit_time, results))
+    }
+
+    /// Build the request which commits the given ops, along with the document written by
+    /// each (or `None` if it was deleted or transformed) and whether it may be retried.
+    fn commit_request<K>(
+        &self,
+        ops: Vec<Op<K, T>>,
+    ) -> anyhow::Result<(CommitRequest, Written<T>, Idempotency)>
+    where
+        K: QualifyDocumentName,
+    {
+        if ops.len() > MAX_WRITES_PER_COMMIT {
+            return Err(anyhow::anyhow!(
+                "Cannot apply more than {} writes at once, but got {}.",
+                MAX_WRITES_PER_COMMIT,
+                ops.len()
+            ));
+        }
+
+        let mut idempotency = Idempotency::Idempotent;
+        let mut writes = Vec::with_capacity(ops.len());
+        let mut written = Vec::with_capacity(ops.len());
+        for op in ops {
+            let (key, ob, exists) = match op {
+                Op::CreateIfAbsent(key, ob) => {
+                    // A retried create would fail against its own first attempt.
+                    idempotency = Idempotency::NotIdempotent;
+                    (key, Some(ob), Some(false))
+                }
+                Op::UpdateIfPresent(key, ob) => (key, Some(ob), Some(true)),
+                Op::Upsert(key, ob) => (key, Some(ob), None),
+                Op::Delete(key) => (key, None, Some(true)),
+                Op::Transform(key, transforms) => {
+                    if transforms.idempotency() == Idempotency::NotIdempotent {
+                        idempotency = Idempotency::NotIdempotent;
+                    }
+                    let name = self.qualify(key)?;
+                    writes.push(Write {
+                        operation: Some(Operation::Transform(DocumentTransform {
+                            document: name.name(),
+                            field_transforms: transforms.into_field_transforms(),
+                        })),
+                        ..Write::default()
+                    });
+                    written.push((name, None));
+                    continue;
+                }
+            };
+            let operation = match ob {
+                Some(ob) => {
+                    let (name, document) = self.to_document_with_key(&ob, key)?;
+                    written.push((name, Some(ob)));
+                    Operation::Update(document)
+                }
+                None => {
+                    let name = self.qualify(key)?;
+                    let operation = Operation::Delete(name.name());
+                    written.push((name, None));
+                    operation
+                }
+            };
+
+            writes.push(Write {
+                operation: Some(operation),
+                current_document: exists.map(|exists| Precondition {
+                    condition_type: Some(ConditionType::Exists(exists)),
+                }),
+                ..Write::default()
+            });
+        }
+
+        let request = CommitRequest {
+            database: self.name.database_name(),
+            writes,
+            ..CommitRequest::default()
+        };
+        Ok((request, written, idempotency))
+    }
+
+    /// Update the cache after the given documents were written (or deleted or transformed,
+    /// if `None`).
+    fn update_cache(&self, written: Written<T>) {
+        for (name, ob) in written {
+            match ob {
+                Some(ob) => self.cache_insert(name, &ob),
+                None => self.cache_remove(&name),
+            }
+        }
+    }
+
+    /// Delete the document with a given key.
+    ///
+    /// Deletes are issued as a single-write commit, because the `DeleteDocument` call does not
+    /// report when the delete took effect.
+    pub async fn delete(&self, key: impl QualifyDocumentName) -> anyhow::Result<WriteResult> {
+        let name = self.qualify(key)?;
+        self.cache_remove(&name);
+        let name = name.name();
+        let request = CommitRequest {
+            database: self.name.database_name(),
+            writes: vec![Write {
+                operation: Some(Operation::Delete(name)),
+                current_document: Some(Precondition {
+                    condition_type: Some(ConditionType::Exists(true)),
+                }),
+                ..Write::default()
+            }],
+            ..CommitRequest::default()
+        };
+        let result = self
+            .run(
+                Idempotency::Idempotent,
+                &request,
+                |db, request| async move { db.clone().commit(request.clone()).await },
+            )
+            .await?
+            .into_inner();
+        WriteResult::new(result.commit_time)
+    }
+
+    /// Delete the document with a given key, along with every document in every subcollection
+    /// beneath it. Returns the total number of documents removed.
+    ///
+    /// Unlike `delete`, this does not return an error if the document itself does not exist,
+    /// since a nonexistent document may still have subcollections.
+    ///
+    /// Equivalent to `delete_recursive_with_max_depth` with [DEFAULT_MAX_DELETE_DEPTH].
+    pub async fn delete_recursive(&self, key: impl QualifyDocumentName) -> anyhow::Result<usize> {
+        self.delete_recursive_with_max_depth(key, DEFAULT_MAX_DELETE_DEPTH)
+            .await
+    }
+
+    /// Delete the document with a given key, along with every document in every subcollection
+    /// beneath it, descending at most `max_depth` levels of subcollections.
+    ///
+    /// Firestore does not delete a document's subcollections when the document is deleted, so
+    /// this discovers them (with `ListCollectionIds`) and deletes their contents in batches.
+    /// The whole tree is discovered before anything is deleted; if documents are nested
+    /// deeper than `max_depth`, an error is returned and nothing is deleted. The deletes
+    /// themselves are not atomic across batches.
+    pub async fn delete_recursive_with_max_depth(
+        &self,
+        key: impl QualifyDocumentName,
+        max_depth: usize,
+    ) -> anyhow::Result<usize> {
+        let root = self.qualify(key)?;
+        self.cache_remove(&root);
+        let root = root.name();
+        let mut to_delete: Vec<String> = Vec::new();
+        let mut to_visit: Vec<(String, usize)> = vec![(root.clone(), 0)];
+
+        while let Some((parent, depth)) = to_visit.pop() {
+            let collection_ids = list_collection_ids(&self.db, &self.retry, &parent).await?;
+
+            if !collection_ids.is_empty() && depth >= max_depth {
+                return Err(anyhow::anyhow!(
+                    "Document {} has subcollections nested more than {} levels deep.",
+                    root,
+                    max_depth
+                ));
+            }
+
+            for collection_id in collection_ids {
+                for document in
+                    list_document_stubs(&self.db, &self.retry, &parent, &collection_id).await?
+                {
+                    // Documents that only exist as the parent of a subcollection are "missing";
+                    // there is nothing to delete, but we still need to descend into them.
+                    if document.create_time.is_some() {
+                        to_delete.push(document.name.clone());
+                    }
+                    to_visit.push((document.name, depth + 1));
+                }
+            }
+        }
+
+        if document_exists(&self.db, &self.retry, &root).await? {
+            to_delete.push(root);
+        }
+
+        let database = self.name.database_name();
+        for chunk in to_delete.chunks(MAX_WRITES_PER_COMMIT) {
+            let writes = chunk
+                .iter()
+                .map(|name| Write {
+                    operation: Some(Operation::Delete(name.clone())),
+                    ..Write::default()
+                })
+                .collect();
+
+            let request = CommitRequest {
+                database: database.clone(),
+                writes,
+                ..CommitRequest::default()
+            };
+            self.run(
+                Idempotency::Idempotent,
+                &request,
+                |db, request| async move { db.clone().commit(request.clone()).await },
+            )
+            .await?;
+        }
+
+        Ok(to_delete.len())
+    }
+
+    /// Returns the name of every document in this collection and in the subcollections beneath
+    /// it, each with its depth: 0 for documents in this collection, 1 for documents in their
+    /// subcollections, and so on. Subcollections more than `max_depth` levels deep are not
+    /// descended into.
+    ///
+    /// Results are in depth-first order, so each document directly precedes the documents in
+    /// its subcollections. Documents which do not exist but have subcollections are included.
+    /// Every document found costs a read and a `ListCollectionIds` call, so walking a large
+    /// tree is slow and expensive.
+    pub async fn walk_tree(&self, max_depth: usize) -> anyhow::Result<Vec<(DocumentName, usize)>> {
+        walk_tree(
+            &self.db,
+            &self.retry,
+            &self.name.parent().name(),
+            vec![self.name.leaf_name()],
+            max_depth,
+        )
+        .await
+    }
+
+    /// Check whether every document in this collection deserializes into `T`, for example
+    /// before a migration changes `T`. Documents that do not are collected in the report
+    /// rather than causing an error; an error is only returned if a page cannot be fetched.
+    ///
+    /// Documents are fetched a page at a time, so only one page is held in memory, but every
+    /// document in the collection is read. The validator, if any, is not applied.
+    pub async fn validate_schema(&self) -> anyhow::Result<SchemaReport> {
+        let mut report = SchemaReport {
+            conforming: 0,
+            non_conforming: Vec::new(),
+        };
+        let mut page_token = String::new();
+
+        loop {
+            let response = self.list_page(page_token).await?;
+
+            for document in response.documents {
+                let name = DocumentName::parse(&document.name)?;
+                match from_document::<T>(document) {
+                    Ok(_) => report.conforming += 1,
+                    Err(e) => report.non_conforming.push((name, e)),
+                }
+            }
+
+            if response.next_page_token.is_empty() {
+                return Ok(report);
+            }
+            page_token = response.next_page_token;
+        }
+    }
+
+    /// Write every document in this collection to `writer` as a sequence of length-delimited
+    /// `Document` protobufs, returning the number of documents written. The export can be
+    /// restored with [Collection::import_binary].
+    ///
+    /// Documents are written as Firestore returns them, without being deserialized into `T`,
+    /// so Firestore-native values (such as timestamps, references and bytes) are preserved
+    /// exactly. Documents are fetched a page at a time, so only one page is held in memory.
+    /// Subcollections are not exported.
+    pub async fn export_binary<W: std::io::Write>(&self, mut writer: W) -> anyhow::Result<usize> {
+        let mut exported = 0;
+        let mut page_token = String::new();
+
+        loop {
+            let response = self.list_page(page_token).await?;
+
+            let mut buf = Vec::new();
+            for document in response.documents {
+                document.encode_length_delimited(&mut buf)?;
+                exported += 1;
+            }
+            writer.write_all(&buf)?;
+
+            if response.next_page_token.is_empty() {
+                writer.flush()?;
+                return Ok(exported);
+            }
+            page_token = response.next_page_token;
+        }
+    }
+
+    /// Upsert every document read from `reader`, in the format written by
+    /// [Collection::export_binary], into this collection, returning the number of documents
+    /// written.
+    ///
+    /// Each document keeps its key, but is written into this collection regardless of the
+    /// collection (or project) it was exported from. Documents are written in batches as they
+    /// are read, so the import is not atomic: if it fails part-way through, the batches already
+    /// committed remain. Documents are not deserialized into `T`, so the validator, if any, is
+    /// not applied.
+    pub async fn import_binary<R: Read>(&self, mut reader: R) -> anyhow::Result<usize> {
+        let mut imported = 0;
+        let mut batch = Vec::with_capacity(MAX_WRITES_PER_COMMIT);
+
+        while let Some(document) = read_length_delimited(&mut reader)? {
+            batch.push(document);
+            if batch.len() == MAX_WRITES_PER_COMMIT {
+                imported += batch.len();
+                self.upsert_raw(std::mem::take(&mut batch)).await?;
+            }
+        }
+        if !batch.is_empty() {
+            imported += batch.len();
+            self.upsert_raw(batch).await?;
+        }
+
+        Ok(imported)
+    }
+
+    /// Rename the top-level field `from` to `to` in every document of this collection that has
+    /// it, returning the number of documents changed. A document that already has a field
+    /// named `to` has it overwritten; documents without a field named `from` are skipped.
+    ///
+    /// Documents are read a page at a time, without being deserialized into `T`, and each
+    /// page's changes are written in a single commit that only touches the two fields. Each
+    /// write is conditional on the document not having changed since it was read, so a
+    /// concurrent edit makes the rename fail rather than be clobbered. The rename is not
+    /// atomic across pages: if it fails part-way through, the pages already committed stay
+    /// renamed, and it can be run again to finish.
+    pub async fn rename_field(&self, from: &str, to: &str) -> anyhow::Result<usize> {
+        if from == to {
+            return Err(anyhow::anyhow!("Cannot rename field {} to itself.", from));
+        }
+
+        let mut renamed = 0;
+        let mut page_token = String::new();
+
+        loop {
+            let response = self.list_page(page_token).await?;
+
+            let mut writes = Vec::new();
+            for mut document in response.documents {
+                let value = match document.fields.remove(from) {
+                    Some(value) => value,
+                    None => continue,
+                };
+                self.cache_remove(&DocumentName::parse(&document.name)?);
+
+                let mut fields = HashMap::new();
+                fields.insert(to.to_string(), value);
+                writes.push(Write {
+                    operation: Some(Operation::Update(Document {
+                        name: document.name,
+                        fields,
+                        ..Document::default()
+                    })),
+                    // Fields in the mask but not the document are deleted.
+                    update_mask: Some(DocumentMask {
+                        field_paths: vec![quote_field_path(to), quote_field_path(from)],
+                    }),
+                    current_document: Some(Precondition {
+                        condition_type: document.update_time.map(ConditionType::UpdateTime),
+                    }),
+                    ..Write::default()
+                });
+            }
+
+            if !writes.is_empty() {
+                renamed += writes.len();
+                let request = CommitRequest {
+                    database: self.name.database_name(),
+                    writes,
+                    ..CommitRequest::default()
+                };
+                self.run(
+                    Idempotency::Idempotent,
+                    &request,
+                    |db, request| async move { db.clone().commit(request.clone()).await },
+                )
+                .await?;
+            }
+
+            if response.next_page_token.is_empty() {
+                return Ok(renamed);
+            }
+            page_token = response.next_page_token;
+        }
+    }
+
+    /// Fetch the page of documents in this collection starting at `page_token` (which is empty
+    /// for the first page).
+    async fn list_page(&self, page_token: String) -> anyhow::Result<ListDocumentsResponse> {
+        let request = ListDocumentsRequest {
+            parent: self.name.parent().name(),
+            collection_id: self.name.leaf_name(),
+            page_size: SCAN_PAGE_SIZE,
+            page_token,
+            ..ListDocumentsRequest::default()
+        };
+        let response = self
+            .run(
+                Idempotency::Idempotent,
+                &request,
+                |db, request| async move { db.clone().list_documents(request.clone()).await },
+            )
+            .await?
+            .into_inner();
+        Ok(response)
+    }
+
+    /// Upsert the given documents, without deserializing them, in a single commit. Each is
+    /// written into this collection with the key of its name.
+    async fn upsert_raw(&self, documents: Vec<Document>) -> anyhow::Result<()> {
+        let mut writes = Vec::with_capacity(documents.len());
+        for document in documents {
+            let name = self.qualify(DocumentName::parse(&document.name)?.leaf_name())?;
+            self.cache_remove(&name);
+            writes.push(Write {
+                operation: Some(Operation::Update(Document {
+                    name: name.name(),
+                    fields: document.fields,
+                    ..Document::default()
+                })),
+                ..Write::default()
+            });
+        }
+
+        let request = CommitRequest {
+            database: self.name.database_name(),
+            writes,
+            ..CommitRequest::default()
+        };
+        self.run(
+            Idempotency::Idempotent,
+            &request,
+            |db, request| async move { db.clone().commit(request.clone()).await },
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Validate a document to be written with the collection's validator, if any, and
+    /// serialize it.
+    fn to_document(&self, ob: &T) -> anyhow::Result<Document> {
+        if let Some(validator) = &self.validator {
+            validator(ob).map_err(|message| ValidationError { message })?;
+        }
+        let mut document = to_document(ob)?;
+        if self.skip_nulls {
+            remove_nulls(&mut document.fields);
+        }
+        Ok(document)
+    }
+
+    /// Serialize a document to be written with the given key, recording the key as given if
+    /// IDs are case-insensitive.
+    fn to_document_with_key(
+        &self,
+        ob: &T,
+        key: impl QualifyDocumentName,
+    ) -> anyhow::Result<(DocumentName, Document)> {
+        let mut document = self.to_document(ob)?;
+        let name = key.qualify(&self.name)?;
+        if self.case_insensitive_ids {
+            document.fields.insert(
+                ORIGINAL_ID_FIELD.to_string(),
+                Value {
+                    value_type: Some(ValueType::StringValue(name.leaf_name().to_string())),
+                },
+            );
+        }
+        let name = self.normalize(&name);
+        document.name = name.name();
+        Ok((name, document))
+    }
+
+    fn cached(&self, name: &DocumentName) -> Option<T> {
+        let cache = self.cache.as_ref()?;
+        cache.get(name, self.clock.now())
+    }
+
+    fn cache_insert(&self, name: DocumentName, ob: &T) {
+        if let Some(cache) = &self.cache {
+            cache.insert(name, ob, self.clock.now());
+        }
+    }
+
+    fn cache_remove(&self, name: &DocumentName) {
+        if let Some(cache) = &self.cache {
+            cache.remove(name);
+        }
+    }
+
+    /// Qualify a key into the name of the document it refers to in this collection.
+    fn qualify(&self, key: impl QualifyDocumentName) -> anyhow::Result<DocumentName> {
+        Ok(self.normalize(&key.qualify(&self.name)?))
+    }
+
+    /// The names to try, in order, when reading the document with the given key: its
+    /// normalized name, then if IDs are case-insensitive and the key is not already
+    /// lowercase, the key as given.
+    fn lookup_names(&self, key: impl QualifyDocumentName) -> anyhow::Result<Vec<DocumentName>> {
+        let name = key.qualify(&self.name)?;
+        let normalized = self.normalize(&name);
+        if normalized == name {
+            Ok(vec![name])
+        } else {
+            Ok(vec![normalized, name])
+        }
+    }
+
+    fn normalize(&self, name: &DocumentName) -> DocumentName {
+        name.collection()
+            .document(&self.normalize_id(name.leaf_name()))
+    }
+
+    fn normalize_id(&self, id: &str) -> String {
+        if self.case_insensitive_ids {
+            id.to_lowercase()
+        } else {
+            id.to_string()
+        }
+    }
+
+    /// Make a request with this collection's retry policy. `request` is passed to each attempt
+    /// by reference, so that it can be cloned rather than rebuilt.
+    async fn run<'a, Req, F, Fut, R>(
+        &'a self,
+        idempotency: Idempotency,
+        request: &'a Req,
+        mut call: F,
+    ) -> Result<R, Status>
+    where
+        F: FnMut(&'a SharedFirestoreClient, &'a Req) -> Fut,
+        Fut: Future<Output = Result<R, Status>>,
+    {
+        self.retry
+            .run(idempotency, || call(&self.db, request))
+            .await
+    }
+}
+
+#[cfg(feature = "schemars")]
+impl<T> Collection<T>
+where
+    T: Serialize + DeserializeOwned + Unpin + schemars::JsonSchema,
+{
+    /// Returns a JSON Schema describing the documents of this collection, as derived from
+    /// `T`. Only available with the `schemars` feature.
+    ///
+    /// The schema describes `T` as serialized by `serde_json`, which matches how it is
+    /// stored except where Firestore has its own types (e.g. timestamps and references).
+    pub fn json_schema() -> schemars::schema::RootSchema {
+        schemars::schema_for!(T)
+    }
+}
+
+/// Returns whether the given document, as returned by a write, was created by that write.
+fn write_outcome(document: &Document) -> WriteOutcome {
+    if document.create_time.is_some() && document.create_time == document.update_time {
+        WriteOutcome::Created
+    } else {
+        WriteOutcome::Updated
+    }
+}
+
+/// Compare two values of a version field, if they are of comparable types: numbers,
+/// timestamps, or strings.
+fn compare_versions(a: &Value, b: &Value) -> Option<Ordering> {
+    match (a.value_type.as_ref()?, b.value_type.as_ref()?) {
+        (ValueType::IntegerValue(a), ValueType::IntegerValue(b)) => Some(a.cmp(b)),
+        (ValueType::IntegerValue(a), ValueType::DoubleValue(b)) => (*a as f64).partial_cmp(b),
+        (ValueType::DoubleValue(a), ValueType::IntegerValue(b)) => a.partial_cmp(&(*b as f64)),
+        (ValueType::DoubleValue(a), ValueType::DoubleValue(b)) => a.partial_cmp(b),
+        (ValueType::TimestampValue(a), ValueType::TimestampValue(b)) => {
+            Some((a.seconds, a.nanos).cmp(&(b.seconds, b.nanos)))
+        }
+        (ValueType::StringValue(a), ValueType::StringValue(b)) => Some(a.cmp(b)),
+        _ => None,
+    }
+}
+
+/// Remove every null field from `fields` and from the maps nested in them, leaving arrays as
+/// they are.
+fn remove_nulls(fields: &mut HashMap<String, Value>) {
+    fields.retain(|_, value| match &mut value.value_type {
+        Some(ValueType::NullValue(_)) => false,
+        Some(ValueType::MapValue(map)) => {
+            remove_nulls(&mut map.fields);
+            true
+        }
+        _ => true,
+    });
+}
+
+/// Returns the field paths of the top-level fields which differ between two documents,
+/// including fields present in only one of them, in sorted order.
+fn changed_fields(old: &Document, new: &Document) -> Vec<String> {
+    let mut fields: Vec<String> = old
+        .fields
+        .keys()
+        .chain(new.fields.keys())
+        .filter(|key| old.fields.get(*key) != new.fields.get(*key))
+        .map(|key| quote_field_path(key))
+        .collect();
+    fields.sort();
+    fields.dedup();
+    fields
+}
+
+/// Quote a field name for use in a field path, if it is not a simple identifier.
+fn quote_field_path(field: &str) -> String {
+    let simple = field.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && field.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if simple {
+        field.to_string()
+    } else {
+        format!("`{}`", field.replace('\\', "\\\\").replace('`', "\\`"))
+    }
+}
+
+/// Returns the name and depth of every document in the given collections beneath `parent`
+/// and in the subcollections beneath them, descending at most `max_depth` levels.
+///
+/// Results are in depth-first order, so each document directly precedes the documents in its
+/// subcollections. Documents which do not exist but have subcollections are included, since
+/// they are still nodes of the tree.
+///
+/// This makes one `ListCollectionIds` call for every document found, and lists the documents
+/// of every collection found (which costs at least one read per document, although their
+/// fields are not fetched), so walking a large tree is slow and expensive.
+pub(crate) async fn walk_tree(
+    db: &SharedFirestoreClient,
+    retry: &RetryPolicy,
+    parent: &str,
+    collection_ids: Vec<String>,
+    max_depth: usize,
+) -> anyhow::Result<Vec<(DocumentName, usize)>> {
+    let mut found: Vec<(DocumentName, usize)> = Vec::new();
+    let mut to_visit: Vec<(String, String, usize)> = collection_ids
+        .into_iter()
+        .map(|collection_id| (parent.to_string(), collection_id, 0))
+        .collect();
+
+    while let Some((parent, collection_id, depth)) = to_visit.pop() {
+        for document in list_document_stubs(db, retry, &parent, &collection_id).await? {
+            if depth < max_depth {
+                for collection_id in list_collection_ids(db, retry, &document.name).await? {
+                    to_visit.push((document.name.clone(), collection_id, depth + 1));
+                }
+            }
+            found.push((DocumentName::parse(&document.name)?, depth));
+        }
+    }
+
+    // Comparing names segment by segment puts each document before its descendants.
+    found.sort_by(|(a, _), (b, _)| {
+        let a = a.name();
+        let b = b.name();
+        a.split('/').cmp(b.split('/'))
+    });
+    Ok(found)
+}
+
+/// Fetch the given documents with `BatchGetDocuments`, returning a map from each requested
+/// name to its document, or `None` if it does not exist.
+async fn batch_get(
+    db: &SharedFirestoreClient,
+    retry: &RetryPolicy,
+    database: &str,
+    names: &[DocumentName],
+) -> anyhow::Result<HashMap<String, Option<Document>>> {
+    if names.is_empty() {
+        return Ok(HashMap::new());
+    }
+
+    let mut requested: Vec<String> = names.iter().map(DocumentName::name).collect();
+    requested.sort();
+    requested.dedup();
+
+    let request = BatchGetDocumentsRequest {
+        database: database.to_string(),
+        documents: requested,
+        ..BatchGetDocumentsRequest::default()
+    };
+    let request = &request;
+
+    // The whole stream is read within each attempt, so that an error part-way through it
+    // restarts the request.
+    let documents = retry
+        .run(Idempotency::Idempotent, || async move {
+            let mut documents: HashMap<String, Option<Document>> = HashMap::new();
+            let mut stream = db
+                .clone()
+                .batch_get_documents(request.clone())
+                .await?
+                .into_inner();
+
+            while let Some(response) = stream.message().await? {
+                match response.result {
+                    Some(BatchGetResult::Found(document)) => {
+                        documents.insert(document.name.clone(), Some(document));
+                    }
+                    Some(BatchGetResult::Missing(name)) => {
+                        documents.insert(name, None);
+                    }
+                    None => (),
+                }
+            }
+
+            Ok(documents)
+        })
+        .await?;
+
+    Ok(documents)
+}
+
+/// Returns the least string greater than every string starting with `prefix`, or `None` if
+/// there is none (because every character of `prefix` is `char::MAX`). Strings are compared
+/// by their UTF-8 bytes, which orders them by code point.
+fn prefix_successor(prefix: &str) -> Option<String> {
+    let mut chars: Vec<char> = prefix.chars().collect();
+    while let Some(last) = chars.pop() {
+        let next = (last as u32 + 1..=char::MAX as u32).find_map(char::from_u32);
+        if let Some(next) = next {
+            chars.push(next);
+            return Some(chars.into_iter().collect());
+        }
+    }
+    None
+}
+
+/// Return the IDs of every collection directly beneath the given document.
+pub(crate) async fn list_collection_ids(
+    db: &SharedFirestoreClient,
+    retry: &RetryPolicy,
+    parent: &str,
+) -> anyhow::Result<Vec<String>> {
+    let mut collection_ids = Vec::new();
+    let mut page_token = String::new();
+
+    loop {
+        let request = &ListCollectionIdsRequest {
+            parent: parent.to_string(),
+            page_token,
+            ..ListCollectionIdsRequest::default()
+        };
+        let response = retry
+            .run(Idempotency::Idempotent, || async move {
+                db.clone().list_collection_ids(request.clone()).await
+            })
+            .await?
+            .into_inner();
+
+        collection_ids.extend(response.collection_ids);
+
+        if response.next_page_token.is_empty() {
+            return Ok(collection_ids);
+        }
+        page_token = response.next_page_token;
+    }
+}
+
+/// Return every document in the given collection, including missing documents (those which
+/// do not exist but have subcollections), without any of their fields.
+async fn list_document_stubs(
+    db: &SharedFirestoreClient,
+    retry: &RetryPolicy,
+    parent: &str,
+    collection_id: &str,
+) -> anyhow::Result<Vec<Document>> {
+    let mut documents = Vec::new();
+    let mut page_token = String::new();
+
+    loop {
+        let request = &ListDocumentsRequest {
+            parent: parent.to_string(),
+            collection_id: collection_id.to_string(),
+            page_token,
+            mask: Some(DocumentMask::default()),
+            show_missing: true,
+            ..ListDocumentsRequest::default()
+        };
+        let response = retry
+            .run(Idempotency::Idempotent, || async move {
+                db.clone().list_documents(request.clone()).await
+            })
+            .await?
+            .into_inner();
+
+        documents.extend(response.documents);
+
+        if response.next_page_token.is_empty() {
+            return Ok(documents);
+        }
+        page_token = response.next_page_token;
+    }
+}
+
+/// Read the next length-delimited document from `reader`, returning `None` if the input ends
+/// before it.
+fn read_length_delimited(reader: &mut impl Read) -> anyhow::Result<Option<Document>> {
+    // The length is a varint of at most 10 bytes, each carrying 7 bits.
+    let mut len: u64 = 0;
+    for i in 0..10 {
+        let mut byte = [0];
+        match reader.read_exact(&mut byte) {
+            Err(e) if i == 0 && e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            result => result?,
+        }
+        len |= u64::from(byte[0] & 0x7f) << (7 * i);
+
+        if byte[0] & 0x80 == 0 {
+            // Read through `take` rather than into a buffer of the given length, so that a
+            // corrupt length cannot allocate more than the input actually holds.
+            let mut buf = Vec::new();
+            reader.take(len).read_to_end(&mut buf)?;
+            if buf.len() as u64 != len {
+                return Err(anyhow::anyhow!(
+                    "Binary export ends part-way through a document."
+                ));
+            }
+            return Ok(Some(Document::decode(buf.as_slice())?));
+        }
+    }
+
+    Err(anyhow::anyhow!("Invalid length prefix in binary export."))
+}
+
+/// Returns the time `staleness` before `now`, truncated to the microsecond precision which
+/// Firestore requires of read times. A staleness reaching back before the epoch is clamped to
+/// the epoch.
+fn stale_read_time(now: SystemTime, staleness: Duration) -> Timestamp {
+    let read_time = now
+        .checked_sub(staleness)
+        .unwrap_or(SystemTime::UNIX_EPOCH)
+        .max(SystemTime::UNIX_EPOCH);
+    let mut read_time = Timestamp::from(read_time);
+    read_time.nanos -= read_time.nanos % 1_000;
+    read_time
+}
+
+/// Returns `true` if a document with the given fully-qualified name exists, without fetching
+/// any of its fields.
+async fn document_exists(
+    db: &SharedFirestoreClient,
+    retry: &RetryPolicy,
+    name: &str,
+) -> anyhow::Result<bool> {
+    let request = &GetDocumentRequest {
+        name: name.to_string(),
+        mask: Some(DocumentMask::default()),
+        ..GetDocumentRequest::default()
+    };
+    let result = retry
+        .run(Idempotency::Idempotent, || async move {
+            db.clone().get_document(request.clone()).await
+        })
+        .await;
+
+    match result {
+        Ok(_) => Ok(true),
+        Err(e) if e.code() == Code::NotFound => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::dynamic_firestore_client::test_client;
+    use crate::MockClock;
+    use firestore_serde::firestore::value::ValueType;
+    use serde::Deserialize;
+    use std::time::{Duration, SystemTime};
+
+    #[derive(Serialize, Deserialize)]
+    struct Profile {
+        name: String,
+        #[serde(rename = "favourite colour")]
+        colour: String,
+        age: Option<u32>,
+    }
+
+    #[cfg(feature = "schemars")]
+    #[test]
+    fn test_json_schema() {
+        #[derive(Serialize, Deserialize, schemars::JsonSchema)]
+        struct Account {
+            name: String,
+            age: Option<u32>,
+        }
+
+        let schema = serde_json::to_value(Collection::<Account>::json_schema()).unwrap();
+        assert_eq!("Account", schema["title"]);
+        assert_eq!(serde_json::json!(["name"]), schema["required"]);
+        assert_eq!("string", schema["properties"]["name"]["type"]);
+    }
+
+    #[test]
+    fn test_write_outcome() {
+        let time = |seconds| Some(Timestamp { seconds, nanos: 0 });
+        let written = |create_time, update_time| Document {
+            create_time,
+            update_time,
+            ..Document::default()
+        };
+
+        assert_eq!(
+            WriteOutcome::Created,
+            write_outcome(&written(time(5), time(5)))
+        );
+        assert_eq!(
+            WriteOutcome::Updated,
+            write_outcome(&written(time(1), time(5)))
+        );
+    }
+
+    #[test]
+    fn test_stale_read_time() {
+        let now = SystemTime::UNIX_EPOCH + Duration::new(100, 123_456_789);
+        assert_eq!(
+            Timestamp {
+                seconds: 90,
+                nanos: 123_456_000
+            },
+            stale_read_time(now, Duration::from_secs(10))
+        );
+
+        // A staleness longer than the time since the epoch does not underflow.
+        assert_eq!(
+            Timestamp::default(),
+            stale_read_time(now, Duration::from_secs(u64::MAX))
+        );
+    }
+
+    #[test]
+    fn test_changed_fields() {
+        let old = firestore_serde::to_document(&Profile {
+            name: "Ada".to_string(),
+            colour: "blue".to_string(),
+            age: Some(36),
+        })
+        .unwrap();
+        assert!(changed_fields(&old, &old).is_empty());
+
+        let new = firestore_serde::to_document(&Profile {
+            name: "Ada".to_string(),
+            colour: "green".to_string(),
+            age: Some(37),
+        })
+        .unwrap();
+        assert_eq!(
+            vec!["`favourite colour`".to_string(), "age".to_string()],
+            changed_fields(&old, &new)
+        );
+
+        // A field present in only one document has changed.
+        let mut removed = old.clone();
+        removed.fields.remove("age");
+        assert_eq!(vec!["age".to_string()], changed_fields(&old, &removed));
+        assert_eq!(vec!["age".to_string()], changed_fields(&removed, &old));
+    }
+
+    #[tokio::test]
+    async fn test_increment_many_requires_increments() {
+        let profiles: Collection<Profile> =
+            Collection::new(test_client(), CollectionName::new("my-project", "profiles"));
+
+        // The client is never connected, so this can only fail locally.
+        let updates: &[(&str, &str, i64)] = &[];
+        assert!(profiles.increment_many(updates).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_rename_field_to_itself() {
+        let profiles: Collection<Profile> =
+            Collection::new(test_client(), CollectionName::new("my-project", "profiles"));
+
+        // The client is never connected, so this can only fail locally.
+        assert!(profiles.rename_field("name", "name").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_validator_rejects_writes_locally() {
+        let profiles: Collection<Profile> =
+            Collection::new(test_client(), CollectionName::new("my-project", "profiles"))
+                .with_validator(|profile: &Profile| match profile.age {
+                    Some(age) if age > 150 => Err(format!("Implausible age {}.", age)),
+                    _ => Ok(()),
+                });
+
+        let profile = Profile {
+            name: "Ada".to_string(),
+            colour: "blue".to_string(),
+            age: Some(200),
+        };
+
+        // The client is never connected, so these can only fail locally.
+        let error = profiles.upsert(&profile, "ada").await.unwrap_err();
+        assert_eq!(
+            Some(&ValidationError {
+                message: "Implausible age 200.".to_string()
+            }),
+            error.downcast_ref::<ValidationError>()
+        );
+
+        match profiles.create_with_key(&profile, "ada").await {
+            Err(FirestoreError::Other(error)) => assert!(error.is::<ValidationError>()),
+            result => panic!("Expected a validation error, got {:?}.", result),
+        }
+    }
+
+    #[test]
+    fn test_compare_versions() {
+        let value = |value_type| Value {
+            value_type: Some(value_type),
+        };
+        let timestamp =
+            |seconds, nanos| value(ValueType::TimestampValue(Timestamp { seconds, nanos }));
+
+        assert_eq!(
+            Some(Ordering::Greater),
+            compare_versions(
+                &value(ValueType::IntegerValue(3)),
+                &value(ValueType::DoubleValue(2.5))
+            )
+        );
+        assert_eq!(
+            Some(Ordering::Less),
+            compare_versions(&timestamp(10, 1), &timestamp(10, 2))
+        );
+        assert_eq!(
+            Some(Ordering::Equal),
+            compare_versions(
+                &value(ValueType::StringValue("v2".to_string())),
+                &value(ValueType::StringValue("v2".to_string()))
+            )
+        );
+        assert_eq!(
+            None,
+            compare_versions(
+                &value(ValueType::IntegerValue(3)),
+                &value(ValueType::StringValue("3".to_string()))
+            )
+        );
+    }
+
+    #[test]
+    fn test_prefix_successor() {
+        assert_eq!(Some("2021-10-"), prefix_successor("2021-10,").as_deref());
+        assert_eq!(Some("tenant-b"), prefix_successor("tenant-a").as_deref());
+        // Surrogates are not characters, so are skipped over.
+        assert_eq!(Some("a\u{e000}"), prefix_successor("a\u{d7ff}").as_deref());
+        assert_eq!(Some("b"), prefix_successor("a\u{10ffff}").as_deref());
+        assert_eq!(None, prefix_successor("\u{10ffff}"));
+        assert_eq!(None, prefix_successor(""));
+    }
+
+    #[tokio::test]
+    async fn test_skip_nulls() {
+        #[derive(Serialize, Deserialize)]
+        struct Account {
+            profile: Profile,
+            aliases: Vec<Option<String>>,
+        }
+
+        let accounts: Collection<Account> =
+            Collection::new(test_client(), CollectionName::new("my-project", "accounts"));
+        let account = Account {
+            profile: Profile {
+                name: "Ada".to_string(),
+                colour: "blue".to_string(),
+                age: None,
+            },
+            aliases: vec![None],
+        };
+        let profile_fields = |document: &Document| match &document.fields["profile"].value_type {
+            Some(ValueType::MapValue(map)) => map.fields.clone(),
+            value => panic!("Expected a map, got {:?}.", value),
+        };
+
+        let document = accounts.to_document(&account).unwrap();
+        assert!(profile_fields(&document).contains_key("age"));
+
+        let accounts = accounts.with_skip_nulls(true);
+        let document = accounts.to_document(&account).unwrap();
+        assert!(!profile_fields(&document).contains_key("age"));
+        assert_eq!(2, profile_fields(&document).len());
+
+        // Array elements keep their positions.
+        match &document.fields["aliases"].value_type {
+            Some(ValueType::ArrayValue(array)) => assert_eq!(1, array.values.len()),
+            value => panic!("Expected an array, got {:?}.", value),
+        }
+
+        // An absent optional reads back as `None`.
+        let account: Account = from_document(document).unwrap();
+        assert_eq!(None, account.profile.age);
+    }
+
+    #[tokio::test]
+    async fn test_case_insensitive_ids() {
+        let profiles: Collection<Profile> =
+            Collection::new(test_client(), CollectionName::new("my-project", "profiles"))
+                .with_case_insensitive_ids();
+        let profile = Profile {
+            name: "Ada".to_string(),
+            colour: "blue".to_string(),
+            age: None,
+        };
+
+        let (_, document) = profiles.to_document_with_key(&profile, "Ada").unwrap();
+        assert_eq!(profiles.name().document("ada").name(), document.name);
+        assert_eq!(
+            Some(ValueType::StringValue("Ada".to_string())),
+            document.fields[ORIGINAL_ID_FIELD].value_type
+        );
+
+        assert_eq!(
+            vec![
+                profiles.name().document("ada"),
+                profiles.name().document("Ada")
+            ],
+            profiles.lookup_names("Ada").unwrap()
+        );
+        assert_eq!(
+            vec![profiles.name().document("ada")],
+            profiles.lookup_names("ada").unwrap()
+        );
+
+        let devices: Collection<Profile> = profiles.subcollection("Ada", "devices");
+        assert_eq!(
+            profiles.name().subcollection("ada", "devices"),
+            devices.name()
+        );
+
+        let visits = profiles.sharded_counter("Ada", 4).unwrap();
+        assert_eq!(
+            profiles.name().subcollection("ada", "shards"),
+            visits.shards()
+        );
+        assert!(profiles.sharded_counter("Ada", 0).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_cache_serves_reads_locally() {
+        let clock = MockClock::new(SystemTime::UNIX_EPOCH);
+        let items: Collection<LineItem> =
+            Collection::new(test_client(), CollectionName::new("my-project", "items"))
+                .with_clock(clock.clone())
+                .with_cache(CacheConfig {
+                    max_entries: 10,
+                    ttl: Duration::from_secs(60),
+                });
+        let item = LineItem {
+            sku: "A1".to_string(),
+            quantity: 2,
+            note: None,
+        };
+        items.cache_insert(items.name().document("a1"), &item);
+
+        // The client is never connected, so these can only succeed from the cache.
+        assert_eq!(item, items.get("a1").await.unwrap());
+        let result = items.get_many(vec!["a1", "a1"]).await.unwrap();
+        assert_eq!(2, result.found.len());
+        assert_eq!(item, result.found[1].value);
+        let ordered = items.get_many_strict(vec!["a1", "a1"]).await.unwrap();
+        assert_eq!(item, ordered[1].value);
+
+        // Deleting evicts the entry, even though the delete itself fails.
+        assert!(items.delete("a1").await.is_err());
+        assert!(items.get("a1").await.is_err());
+
+        items.cache_insert(items.name().document("a1"), &item);
+        clock.advance(Duration::from_secs(60));
+        assert!(items.get("a1").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_get_many_duplicate_keys() {
+        let items: Collection<LineItem> =
+            Collection::new(test_client(), CollectionName::new("my-project", "items"));
+        let item = LineItem {
+            sku: "A1".to_string(),
+            quantity: 2,
+            note: None,
+        };
+        let name = items.name().document("a1");
+        let mut document = to_document(&item).unwrap();
+        document.name = name.name();
+        let documents = vec![(name.name(), Some(document))].into_iter().collect();
+
+        let cached = HashMap::new();
+        for _ in 0..2 {
+            let document = items.fetched_document(&name, &cached, &documents);
+            assert_eq!(item, document.unwrap().unwrap().value);
+        }
+        assert!(items
+            .fetched_document(&items.name().document("b2"), &cached, &documents)
+            .is_none());
+    }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+    struct LineItem {
+        sku: String,
+        quantity: u32,
+        note: Option<String>,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Order {
+        items: Vec<LineItem>,
+    }
+
+    #[test]
+    fn test_nested_struct_array_round_trip() {
+        let order = Order {
+            items: vec![
+                LineItem {
+                    sku: "apple".to_string(),
+                    quantity: 3,
+                    note: None,
+                },
+                LineItem {
+                    sku: "pear".to_string(),
+                    quantity: 1,
+                    note: Some("ripe".to_string()),
+                },
+            ],
+        };
+
+        let document = firestore_serde::to_document(&order).unwrap();
+        assert_eq!(order, from_document::<Order>(document).unwrap());
+    }
+
+    #[test]
+    fn test_to_array_value() {
+        let item = LineItem {
+            sku: "apple".to_string(),
+            quantity: 3,
+            note: None,
+        };
+        let array = to_array_value(std::slice::from_ref(&item)).unwrap();
+        assert_eq!(1, array.values.len());
+
+        // Structs are sent as maps, including their `None` fields, which is what array
+        // transforms compare stored elements against.
+        match &array.values[0].value_type {
+            Some(ValueType::MapValue(map)) => {
+                let mut keys: Vec<&str> = map.fields.keys().map(String::as_str).collect();
+                keys.sort_unstable();
+                assert_eq!(vec!["note", "quantity", "sku"], keys);
+            }
+            value => panic!("Expected a map, got {:?}.", value),
+        }
+
+        let document = firestore_serde::to_document(&Order { items: vec![item] }).unwrap();
+        match &document.fields["items"].value_type {
+            Some(ValueType::ArrayValue(stored)) => assert_eq!(&array, stored),
+            value => panic!("Expected an array, got {:?}.", value),
+        }
+    }
+
+    #[test]
+    fn test_quote_field_path() {
+        assert_eq!("name", quote_field_path("name"));
+        assert_eq!("_id2", quote_field_path("_id2"));
+        assert_eq!("`2fa`", quote_field_path("2fa"));
+        assert_eq!("`a.b`", quote_field_path("a.b"));
+        assert_eq!("`a\\`b`", quote_field_path("a`b"));
+    }
+
+    #[test]
+    fn test_read_length_delimited() {
+        let documents: Vec<Document> = (0..3)
+            .map(|i| Document {
+                name: format!("projects/p/databases/(default)/documents/things/{}", i),
+                fields: to_document(&Profile {
+                    name: "x".repeat(100 * i),
+                    colour: "green".to_string(),
+                    age: None,
+                })
+                .unwrap()
+                .fields,
+                ..Document::default()
+            })
+            .collect();
+        let mut buf = Vec::new();
+        for document in &documents {
+            document.encode_length_delimited(&mut buf).unwrap();
+        }
+
+        let mut reader = buf.as_slice();
+        for document in &documents {
+            assert_eq!(
+                Some(document),
+                read_length_delimited(&mut reader).unwrap().as_ref()
+            );
+        }
+        assert_eq!(None, read_length_delimited(&mut reader).unwrap());
+
+        // Input which ends part-way through a document is an error.
+        let mut truncated = &buf[..buf.len() - 1];
+        read_length_delimited(&mut truncated).unwrap();
+        read_length_delimited(&mut truncated).unwrap();
+        assert!(read_length_delimited(&mut truncated).is_err());
+
+        // So is a length prefix far longer than the input.
+        let mut oversized: &[u8] = &[0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x7f, 0x0a];
+        assert!(read_length_delimited(&mut oversized).is_err());
     }
 }
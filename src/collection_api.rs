@@ -0,0 +1,166 @@
+use crate::{Collection, DocumentName, NamedDocument, WriteResult};
+use serde::{de::DeserializeOwned, Serialize};
+use std::future::Future;
+use std::pin::Pin;
+use tokio_stream::Stream;
+
+/// A future returned by a [CollectionApi] method.
+pub type BoxFuture<'a, R> = Pin<Box<dyn Future<Output = R> + Send + 'a>>;
+
+/// A stream of documents returned by [CollectionApi::list].
+pub type BoxStream<'a, T> = Pin<Box<dyn Stream<Item = NamedDocument<T>> + Send + 'a>>;
+
+/// The core operations of a [Collection], as an object-safe trait, so that code can accept a
+/// `Box<dyn CollectionApi<T>>` and be given a real collection, a mock, or a decorator that
+/// adds logging, caching or metrics around another implementation.
+///
+/// Keys are IDs relative to the collection. Methods return boxed futures rather than being
+/// `async`, so that the trait can be used as a trait object.
+pub trait CollectionApi<T>: Send + Sync {
+    /// See [Collection::get].
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, anyhow::Result<T>>;
+
+    /// See [Collection::create].
+    fn create<'a>(&'a self, ob: &'a T) -> BoxFuture<'a, anyhow::Result<DocumentName>>;
+
+    /// See [Collection::upsert].
+    fn upsert<'a>(&'a self, ob: &'a T, key: &'a str) -> BoxFuture<'a, anyhow::Result<WriteResult>>;
+
+    /// See [Collection::update].
+    fn update<'a>(&'a self, ob: &'a T, key: &'a str) -> BoxFuture<'a, anyhow::Result<WriteResult>>;
+
+    /// See [Collection::delete].
+    fn delete<'a>(&'a self, key: &'a str) -> BoxFuture<'a, anyhow::Result<WriteResult>>;
+
+    /// See [Collection::list].
+    fn list(&self) -> BoxStream<'_, T>;
+}
+
+impl<T> CollectionApi<T> for Collection<T>
+where
+    T: Serialize + DeserializeOwned + Unpin + Send + Sync + 'static,
+{
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, anyhow::Result<T>> {
+        Box::pin(Collection::get(self, key))
+    }
+
+    fn create<'a>(&'a self, ob: &'a T) -> BoxFuture<'a, anyhow::Result<DocumentName>> {
+        Box::pin(Collection::create(self, ob))
+    }
+
+    fn upsert<'a>(&'a self, ob: &'a T, key: &'a str) -> BoxFuture<'a, anyhow::Result<WriteResult>> {
+        Box::pin(Collection::upsert(self, ob, key))
+    }
+
+    fn update<'a>(&'a self, ob: &'a T, key: &'a str) -> BoxFuture<'a, anyhow::Result<WriteResult>> {
+        Box::pin(Collection::update(self, ob, key))
+    }
+
+    fn delete<'a>(&'a self, key: &'a str) -> BoxFuture<'a, anyhow::Result<WriteResult>> {
+        Box::pin(Collection::delete(self, key))
+    }
+
+    fn list(&self) -> BoxStream<'_, T> {
+        Box::pin(Collection::list(self))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::dynamic_firestore_client::test_client;
+    use crate::{CollectionName, Timestamp};
+    use std::collections::BTreeMap;
+    use std::sync::Mutex;
+    use tokio_stream::StreamExt;
+
+    /// An in-memory stand-in for a collection of strings.
+    struct MockCollection {
+        name: CollectionName,
+        documents: Mutex<BTreeMap<String, String>>,
+    }
+
+    impl MockCollection {
+        fn write(&self, ob: &str, key: &str) -> anyhow::Result<WriteResult> {
+            self.documents
+                .lock()
+                .unwrap()
+                .insert(key.to_string(), ob.to_string());
+            Ok(WriteResult {
+                update_time: Timestamp::default(),
+            })
+        }
+    }
+
+    impl CollectionApi<String> for MockCollection {
+        fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, anyhow::Result<String>> {
+            let result = self.documents.lock().unwrap().get(key).cloned();
+            Box::pin(async move { result.ok_or_else(|| anyhow::anyhow!("Not found.")) })
+        }
+
+        fn create<'a>(&'a self, ob: &'a String) -> BoxFuture<'a, anyhow::Result<DocumentName>> {
+            let key = self.documents.lock().unwrap().len().to_string();
+            let result = self.write(ob, &key).map(|_| self.name.document(&key));
+            Box::pin(async move { result })
+        }
+
+        fn upsert<'a>(
+            &'a self,
+            ob: &'a String,
+            key: &'a str,
+        ) -> BoxFuture<'a, anyhow::Result<WriteResult>> {
+            Box::pin(async move { self.write(ob, key) })
+        }
+
+        fn update<'a>(
+            &'a self,
+            ob: &'a String,
+            key: &'a str,
+        ) -> BoxFuture<'a, anyhow::Result<WriteResult>> {
+            Box::pin(async move { self.write(ob, key) })
+        }
+
+        fn delete<'a>(&'a self, key: &'a str) -> BoxFuture<'a, anyhow::Result<WriteResult>> {
+            self.documents.lock().unwrap().remove(key);
+            Box::pin(async move {
+                Ok(WriteResult {
+                    update_time: Timestamp::default(),
+                })
+            })
+        }
+
+        fn list(&self) -> BoxStream<'_, String> {
+            let documents: Vec<NamedDocument<String>> = self
+                .documents
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(key, value)| NamedDocument::new(self.name.document(key), value.clone()))
+                .collect();
+            Box::pin(tokio_stream::iter(documents))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_collection_api_objects() {
+        let name = CollectionName::new("my-project", "words");
+        let mock: Box<dyn CollectionApi<String>> = Box::new(MockCollection {
+            name: name.clone(),
+            documents: Mutex::default(),
+        });
+
+        mock.upsert(&"hello".to_string(), "greeting").await.unwrap();
+        assert_eq!("hello", mock.get("greeting").await.unwrap());
+        assert_eq!(
+            name.document("1"),
+            mock.create(&"bye".to_string()).await.unwrap()
+        );
+        assert_eq!(2, mock.list().collect::<Vec<_>>().await.len());
+        mock.delete("greeting").await.unwrap();
+        assert!(mock.get("greeting").await.is_err());
+
+        // A real collection can be used in its place.
+        let real: Box<dyn CollectionApi<String>> = Box::new(Collection::new(test_client(), name));
+        assert!(real.get("greeting").await.is_err());
+    }
+}
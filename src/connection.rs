@@ -0,0 +1,272 @@
+use http::Uri;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::watch;
+use tower_service::Service;
+
+/// The state of a [crate::Database]'s connection to Firestore, as observed from its attempts to
+/// connect and from the connection itself.
+///
+/// The state is derived from the underlying TCP connection, so it does not reflect failures of
+/// individual requests (such as a request rejected with `UNAVAILABLE` by a connected server).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// A connection is being established.
+    Connecting,
+
+    /// Connected, so requests can be sent without first reconnecting.
+    Ready,
+
+    /// The last attempt to connect failed, or the connection failed while in use. The next
+    /// request attempts to reconnect.
+    TransientFailure,
+
+    /// The connection was closed without an error (e.g. by the server, after it was unused for
+    /// a while). The next request reconnects.
+    Idle,
+}
+
+/// Publishes the state of the most recent connection made by a [MonitorConnection].
+#[derive(Clone)]
+pub(crate) struct ConnectionMonitor {
+    sender: Arc<watch::Sender<ConnectionState>>,
+
+    /// The number of the most recent connection attempt. Changes reported for earlier
+    /// connections (such as one closing after it was replaced) are ignored.
+    latest: Arc<Mutex<u64>>,
+}
+
+impl ConnectionMonitor {
+    /// Construct a monitor, with a receiver which observes its state.
+    pub(crate) fn new() -> (Self, watch::Receiver<ConnectionState>) {
+        let (sender, receiver) = watch::channel(ConnectionState::Connecting);
+        let monitor = ConnectionMonitor {
+            sender: Arc::new(sender),
+            latest: Arc::new(Mutex::new(0)),
+        };
+        (monitor, receiver)
+    }
+
+    /// Record the start of a connection attempt, returning its number.
+    fn start_attempt(&self) -> u64 {
+        let mut latest = self.latest.lock().unwrap();
+        *latest += 1;
+        self.publish(ConnectionState::Connecting);
+        *latest
+    }
+
+    /// Record the state of the given connection attempt, if it is the most recent.
+    fn set(&self, attempt: u64, state: ConnectionState) {
+        let latest = self.latest.lock().unwrap();
+        if *latest == attempt {
+            self.publish(state);
+        }
+    }
+
+    fn publish(&self, state: ConnectionState) {
+        // Only changes are published, so that observers are not woken by repeats.
+        if *self.sender.borrow() != state {
+            // This fails only if every receiver is gone, in which case nobody is watching.
+            let _ = self.sender.send(state);
+        }
+    }
+}
+
+/// Wraps a connector, reporting the outcome of each connection it makes to a
+/// [ConnectionMonitor].
+pub(crate) struct MonitorConnection<C> {
+    connector: C,
+    monitor: ConnectionMonitor,
+}
+
+impl<C> MonitorConnection<C> {
+    pub(crate) fn new(connector: C, monitor: ConnectionMonitor) -> Self {
+        MonitorConnection { connector, monitor }
+    }
+}
+
+impl<C> Service<Uri> for MonitorConnection<C>
+where
+    C: Service<Uri>,
+    C::Future: Send + 'static,
+{
+    type Response = MonitoredIo<C::Response>;
+    type Error = C::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.connector.poll_ready(cx)
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let attempt = self.monitor.start_attempt();
+        let monitor = self.monitor.clone();
+        let connecting = self.connector.call(uri);
+
+        Box::pin(async move {
+            match connecting.await {
+                Ok(io) => {
+                    monitor.set(attempt, ConnectionState::Ready);
+                    Ok(MonitoredIo {
+                        io,
+                        attempt,
+                        monitor,
+                    })
+                }
+                Err(error) => {
+                    monitor.set(attempt, ConnectionState::TransientFailure);
+                    Err(error)
+                }
+            }
+        })
+    }
+}
+
+/// A connection made by a [MonitorConnection], which reports when it fails or is closed.
+pub(crate) struct MonitoredIo<I> {
+    io: I,
+    attempt: u64,
+    monitor: ConnectionMonitor,
+}
+
+impl<I> MonitoredIo<I> {
+    /// Report the failure of the connection if `result` is an error.
+    fn observe<R>(&self, result: Poll<io::Result<R>>) -> Poll<io::Result<R>> {
+        if let Poll::Ready(Err(_)) = &result {
+            self.monitor
+                .set(self.attempt, ConnectionState::TransientFailure);
+        }
+        result
+    }
+}
+
+impl<I: AsyncRead + Unpin> AsyncRead for MonitoredIo<I> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let wanted = buf.remaining() > 0;
+        let filled = buf.filled().len();
+        let result = Pin::new(&mut self.io).poll_read(cx, buf);
+
+        // Reading nothing into a buffer with room means the other end closed the connection.
+        if let Poll::Ready(Ok(())) = result {
+            if wanted && buf.filled().len() == filled {
+                self.monitor.set(self.attempt, ConnectionState::Idle);
+            }
+        }
+        self.observe(result)
+    }
+}
+
+impl<I: AsyncWrite + Unpin> AsyncWrite for MonitoredIo<I> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let result = Pin::new(&mut self.io).poll_write(cx, buf);
+        self.observe(result)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let result = Pin::new(&mut self.io).poll_flush(cx);
+        self.observe(result)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let result = Pin::new(&mut self.io).poll_shutdown(cx);
+        self.observe(result)
+    }
+}
+
+impl<I> Drop for MonitoredIo<I> {
+    fn drop(&mut self) {
+        // A connection which failed stays failed, rather than becoming idle.
+        let latest = self.monitor.latest.lock().unwrap();
+        if *latest == self.attempt && *self.monitor.sender.borrow() == ConnectionState::Ready {
+            self.monitor.publish(ConnectionState::Idle);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Cursor;
+
+    /// A connector which connects to an in-memory buffer, or fails.
+    struct FakeConnector {
+        fail: bool,
+    }
+
+    impl Service<Uri> for FakeConnector {
+        type Response = Cursor<Vec<u8>>;
+        type Error = io::Error;
+        type Future = std::future::Ready<io::Result<Cursor<Vec<u8>>>>;
+
+        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, _uri: Uri) -> Self::Future {
+            if self.fail {
+                std::future::ready(Err(io::ErrorKind::ConnectionRefused.into()))
+            } else {
+                std::future::ready(Ok(Cursor::new(Vec::new())))
+            }
+        }
+    }
+
+    fn connect(
+        monitor: &ConnectionMonitor,
+        fail: bool,
+    ) -> <MonitorConnection<FakeConnector> as Service<Uri>>::Future {
+        MonitorConnection::new(FakeConnector { fail }, monitor.clone())
+            .call(Uri::from_static("http://localhost"))
+    }
+
+    #[tokio::test]
+    async fn test_connection_state() {
+        let (monitor, state) = ConnectionMonitor::new();
+        assert_eq!(ConnectionState::Connecting, *state.borrow());
+
+        let connection = connect(&monitor, false).await.unwrap();
+        assert_eq!(ConnectionState::Ready, *state.borrow());
+        drop(connection);
+        assert_eq!(ConnectionState::Idle, *state.borrow());
+
+        assert!(connect(&monitor, true).await.is_err());
+        assert_eq!(ConnectionState::TransientFailure, *state.borrow());
+    }
+
+    #[tokio::test]
+    async fn test_connection_closed_by_server() {
+        let (monitor, state) = ConnectionMonitor::new();
+        let mut connection = connect(&monitor, false).await.unwrap();
+
+        // The buffer is empty, so reading from it finds the end of the stream.
+        let mut bytes = [0; 16];
+        let mut buf = ReadBuf::new(&mut bytes);
+        std::future::poll_fn(|cx| Pin::new(&mut connection).poll_read(cx, &mut buf))
+            .await
+            .unwrap();
+        assert_eq!(ConnectionState::Idle, *state.borrow());
+    }
+
+    #[tokio::test]
+    async fn test_replaced_connection_is_ignored() {
+        let (monitor, state) = ConnectionMonitor::new();
+        let old = connect(&monitor, false).await.unwrap();
+        let _new = connect(&monitor, false).await.unwrap();
+
+        // Closing the old connection does not change the state of the new one.
+        drop(old);
+        assert_eq!(ConnectionState::Ready, *state.borrow());
+    }
+}
@@ -0,0 +1,95 @@
+use crate::dynamic_firestore_client::SharedFirestoreClient;
+use crate::identifiers::CollectionName;
+use crate::list_response::ListResponse;
+use crate::retry::RetryPolicy;
+use crate::transforms::increment;
+use firestore_serde::firestore::{value::ValueType, Value};
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+
+/// Name of the numeric field stored in each shard document.
+const COUNT_FIELD: &str = "count";
+
+#[derive(Serialize, Deserialize)]
+struct Shard {
+    count: i64,
+}
+
+/// A counter that spreads its value across several shard documents.
+///
+/// Firestore limits the sustained write rate to any single document to roughly one write per
+/// second, so a counter stored in one document cannot keep up with frequent increments.
+/// A `ShardedCounter` instead increments one of `num_shards` documents at random, and sums
+/// all of them when read. Write throughput scales with the number of shards, at the cost of
+/// reading every shard to get the total.
+///
+/// Shards are stored as documents named `0` to `num_shards - 1` in a dedicated collection.
+/// The number of shards can be increased later without losing counts, but should not be
+/// decreased, since shards beyond `num_shards` are still included when reading.
+pub struct ShardedCounter {
+    db: SharedFirestoreClient,
+    shards: CollectionName,
+    num_shards: usize,
+    retry: RetryPolicy,
+}
+
+impl ShardedCounter {
+    /// Construct a counter whose shards are stored in the given collection. Returns an error if
+    /// `num_shards` is zero.
+    pub fn new(
+        db: SharedFirestoreClient,
+        shards: CollectionName,
+        num_shards: usize,
+    ) -> anyhow::Result<Self> {
+        if num_shards == 0 {
+            return Err(anyhow::anyhow!(
+                "Cannot create sharded counter in {} with no shards; at least one is needed.",
+                shards.name()
+            ));
+        }
+
+        Ok(ShardedCounter {
+            db,
+            shards,
+            num_shards,
+            retry: RetryPolicy::default(),
+        })
+    }
+
+    /// Use the given policy to retry requests that fail with a transient error. Increments are
+    /// not idempotent, so are only retried if the policy forces it.
+    pub fn with_retry_policy(self, retry: RetryPolicy) -> Self {
+        ShardedCounter { retry, ..self }
+    }
+
+    /// Returns the name of the collection in which shards are stored.
+    pub fn shards(&self) -> CollectionName {
+        self.shards.clone()
+    }
+
+    /// Atomically add `by` (which may be negative) to a randomly-chosen shard.
+    pub async fn increment(&self, by: i64) -> anyhow::Result<()> {
+        let shard = RandomState::new().build_hasher().finish() as usize % self.num_shards;
+        let value = Value {
+            value_type: Some(ValueType::IntegerValue(by)),
+        };
+
+        increment(
+            &self.db,
+            &self.retry,
+            &self.shards.document(&shard.to_string()),
+            COUNT_FIELD,
+            value,
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Returns the value of the counter, by reading and summing every shard.
+    pub async fn get(&self) -> anyhow::Result<i64> {
+        ListResponse::<Shard>::new(self.shards.clone(), self.db.clone())
+            .fold(0, |total, shard| total + shard.value.count)
+            .await
+    }
+}
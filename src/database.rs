@@ -1,40 +1,422 @@
 use google_authz::TokenSource;
 use serde::{de::DeserializeOwned, Serialize};
+use std::future::Future;
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use tokio::sync::watch;
+use tokio_stream::wrappers::WatchStream;
+use tokio_stream::Stream;
 
-use crate::client::get_client;
+use crate::admin::{get_database, DatabaseInfo, SharedAdminClient};
+use crate::auth::TokenRefresher;
+use crate::batch::WriteBatch;
+use crate::client::{get_data_and_admin_clients, get_emulator_clients, ClientOptions, Clients};
+use crate::clock::{Clock, SystemClock};
+use crate::collection::{list_collection_ids, walk_tree};
+use crate::connection::ConnectionState;
 use crate::dynamic_firestore_client::SharedFirestoreClient;
-use crate::{Collection, CollectionName};
+use crate::identifiers::{ParentDocumentOrRoot, DEFAULT_DATABASE_ID};
+use crate::offline::OfflineQueue;
+use crate::retry::{RetryBudget, RetryPolicy};
+use crate::transaction::Transaction;
+use crate::{Collection, CollectionName, DocumentName, ParseError, Query, Timestamp};
+
+/// Declares a top-level collection's name together with the type of its documents, so that
+/// call sites cannot disagree about either.
+///
+/// ```
+/// use serde::{Deserialize, Serialize};
+/// use tiny_firestore_odm::{Collection, CollectionSpec, Database};
+///
+/// #[derive(Serialize, Deserialize)]
+/// struct User {
+///     name: String,
+/// }
+///
+/// struct Users;
+///
+/// impl CollectionSpec for Users {
+///     type Document = User;
+///     const NAME: &'static str = "users";
+/// }
+///
+/// fn users(db: &Database) -> Collection<User> {
+///     db.typed::<Users>()
+/// }
+/// ```
+pub trait CollectionSpec {
+    /// The type that documents in the collection are serialized from and deserialized into.
+    type Document: Serialize + DeserializeOwned + Unpin + 'static;
+
+    /// The ID of the collection, relative to the root of the database.
+    const NAME: &'static str;
+}
 
 /// Represents a Firestore database.
 pub struct Database {
     client: SharedFirestoreClient,
+    /// A client for the admin API, which is only available if this database constructed its
+    /// own connection.
+    admin_client: Option<SharedAdminClient>,
     project_id: String,
+    database_id: String,
+    retry: RetryPolicy,
+    /// Discards the cached access token, if this database constructed its own connection
+    /// and its options enable refreshing.
+    token_refresher: Option<TokenRefresher>,
+    clock: Arc<dyn Clock>,
+    /// Observes the state of the connection, if this database constructed its own.
+    connection_state: Option<watch::Receiver<ConnectionState>>,
+    /// Passed on to collections, to hold writes while Firestore is unreachable.
+    offline_queue: Option<OfflineQueue>,
 }
 
 impl Database {
+    /// Construct a database, connecting to Firestore with credentials from the given token
+    /// source.
+    ///
+    /// If the `FIRESTORE_EMULATOR_HOST` environment variable is set (e.g. to
+    /// `localhost:8080`), this connects to the Firestore emulator at that address instead,
+    /// and the token source is not used.
+    ///
+    /// This uses the project's default database; call `with_database_id` to use a named one.
     pub async fn new(token_source: TokenSource, project_id: &str) -> Self {
-        let client = Arc::new(Mutex::new(get_client(token_source).await.unwrap()));
+        Self::new_with_options(token_source, project_id, &ClientOptions::default()).await
+    }
+
+    /// Construct a database with a client configured by the given [ClientOptions]. Like
+    /// `new`, this connects to the emulator if `FIRESTORE_EMULATOR_HOST` is set.
+    pub async fn new_with_options(
+        token_source: TokenSource,
+        project_id: &str,
+        options: &ClientOptions,
+    ) -> Self {
+        let clients = get_data_and_admin_clients(token_source, options)
+            .await
+            .unwrap();
+        Self::from_clients(clients, project_id)
+    }
+
+    /// Construct a database backed by the Firestore emulator at the given host and port (e.g.
+    /// `localhost:8080`), which needs no credentials.
+    pub async fn new_emulator(host: &str, project_id: &str) -> Self {
+        let clients = get_emulator_clients(host, &ClientOptions::default())
+            .await
+            .unwrap();
+        Self::from_clients(clients, project_id)
+    }
+
+    fn from_clients(clients: Clients, project_id: &str) -> Self {
         Database {
-            client,
+            client: clients.data,
+            admin_client: Some(clients.admin),
             project_id: project_id.to_string(),
+            database_id: DEFAULT_DATABASE_ID.to_string(),
+            retry: RetryPolicy::default(),
+            token_refresher: clients.token_refresher,
+            clock: Arc::new(SystemClock),
+            connection_state: Some(clients.connection_state),
+            offline_queue: None,
         }
     }
 
     pub fn new_from_client(client: SharedFirestoreClient, project_id: &str) -> Self {
         Database {
             client,
+            admin_client: None,
             project_id: project_id.to_string(),
+            database_id: DEFAULT_DATABASE_ID.to_string(),
+            retry: RetryPolicy::default(),
+            token_refresher: None,
+            clock: Arc::new(SystemClock),
+            connection_state: None,
+            offline_queue: None,
+        }
+    }
+
+    /// Use the named database with the given ID in this database's project, rather than the
+    /// project's default database.
+    pub fn with_database_id(self, database_id: &str) -> Self {
+        Database {
+            database_id: database_id.to_string(),
+            ..self
+        }
+    }
+
+    /// Use the given policy to retry requests that fail with a transient error. The policy is
+    /// passed on to every collection obtained from this database. See [RetryPolicy] for which
+    /// operations are retried.
+    pub fn with_retry_policy(self, retry: RetryPolicy) -> Self {
+        Database { retry, ..self }
+    }
+
+    /// Limit retries across every collection obtained from this database to a shared budget,
+    /// allowing bursts of up to `capacity` retries and refilling at `refill_per_second`
+    /// retries per second. Once the budget is exhausted, failed requests are returned without
+    /// being retried. See [RetryBudget].
+    ///
+    /// This applies to the current retry policy, so should be called after
+    /// `with_retry_policy`.
+    pub fn with_retry_budget(self, capacity: u32, refill_per_second: f64) -> Self {
+        let retry = self
+            .retry
+            .clone()
+            .with_budget(RetryBudget::new(capacity, refill_per_second));
+        Database { retry, ..self }
+    }
+
+    /// Retry requests that fail with a status for which `predicate` returns `true`, in place
+    /// of the default classification of transient errors. See [RetryPolicy::with_retry_on].
+    ///
+    /// This applies to the current retry policy, so should be called after
+    /// `with_retry_policy`.
+    pub fn with_retry_on<F>(self, predicate: F) -> Self
+    where
+        F: Fn(&tonic::Status) -> bool + Send + Sync + 'static,
+    {
+        let retry = self.retry.clone().with_retry_on(predicate);
+        Database { retry, ..self }
+    }
+
+    /// Use the given clock for timestamps computed on the client, such as [Database::now] and
+    /// the expiry of cached counts. Timestamps set by the server are unaffected. The clock is
+    /// passed on to every collection obtained from this database.
+    pub fn with_clock(self, clock: impl Clock + 'static) -> Self {
+        Database {
+            clock: Arc::new(clock),
+            ..self
         }
     }
 
+    /// Hold writes made with [Collection::apply_or_queue] in the given queue while Firestore
+    /// is unreachable. The queue is passed on to every collection obtained from this database.
+    pub fn with_offline_queue(self, offline_queue: OfflineQueue) -> Self {
+        Database {
+            offline_queue: Some(offline_queue),
+            ..self
+        }
+    }
+
+    /// Apply the writes held in this database's [OfflineQueue], in the order they were
+    /// queued, returning the number of batches applied. Call it once Firestore is reachable
+    /// again, e.g. when [Database::connection_state_changes] yields
+    /// [ConnectionState::Ready].
+    ///
+    /// Stops at the first batch which fails, leaving it and the batches behind it queued, and
+    /// returns its error. If Firestore rejected the batch (rather than being unreachable),
+    /// replaying it is likely to fail again; it can be dropped with
+    /// [OfflineQueue::discard_next].
+    pub async fn flush_offline_queue(&self) -> anyhow::Result<usize> {
+        let offline_queue = self.offline_queue.as_ref().ok_or_else(|| {
+            anyhow::anyhow!("flush_offline_queue requires the database to have an offline queue.")
+        })?;
+        offline_queue
+            .flush(&self.client, &self.retry_policy())
+            .await
+    }
+
+    /// Returns the current time according to this database's [Clock], for storing
+    /// client-set timestamps in documents.
+    pub fn now(&self) -> Timestamp {
+        self.clock.now().into()
+    }
+
+    /// The retry policy passed on to collections, which refreshes this database's access
+    /// token if a request is unauthenticated.
+    fn retry_policy(&self) -> RetryPolicy {
+        self.retry
+            .clone()
+            .with_token_refresher(self.token_refresher.clone())
+    }
+
+    /// The fully-qualified name of this database, as passed in the Firestore API.
+    fn database_name(&self) -> String {
+        format!(
+            "projects/{}/databases/{}",
+            self.project_id, self.database_id
+        )
+    }
+
+    /// The root of this database, which top-level collections are directly under.
+    fn root(&self) -> ParentDocumentOrRoot {
+        ParentDocumentOrRoot::root_with_database(&self.project_id, &self.database_id)
+    }
+
+    /// The name of the top-level collection with the given ID in this database.
+    fn collection_name(&self, collection_id: &str) -> CollectionName {
+        CollectionName::new_with_database(&self.project_id, &self.database_id, collection_id)
+    }
+
+    /// Start a transaction. See [Transaction] for how reads and writes within it behave.
+    ///
+    /// The transaction must be committed with [Transaction::commit] for its writes to take
+    /// effect.
+    pub async fn begin_transaction(&self) -> anyhow::Result<Transaction> {
+        Transaction::begin(self.client.clone(), self.database_name(), Vec::new()).await
+    }
+
+    /// Run `body` in a transaction, and commit the writes it buffers if it succeeds. Returns
+    /// the value returned by `body`.
+    ///
+    /// If `body` returns an error, the transaction is rolled back and the error returned. If
+    /// the transaction is aborted, because a document it read was changed by someone else
+    /// before it committed, it is retried from the start by calling `body` again, up to 5
+    /// attempts in total. `body` should therefore not have side effects outside the
+    /// transaction.
+    ///
+    /// `body` is passed a handle to the transaction, which it should not commit or roll back
+    /// itself:
+    ///
+    /// ```
+    /// # use serde::{Deserialize, Serialize};
+    /// # use tiny_firestore_odm::{Collection, Database};
+    /// #[derive(Serialize, Deserialize)]
+    /// struct Account {
+    ///     balance: i64,
+    /// }
+    ///
+    /// async fn deposit(db: &Database, accounts: &Collection<Account>) -> anyhow::Result<()> {
+    ///     db.transaction(|tx| async move {
+    ///         let mut account = tx.get(accounts, "a").await?.unwrap();
+    ///         account.balance += 10;
+    ///         tx.update(accounts, "a", &account)
+    ///     })
+    ///     .await
+    /// }
+    /// ```
+    pub async fn transaction<F, Fut, R>(&self, body: F) -> anyhow::Result<R>
+    where
+        F: FnMut(Transaction) -> Fut,
+        Fut: Future<Output = anyhow::Result<R>>,
+    {
+        Transaction::run(self.client.clone(), self.database_name(), body).await
+    }
+
+    /// Start a batch of writes to documents in any collections of this database, which are
+    /// sent together when it is committed. See [WriteBatch].
+    pub fn batch(&self) -> WriteBatch {
+        WriteBatch::new(
+            self.client.clone(),
+            self.database_name(),
+            self.retry_policy(),
+        )
+    }
+
+    /// Returns metadata about this database: its location, type, and concurrency mode.
+    ///
+    /// This uses the `GetDatabase` call of the Firestore Admin API, which requires the
+    /// credentials to have the `datastore.databases.getMetadata` permission. It is only
+    /// available on databases constructed with `Database::new`, since a client passed to
+    /// `new_from_client` cannot be used for the admin API.
+    pub async fn info(&self) -> anyhow::Result<DatabaseInfo> {
+        let admin_client = self.admin_client.as_ref().ok_or_else(|| {
+            anyhow::anyhow!("Database::info is not available on a database created from a client.")
+        })?;
+
+        Ok(get_database(admin_client, &self.database_name()).await?)
+    }
+
+    /// Returns the current state of this database's connection to Firestore. See
+    /// [ConnectionState] for how the state is determined.
+    ///
+    /// Returns `None` for databases constructed with `new_from_client`, since the connection
+    /// of a client passed in cannot be observed.
+    pub fn connection_state(&self) -> Option<ConnectionState> {
+        self.connection_state
+            .as_ref()
+            .map(|receiver| *receiver.borrow())
+    }
+
+    /// Returns a stream which yields the current state of this database's connection to
+    /// Firestore, then each new state as it changes. If the state changes more than once
+    /// between polls of the stream, only the latest state is yielded.
+    ///
+    /// Returns `None` for databases constructed with `new_from_client`.
+    pub fn connection_state_changes(
+        &self,
+    ) -> Option<impl Stream<Item = ConnectionState> + Send + 'static> {
+        self.connection_state.clone().map(WatchStream::new)
+    }
+
+    /// Returns the fully-qualified name of a document, given its path relative to the root of
+    /// this database (e.g. `users/u1/devices/d1`).
+    ///
+    /// Returns a [ParseError] if the path names a collection rather than a document.
+    pub fn document_name(&self, relative_path: &str) -> Result<DocumentName, ParseError> {
+        let root = self.root().name();
+        DocumentName::parse(&format!("{}/{}", root, relative_path))
+    }
+
+    /// Get the document at the given path relative to the root of this database
+    /// (e.g. `users/u1/devices/d1`).
+    ///
+    /// Returns an error wrapping a [ParseError] if the path names a collection rather than a
+    /// document.
+    pub async fn get_by_path<T>(&self, relative_path: &str) -> anyhow::Result<T>
+    where
+        T: Serialize + DeserializeOwned + 'static + Unpin,
+    {
+        let name = self.document_name(relative_path)?;
+        Collection::new(self.client.clone(), name.collection().clone())
+            .with_retry_policy(self.retry_policy())
+            .get(&name)
+            .await
+    }
+
+    /// Returns the name of every document in the database, each with its depth: 0 for
+    /// documents in top-level collections, 1 for documents in their subcollections, and so on.
+    /// Subcollections more than `max_depth` levels deep are not descended into.
+    ///
+    /// Documents are returned in depth-first order. This reads the entire tree, which is slow
+    /// and expensive for a large database; see [Collection::walk_tree] to walk part of it.
+    pub async fn walk_tree(&self, max_depth: usize) -> anyhow::Result<Vec<(DocumentName, usize)>> {
+        let root = self.root().name();
+        let retry = self.retry_policy();
+        let collection_ids = list_collection_ids(&self.client, &retry, &root).await?;
+        walk_tree(&self.client, &retry, &root, collection_ids, max_depth).await
+    }
+
     /// Returns a top-level collection from this database.
     pub fn collection<T>(&self, name: &str) -> Collection<T>
     where
         T: Serialize + DeserializeOwned + 'static + Unpin,
     {
-        let name = CollectionName::new(&self.project_id, name);
-        Collection::new(self.client.clone(), name)
+        let name = self.collection_name(name);
+        let collection = Collection::new(self.client.clone(), name)
+            .with_retry_policy(self.retry_policy())
+            .with_clock(self.clock.clone());
+        match &self.offline_queue {
+            Some(offline_queue) => collection.with_offline_queue(offline_queue.clone()),
+            None => collection,
+        }
+    }
+
+    /// Returns a collection group [Query], which matches the documents of every collection
+    /// with the given ID in this database, whatever its parent (e.g. the `devices`
+    /// subcollection of every user). Filters, ordering, offsets and cursors apply across all
+    /// of them.
+    ///
+    /// Filtering or ordering a collection group query on a field requires a collection group
+    /// index on it. If it does not exist, running the query fails with a
+    /// [crate::MissingIndexError] containing a link to create it.
+    ///
+    /// Keys passed to [Query::where_id_in] and [Query::where_name] are qualified against the
+    /// top-level collection with this ID, so those filters cannot match documents nested
+    /// deeper.
+    pub fn collection_group<T>(&self, collection_id: &str) -> Query<T>
+    where
+        T: Serialize + DeserializeOwned + 'static + Unpin,
+    {
+        let name = self.collection_name(collection_id);
+        Query::new(name, self.client.clone())
+            .with_retry_policy(self.retry.clone())
+            .with_all_descendants()
+    }
+
+    /// Returns the top-level collection declared by the given [CollectionSpec].
+    pub fn typed<S>(&self) -> Collection<S::Document>
+    where
+        S: CollectionSpec,
+    {
+        self.collection(S::NAME)
     }
 }
@@ -1,24 +1,46 @@
-use std::sync::Arc;
-
 use firestore_serde::firestore::firestore_client::FirestoreClient;
 use http::Request;
-use hyper::Body;
-use tokio::sync::Mutex;
+use std::sync::Mutex;
 use tonic::{body::BoxBody, client::GrpcService, transport::channel::ResponseFuture};
 use tower_service::Service;
 
+/// A boxed service which can clone itself.
+trait CloneService:
+    Service<
+        Request<BoxBody>,
+        Response = http::Response<hyper::Body>,
+        Error = tonic::transport::Error,
+        Future = ResponseFuture,
+    > + Send
+{
+    fn clone_box(&self) -> Box<dyn CloneService>;
+}
+
+impl<T> CloneService for T
+where
+    T: Service<
+            Request<BoxBody>,
+            Response = http::Response<hyper::Body>,
+            Error = tonic::transport::Error,
+            Future = ResponseFuture,
+        >
+        + Clone
+        + 'static
+        + Send,
+{
+    fn clone_box(&self) -> Box<dyn CloneService> {
+        Box::new(self.clone())
+    }
+}
+
 /// A Service which uses dynamic dispatch on another service.
 ///
-/// This mainly prevents the service type from polluting everything else.
+/// This mainly prevents the service type from polluting everything else. Cloning it clones
+/// the underlying service, which for a channel is cheap and shares its connection.
 pub struct WrappedService {
-    service: Box<
-        dyn Service<
-                Request<BoxBody>,
-                Response = http::Response<hyper::Body>,
-                Error = tonic::transport::Error,
-                Future = ResponseFuture,
-            > + Send,
-    >,
+    /// Behind a mutex only so that a client can be shared between threads and cloned from
+    /// there; requests are made through `&mut self`, which needs no locking.
+    service: Mutex<Box<dyn CloneService>>,
 }
 
 impl WrappedService {
@@ -30,34 +52,74 @@ impl WrappedService {
                 Error = tonic::transport::Error,
                 Future = ResponseFuture,
             >
+            + Clone
             + 'static
             + Send,
     {
         WrappedService {
-            service: Box::new(service),
+            service: Mutex::new(Box::new(service)),
+        }
+    }
+}
+
+impl Clone for WrappedService {
+    fn clone(&self) -> Self {
+        WrappedService {
+            service: Mutex::new(self.service.lock().unwrap().clone_box()),
         }
     }
 }
 
 impl GrpcService<BoxBody> for WrappedService {
-    type ResponseBody = Body;
+    #[cfg(not(feature = "opentelemetry"))]
+    type ResponseBody = hyper::Body;
+
+    #[cfg(feature = "opentelemetry")]
+    type ResponseBody = crate::telemetry::TracedBody;
 
     type Error = tonic::transport::Error;
 
+    #[cfg(not(feature = "opentelemetry"))]
     type Future = ResponseFuture;
 
+    #[cfg(feature = "opentelemetry")]
+    type Future = crate::telemetry::TracedFuture;
+
     fn poll_ready(
         &mut self,
         cx: &mut std::task::Context<'_>,
     ) -> std::task::Poll<Result<(), Self::Error>> {
-        GrpcService::poll_ready(&mut self.service, cx)
+        GrpcService::poll_ready(self.service.get_mut().unwrap(), cx)
     }
 
+    #[cfg(not(feature = "opentelemetry"))]
     fn call(&mut self, request: Request<BoxBody>) -> Self::Future {
-        GrpcService::call(&mut self.service, request)
+        GrpcService::call(self.service.get_mut().unwrap(), request)
+    }
+
+    /// Make the request inside a client span, which ends when its gRPC status is received.
+    #[cfg(feature = "opentelemetry")]
+    fn call(&mut self, request: Request<BoxBody>) -> Self::Future {
+        let (request, span) = crate::telemetry::start_span(request);
+        let response = GrpcService::call(self.service.get_mut().unwrap(), request);
+        Box::pin(crate::telemetry::trace_response(response, span))
     }
 }
 
 pub type DynamicFirestoreClient = FirestoreClient<WrappedService>;
 
-pub type SharedFirestoreClient = Arc<Mutex<DynamicFirestoreClient>>;
+/// A client shared between collections, queries and transactions.
+///
+/// Requests are multiplexed over one connection, so rather than locking a shared client, each
+/// request is made through its own clone.
+pub type SharedFirestoreClient = DynamicFirestoreClient;
+
+/// Construct a client that is never connected, for use in unit tests that do not issue
+/// requests.
+#[cfg(test)]
+pub(crate) fn test_client() -> SharedFirestoreClient {
+    let channel = tonic::transport::Endpoint::from_static("http://localhost")
+        .connect_lazy()
+        .unwrap();
+    FirestoreClient::new(WrappedService::new(channel))
+}
@@ -0,0 +1,701 @@
+use crate::identifiers::DocumentName;
+use firestore_serde::firestore::{value::ValueType, Document, MapValue, Value};
+use googapis::google::rpc;
+use prost::Message;
+use serde::{de::DeserializeOwned, Serialize};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::Display;
+use tonic::{Code, Status};
+
+/// An error encountered when converting a Firestore document into a Rust value.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeserializeError {
+    /// Description of the problem, as reported by `firestore_serde`.
+    pub message: String,
+
+    /// Dot-separated path of the field which could not be deserialized, if it could be
+    /// determined.
+    pub field: Option<String>,
+
+    /// The type of the Firestore value found in `field` (e.g. `"string"`), if the field
+    /// could be determined.
+    pub found: Option<&'static str>,
+}
+
+impl Display for DeserializeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (&self.field, self.found) {
+            (Some(field), Some(found)) => write!(
+                f,
+                "Error deserializing field `{}` (found {} value): {}",
+                field, found, self.message
+            ),
+            _ => write!(f, "Error deserializing document: {}", self.message),
+        }
+    }
+}
+
+impl Error for DeserializeError {}
+
+/// An error from an operation that has failure cases callers may want to handle.
+///
+/// Converts into an `anyhow::Error` with `?` like any other error.
+#[derive(Debug)]
+pub enum FirestoreError {
+    /// A document could not be created because a document with the same name already exists.
+    AlreadyExists(DocumentName),
+
+    /// A document was not written because the stored document has a version at least as new
+    /// (see [crate::Collection::create_if_newer]), or has changed since it was read (see
+    /// [crate::Collection::update_if_unchanged]).
+    Stale(DocumentName),
+
+    /// Any other error.
+    Other(anyhow::Error),
+}
+
+impl FirestoreError {
+    pub(crate) fn other(error: impl Into<anyhow::Error>) -> Self {
+        FirestoreError::Other(error.into())
+    }
+
+    /// Returns the gRPC status returned by Firestore which caused this error, if any. See
+    /// [grpc_status].
+    ///
+    /// This is always `None` for the variants other than `Other`, which are identified
+    /// without needing the status.
+    pub fn status(&self) -> Option<&Status> {
+        match self {
+            FirestoreError::AlreadyExists(_) | FirestoreError::Stale(_) => None,
+            FirestoreError::Other(error) => grpc_status(error),
+        }
+    }
+}
+
+/// Returns the gRPC status returned by Firestore which caused an error, if any, as it was
+/// received: with its metadata (including trailers) and its encoded details.
+///
+/// This finds the status wherever it is in the chain of causes, including underneath a
+/// [MissingIndexError] or a [FirestoreError]. Use [status_details] to decode the structured
+/// details Firestore attaches to some errors.
+pub fn grpc_status(error: &anyhow::Error) -> Option<&Status> {
+    error
+        .chain()
+        .find_map(|cause| cause.downcast_ref::<Status>())
+}
+
+/// Decode the `google.rpc.Status` carried in a status's details, if it has any.
+///
+/// Its `details` are `Any` messages of the types in [crate::rpc] (such as `ErrorInfo`,
+/// `QuotaFailure` or `RetryInfo`), which can be told apart by their `type_url` (e.g.
+/// `type.googleapis.com/google.rpc.ErrorInfo`) and decoded with [prost::Message::decode].
+pub fn status_details(status: &Status) -> Option<rpc::Status> {
+    if status.details().is_empty() {
+        return None;
+    }
+    rpc::Status::decode(status.details()).ok()
+}
+
+impl Display for FirestoreError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FirestoreError::AlreadyExists(name) => {
+                write!(f, "Document {} already exists.", name.name())
+            }
+            FirestoreError::Stale(name) => write!(
+                f,
+                "Document {} was not written because the stored version is newer than expected.",
+                name.name()
+            ),
+            FirestoreError::Other(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl Error for FirestoreError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            FirestoreError::AlreadyExists(_) | FirestoreError::Stale(_) => None,
+            FirestoreError::Other(error) => Some(error.as_ref()),
+        }
+    }
+}
+
+/// An error returned when a document is rejected by a collection's validator (see
+/// [crate::Collection::with_validator]) before being written.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ValidationError {
+    /// The message returned by the validator.
+    pub message: String,
+}
+
+impl Display for ValidationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Document failed validation: {}", self.message)
+    }
+}
+
+impl Error for ValidationError {}
+
+/// An error returned by [crate::Collection::get_many_strict] when some of the documents
+/// requested do not exist.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MissingDocuments {
+    /// The name of every document requested that does not exist, in the order their keys
+    /// were given.
+    pub names: Vec<DocumentName>,
+}
+
+impl Display for MissingDocuments {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let names: Vec<String> = self.names.iter().map(DocumentName::name).collect();
+        write!(
+            f,
+            "{} documents not found: {}",
+            names.len(),
+            names.join(", ")
+        )
+    }
+}
+
+impl Error for MissingDocuments {}
+
+/// An error returned when a query needs a composite index that does not exist.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MissingIndexError {
+    /// The error message returned by Firestore.
+    pub message: String,
+
+    /// The fields the query filters and orders on, with their operators or directions
+    /// (e.g. `a asc, b >`).
+    pub query_shape: String,
+
+    /// A link to the Firebase console which creates the missing index, if Firestore
+    /// provided one.
+    pub create_index_url: Option<String>,
+}
+
+impl MissingIndexError {
+    /// Convert a status returned while running a query into an error, which is a
+    /// [MissingIndexError] if the status says an index is needed. `query_shape` describes
+    /// the query that was run.
+    pub(crate) fn from_status(status: Status, query_shape: String) -> anyhow::Error {
+        if status.code() != Code::FailedPrecondition || !status.message().contains("index") {
+            return status.into();
+        }
+
+        let message = status.message().to_string();
+        let create_index_url = message
+            .find("https://")
+            .map(|start| {
+                message[start..]
+                    .split_whitespace()
+                    .next()
+                    .unwrap_or_default()
+            })
+            .map(|url| url.trim_end_matches(['.', ',']).to_string());
+
+        // The status is kept as the cause, so that its details remain available.
+        anyhow::Error::new(status).context(MissingIndexError {
+            message,
+            query_shape,
+            create_index_url,
+        })
+    }
+}
+
+impl Display for MissingIndexError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.create_index_url {
+            Some(url) => write!(
+                f,
+                "Query on fields [{}] needs index: {}",
+                self.query_shape, url
+            ),
+            None => write!(
+                f,
+                "Query on fields [{}] needs index: {}",
+                self.query_shape, self.message
+            ),
+        }
+    }
+}
+
+impl Error for MissingIndexError {}
+
+/// An error raised when a listing fails because the page token used to resume it has expired,
+/// which can happen when a listing is consumed over a very long time.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PageTokenExpired;
+
+impl PageTokenExpired {
+    /// Returns true if `status`, returned in response to a request which carried a page
+    /// token, indicates that the token has expired.
+    pub(crate) fn matches(status: &Status) -> bool {
+        status.code() == Code::InvalidArgument && status.message().to_lowercase().contains("token")
+    }
+}
+
+impl Display for PageTokenExpired {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "The page token for this listing has expired; it must be restarted from the beginning."
+        )
+    }
+}
+
+impl Error for PageTokenExpired {}
+
+/// An error returned by [crate::cancellable] when an operation is abandoned because its
+/// cancellation signal fired before it completed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cancelled;
+
+impl Display for Cancelled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "The operation was cancelled before it completed.")
+    }
+}
+
+impl Error for Cancelled {}
+
+/// Deserialize a document into `T`, reporting which field caused the failure if it fails.
+///
+/// `firestore_serde` does not report where in a document an error occurred, so on failure
+/// we find the culprit ourselves: a field is to blame if removing it from the document
+/// changes the error. When the culprit is itself a map, we descend into it to find the
+/// innermost field responsible. This costs one extra deserialization per field examined,
+/// but only on the error path.
+pub(crate) fn from_document<T>(document: Document) -> Result<T, DeserializeError>
+where
+    T: DeserializeOwned,
+{
+    let mut root = Value {
+        value_type: Some(ValueType::MapValue(MapValue {
+            fields: document.fields,
+        })),
+    };
+
+    let message = match deserialize::<T>(&root) {
+        Ok(value) => return Ok(value),
+        Err(message) => message,
+    };
+
+    // Removing a field cannot localize a missing field, since removing any other required
+    // field changes the error too; the message already names the field in that case.
+    let mut path = Vec::new();
+    if !message.starts_with("missing field") {
+        locate_culprit::<T>(&mut root, &mut path, &message);
+    }
+
+    if path.is_empty() {
+        return Err(DeserializeError {
+            message,
+            field: None,
+            found: None,
+        });
+    }
+
+    Err(DeserializeError {
+        message,
+        found: value_at(&root, &path).map(value_type_name),
+        field: Some(path.join(".")),
+    })
+}
+
+/// Serialize a value into a document to be written, rejecting field names that Firestore
+/// reserves (those of the form `__*__`, such as `__name__`), which it would refuse anyway.
+///
+/// The document's name is not one of its fields, so a field called `name` is stored like
+/// any other and does not conflict with it.
+pub(crate) fn to_document<T>(ob: &T) -> anyhow::Result<Document>
+where
+    T: Serialize,
+{
+    let document = firestore_serde::to_document(ob)?;
+    let mut path = Vec::new();
+    check_field_names(&document.fields, &mut path)?;
+    Ok(document)
+}
+
+fn check_field_names(
+    fields: &HashMap<String, Value>,
+    path: &mut Vec<String>,
+) -> anyhow::Result<()> {
+    for (key, value) in fields {
+        path.push(key.clone());
+        if key.len() >= 4 && key.starts_with("__") && key.ends_with("__") {
+            return Err(anyhow::anyhow!(
+                "Field `{}` has a name reserved by Firestore; rename it (e.g. with `#[serde(rename = \"...\")]`).",
+                path.join(".")
+            ));
+        }
+        check_value_field_names(value, path)?;
+        path.pop();
+    }
+    Ok(())
+}
+
+fn check_value_field_names(value: &Value, path: &mut Vec<String>) -> anyhow::Result<()> {
+    match &value.value_type {
+        Some(ValueType::MapValue(MapValue { fields })) => check_field_names(fields, path),
+        Some(ValueType::ArrayValue(array)) => {
+            for value in &array.values {
+                check_value_field_names(value, path)?;
+            }
+            Ok(())
+        }
+        _ => Ok(()),
+    }
+}
+
+fn deserialize<T>(root: &Value) -> Result<T, String>
+where
+    T: DeserializeOwned,
+{
+    firestore_serde::from_grpc_value(root).map_err(|e| e.to_string().trim_end().to_string())
+}
+
+/// Extend `path` (which must refer to a map within `root`) with the key of the field
+/// responsible for the error `message`, recursing into it if it is also a map.
+fn locate_culprit<T>(root: &mut Value, path: &mut Vec<String>, message: &str)
+where
+    T: DeserializeOwned,
+{
+    let mut keys: Vec<String> = match map_at(root, path) {
+        Some(fields) => fields.keys().cloned().collect(),
+        None => return,
+    };
+    keys.sort();
+
+    for key in keys {
+        let removed = match map_at(root, path).and_then(|fields| fields.remove(&key)) {
+            Some(removed) => removed,
+            None => continue,
+        };
+        let changed = deserialize::<T>(root).err().as_deref() != Some(message);
+        if let Some(fields) = map_at(root, path) {
+            fields.insert(key.clone(), removed);
+        }
+
+        if changed {
+            path.push(key);
+            locate_culprit::<T>(root, path, message);
+            return;
+        }
+    }
+}
+
+/// Returns the fields of the map at the given path within `root`, if there is one.
+fn map_at<'a>(root: &'a mut Value, path: &[String]) -> Option<&'a mut HashMap<String, Value>> {
+    let mut fields = match &mut root.value_type {
+        Some(ValueType::MapValue(MapValue { fields })) => fields,
+        _ => return None,
+    };
+    for key in path {
+        fields = match fields
+            .get_mut(key)
+            .and_then(|value| value.value_type.as_mut())
+        {
+            Some(ValueType::MapValue(MapValue { fields })) => fields,
+            _ => return None,
+        };
+    }
+    Some(fields)
+}
+
+/// Returns the value at the given path within `root`, if there is one.
+fn value_at<'a>(root: &'a Value, path: &[String]) -> Option<&'a Value> {
+    let mut value = root;
+    for key in path {
+        value = match &value.value_type {
+            Some(ValueType::MapValue(MapValue { fields })) => fields.get(key)?,
+            _ => return None,
+        };
+    }
+    Some(value)
+}
+
+fn value_type_name(value: &Value) -> &'static str {
+    match &value.value_type {
+        None | Some(ValueType::NullValue(_)) => "null",
+        Some(ValueType::BooleanValue(_)) => "boolean",
+        Some(ValueType::IntegerValue(_)) => "integer",
+        Some(ValueType::DoubleValue(_)) => "double",
+        Some(ValueType::TimestampValue(_)) => "timestamp",
+        Some(ValueType::StringValue(_)) => "string",
+        Some(ValueType::BytesValue(_)) => "bytes",
+        Some(ValueType::ReferenceValue(_)) => "reference",
+        Some(ValueType::GeoPointValue(_)) => "geo point",
+        Some(ValueType::ArrayValue(_)) => "array",
+        Some(ValueType::MapValue(_)) => "map",
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Address {
+        street: String,
+        zip: u32,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Person {
+        name: String,
+        age: u32,
+        nickname: Option<String>,
+        address: Address,
+    }
+
+    #[derive(Serialize)]
+    struct DriftedAddress {
+        street: String,
+        zip: String,
+    }
+
+    #[derive(Serialize)]
+    struct DriftedPerson {
+        name: String,
+        age: u32,
+        nickname: Option<bool>,
+        address: DriftedAddress,
+    }
+
+    fn address() -> Address {
+        Address {
+            street: "1 Main St".to_string(),
+            zip: 12345,
+        }
+    }
+
+    #[test]
+    fn test_from_document() {
+        let person = Person {
+            name: "Ada".to_string(),
+            age: 36,
+            nickname: None,
+            address: address(),
+        };
+        let document = firestore_serde::to_document(&person).unwrap();
+
+        assert_eq!(person, from_document::<Person>(document).unwrap());
+    }
+
+    #[test]
+    fn test_locate_wrong_type() {
+        #[derive(Serialize)]
+        struct Drifted {
+            name: String,
+            age: String,
+            nickname: Option<String>,
+            address: Address,
+        }
+
+        let document = firestore_serde::to_document(&Drifted {
+            name: "Ada".to_string(),
+            age: "thirty-six".to_string(),
+            nickname: None,
+            address: address(),
+        })
+        .unwrap();
+
+        let error = from_document::<Person>(document).unwrap_err();
+        assert_eq!(Some("age".to_string()), error.field);
+        assert_eq!(Some("string"), error.found);
+        assert!(error
+            .to_string()
+            .starts_with("Error deserializing field `age` (found string value): "));
+    }
+
+    #[test]
+    fn test_locate_nested_and_optional_fields() {
+        let document = firestore_serde::to_document(&DriftedPerson {
+            name: "Ada".to_string(),
+            age: 36,
+            nickname: None,
+            address: DriftedAddress {
+                street: "1 Main St".to_string(),
+                zip: "12345".to_string(),
+            },
+        })
+        .unwrap();
+
+        let error = from_document::<Person>(document).unwrap_err();
+        assert_eq!(Some("address.zip".to_string()), error.field);
+        assert_eq!(Some("string"), error.found);
+
+        let document = firestore_serde::to_document(&DriftedPerson {
+            name: "Ada".to_string(),
+            age: 36,
+            nickname: Some(true),
+            address: DriftedAddress {
+                street: "1 Main St".to_string(),
+                zip: "12345".to_string(),
+            },
+        })
+        .unwrap();
+
+        // Either culprit may be reported, depending on the order fields are visited.
+        let error = from_document::<Person>(document).unwrap_err();
+        match error.field.as_deref() {
+            Some("nickname") => assert_eq!(Some("boolean"), error.found),
+            Some("address.zip") => assert_eq!(Some("string"), error.found),
+            field => panic!("Unexpected culprit {:?}.", field),
+        }
+    }
+
+    #[test]
+    fn test_reserved_field_names() {
+        #[derive(Serialize)]
+        struct Named {
+            name: String,
+        }
+
+        // An ordinary `name` field is stored as a field, not as the document's name.
+        let document = to_document(&Named {
+            name: "Ada".to_string(),
+        })
+        .unwrap();
+        assert_eq!("", document.name);
+        assert!(document.fields.contains_key("name"));
+
+        #[derive(Serialize)]
+        struct Reserved {
+            #[serde(rename = "__name__")]
+            id: String,
+        }
+
+        let error = to_document(&Reserved {
+            id: "ada".to_string(),
+        })
+        .unwrap_err();
+        assert!(error.to_string().contains("`__name__`"));
+
+        #[derive(Serialize)]
+        struct Nested {
+            inner: Vec<Reserved>,
+        }
+
+        let error = to_document(&Nested {
+            inner: vec![Reserved {
+                id: "ada".to_string(),
+            }],
+        })
+        .unwrap_err();
+        assert!(error.to_string().contains("`inner.__name__`"));
+    }
+
+    #[test]
+    fn test_grpc_status() {
+        let info = rpc::ErrorInfo {
+            reason: "RATE_LIMIT_EXCEEDED".to_string(),
+            domain: "googleapis.com".to_string(),
+            ..rpc::ErrorInfo::default()
+        };
+        let details = rpc::Status {
+            code: Code::ResourceExhausted as i32,
+            message: "Quota exceeded.".to_string(),
+            details: vec![prost_types::Any {
+                type_url: "type.googleapis.com/google.rpc.ErrorInfo".to_string(),
+                value: info.encode_to_vec(),
+            }],
+        };
+        let status = Status::with_details(
+            Code::ResourceExhausted,
+            "Quota exceeded.",
+            details.encode_to_vec().into(),
+        );
+
+        let error = FirestoreError::other(status);
+        let status = error.status().unwrap();
+        assert_eq!(Code::ResourceExhausted, status.code());
+
+        let decoded = status_details(status).unwrap();
+        assert_eq!(details, decoded);
+        assert_eq!(
+            info,
+            rpc::ErrorInfo::decode(decoded.details[0].value.as_slice()).unwrap()
+        );
+
+        assert!(status_details(&Status::new(Code::Internal, "Oops.")).is_none());
+        assert!(FirestoreError::other(anyhow::anyhow!("Oops."))
+            .status()
+            .is_none());
+    }
+
+    #[test]
+    fn test_missing_index_error() {
+        let status = Status::new(
+            Code::FailedPrecondition,
+            "The query requires an index. You can create it here: https://console.firebase.google.com/v1/r/project/p/firestore/indexes?create_composite=abc",
+        );
+        let error = MissingIndexError::from_status(status, "a asc, b >".to_string());
+        assert_eq!(
+            Code::FailedPrecondition,
+            grpc_status(&error).unwrap().code()
+        );
+        let error = error.downcast_ref::<MissingIndexError>().unwrap();
+        assert_eq!(
+            Some("https://console.firebase.google.com/v1/r/project/p/firestore/indexes?create_composite=abc"),
+            error.create_index_url.as_deref()
+        );
+        assert_eq!(
+            "Query on fields [a asc, b >] needs index: https://console.firebase.google.com/v1/r/project/p/firestore/indexes?create_composite=abc",
+            error.to_string()
+        );
+
+        let status = Status::new(Code::FailedPrecondition, "Transaction expired.");
+        let error = MissingIndexError::from_status(status, "a asc, b >".to_string());
+        assert!(error.downcast_ref::<MissingIndexError>().is_none());
+        assert!(error.downcast_ref::<Status>().is_some());
+    }
+
+    #[test]
+    fn test_missing_documents() {
+        let people = crate::CollectionName::new("my-project", "people");
+        let error = MissingDocuments {
+            names: vec![people.document("ann"), people.document("bo")],
+        };
+        assert_eq!(
+            "2 documents not found: \
+            projects/my-project/databases/(default)/documents/people/ann, \
+            projects/my-project/databases/(default)/documents/people/bo",
+            error.to_string()
+        );
+    }
+
+    #[test]
+    fn test_page_token_expired() {
+        let status = Status::new(Code::InvalidArgument, "The page token has expired.");
+        assert!(PageTokenExpired::matches(&status));
+
+        let status = Status::new(Code::InvalidArgument, "Invalid collection ID.");
+        assert!(!PageTokenExpired::matches(&status));
+
+        let status = Status::new(Code::Unauthenticated, "Invalid auth token.");
+        assert!(!PageTokenExpired::matches(&status));
+    }
+
+    #[test]
+    fn test_missing_field() {
+        #[derive(Serialize)]
+        struct Partial {
+            name: String,
+        }
+
+        let document = firestore_serde::to_document(&Partial {
+            name: "Ada".to_string(),
+        })
+        .unwrap();
+
+        let error = from_document::<Person>(document).unwrap_err();
+        assert_eq!(None, error.field);
+        assert_eq!("missing field `age`", error.message);
+    }
+}
@@ -0,0 +1,89 @@
+/// Generates a `FIELD_*` associated constant for each serialized field of a struct.
+///
+/// Field names passed to list ordering and queries are plain strings, so a typo or a renamed
+/// struct field silently produces an empty result instead of a compile error. This macro ties
+/// those strings to the struct definition:
+///
+/// ```
+/// use serde::{Deserialize, Serialize};
+/// use tiny_firestore_odm::field_paths;
+///
+/// #[derive(Serialize, Deserialize)]
+/// struct User {
+///     name: String,
+///     #[serde(rename = "emailAddress")]
+///     email: String,
+/// }
+///
+/// field_paths!(User { name, email as "emailAddress" });
+///
+/// assert_eq!("name", User::FIELD_NAME);
+/// assert_eq!("emailAddress", User::FIELD_EMAIL);
+/// ```
+///
+/// Every listed field must exist on the struct, or the invocation fails to compile. The macro
+/// cannot see `serde` attributes, so fields renamed with `#[serde(rename = "...")]` must be
+/// given their serialized name with `as`, as above.
+#[macro_export]
+macro_rules! field_paths {
+    ($ty:ty { $($field:ident $(as $rename:literal)?),* $(,)? }) => {
+        $crate::paste::paste! {
+            impl $ty {
+                $(
+                    #[allow(dead_code)]
+                    pub const [<FIELD_ $field:upper>]: &'static str =
+                        $crate::__field_path!($field $(as $rename)?);
+                )*
+            }
+        }
+
+        // Fails to compile if any listed field does not exist on the struct.
+        const _: fn(&$ty) = |_value| {
+            $(let _ = &_value.$field;)*
+        };
+    };
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __field_path {
+    ($field:ident) => {
+        stringify!($field)
+    };
+    ($field:ident as $rename:literal) => {
+        $rename
+    };
+}
+
+#[cfg(test)]
+mod test {
+    use serde::Serialize;
+
+    #[derive(Serialize)]
+    #[allow(dead_code)]
+    struct Movie {
+        name: String,
+        #[serde(rename = "releaseYear")]
+        year: u32,
+    }
+
+    crate::field_paths!(Movie { name, year as "releaseYear" });
+
+    #[test]
+    fn test_field_paths() {
+        assert_eq!("name", Movie::FIELD_NAME);
+        assert_eq!("releaseYear", Movie::FIELD_YEAR);
+    }
+
+    #[test]
+    fn test_field_paths_match_serialized_names() {
+        let document = firestore_serde::to_document(&Movie {
+            name: "Heat".to_string(),
+            year: 1995,
+        })
+        .unwrap();
+
+        assert!(document.fields.contains_key(Movie::FIELD_NAME));
+        assert!(document.fields.contains_key(Movie::FIELD_YEAR));
+    }
+}
@@ -17,33 +17,115 @@ pub enum ParseError {
     ///
     /// The parameter indicates the index of the offending part.
     InvalidPart(usize),
+
+    /// A collection or document ID in the path is not one that Firestore allows.
+    ///
+    /// The parameter indicates the index of the offending part.
+    InvalidId(usize),
+
+    /// The path is nested more deeply than allowed (see [MAX_PATH_DEPTH]).
+    ///
+    /// The parameter indicates the depth of the path: the number of documents above its
+    /// collection.
+    TooDeep(usize),
 }
 
 impl Display for ParseError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             ParseError::InvalidPart(part) => write!(f, "Invalid part at index {}", part),
+            ParseError::InvalidId(part) => write!(f, "Invalid ID at index {}", part),
             ParseError::WrongNumberOfParts(parts) => write!(f, "Invalid number of parts {}", parts),
             ParseError::TooFewParts(parts) => write!(f, "Expected at least 6 parts, got {}", parts),
+            ParseError::TooDeep(depth) => write!(f, "Path is nested too deeply ({})", depth),
         }
     }
 }
 
 impl Error for ParseError {}
 
+/// The maximum depth of a path accepted by `parse`, counted as the number of documents above
+/// its collection. This is the deepest Firestore allows subcollections to be nested.
+pub const MAX_PATH_DEPTH: usize = 100;
+
+/// The ID of the database each project has by default, used by names constructed without a
+/// database ID.
+pub const DEFAULT_DATABASE_ID: &str = "(default)";
+
+/// Maximum length, in bytes, of a collection or document ID.
+const MAX_ID_BYTES: usize = 1500;
+
+/// Returns `true` if Firestore would accept `id` as a collection or document ID.
+fn is_valid_id(id: &str) -> bool {
+    let reserved = id.len() >= 4 && id.starts_with("__") && id.ends_with("__");
+
+    !id.is_empty() && id.len() <= MAX_ID_BYTES && id != "." && id != ".." && !reserved
+}
+
 /// Represents the parent of a collection, which is either another document or the “root” collection.
+#[derive(Clone, Hash, Debug, PartialEq, Eq)]
 pub enum ParentDocumentOrRoot {
-    Root { project_id: String },
-    ParentDocument { document: DocumentName },
+    Root {
+        project_id: String,
+        database_id: String,
+    },
+    ParentDocument {
+        document: DocumentName,
+    },
 }
 
 impl ParentDocumentOrRoot {
+    /// Construct the root of the given project's default database, under which its top-level
+    /// collections are.
+    pub fn root(project_id: &str) -> Self {
+        ParentDocumentOrRoot::root_with_database(project_id, DEFAULT_DATABASE_ID)
+    }
+
+    /// Construct the root of a named database, rather than the project's default database.
+    pub fn root_with_database(project_id: &str, database_id: &str) -> Self {
+        ParentDocumentOrRoot::Root {
+            project_id: project_id.to_string(),
+            database_id: database_id.to_string(),
+        }
+    }
+
+    /// Attempt to parse the parent of a collection from a slash-delimited string, which is
+    /// either the root (`projects/{project_id}/databases/{database_id}/documents`) or the name
+    /// of a document.
+    pub fn parse(name: &str) -> Result<Self, ParseError> {
+        let parts: Vec<&str> = name.split('/').collect();
+        if parts.len() != 5 {
+            return Ok(ParentDocumentOrRoot::ParentDocument {
+                document: DocumentName::parse(name)?,
+            });
+        }
+
+        if parts[0] != "projects" {
+            return Err(ParseError::InvalidPart(0));
+        }
+        if parts[2] != "databases" {
+            return Err(ParseError::InvalidPart(2));
+        }
+        if parts[3].is_empty() {
+            return Err(ParseError::InvalidPart(3));
+        }
+        if parts[4] != "documents" {
+            return Err(ParseError::InvalidPart(4));
+        }
+
+        Ok(ParentDocumentOrRoot::root_with_database(parts[1], parts[3]))
+    }
+
     /// Returns a string suitable for passing in the Firestore API as a `parent` parameter.
     pub fn name(&self) -> String {
         match self {
-            Self::Root { project_id } => {
-                format!("projects/{}/databases/(default)/documents", project_id)
-            }
+            Self::Root {
+                project_id,
+                database_id,
+            } => format!(
+                "projects/{}/databases/{}/documents",
+                project_id, database_id
+            ),
             Self::ParentDocument { document } => document.name(),
         }
     }
@@ -55,12 +137,26 @@ impl ParentDocumentOrRoot {
             Self::ParentDocument { document } => Some(document.collection.clone()),
         }
     }
+
+    /// Returns the collection with the given ID directly under this parent.
+    pub fn collection(&self, collection: &str) -> CollectionName {
+        match self {
+            Self::Root {
+                project_id,
+                database_id,
+            } => CollectionName::new_with_database(project_id, database_id, collection),
+            Self::ParentDocument { document } => document
+                .collection
+                .subcollection(&document.name, collection),
+        }
+    }
 }
 
 /// Represents the fully-qualified path of a collection.
 #[derive(Clone, Hash, Debug, PartialEq, Eq)]
 pub struct CollectionName {
     project_id: String,
+    database_id: String,
     /// Vector of (collection, name) pairs of parent.
     parent_path: Vec<(String, String)>,
 
@@ -76,6 +172,15 @@ impl CollectionName {
         CollectionName::new_with_path(project_id, &[], collection)
     }
 
+    /// Construct a `CollectionName` for a top-level collection of a named database, rather than
+    /// the project's default database.
+    pub fn new_with_database(project_id: &str, database_id: &str, collection: &str) -> Self {
+        CollectionName {
+            database_id: database_id.to_string(),
+            ..CollectionName::new(project_id, collection)
+        }
+    }
+
     /// Construct a `CollectionName` nested under a document.
     pub fn new_with_path(project_id: &str, path: &[(&str, &str)], collection: &str) -> Self {
         let parent_path: Vec<(String, String)> = path
@@ -85,6 +190,7 @@ impl CollectionName {
 
         CollectionName {
             project_id: project_id.to_string(),
+            database_id: DEFAULT_DATABASE_ID.to_string(),
             parent_path,
             collection: collection.to_string(),
         }
@@ -96,10 +202,20 @@ impl CollectionName {
         CollectionName {
             collection: collection.to_string(),
             project_id: self.project_id.clone(),
+            database_id: self.database_id.clone(),
             parent_path,
         }
     }
 
+    /// Returns a copy of this collection in the given project, with the same database ID and
+    /// path.
+    pub fn with_project_id(&self, project_id: &str) -> CollectionName {
+        CollectionName {
+            project_id: project_id.to_string(),
+            ..self.clone()
+        }
+    }
+
     /// Return a representation of the parent of this collection, which may either be a document or the root.
     pub fn parent(&self) -> ParentDocumentOrRoot {
         let mut parent_path = self.parent_path.clone();
@@ -109,6 +225,7 @@ impl CollectionName {
                 document: DocumentName {
                     collection: CollectionName {
                         project_id: self.project_id.clone(),
+                        database_id: self.database_id.clone(),
                         parent_path,
                         collection,
                     },
@@ -118,6 +235,7 @@ impl CollectionName {
         } else {
             ParentDocumentOrRoot::Root {
                 project_id: self.project_id.clone(),
+                database_id: self.database_id.clone(),
             }
         }
     }
@@ -137,11 +255,49 @@ impl CollectionName {
         }
     }
 
+    /// Check that every part of this collection's path is one Firestore accepts, for
+    /// collection names constructed by hand rather than parsed.
+    ///
+    /// Returns [ParseError::InvalidPart] if the project or database ID is empty or contains a
+    /// `/`, and
+    /// [ParseError::InvalidId] if a collection or document ID in the path would be rejected
+    /// by `parse_lenient` or contains a `/`. Indices are those of the part in [Self::name].
+    pub fn validate(&self) -> Result<(), ParseError> {
+        if self.project_id.is_empty() || self.project_id.contains('/') {
+            return Err(ParseError::InvalidPart(1));
+        }
+        if self.database_id.is_empty() || self.database_id.contains('/') {
+            return Err(ParseError::InvalidPart(3));
+        }
+
+        let ids = self
+            .parent_path
+            .iter()
+            .flat_map(|(collection, name)| [collection, name])
+            .chain(std::iter::once(&self.collection));
+        for (index, id) in ids.enumerate() {
+            if !is_valid_id(id) || id.contains('/') {
+                return Err(ParseError::InvalidId(index + 5));
+            }
+        }
+
+        Ok(())
+    }
+
     /// Returns the short name of this collection without the full path.
     pub fn leaf_name(&self) -> String {
         self.collection.clone()
     }
 
+    /// Returns the fully-qualified name of the database this collection belongs to, suitable for
+    /// passing in the Firestore API as a `database` parameter.
+    pub fn database_name(&self) -> String {
+        format!(
+            "projects/{}/databases/{}",
+            self.project_id, self.database_id
+        )
+    }
+
     /// Returns the fully-qualified name of this collection as a string.
     pub fn name(&self) -> String {
         let path = if self.parent_path.is_empty() {
@@ -156,26 +312,72 @@ impl CollectionName {
             format!("documents/{}/{}", path, self.collection)
         };
 
-        format!("projects/{}/databases/(default)/{}", self.project_id, path)
+        format!(
+            "projects/{}/databases/{}/{}",
+            self.project_id, self.database_id, path
+        )
+    }
+
+    /// Attempt to parse a collection name from a path imported from another system.
+    ///
+    /// `parse` only checks the structure of the path. This additionally tolerates a single
+    /// leading and trailing `/`, and checks every collection and document ID in the path
+    /// against the rules Firestore applies to IDs:
+    ///
+    /// - Tolerated: any UTF-8 text, including spaces and other whitespace, non-ASCII
+    ///   characters, and punctuation such as `.`, `#`, `?`, `%` and `\`. No escaping is
+    ///   interpreted; IDs are taken verbatim.
+    /// - Rejected with [ParseError::InvalidId]: empty IDs (e.g. from `//`), the IDs `.` and
+    ///   `..`, IDs of the form `__...__` (reserved by Firestore), and IDs longer than
+    ///   1,500 bytes.
+    ///
+    /// Since `/` is always treated as a separator, an ID can never contain one.
+    pub fn parse_lenient(name: &str) -> Result<Self, ParseError> {
+        let name = name.strip_prefix('/').unwrap_or(name);
+        let name = name.strip_suffix('/').unwrap_or(name);
+        let collection = Self::parse(name)?;
+
+        for (index, id) in name.split('/').enumerate().skip(5) {
+            if !is_valid_id(id) {
+                return Err(ParseError::InvalidId(index));
+            }
+        }
+
+        Ok(collection)
     }
 
     /// Attempt to parse a collection name from a slash-delimited string.
+    ///
+    /// Paths nested more than [MAX_PATH_DEPTH] levels deep are rejected with
+    /// [ParseError::TooDeep].
     pub fn parse(name: &str) -> Result<Self, ParseError> {
-        let parts: Vec<&str> = name.split('/').into_iter().collect();
+        Self::parse_with_max_depth(name, MAX_PATH_DEPTH)
+    }
+
+    /// Like `parse`, but rejects paths with more than `max_depth` documents above the
+    /// collection (so a `max_depth` of 0 accepts only top-level collections). Use it to
+    /// bound the nesting of paths from untrusted input.
+    pub fn parse_with_max_depth(name: &str, max_depth: usize) -> Result<Self, ParseError> {
+        let parts: Vec<&str> = name.split('/').collect();
 
         if parts.len() < 5 {
             return Err(ParseError::TooFewParts(parts.len()));
-        } else if parts.len() % 2 != 0 {
+        } else if !parts.len().is_multiple_of(2) {
             return Err(ParseError::WrongNumberOfParts(parts.len()));
         }
 
-        if parts.get(0) != Some(&"projects") {
+        let depth = (parts.len() - 6) / 2;
+        if depth > max_depth {
+            return Err(ParseError::TooDeep(depth));
+        }
+
+        if parts.first() != Some(&"projects") {
             return Err(ParseError::InvalidPart(0));
         }
         if parts.get(2) != Some(&"databases") {
             return Err(ParseError::InvalidPart(2));
         }
-        if parts.get(3) != Some(&"(default)") {
+        if parts.get(3) == Some(&"") {
             return Err(ParseError::InvalidPart(3));
         }
         if parts.get(4) != Some(&"documents") {
@@ -183,8 +385,7 @@ impl CollectionName {
         }
 
         let project_id = parts.get(1).unwrap().to_string();
-
-        let depth = (parts.len() - 6) / 2;
+        let database_id = parts.get(3).unwrap().to_string();
 
         let parent_path: Vec<(String, String)> = (0..depth)
             .map(|d| {
@@ -198,6 +399,7 @@ impl CollectionName {
 
         Ok(CollectionName {
             project_id,
+            database_id,
             collection,
             parent_path,
         })
@@ -221,11 +423,47 @@ impl DocumentName {
         &self.name
     }
 
+    /// Returns the collection that this document belongs to.
+    pub fn collection(&self) -> &CollectionName {
+        &self.collection
+    }
+
+    /// Returns the documents above this one in its path, starting from the top-level
+    /// document. For example, the ancestors of `apps/phone/users/john` are `apps/phone`.
+    /// Top-level documents have no ancestors.
+    pub fn ancestors(&self) -> Vec<DocumentName> {
+        let mut ancestors = Vec::new();
+        let mut parent = self.collection.parent();
+        while let ParentDocumentOrRoot::ParentDocument { document } = parent {
+            parent = document.collection.parent();
+            ancestors.push(document);
+        }
+        ancestors.reverse();
+        ancestors
+    }
+
     /// Parse a document name from a fully-qualified string.
+    ///
+    /// Paths nested more than [MAX_PATH_DEPTH] levels deep are rejected with
+    /// [ParseError::TooDeep].
     pub fn parse(name: &str) -> Result<Self, ParseError> {
-        let (collection_name, name) = name.rsplit_once("/").unwrap();
+        Self::parse_with_max_depth(name, MAX_PATH_DEPTH)
+    }
 
-        let collection = CollectionName::parse(collection_name).unwrap();
+    /// Like `parse`, but rejects paths with more than `max_depth` documents above the
+    /// document's collection (so a `max_depth` of 0 accepts only documents in top-level
+    /// collections).
+    pub fn parse_with_max_depth(name: &str, max_depth: usize) -> Result<Self, ParseError> {
+        let (collection_name, name) = name.rsplit_once('/').ok_or(ParseError::TooFewParts(1))?;
+
+        // Part counts reported by the collection parser don't include the document's own part.
+        let collection = CollectionName::parse_with_max_depth(collection_name, max_depth).map_err(
+            |e| match e {
+                ParseError::TooFewParts(parts) => ParseError::TooFewParts(parts + 1),
+                ParseError::WrongNumberOfParts(parts) => ParseError::WrongNumberOfParts(parts + 1),
+                e => e,
+            },
+        )?;
 
         Ok(DocumentName {
             collection,
@@ -234,6 +472,25 @@ impl DocumentName {
     }
 }
 
+/// A fully-qualified path which may name either a collection or a document.
+#[derive(Clone, Hash, Debug, PartialEq, Eq)]
+pub enum FirestorePath {
+    Collection(CollectionName),
+    Document(DocumentName),
+}
+
+impl FirestorePath {
+    /// Parse a path which is not known in advance to name a collection or a document.
+    /// Collections have an even number of parts, and documents an odd number.
+    pub fn parse(name: &str) -> Result<Self, ParseError> {
+        if name.split('/').count().is_multiple_of(2) {
+            CollectionName::parse(name).map(FirestorePath::Collection)
+        } else {
+            DocumentName::parse(name).map(FirestorePath::Document)
+        }
+    }
+}
+
 #[derive(Debug, PartialEq)]
 pub enum QualifyError {
     ProjectMismatch(String, String),
@@ -262,6 +519,7 @@ impl Error for QualifyError {}
 /// Represents a type that can be turned into a fully-qualified document name.
 pub trait QualifyDocumentName {
     /// Create a document name from self, using the given collection as its parent.
+    #[allow(clippy::result_large_err)]
     fn qualify(&self, parent: &CollectionName) -> Result<DocumentName, QualifyError>;
 }
 
@@ -291,6 +549,24 @@ impl QualifyDocumentName for &DocumentName {
     }
 }
 
+impl QualifyDocumentName for String {
+    fn qualify(&self, parent: &CollectionName) -> Result<DocumentName, QualifyError> {
+        self.as_str().qualify(parent)
+    }
+}
+
+impl QualifyDocumentName for &String {
+    fn qualify(&self, parent: &CollectionName) -> Result<DocumentName, QualifyError> {
+        self.as_str().qualify(parent)
+    }
+}
+
+impl QualifyDocumentName for DocumentName {
+    fn qualify(&self, parent: &CollectionName) -> Result<DocumentName, QualifyError> {
+        (&self).qualify(parent)
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -308,8 +584,22 @@ mod test {
 
         assert_eq!(
             "projects/my-project/databases/(default)/documents/things/mydoc",
-            (&doc).qualify(&collection).unwrap().name()
+            qualify(&doc, &collection).unwrap().name()
         );
+
+        // Owned names qualify the same way as borrowed ones.
+        let key = "blah".to_string();
+        assert_eq!(qualify("blah", &collection), qualify(&key, &collection));
+        assert_eq!(qualify("blah", &collection), qualify(key, &collection));
+        assert_eq!(Ok(doc.clone()), qualify(doc, &collection));
+    }
+
+    #[allow(clippy::result_large_err)]
+    fn qualify(
+        key: impl QualifyDocumentName,
+        collection: &CollectionName,
+    ) -> Result<DocumentName, QualifyError> {
+        key.qualify(collection)
     }
 
     #[test]
@@ -324,6 +614,49 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_validate() {
+        let collection = CollectionName::new_with_path("my-project", &[("people", "john")], "apps");
+        assert_eq!(Ok(()), collection.validate());
+
+        assert_eq!(
+            Err(ParseError::InvalidId(7)),
+            CollectionName::new_with_path("my-project", &[("people", "john")], "").validate()
+        );
+        assert_eq!(
+            Err(ParseError::InvalidId(8)),
+            collection.subcollection("a/b", "apps").validate()
+        );
+        assert_eq!(
+            Err(ParseError::InvalidId(5)),
+            CollectionName::new("my-project", "__things__").validate()
+        );
+        assert_eq!(
+            Err(ParseError::InvalidPart(1)),
+            CollectionName::new("", "things").validate()
+        );
+    }
+
+    #[test]
+    fn test_with_project_id() {
+        let collection = CollectionName::new_with_path("my-project", &[("people", "john")], "apps");
+
+        let retargeted = collection.with_project_id("other-project");
+
+        assert_eq!(
+            "projects/other-project/databases/(default)/documents/people/john/apps",
+            retargeted.name(),
+        );
+        assert_eq!(
+            "projects/other-project/databases/(default)/documents/people/john",
+            retargeted.parent().name(),
+        );
+        assert_eq!(
+            "projects/my-project/databases/(default)/documents/people/john/apps",
+            collection.name(),
+        );
+    }
+
     #[test]
     fn test_fail_qualify() {
         let collection1 = CollectionName::new("my-project", "things");
@@ -332,14 +665,16 @@ mod test {
 
         assert_eq!(
             QualifyError::CollectionMismatch(collection1.clone(), collection2.clone()),
-            (&collection1.document("foobar"))
+            collection1
+                .document("foobar")
                 .qualify(&collection2)
                 .unwrap_err()
         );
 
         assert_eq!(
             QualifyError::ProjectMismatch("my-project".to_string(), "my-other-project".to_string()),
-            (&collection1.document("foobar"))
+            collection1
+                .document("foobar")
                 .qualify(&collection3)
                 .unwrap_err()
         );
@@ -390,6 +725,7 @@ mod test {
             parent_path: vec![],
 
             project_id: "employee-directory".to_string(),
+            database_id: "(default)".to_string(),
         };
 
         let result = CollectionName::parse(name_to_parse).unwrap();
@@ -409,6 +745,7 @@ mod test {
                 ("items".to_string(), "phone".to_string()),
             ],
             project_id: "stuff".to_string(),
+            database_id: "(default)".to_string(),
         };
 
         let result = CollectionName::parse(name_to_parse).unwrap();
@@ -429,6 +766,7 @@ mod test {
                     ("items".to_string(), "phone".to_string()),
                 ],
                 project_id: "stuff".to_string(),
+                database_id: "(default)".to_string(),
             },
             name: "clock".to_string(),
         };
@@ -469,7 +807,7 @@ mod test {
 
         assert_eq!(
             ParseError::InvalidPart(3),
-            CollectionName::parse("projects/employee-directory/databases/default/documents/people")
+            CollectionName::parse("projects/employee-directory/databases//documents/people")
                 .unwrap_err()
         );
 
@@ -480,6 +818,110 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_parse_max_depth() {
+        let path = |depth: usize| {
+            let mut path = "projects/p/databases/(default)/documents".to_string();
+            for level in 0..depth {
+                path.push_str(&format!("/c{}/d{}", level, level));
+            }
+            path
+        };
+
+        let collection = format!("{}/leaf", path(2));
+        assert_eq!(
+            2,
+            CollectionName::parse_with_max_depth(&collection, 2)
+                .unwrap()
+                .parent_path
+                .len()
+        );
+        assert_eq!(
+            ParseError::TooDeep(2),
+            CollectionName::parse_with_max_depth(&collection, 1).unwrap_err()
+        );
+        assert_eq!(
+            ParseError::TooDeep(2),
+            DocumentName::parse_with_max_depth(&format!("{}/doc", collection), 1).unwrap_err()
+        );
+
+        // Paths up to Firestore's limit are accepted by default.
+        let deepest = format!("{}/leaf/doc", path(MAX_PATH_DEPTH));
+        assert!(DocumentName::parse(&deepest).is_ok());
+        let too_deep = format!("{}/leaf/doc", path(MAX_PATH_DEPTH + 1));
+        assert_eq!(
+            ParseError::TooDeep(MAX_PATH_DEPTH + 1),
+            DocumentName::parse(&too_deep).unwrap_err()
+        );
+    }
+
+    #[test]
+    fn test_parse_lenient_collection_name() {
+        let result = CollectionName::parse_lenient(
+            "/projects/stuff/databases/(default)/documents/people/José Álvarez/saved items/",
+        )
+        .unwrap();
+
+        assert_eq!(
+            CollectionName::new_with_path("stuff", &[("people", "José Álvarez")], "saved items"),
+            result
+        );
+
+        let result = CollectionName::parse_lenient(
+            "projects/stuff/databases/(default)/documents/日本/ 100% #1? /カテゴリー",
+        )
+        .unwrap();
+
+        assert_eq!(
+            CollectionName::new_with_path("stuff", &[("日本", " 100% #1? ")], "カテゴリー"),
+            result
+        );
+    }
+
+    #[test]
+    fn test_fail_parse_lenient_collection_name() {
+        assert_eq!(
+            ParseError::InvalidId(6),
+            CollectionName::parse_lenient(
+                "projects/stuff/databases/(default)/documents/people//apps"
+            )
+            .unwrap_err()
+        );
+
+        assert_eq!(
+            ParseError::InvalidId(6),
+            CollectionName::parse_lenient(
+                "projects/stuff/databases/(default)/documents/people/../apps"
+            )
+            .unwrap_err()
+        );
+
+        assert_eq!(
+            ParseError::InvalidId(5),
+            CollectionName::parse_lenient(
+                "projects/stuff/databases/(default)/documents/__people__"
+            )
+            .unwrap_err()
+        );
+
+        let long_id = "x".repeat(1501);
+        assert_eq!(
+            ParseError::InvalidId(7),
+            CollectionName::parse_lenient(&format!(
+                "projects/stuff/databases/(default)/documents/people/john/{}",
+                long_id
+            ))
+            .unwrap_err()
+        );
+
+        // An embedded slash is always a separator, so it changes the shape of the path.
+        assert_eq!(
+            ParseError::WrongNumberOfParts(7),
+            CollectionName::parse_lenient("projects/stuff/databases/(default)/documents/AC/DC")
+                .unwrap_err()
+        );
+    }
+
     #[test]
     fn test_parse_document_name() {
         let name_to_parse = "projects/employee-directory/databases/(default)/documents/people/jack";
@@ -490,6 +932,7 @@ mod test {
                 parent_path: vec![],
 
                 project_id: "employee-directory".to_string(),
+                database_id: "(default)".to_string(),
             },
             name: "jack".to_string(),
         };
@@ -499,6 +942,33 @@ mod test {
         assert_eq!(expected, result);
     }
 
+    #[test]
+    fn test_fail_parse_document_name() {
+        assert_eq!(
+            ParseError::TooFewParts(1),
+            DocumentName::parse("people").unwrap_err()
+        );
+
+        assert_eq!(
+            ParseError::TooFewParts(3),
+            DocumentName::parse("projects/employee-directory/databases").unwrap_err()
+        );
+
+        assert_eq!(
+            ParseError::WrongNumberOfParts(6),
+            DocumentName::parse("projects/employee-directory/databases/(default)/documents/people")
+                .unwrap_err()
+        );
+
+        assert_eq!(
+            ParseError::InvalidPart(4),
+            DocumentName::parse(
+                "projects/employee-directory/databases/(default)/stuff/people/jack"
+            )
+            .unwrap_err()
+        );
+    }
+
     #[test]
     fn test_walk_to_root() {
         let name_to_parse =
@@ -544,6 +1014,133 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_ancestors() {
+        let document = DocumentName::parse(
+            "projects/stuff/databases/(default)/documents/people/john/items/phone/apps/clock",
+        )
+        .unwrap();
+
+        let ancestors: Vec<String> = document
+            .ancestors()
+            .iter()
+            .map(DocumentName::name)
+            .collect();
+        assert_eq!(
+            vec![
+                "projects/stuff/databases/(default)/documents/people/john",
+                "projects/stuff/databases/(default)/documents/people/john/items/phone",
+            ],
+            ancestors
+        );
+
+        assert!(CollectionName::new("stuff", "people")
+            .document("john")
+            .ancestors()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_database_name() {
+        let collection = CollectionName::new_with_path("my-project", &[("people", "john")], "apps");
+
+        assert_eq!(
+            "projects/my-project/databases/(default)",
+            collection.database_name()
+        );
+    }
+
+    #[test]
+    fn test_parse_path() {
+        let collection = CollectionName::new_with_path("stuff", &[("people", "john")], "apps");
+        assert_eq!(
+            FirestorePath::Collection(collection.clone()),
+            FirestorePath::parse(&collection.name()).unwrap()
+        );
+
+        let document = collection.document("clock");
+        assert_eq!(
+            FirestorePath::Document(document.clone()),
+            FirestorePath::parse(&document.name()).unwrap()
+        );
+
+        assert_eq!(
+            ParseError::TooFewParts(5),
+            FirestorePath::parse("projects/stuff/databases/(default)/documents").unwrap_err()
+        );
+        assert_eq!(
+            ParseError::InvalidPart(4),
+            FirestorePath::parse("projects/stuff/databases/(default)/stuff/people/john")
+                .unwrap_err()
+        );
+    }
+
+    #[test]
+    fn test_parse_root() {
+        let root = ParentDocumentOrRoot::root("stuff");
+        assert_eq!("projects/stuff/databases/(default)/documents", root.name());
+        assert_eq!(root, ParentDocumentOrRoot::parse(&root.name()).unwrap());
+
+        let people = root.collection("people");
+        assert_eq!(CollectionName::new("stuff", "people"), people);
+        assert_eq!(root, people.parent());
+
+        let root = ParentDocumentOrRoot::root_with_database("stuff", "my-db");
+        assert_eq!(root, ParentDocumentOrRoot::parse(&root.name()).unwrap());
+        assert_eq!(None, root.parent());
+
+        let john = ParentDocumentOrRoot::parse(&people.document("john").name()).unwrap();
+        assert_eq!(
+            people.subcollection("john", "apps"),
+            john.collection("apps")
+        );
+        assert_eq!(people.subcollection("john", "apps").parent(), john);
+        assert_eq!(Some(people), john.parent());
+
+        assert_eq!(
+            ParseError::InvalidPart(4),
+            ParentDocumentOrRoot::parse("projects/stuff/databases/(default)/stuff").unwrap_err()
+        );
+        assert_eq!(
+            ParseError::InvalidPart(3),
+            ParentDocumentOrRoot::parse("projects/stuff/databases//documents").unwrap_err()
+        );
+    }
+
+    #[test]
+    fn test_named_database() {
+        let collection = CollectionName::new_with_database("my-project", "my-db", "people");
+        let document = collection.document("john");
+        assert_eq!(
+            "projects/my-project/databases/my-db/documents/people/john",
+            document.name()
+        );
+        assert_eq!(
+            "projects/my-project/databases/my-db",
+            collection.database_name()
+        );
+
+        let apps = collection.subcollection("john", "apps");
+        assert_eq!(
+            "projects/my-project/databases/my-db/documents/people/john/apps",
+            apps.name()
+        );
+        assert_eq!(
+            "projects/my-project/databases/my-db/documents",
+            collection.parent().name()
+        );
+
+        assert_eq!(apps, CollectionName::parse(&apps.name()).unwrap());
+        assert_eq!(document, DocumentName::parse(&document.name()).unwrap());
+
+        // A document in another database does not belong to the collection.
+        let default_document = CollectionName::new("my-project", "people").document("john");
+        assert!(matches!(
+            default_document.qualify(&collection),
+            Err(QualifyError::CollectionMismatch(..))
+        ));
+    }
+
     #[test]
     fn test_walk_from_root() {
         let collection = CollectionName::new("my-project", "beers");
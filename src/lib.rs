@@ -1,17 +1,178 @@
-pub use collection::Collection;
-pub use database::Database;
-pub use identifiers::{CollectionName, DocumentName, QualifyDocumentName};
+pub use admin::{ConcurrencyMode, DatabaseInfo, DatabaseType};
+pub use batch::WriteBatch;
+pub use cache::CacheConfig;
+pub use cancel::cancellable;
+pub use clock::{Clock, MockClock, SystemClock};
+pub use collection::{
+    Collection, Consistency, GetManyResult, Op, SchemaReport, WriteOutcome,
+    DEFAULT_MAX_DELETE_DEPTH, TOUCH_FIELD,
+};
+pub use collection_api::{BoxFuture, BoxStream, CollectionApi};
+pub use connection::ConnectionState;
+pub use counter::ShardedCounter;
+pub use database::{CollectionSpec, Database};
+pub use error::{
+    grpc_status, status_details, Cancelled, DeserializeError, FirestoreError, MissingDocuments,
+    MissingIndexError, PageTokenExpired, ValidationError,
+};
+pub use identifiers::{
+    CollectionName, DocumentName, FirestorePath, ParentDocumentOrRoot, ParseError,
+    QualifyDocumentName, QualifyError, DEFAULT_DATABASE_ID, MAX_PATH_DEPTH,
+};
+pub use offline::{ApplyOutcome, MemoryQueueStore, OfflineQueue, QueueStore};
+pub use query::{CountedPage, Direction, FieldOp, Query, QueryCursor};
+pub use retry::{RetryBudget, RetryPolicy};
+pub use timestamp::FirestoreTimestamp;
+pub use transaction::Transaction;
+pub use transforms::{TransformResult, Transforms};
 
+mod admin;
+mod auth;
+mod batch;
+mod cache;
+mod cancel;
 pub mod client;
+mod clock;
 mod collection;
+mod collection_api;
+mod connection;
+mod counter;
 mod database;
 pub mod dynamic_firestore_client;
+mod error;
+mod fields;
 mod identifiers;
 mod list_response;
+mod offline;
+mod query;
+mod retry;
+#[cfg(feature = "opentelemetry")]
+mod telemetry;
+mod timestamp;
+mod transaction;
+mod transforms;
+
+pub use prost_types::Timestamp;
+use std::hash::{Hash, Hasher};
+
+/// Types of the structured error details Firestore attaches to some errors, decoded with
+/// [status_details].
+pub use googapis::google::rpc;
+
+#[doc(hidden)]
+pub use paste;
 
 /// Represents a key/value pair, where the key (name) is a fully-qualified path to the document.
-#[derive(Hash, PartialEq, Debug, Eq)]
+#[derive(Clone, PartialEq, Debug)]
 pub struct NamedDocument<T> {
     pub name: DocumentName,
     pub value: T,
+
+    /// The time at which the document was created, if it was read from Firestore (rather
+    /// than, for example, served from a collection's cache).
+    pub create_time: Option<Timestamp>,
+
+    /// The time at which the document was last changed, if it was read from Firestore.
+    pub update_time: Option<Timestamp>,
+}
+
+impl<T> NamedDocument<T> {
+    /// Construct a document with the given name and value, without timestamps.
+    pub fn new(name: DocumentName, value: T) -> Self {
+        NamedDocument {
+            name,
+            value,
+            create_time: None,
+            update_time: None,
+        }
+    }
+
+    /// Convert a document read from Firestore, keeping its timestamps.
+    pub(crate) fn from_proto(
+        name: DocumentName,
+        document: firestore_serde::firestore::Document,
+    ) -> Result<Self, DeserializeError>
+    where
+        T: serde::de::DeserializeOwned,
+    {
+        let create_time = document.create_time.clone();
+        let update_time = document.update_time.clone();
+        let value = error::from_document(document)?;
+
+        Ok(NamedDocument {
+            name,
+            value,
+            create_time,
+            update_time,
+        })
+    }
+
+    /// Convert this document into a lightweight view of it, such as a reference struct
+    /// holding its key and a few of its fields. See [FromNamedDocument].
+    pub fn into_ref<R: FromNamedDocument<T>>(self) -> R {
+        R::from_named_document(self)
+    }
+}
+
+// Implemented by hand, since `Timestamp` implements neither trait.
+impl<T: Eq> Eq for NamedDocument<T> {}
+
+impl<T: Hash> Hash for NamedDocument<T> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.name.hash(state);
+        self.value.hash(state);
+        for time in &[&self.create_time, &self.update_time] {
+            time.as_ref()
+                .map(|time| (time.seconds, time.nanos))
+                .hash(state);
+        }
+    }
+}
+
+/// A type which can be derived from a [NamedDocument], such as a reference to a document that
+/// is stored elsewhere in denormalized form.
+///
+/// ```
+/// use tiny_firestore_odm::{FromNamedDocument, NamedDocument};
+///
+/// struct User {
+///     name: String,
+///     email: String,
+/// }
+///
+/// struct UserRef {
+///     id: String,
+///     display_name: String,
+/// }
+///
+/// impl FromNamedDocument<User> for UserRef {
+///     fn from_named_document(document: NamedDocument<User>) -> Self {
+///         UserRef {
+///             id: document.name.leaf_name().to_string(),
+///             display_name: document.value.name,
+///         }
+///     }
+/// }
+///
+/// fn refs(users: Vec<NamedDocument<User>>) -> Vec<UserRef> {
+///     users.into_iter().map(NamedDocument::into_ref).collect()
+/// }
+/// ```
+pub trait FromNamedDocument<T> {
+    fn from_named_document(document: NamedDocument<T>) -> Self;
+}
+
+/// Information about a write that was applied to a document.
+#[derive(Clone, PartialEq, Debug)]
+pub struct WriteResult {
+    /// The server time at which the write took effect.
+    pub update_time: Timestamp,
+}
+
+impl WriteResult {
+    pub(crate) fn new(update_time: Option<Timestamp>) -> anyhow::Result<Self> {
+        let update_time =
+            update_time.ok_or_else(|| anyhow::anyhow!("Expected write to return a time."))?;
+        Ok(WriteResult { update_time })
+    }
 }
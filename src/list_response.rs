@@ -1,18 +1,49 @@
 use crate::dynamic_firestore_client::SharedFirestoreClient;
+use crate::error::PageTokenExpired;
 use crate::identifiers::{CollectionName, DocumentName};
 use crate::NamedDocument;
-use firestore_serde::firestore::{Document, ListDocumentsRequest};
-use serde::{de::DeserializeOwned, Serialize};
+use anyhow::Context;
+use firestore_serde::firestore::{Document, DocumentMask, ListDocumentsRequest};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use std::collections::VecDeque;
 use std::future::Future;
 use std::marker::PhantomData;
 use std::pin::Pin;
 use std::task::Poll;
+use tokio::task::JoinHandle;
 use tokio_stream::Stream;
+use tonic::Status;
 
-type ListResponseFuture = Pin<Box<dyn Future<Output = (VecDeque<Document>, String)> + 'static + Send>>;
+/// The special field path which refers to a document's name.
+const NAME_FIELD: &str = "__name__";
+
+/// A page of documents, with the token for the page after it (empty for the last page).
+type Page = (VecDeque<Document>, String);
+
+type ListResponseFuture = Pin<Box<dyn Future<Output = Result<Page, Status>> + 'static + Send>>;
+
+/// A page being fetched in the background by a task, which is aborted if this is dropped.
+struct Prefetch(JoinHandle<Result<Page, Status>>);
+
+impl Drop for Prefetch {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+type ProgressCallback = Box<dyn FnMut(usize, &DocumentName) + Send>;
 
 /// Stream of documents returned from a Firestore list query.
+///
+/// Dropping the stream part-way through cancels any request for the next page that is in
+/// flight.
+///
+/// If a page cannot be fetched or a document cannot be deserialized, the stream ends early,
+/// and the error can be retrieved with [ListResponse::take_error]. A collection name that fails
+/// [CollectionName::validate] is reported this way before any request is made. Firestore's page
+/// tokens also expire, so a listing consumed over a very long time may end with
+/// [PageTokenExpired] unless [ListResponse::with_restart_on_expired_token] is set.
+/// [ListResponse::try_next] and [ListResponse::fold] return these failures directly.
 pub struct ListResponse<T>
 where
     T: Serialize + DeserializeOwned + Unpin + 'static,
@@ -38,7 +69,44 @@ where
 
     page_size: u32,
 
-    order_by: String,
+    /// The `order_by` clause to list documents by, if set. If not, Firestore orders
+    /// documents by name.
+    order_by: Option<String>,
+
+    /// The fields to return for each document, if set. If not, every field is returned.
+    mask: Option<Vec<String>>,
+
+    /// Called each time a page of documents is received from the server, if set.
+    progress_callback: Option<ProgressCallback>,
+
+    /// The number of documents received from the server so far. Only tracked when a
+    /// progress callback is set.
+    processed: usize,
+
+    /// Whether to restart the listing from the beginning if its page token expires, rather
+    /// than failing.
+    restart_on_expired_token: bool,
+
+    /// Whether to fetch the next page while the current one is being consumed.
+    prefetch: bool,
+
+    /// The next page, if it is being prefetched.
+    prefetched: Option<Prefetch>,
+
+    /// The maximum number of documents to return, if set.
+    limit: Option<u32>,
+
+    /// The number of documents to skip before returning any.
+    offset: u32,
+
+    /// The number of documents returned so far.
+    returned: usize,
+
+    /// The number of documents skipped so far, up to `offset`.
+    skipped: usize,
+
+    /// The failure which ended the stream early, until it is taken.
+    error: Option<anyhow::Error>,
 
     _ph: PhantomData<T>,
 }
@@ -60,8 +128,19 @@ where
             depleated: false,
             future: None,
             page_size: 0,
-            order_by: "".to_string(),
-            _ph: PhantomData::default(),
+            order_by: None,
+            mask: None,
+            progress_callback: None,
+            processed: 0,
+            restart_on_expired_token: false,
+            prefetch: false,
+            prefetched: None,
+            limit: None,
+            offset: 0,
+            returned: 0,
+            skipped: 0,
+            error: None,
+            _ph: PhantomData,
         }
     }
 
@@ -69,65 +148,315 @@ where
         Self { page_size, ..self }
     }
 
+    /// Stop after returning `limit` documents in total, across all pages. Pages are made no
+    /// larger than needed to reach the limit.
+    pub fn with_limit(self, limit: u32) -> Self {
+        Self {
+            limit: Some(limit),
+            ..self
+        }
+    }
+
+    /// Skip the first `offset` documents. The listing API has no offset, so skipped documents
+    /// are still fetched (and billed as reads); [crate::Query::offset] skips them on the
+    /// server instead.
+    pub fn with_offset(self, offset: u32) -> Self {
+        Self { offset, ..self }
+    }
+
+    /// Order documents by the given fields, in the form `field [desc], ...`
+    /// (e.g. `"city, id desc"`).
+    ///
+    /// If no ordering is given, documents are ordered by name.
     pub fn with_order_by(self, order_by: &str) -> Self {
         Self {
-            order_by: order_by.to_string(),
+            order_by: Some(order_by.to_string()),
+            ..self
+        }
+    }
+
+    /// Order documents by name, descending. For documents with time-sortable keys (such as
+    /// ULIDs), this lists the most recently created first.
+    pub fn newest_first(self) -> Self {
+        self.with_order_by(&format!("{} desc", NAME_FIELD))
+    }
+
+    /// Only fetch the given fields of each document, reducing the amount of data transferred
+    /// when listing large documents.
+    ///
+    /// Fields that are not selected are absent when documents are deserialized, so every
+    /// other field of `T` must tolerate being missing (e.g. be an `Option`, or be marked
+    /// `#[serde(default)]`).
+    pub fn with_mask(self, fields: &[&str]) -> Self {
+        Self {
+            mask: Some(fields.iter().map(|field| field.to_string()).collect()),
             ..self
         }
     }
 
-    pub async fn get_page(self) -> Vec<NamedDocument<T>> {
-        let (docs, _) = Self::fetch_documents(
-            self.collection.parent().name(),
-            self.collection.leaf_name(),
-            self.page_token.clone(),
-            self.db.clone(),
-            self.page_size,
-            self.order_by,
-        )
-        .await;
+    /// If the page token used to fetch the next page expires, restart the listing from the
+    /// beginning instead of failing with [PageTokenExpired].
+    ///
+    /// Restarting re-yields every document already returned by the stream, so this should
+    /// only be enabled if the caller can tolerate (or filter out) duplicates.
+    pub fn with_restart_on_expired_token(self, restart_on_expired_token: bool) -> Self {
+        Self {
+            restart_on_expired_token,
+            ..self
+        }
+    }
 
+    /// Start fetching the next page as soon as a page arrives, rather than when the consumer
+    /// reaches the end of it, hiding the latency of each fetch behind the consumption of the
+    /// previous page. At most one page is fetched ahead, so at most two are held at once.
+    ///
+    /// The next page is fetched by a task spawned on the current Tokio runtime, using its own
+    /// clone of the client, so other requests made in the meantime are not held up by it.
+    pub fn with_prefetch(self, prefetch: bool) -> Self {
+        Self { prefetch, ..self }
+    }
+
+    /// Register a callback to be invoked each time a page of documents arrives from the server.
+    ///
+    /// The callback receives the total number of documents received so far (including the
+    /// new page) and the name of the last document in the page, which can be logged or
+    /// checkpointed by long-running jobs. It is not called for empty pages.
+    pub fn on_progress(self, callback: impl FnMut(usize, &DocumentName) + Send + 'static) -> Self {
+        Self {
+            progress_callback: Some(Box::new(callback)),
+            ..self
+        }
+    }
+
+    /// Fetch only the first page of the listing.
+    ///
+    /// Returns an error, rather than panicking, if the collection name is invalid, the page
+    /// cannot be fetched, or a document cannot be deserialized.
+    pub async fn get_page(mut self) -> anyhow::Result<Vec<NamedDocument<T>>> {
+        self.validate()?;
+
+        let (docs, _) = Self::fetch_documents(self.db.clone(), self.request())
+            .await
+            .context("Could not list documents.")?;
+
+        self.report_progress(&docs);
+
+        let limit = self.limit.map_or(usize::MAX, |limit| limit as usize);
         docs.into_iter()
+            .skip(self.offset as usize)
+            .take(limit)
             .map(|doc| {
-                let name = DocumentName::parse(&doc.name).unwrap();
-                let value =
-                    firestore_serde::from_document(doc).expect("Could not convert document.");
-
-                NamedDocument { name, value }
+                let name = DocumentName::parse(&doc.name)?;
+                Ok(NamedDocument::from_proto(name, doc)?)
             })
             .collect()
     }
 
+    /// Invoke the progress callback, if there is one, for a newly-received page.
+    fn report_progress(&mut self, page: &VecDeque<Document>) {
+        if let (Some(callback), Some(last)) = (&mut self.progress_callback, page.back()) {
+            self.processed += page.len();
+            callback(self.processed, &DocumentName::parse(&last.name).unwrap());
+        }
+    }
+
+    /// Returns the error which ended the stream early, if there was one, leaving `None` in its
+    /// place. A stream which ends without an error has returned every document.
+    pub fn take_error(&mut self) -> Option<anyhow::Error> {
+        self.error.take()
+    }
+
+    /// Check the collection name before making a nonsensical request with it.
+    fn validate(&self) -> anyhow::Result<()> {
+        self.collection.validate().with_context(|| {
+            format!(
+                "Cannot list documents in invalid collection {}",
+                self.collection.name()
+            )
+        })
+    }
+
+    /// End the stream early because of `error`, which is kept for [ListResponse::take_error].
+    fn fail(&mut self, error: anyhow::Error) {
+        self.finish();
+        self.error = Some(error);
+    }
+
+    /// Handle the outcome of fetching the page at `self.page_token`. If its token expired and
+    /// the listing restarts, the next fetch is of the first page.
+    fn try_receive(&mut self, result: Result<Page, Status>) -> anyhow::Result<()> {
+        match result {
+            Err(status) => {
+                // Only a request that carried a page token can fail because it expired.
+                if self.page_token.is_none() || !PageTokenExpired::matches(&status) {
+                    return Err(anyhow::Error::new(status).context("Could not list documents."));
+                }
+                if !self.restart_on_expired_token {
+                    return Err(PageTokenExpired.into());
+                }
+
+                self.page_token = None;
+                self.skipped = 0;
+            }
+            Ok((items, page_token)) => {
+                self.page_token = if page_token.is_empty() {
+                    self.depleated = true;
+                    None
+                } else {
+                    Some(page_token)
+                };
+                self.report_progress(&items);
+                self.items.extend(items);
+
+                if self.prefetch && !self.depleated && self.wanted() != Some(0) {
+                    let fetch = Self::fetch_documents(self.db.clone(), self.request());
+                    self.prefetched = Some(Prefetch(tokio::spawn(fetch)));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Consume the listing, combining each document into an accumulator, starting from
+    /// `init`, with `f`. Only a page of documents is held in memory at a time.
+    ///
+    /// Unlike reading the listing as a stream, a page which cannot be fetched or a document
+    /// which cannot be deserialized is returned as an error rather than ending the listing.
+    ///
+    /// ```no_run
+    /// # async fn example(sizes: tiny_firestore_odm::Collection<u64>) -> anyhow::Result<()> {
+    /// let total = sizes.list().fold(0, |total, size| total + size.value).await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn fold<A>(
+        mut self,
+        init: A,
+        mut f: impl FnMut(A, NamedDocument<T>) -> A,
+    ) -> anyhow::Result<A> {
+        let mut accumulator = init;
+        while let Some(document) = self.try_next().await? {
+            accumulator = f(accumulator, document);
+        }
+        Ok(accumulator)
+    }
+
+    /// Returns the next document in the listing, fetching pages as needed, or an error if a
+    /// page cannot be fetched or the document cannot be deserialized. This includes an error
+    /// which ended the listing while it was read as a stream.
+    pub async fn try_next(&mut self) -> anyhow::Result<Option<NamedDocument<T>>> {
+        if let Some(error) = self.error.take() {
+            return Err(error);
+        }
+
+        loop {
+            if self.limit_reached() {
+                self.finish();
+                return Ok(None);
+            }
+
+            if let Some(doc) = self.pop_item() {
+                let name = DocumentName::parse(&doc.name)?;
+                return Ok(Some(NamedDocument::from_proto(name, doc)?));
+            }
+
+            if let Some(Prefetch(handle)) = &mut self.prefetched {
+                let result = handle.await;
+                self.prefetched = None;
+                self.try_receive(result?)?;
+                continue;
+            }
+
+            // A fetch may have been started by polling the listing as a stream.
+            if let Some(fut) = self.future.take() {
+                self.try_receive(fut.await)?;
+                continue;
+            }
+
+            if self.depleated {
+                return Ok(None);
+            }
+
+            if self.page_token.is_none() {
+                self.validate()?;
+            }
+
+            let result = Self::fetch_documents(self.db.clone(), self.request()).await;
+            self.try_receive(result)?;
+        }
+    }
+
+    /// Returns the next buffered document to return, discarding any to be skipped first.
+    fn pop_item(&mut self) -> Option<Document> {
+        while let Some(doc) = self.items.pop_front() {
+            if self.skipped < self.offset as usize {
+                self.skipped += 1;
+                continue;
+            }
+            self.returned += 1;
+            return Some(doc);
+        }
+        None
+    }
+
+    /// Returns `true` once the limit's worth of documents have been returned.
+    fn limit_reached(&self) -> bool {
+        self.limit
+            .is_some_and(|limit| self.returned >= limit as usize)
+    }
+
+    /// The number of documents still to be fetched to reach the limit (counting those to be
+    /// skipped), beyond those already buffered, if there is a limit.
+    fn wanted(&self) -> Option<usize> {
+        self.limit.map(|limit| {
+            (limit as usize + self.offset as usize)
+                .saturating_sub(self.returned + self.skipped + self.items.len())
+        })
+    }
+
+    /// End the listing, cancelling any fetch in flight.
+    fn finish(&mut self) {
+        self.depleated = true;
+        self.items.clear();
+        self.future = None;
+        self.prefetched = None;
+    }
+
+    /// Build the request for the next page of documents.
+    fn request(&self) -> ListDocumentsRequest {
+        ListDocumentsRequest {
+            collection_id: self.collection.leaf_name(),
+            parent: self.collection.parent().name(),
+            page_token: self.page_token.clone().unwrap_or_default(),
+            page_size: match self.wanted() {
+                Some(wanted) if self.page_size == 0 || wanted < self.page_size as usize => {
+                    wanted.min(i32::MAX as usize) as i32
+                }
+                _ => self.page_size as i32,
+            },
+            // An empty string is omitted from the request, leaving the server's default order.
+            order_by: self.order_by.clone().unwrap_or_default(),
+            mask: self
+                .mask
+                .clone()
+                .map(|field_paths| DocumentMask { field_paths }),
+
+            ..ListDocumentsRequest::default()
+        }
+    }
+
     /// Fetch a chunk of documents from the server. The future returned by this function
     /// gets stored in self.future.
     async fn fetch_documents(
-        parent: String,
-        collection_id: String,
-        page_token: Option<String>,
         db: SharedFirestoreClient,
-        page_size: u32,
-        order_by: String,
-    ) -> (VecDeque<Document>, String) {
-        let parent = parent;
-        let collection_id = collection_id;
-
-        let mut db = db.lock().await;
-        let documents = db
-            .list_documents(ListDocumentsRequest {
-                collection_id,
-                parent,
-                page_token: page_token.unwrap_or_default(),
-                page_size: page_size as i32,
-                order_by,
-
-                ..ListDocumentsRequest::default()
-            })
-            .await
-            .unwrap();
+        request: ListDocumentsRequest,
+    ) -> Result<Page, Status> {
+        let mut db = db;
+        let documents = db.list_documents(request).await?;
 
         let documents = documents.into_inner();
         let page_token = documents.next_page_token;
-        (documents.documents.into_iter().collect(), page_token)
+        Ok((documents.documents.into_iter().collect(), page_token))
     }
 }
 
@@ -149,46 +478,288 @@ where
 
         // Loop because some actions cause a state change that allow us to make progress.
         loop {
+            if self_mut.limit_reached() {
+                self_mut.finish();
+                return Poll::Ready(None);
+            }
+
             // If the items buffer is not empty, we can return a result immediately.
-            if let Some(doc) = self_mut.items.pop_front() {
-                let name = DocumentName::parse(&doc.name).unwrap();
-                let value =
-                    firestore_serde::from_document(doc).expect("Could not convert document.");
+            if let Some(doc) = self_mut.pop_item() {
+                let document = DocumentName::parse(&doc.name)
+                    .map_err(anyhow::Error::from)
+                    .and_then(|name| Ok(NamedDocument::from_proto(name, doc)?));
+                match document {
+                    Ok(document) => return Poll::Ready(Some(document)),
+                    Err(error) => {
+                        self_mut.fail(error);
+                        return Poll::Ready(None);
+                    }
+                }
+            }
 
-                return Poll::Ready(Some(NamedDocument { name, value }));
+            // If the next page was prefetched, wait for it.
+            if let Some(Prefetch(handle)) = &mut self_mut.prefetched {
+                match Pin::new(handle).poll(cx) {
+                    Poll::Pending => return Poll::Pending,
+                    Poll::Ready(result) => {
+                        self_mut.prefetched = None;
+                        let received = result
+                            .context("Could not prefetch documents.")
+                            .and_then(|result| self_mut.try_receive(result));
+                        if let Err(error) = received {
+                            self_mut.fail(error);
+                            return Poll::Ready(None);
+                        }
+                        continue;
+                    }
+                }
             }
 
             // If we are already waiting for a response from the server, we poll it.
             if let Some(fut) = &mut self_mut.future {
-                return match fut.as_mut().poll(cx) {
-                    Poll::Pending => Poll::Pending,
+                match fut.as_mut().poll(cx) {
+                    Poll::Pending => return Poll::Pending,
                     Poll::Ready(result) => {
-                        let (items, page_token) = result;
-
-                        self_mut.page_token = if page_token.is_empty() {
-                            self_mut.depleated = true;
-                            None
-                        } else {
-                            Some(page_token)
-                        };
-                        self_mut.items = items;
                         self_mut.future = None;
+                        if let Err(error) = self_mut.try_receive(result) {
+                            self_mut.fail(error);
+                            return Poll::Ready(None);
+                        }
                         continue;
                     }
-                };
+                }
+            }
+
+            // The last page may have been empty.
+            if self_mut.depleated {
+                return Poll::Ready(None);
+            }
+
+            // Catch a malformed, hand-constructed collection name before making a request.
+            if self_mut.page_token.is_none() {
+                if let Err(error) = self_mut.validate() {
+                    self_mut.fail(error);
+                    return Poll::Ready(None);
+                }
             }
 
             // Store a future for the remaining documents. It will be polled when the loop continues.
             let fut = Box::pin(Self::fetch_documents(
-                self_mut.collection.parent().name(),
-                self_mut.collection.leaf_name(),
-                self_mut.page_token.clone(),
                 self_mut.db.clone(),
-                self_mut.page_size,
-                self_mut.order_by.to_string(),
+                self_mut.request(),
             ));
 
             self_mut.future = Some(fut);
         }
     }
 }
+
+/// A document with its fields ignored, for listings which only need names.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct NoFields {}
+
+/// Stream of the names of documents in a collection, in chunks, constructed by
+/// [crate::Collection::stream_keys_chunked].
+///
+/// Like [ListResponse], the stream ends early if a page cannot be fetched, and the error can be
+/// retrieved with [KeyChunks::take_error].
+pub struct KeyChunks {
+    keys: ListResponse<NoFields>,
+    chunk_size: usize,
+    chunk: Vec<DocumentName>,
+}
+
+impl KeyChunks {
+    pub(crate) fn new(
+        collection: CollectionName,
+        db: SharedFirestoreClient,
+        chunk_size: usize,
+    ) -> Self {
+        assert!(chunk_size > 0, "Chunk size must be at least 1.");
+
+        // Pages are the size of a chunk, so that no more is fetched than the caller consumes.
+        // An empty mask returns each document's name and none of its fields.
+        let keys = ListResponse::new(collection, db)
+            .with_page_size(chunk_size as u32)
+            .with_mask(&[]);
+        KeyChunks {
+            keys,
+            chunk_size,
+            chunk: Vec::with_capacity(chunk_size),
+        }
+    }
+
+    /// Returns the error which ended the stream early, if there was one, leaving `None` in its
+    /// place. The chunk being built when the error occurred is still returned.
+    pub fn take_error(&mut self) -> Option<anyhow::Error> {
+        self.keys.take_error()
+    }
+}
+
+impl Stream for KeyChunks {
+    type Item = Vec<DocumentName>;
+
+    fn poll_next(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        let self_mut = self.get_mut();
+
+        loop {
+            match Pin::new(&mut self_mut.keys).poll_next(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(Some(document)) => {
+                    self_mut.chunk.push(document.name);
+                    if self_mut.chunk.len() == self_mut.chunk_size {
+                        let chunk = std::mem::replace(
+                            &mut self_mut.chunk,
+                            Vec::with_capacity(self_mut.chunk_size),
+                        );
+                        return Poll::Ready(Some(chunk));
+                    }
+                }
+                Poll::Ready(None) if self_mut.chunk.is_empty() => return Poll::Ready(None),
+                Poll::Ready(None) => {
+                    return Poll::Ready(Some(std::mem::take(&mut self_mut.chunk)));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::dynamic_firestore_client::test_client;
+    use prost::Message;
+
+    #[tokio::test]
+    async fn test_default_order_by_is_omitted() {
+        let collection = CollectionName::new("my-project", "things");
+        let list: ListResponse<()> = ListResponse::new(collection.clone(), test_client());
+        let request = list.request();
+
+        assert_eq!("", request.order_by);
+
+        // The field is absent from the encoded request, so the server applies its default.
+        let ordered = ListDocumentsRequest {
+            order_by: "size".to_string(),
+            ..request.clone()
+        };
+        assert_eq!(
+            request.encoded_len() + "size".len() + 2,
+            ordered.encoded_len()
+        );
+
+        let list: ListResponse<()> =
+            ListResponse::new(collection.clone(), test_client()).with_order_by("size desc");
+        assert_eq!("size desc", list.request().order_by);
+
+        let list: ListResponse<()> = ListResponse::new(collection, test_client()).newest_first();
+        assert_eq!("__name__ desc", list.request().order_by);
+    }
+
+    #[tokio::test]
+    async fn test_invalid_collection() {
+        let collection = CollectionName::new("my-project", "");
+        let mut list: ListResponse<()> = ListResponse::new(collection, test_client());
+
+        // The client is never connected, so this can only fail locally.
+        assert!(tokio_stream::StreamExt::next(&mut list).await.is_none());
+        let error = list.take_error().unwrap();
+        assert!(error
+            .to_string()
+            .starts_with("Cannot list documents in invalid collection"));
+        assert!(list.take_error().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_try_next_after_stream_error() {
+        let collection = CollectionName::new("my-project", "");
+        let mut list: ListResponse<()> = ListResponse::new(collection, test_client());
+
+        assert!(tokio_stream::StreamExt::next(&mut list).await.is_none());
+        assert!(list.try_next().await.is_err());
+        assert!(list.try_next().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_fold_invalid_collection() {
+        let collection = CollectionName::new("my-project", "");
+        let list: ListResponse<()> = ListResponse::new(collection, test_client());
+
+        let error = list.fold(0, |count, _| count + 1).await.unwrap_err();
+        assert!(error
+            .to_string()
+            .starts_with("Cannot list documents in invalid collection"));
+    }
+
+    #[tokio::test]
+    async fn test_get_page_invalid_collection() {
+        let collection = CollectionName::new("my-project", "");
+        let list: ListResponse<()> = ListResponse::new(collection, test_client());
+
+        let error = list.get_page().await.unwrap_err();
+        assert!(error
+            .to_string()
+            .starts_with("Cannot list documents in invalid collection"));
+    }
+
+    #[tokio::test]
+    async fn test_mask() {
+        let collection = CollectionName::new("my-project", "things");
+        let list: ListResponse<()> = ListResponse::new(collection.clone(), test_client());
+        assert_eq!(None, list.request().mask);
+
+        let list: ListResponse<()> =
+            ListResponse::new(collection, test_client()).with_mask(&["name", "email"]);
+        assert_eq!(
+            Some(DocumentMask {
+                field_paths: vec!["name".to_string(), "email".to_string()]
+            }),
+            list.request().mask
+        );
+    }
+
+    #[tokio::test]
+    async fn test_limit_and_offset() {
+        let collection = CollectionName::new("my-project", "things");
+        let mut list: ListResponse<NoFields> = ListResponse::new(collection.clone(), test_client())
+            .with_page_size(10)
+            .with_limit(3)
+            .with_offset(2);
+        assert_eq!(5, list.request().page_size);
+
+        // Serve a single page from the buffer, as if it had been fetched.
+        list.depleated = true;
+        for key in &["a", "b", "c", "d", "e", "f"] {
+            list.items.push_back(Document {
+                name: collection.document(key).name(),
+                ..Document::default()
+            });
+        }
+        let keys: Vec<String> = tokio_stream::StreamExt::collect::<Vec<_>>(list)
+            .await
+            .into_iter()
+            .map(|document| document.name.leaf_name().to_string())
+            .collect();
+        assert_eq!(vec!["c", "d", "e"], keys);
+
+        let list: ListResponse<NoFields> =
+            ListResponse::new(collection, test_client()).with_limit(4);
+        assert_eq!(4, list.request().page_size);
+    }
+
+    #[tokio::test]
+    async fn test_key_chunks_request() {
+        let collection = CollectionName::new("my-project", "things");
+        let chunks = KeyChunks::new(collection, test_client(), 50);
+        let request = chunks.keys.request();
+
+        assert_eq!(50, request.page_size);
+        assert_eq!(Some(DocumentMask::default()), request.mask);
+
+        // A document returned with only its name deserializes.
+        assert!(firestore_serde::from_document::<NoFields>(Document::default()).is_ok());
+    }
+}
@@ -0,0 +1,298 @@
+use crate::dynamic_firestore_client::SharedFirestoreClient;
+use crate::retry::{Idempotency, RetryPolicy};
+use crate::WriteResult;
+use firestore_serde::firestore::{CommitRequest, CommitResponse};
+use prost::Message;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use tonic::{Code, Status};
+
+/// Storage for the batches of writes held by an [OfflineQueue]. Implement it over a file or an
+/// embedded database to keep queued writes across restarts.
+///
+/// Batches are opaque bytes, which must be returned unchanged and in the order they were
+/// pushed.
+pub trait QueueStore: Send + Sync {
+    /// Append a batch to the back of the queue.
+    fn push(&self, batch: Vec<u8>) -> anyhow::Result<()>;
+
+    /// Returns the batch at the front of the queue, without removing it.
+    fn front(&self) -> anyhow::Result<Option<Vec<u8>>>;
+
+    /// Remove the batch at the front of the queue, if there is one.
+    fn pop_front(&self) -> anyhow::Result<()>;
+
+    /// Returns the number of batches in the queue.
+    fn pending(&self) -> anyhow::Result<usize>;
+}
+
+/// A [QueueStore] which keeps batches in memory, so they are lost when the process exits.
+#[derive(Default)]
+pub struct MemoryQueueStore {
+    batches: Mutex<VecDeque<Vec<u8>>>,
+}
+
+impl QueueStore for MemoryQueueStore {
+    fn push(&self, batch: Vec<u8>) -> anyhow::Result<()> {
+        self.batches.lock().unwrap().push_back(batch);
+        Ok(())
+    }
+
+    fn front(&self) -> anyhow::Result<Option<Vec<u8>>> {
+        Ok(self.batches.lock().unwrap().front().cloned())
+    }
+
+    fn pop_front(&self) -> anyhow::Result<()> {
+        self.batches.lock().unwrap().pop_front();
+        Ok(())
+    }
+
+    fn pending(&self) -> anyhow::Result<usize> {
+        Ok(self.batches.lock().unwrap().len())
+    }
+}
+
+/// Whether [crate::Collection::apply_or_queue] applied its writes or queued them.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ApplyOutcome {
+    /// The writes were applied at the given time.
+    Applied(WriteResult),
+
+    /// Firestore could not be reached, so the writes were queued, to be applied by a later
+    /// flush.
+    Queued,
+}
+
+/// Holds batches of writes which could not be sent because Firestore was unreachable, until
+/// they are replayed by [crate::Database::flush_offline_queue].
+///
+/// Writes are queued by [crate::Collection::apply_or_queue], on a collection given the queue
+/// with `with_offline_queue` (or obtained from a database given it). Only batches which can
+/// safely be applied more than once are queued: upserts, and transforms other than
+/// increments. A batch with a precondition (a create, update or delete) or an increment is
+/// not queued; the error is returned instead, as it would be from `apply`.
+///
+/// Batches are replayed one at a time, in the order they were queued, each in its own
+/// commit. A write made while the queue is not empty first flushes it, and is queued behind
+/// it if that fails, so writes made through the queue are applied in order. Writes made
+/// other than through the queue are not ordered with respect to queued ones.
+///
+/// Since a batch may have been applied by an attempt whose response was lost, it may be
+/// applied again when replayed; this is why only batches which leave the same result when
+/// applied twice are queued. A queued batch is always applied whole.
+///
+/// Clones of a queue share the same store.
+#[derive(Clone)]
+pub struct OfflineQueue {
+    store: Arc<dyn QueueStore>,
+
+    /// Held while flushing, so that each batch is replayed once, in order.
+    flushing: Arc<tokio::sync::Mutex<()>>,
+}
+
+impl OfflineQueue {
+    /// Construct a queue which keeps its batches in the given store.
+    pub fn new(store: impl QueueStore + 'static) -> Self {
+        OfflineQueue {
+            store: Arc::new(store),
+            flushing: Arc::default(),
+        }
+    }
+
+    /// Construct a queue which keeps its batches in memory.
+    pub fn in_memory() -> Self {
+        OfflineQueue::new(MemoryQueueStore::default())
+    }
+
+    /// Returns the number of batches waiting to be applied.
+    pub fn pending(&self) -> anyhow::Result<usize> {
+        self.store.pending()
+    }
+
+    /// Discard the batch at the front of the queue without applying it, e.g. after a flush
+    /// failed because Firestore rejected it.
+    pub fn discard_next(&self) -> anyhow::Result<()> {
+        self.store.pop_front()
+    }
+
+    /// Replay queued batches in order, returning the number applied.
+    ///
+    /// Stops at the first batch which fails, leaving it and the batches behind it queued, and
+    /// returns its error. If Firestore rejected the batch (rather than being unreachable),
+    /// replaying it is likely to fail again, and it can be dropped with `discard_next`.
+    pub(crate) async fn flush(
+        &self,
+        db: &SharedFirestoreClient,
+        retry: &RetryPolicy,
+    ) -> anyhow::Result<usize> {
+        let _flushing = self.flushing.lock().await;
+
+        let mut applied = 0;
+        while let Some(batch) = self.store.front()? {
+            let request = CommitRequest::decode(batch.as_slice())?;
+            commit(db, retry, &request).await?;
+            self.store.pop_front()?;
+            applied += 1;
+        }
+        Ok(applied)
+    }
+
+    /// Commit the given writes after any queued ones, or queue them if Firestore cannot be
+    /// reached. Returns `None` if they were queued.
+    pub(crate) async fn commit_or_queue(
+        &self,
+        db: &SharedFirestoreClient,
+        retry: &RetryPolicy,
+        request: CommitRequest,
+        idempotency: Idempotency,
+    ) -> anyhow::Result<Option<CommitResponse>> {
+        let replayable = idempotency == Idempotency::Idempotent && is_replayable(&request);
+
+        if self.store.pending()? > 0 {
+            if let Err(error) = self.flush(db, retry).await {
+                return match error.downcast_ref::<Status>() {
+                    Some(status) if replayable && is_offline(status) => {
+                        self.store.push(request.encode_to_vec())?;
+                        Ok(None)
+                    }
+                    _ => Err(error),
+                };
+            }
+        }
+
+        match retry
+            .run(idempotency, || async {
+                db.clone().commit(request.clone()).await
+            })
+            .await
+        {
+            Ok(response) => Ok(Some(response.into_inner())),
+            Err(status) if replayable && is_offline(&status) => {
+                self.store.push(request.encode_to_vec())?;
+                Ok(None)
+            }
+            Err(status) => Err(status.into()),
+        }
+    }
+}
+
+async fn commit(
+    db: &SharedFirestoreClient,
+    retry: &RetryPolicy,
+    request: &CommitRequest,
+) -> Result<CommitResponse, Status> {
+    let response = retry
+        .run(Idempotency::Idempotent, || async {
+            db.clone().commit(request.clone()).await
+        })
+        .await?;
+    Ok(response.into_inner())
+}
+
+/// Returns `true` if applying the writes of `request` twice leaves the same result as
+/// applying them once. This rules out preconditions, which the first application may
+/// invalidate; increments are ruled out by the caller, which knows the request's idempotency.
+fn is_replayable(request: &CommitRequest) -> bool {
+    request
+        .writes
+        .iter()
+        .all(|write| write.current_document.is_none())
+}
+
+/// Returns `true` if `status` means that Firestore could not be reached, rather than that it
+/// rejected the request.
+fn is_offline(status: &Status) -> bool {
+    match status.code() {
+        Code::Unavailable | Code::DeadlineExceeded => true,
+        // Connection failures are reported by tonic as unknown transport errors.
+        Code::Unknown => status.message().starts_with("transport error"),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::dynamic_firestore_client::test_client;
+    use firestore_serde::firestore::{
+        precondition::ConditionType, write::Operation, Document, Precondition, Write,
+    };
+
+    fn upsert(name: &str) -> Write {
+        Write {
+            operation: Some(Operation::Update(Document {
+                name: name.to_string(),
+                ..Document::default()
+            })),
+            ..Write::default()
+        }
+    }
+
+    #[test]
+    fn test_is_replayable() {
+        let request = |writes| CommitRequest {
+            writes,
+            ..CommitRequest::default()
+        };
+
+        assert!(is_replayable(&request(vec![upsert("a"), upsert("b")])));
+
+        let create = Write {
+            current_document: Some(Precondition {
+                condition_type: Some(ConditionType::Exists(false)),
+            }),
+            ..upsert("b")
+        };
+        assert!(!is_replayable(&request(vec![upsert("a"), create])));
+    }
+
+    #[test]
+    fn test_is_offline() {
+        assert!(is_offline(&Status::new(Code::Unavailable, "Unavailable.")));
+        assert!(is_offline(&Status::new(
+            Code::Unknown,
+            "transport error: error trying to connect"
+        )));
+        assert!(!is_offline(&Status::new(Code::Unknown, "Oops.")));
+        assert!(!is_offline(&Status::new(Code::PermissionDenied, "No.")));
+    }
+
+    #[tokio::test]
+    async fn test_queue_while_offline() {
+        // The client is never connected, so every request fails to reach the server.
+        let db = test_client();
+        let retry = RetryPolicy::never();
+        let queue = OfflineQueue::in_memory();
+
+        for name in &["a", "b"] {
+            let request = CommitRequest {
+                writes: vec![upsert(name)],
+                ..CommitRequest::default()
+            };
+            let result = queue
+                .commit_or_queue(&db, &retry, request, Idempotency::Idempotent)
+                .await;
+            assert!(result.unwrap().is_none());
+        }
+        assert_eq!(2, queue.pending().unwrap());
+
+        // A batch which is unsafe to replay is not queued.
+        let request = CommitRequest {
+            writes: vec![upsert("c")],
+            ..CommitRequest::default()
+        };
+        let result = queue
+            .commit_or_queue(&db, &retry, request, Idempotency::NotIdempotent)
+            .await;
+        assert!(result.is_err());
+        assert_eq!(2, queue.pending().unwrap());
+
+        // Flushing fails at the first batch, keeping everything queued in order.
+        assert!(queue.flush(&db, &retry).await.is_err());
+        assert_eq!(2, queue.pending().unwrap());
+
+        queue.discard_next().unwrap();
+        let next = CommitRequest::decode(queue.store.front().unwrap().unwrap().as_slice());
+        assert_eq!(vec![upsert("b")], next.unwrap().writes);
+    }
+}
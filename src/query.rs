@@ -0,0 +1,1357 @@
+use crate::cache::DocumentCache;
+use crate::clock::Clock;
+use crate::collection::MAX_WRITES_PER_COMMIT;
+use crate::dynamic_firestore_client::SharedFirestoreClient;
+use crate::error::MissingIndexError;
+use crate::identifiers::{CollectionName, DocumentName, QualifyDocumentName};
+use crate::retry::{Idempotency, RetryPolicy};
+use crate::NamedDocument;
+use firestore_serde::firestore::{
+    run_query_request::QueryType,
+    structured_query::{
+        composite_filter, field_filter, filter::FilterType, CollectionSelector, CompositeFilter,
+        FieldFilter, FieldReference, Filter, Order, Projection,
+    },
+    value::ValueType,
+    write::Operation,
+    ArrayValue, CommitRequest, Cursor, Document, RunQueryRequest, RunQueryResponse,
+    StructuredQuery, Value, Write,
+};
+use prost::Message;
+use serde::{de::DeserializeOwned, Serialize};
+use std::collections::HashMap;
+use std::future::Future;
+use std::marker::PhantomData;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::Poll;
+use std::time::{Duration, SystemTime};
+use tokio_stream::Stream;
+use tonic::{Status, Streaming};
+
+/// Maximum number of values Firestore accepts in an `in` filter.
+const MAX_IN_VALUES: usize = 30;
+
+/// The special field path which refers to a document's name.
+const NAME_FIELD: &str = "__name__";
+
+/// Counts made by [Query::count_cached], shared by every query of a collection. Keyed by the
+/// encoded `StructuredQuery`, with the time at which each count was made.
+pub(crate) type CountCache = Arc<Mutex<HashMap<Vec<u8>, (SystemTime, i64)>>>;
+
+type QueryFuture =
+    Pin<Box<dyn Future<Output = Result<Streaming<RunQueryResponse>, Status>> + Send>>;
+
+/// Direction in which query results are ordered by a field.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    Ascending,
+    Descending,
+}
+
+impl Direction {
+    fn to_proto(self) -> i32 {
+        use firestore_serde::firestore::structured_query::Direction as ProtoDirection;
+
+        match self {
+            Direction::Ascending => ProtoDirection::Ascending as i32,
+            Direction::Descending => ProtoDirection::Descending as i32,
+        }
+    }
+}
+
+/// Operator used to compare a field against a value in a query filter.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FieldOp {
+    Equal,
+    NotEqual,
+    LessThan,
+    LessThanOrEqual,
+    GreaterThan,
+    GreaterThanOrEqual,
+}
+
+impl FieldOp {
+    fn to_proto(self) -> field_filter::Operator {
+        match self {
+            FieldOp::Equal => field_filter::Operator::Equal,
+            FieldOp::NotEqual => field_filter::Operator::NotEqual,
+            FieldOp::LessThan => field_filter::Operator::LessThan,
+            FieldOp::LessThanOrEqual => field_filter::Operator::LessThanOrEqual,
+            FieldOp::GreaterThan => field_filter::Operator::GreaterThan,
+            FieldOp::GreaterThanOrEqual => field_filter::Operator::GreaterThanOrEqual,
+        }
+    }
+}
+
+/// A position in the results of a query, returned by [Query::list_with_count] and passed to
+/// [Query::start_after] to continue from it, or constructed from values with
+/// [QueryCursor::from_values].
+#[derive(Clone, Debug, PartialEq)]
+pub struct QueryCursor {
+    /// The values of the fields the query is ordered by, followed by the document's name.
+    values: Vec<Value>,
+}
+
+impl QueryCursor {
+    /// Construct a cursor at the given values of the fields a query is ordered by, in the
+    /// order they were passed to [Query::order_by], e.g. `("Paris", 3)` for a query ordered
+    /// by `city` then `rank`. Fields with range filters which are not ordered by explicitly
+    /// are ordered by implicitly, after the others, in lexicographic order. The values are serialized with `firestore_serde`, and must
+    /// serialize to a sequence.
+    ///
+    /// A cursor may give values for only the first of the query's orderings, in which case it
+    /// is positioned at the first (or last) result with those values. Values are compared
+    /// with field values only, so to page by document name, use [Query::where_name].
+    pub fn from_values(values: impl Serialize) -> anyhow::Result<Self> {
+        let value = firestore_serde::to_grpc_value(&values)
+            .map_err(|e| anyhow::anyhow!("Could not serialize cursor values: {}", e))?;
+        match value.value_type {
+            Some(ValueType::ArrayValue(ArrayValue { values })) => Ok(QueryCursor { values }),
+            _ => Err(anyhow::anyhow!(
+                "Expected cursor values to serialize to a sequence, but got {:?}.",
+                value
+            )),
+        }
+    }
+
+    /// Returns this position as a cursor, which is positioned just before it if `before`, or
+    /// just after it otherwise.
+    fn to_proto(&self, before: bool) -> Cursor {
+        Cursor {
+            values: self.values.clone(),
+            before,
+        }
+    }
+}
+
+/// A page of the results of a query, with the number of documents matching it, returned by
+/// [Query::list_with_count].
+#[derive(Debug)]
+pub struct CountedPage<T> {
+    /// The number of documents matching the query's filters, regardless of its offset and
+    /// starting position.
+    pub total: i64,
+
+    /// The documents on this page.
+    pub page: Vec<NamedDocument<T>>,
+
+    /// The position after the last document on this page, or `None` if this is the last page.
+    pub next_cursor: Option<QueryCursor>,
+}
+
+/// Progress of a query through its lifecycle.
+enum QueryState {
+    /// The query is still being built; nothing has been sent to the server.
+    Pending,
+
+    /// The query has been sent, and we are waiting for the server to start responding.
+    Connecting(QueryFuture),
+
+    /// Results are being streamed back from the server.
+    Streaming(Streaming<RunQueryResponse>),
+
+    /// All results have been returned, or an error has been.
+    Done,
+}
+
+/// A query over the documents of a collection, run with Firestore's `RunQuery` call.
+///
+/// A query is constructed with [crate::Collection::query] (or
+/// [crate::Database::collection_group]) and refined by chaining methods on it. It is then
+/// consumed as a stream of documents; nothing is sent to the server until the first document
+/// is awaited.
+///
+/// Errors made while building the query (such as passing too many values to an `in`
+/// filter) are not reported until the query is run, as the first item of the stream.
+///
+/// Dropping a query part-way through cancels it: the underlying `RunQuery` call is reset,
+/// so abandoning a large query does not leave it streaming in the background.
+pub struct Query<T>
+where
+    T: Serialize + DeserializeOwned + Unpin + 'static,
+{
+    /// A shared handle to the Firestore client.
+    db: SharedFirestoreClient,
+
+    /// The collection being queried.
+    collection: CollectionName,
+
+    /// If `true`, the query is a collection group query: it matches documents in every
+    /// collection with the same ID as `collection`, at any depth.
+    all_descendants: bool,
+
+    /// Filters which results must match. If there is more than one, they are combined with
+    /// a logical AND.
+    filters: Vec<Filter>,
+
+    order_by: Vec<Order>,
+
+    /// The number of matching documents to skip before returning results.
+    offset: u32,
+
+    /// The maximum number of documents to return, if set.
+    limit: Option<u32>,
+
+    /// The position at which results start, if set.
+    start_at: Option<Cursor>,
+
+    /// The position at which results end, if set.
+    end_at: Option<Cursor>,
+
+    /// The first error encountered while building the query, if any.
+    error: Option<anyhow::Error>,
+
+    /// The collection's cache for [Query::count_cached], and the clock used to expire it.
+    count_cache: Option<(CountCache, Arc<dyn Clock>)>,
+
+    /// The policy used to retry requests that fail with a transient error.
+    retry: RetryPolicy,
+
+    /// The collection's document cache, from which deleted documents are evicted, if caching
+    /// is enabled.
+    cache: Option<Arc<DocumentCache<T>>>,
+
+    state: QueryState,
+
+    _ph: PhantomData<T>,
+}
+
+impl<T> Query<T>
+where
+    T: Serialize + DeserializeOwned + Unpin + 'static,
+{
+    /// Construct a query which matches every document in the given collection.
+    pub fn new(collection: CollectionName, db: SharedFirestoreClient) -> Self {
+        Query {
+            db,
+            collection,
+            all_descendants: false,
+            filters: Vec::new(),
+            order_by: Vec::new(),
+            offset: 0,
+            limit: None,
+            start_at: None,
+            end_at: None,
+            error: None,
+            count_cache: None,
+            retry: RetryPolicy::default(),
+            cache: None,
+            state: QueryState::Pending,
+            _ph: PhantomData,
+        }
+    }
+
+    /// Only match documents whose keys are among the given keys.
+    ///
+    /// Firestore allows at most 30 keys in a single `in` filter; passing more (or none)
+    /// causes the query to fail when run. Unlike [crate::Collection::get_many], this can be
+    /// combined with ordering and other filters.
+    pub fn where_id_in(mut self, keys: impl IntoIterator<Item = impl QualifyDocumentName>) -> Self {
+        let mut values = Vec::new();
+        for key in keys {
+            match key.qualify(&self.collection) {
+                Ok(name) => values.push(reference(&name)),
+                Err(e) => return self.with_error(e.into()),
+            }
+        }
+
+        if values.is_empty() || values.len() > MAX_IN_VALUES {
+            return self.with_error(anyhow::anyhow!(
+                "where_id_in requires between 1 and {} keys, but got {}.",
+                MAX_IN_VALUES,
+                values.len()
+            ));
+        }
+
+        self.filters.push(field_comparison(
+            NAME_FIELD,
+            field_filter::Operator::In,
+            Value {
+                value_type: Some(ValueType::ArrayValue(ArrayValue { values })),
+            },
+        ));
+        self
+    }
+
+    /// Only match documents whose `field` is equal to `value`.
+    pub fn where_eq(self, field: &str, value: impl Serialize) -> Self {
+        self.filter(field, FieldOp::Equal, value)
+    }
+
+    /// Only match documents whose `field` is not equal to `value`.
+    pub fn where_ne(self, field: &str, value: impl Serialize) -> Self {
+        self.filter(field, FieldOp::NotEqual, value)
+    }
+
+    /// Only match documents whose `field` is less than `value`.
+    pub fn where_lt(self, field: &str, value: impl Serialize) -> Self {
+        self.filter(field, FieldOp::LessThan, value)
+    }
+
+    /// Only match documents whose `field` is less than or equal to `value`.
+    pub fn where_lte(self, field: &str, value: impl Serialize) -> Self {
+        self.filter(field, FieldOp::LessThanOrEqual, value)
+    }
+
+    /// Only match documents whose `field` is greater than `value`.
+    ///
+    /// Range filters (`where_lt`, `where_lte`, `where_gt`, `where_gte` and `where_ne`) may be
+    /// applied to more than one field in the same query, but such queries need a composite
+    /// index. If it does not exist, running the query fails with a [MissingIndexError]
+    /// containing a link to create it.
+    pub fn where_gt(self, field: &str, value: impl Serialize) -> Self {
+        self.filter(field, FieldOp::GreaterThan, value)
+    }
+
+    /// Only match documents whose `field` is greater than or equal to `value`.
+    pub fn where_gte(self, field: &str, value: impl Serialize) -> Self {
+        self.filter(field, FieldOp::GreaterThanOrEqual, value)
+    }
+
+    /// Only match documents whose `field` compares to `value` with `op`. The value is
+    /// serialized with `firestore_serde`, so any serializable Rust value can be passed.
+    ///
+    /// This is equivalent to the `where_*` method for `op`, for when the operator is only
+    /// known at runtime. Multiple filters are combined with `AND`.
+    pub fn filter(mut self, field: &str, op: FieldOp, value: impl Serialize) -> Self {
+        match firestore_serde::to_grpc_value(&value) {
+            Ok(value) => {
+                self.filters
+                    .push(field_comparison(field, op.to_proto(), value));
+                self
+            }
+            Err(e) => self.with_error(anyhow::anyhow!(
+                "Could not serialize value for field {}: {}",
+                field,
+                e
+            )),
+        }
+    }
+
+    /// Only match documents whose name compares to the name of the given key with `op`.
+    ///
+    /// Document names are compared by their full path, which (within one collection) orders
+    /// them by key. Together with [Query::order_by_name], this paginates by key: pass the
+    /// last key of one page with [FieldOp::GreaterThan] to get the next.
+    pub fn where_name(mut self, op: FieldOp, key: impl QualifyDocumentName) -> Self {
+        match key.qualify(&self.collection) {
+            Ok(name) => {
+                self.filters.push(field_comparison(
+                    NAME_FIELD,
+                    op.to_proto(),
+                    reference(&name),
+                ));
+                self
+            }
+            Err(e) => self.with_error(e.into()),
+        }
+    }
+
+    /// Order results by document name, which (within one collection) orders them by key.
+    pub fn order_by_name(self, direction: Direction) -> Self {
+        self.order_by(NAME_FIELD, direction)
+    }
+
+    /// Order results by document name, descending. For documents with time-sortable keys
+    /// (such as ULIDs), this returns the most recently created first.
+    pub fn newest_first(self) -> Self {
+        self.order_by_name(Direction::Descending)
+    }
+
+    /// Order results by the given field. May be called more than once to order by several
+    /// fields; results are ordered by the first field, then by the second, and so on.
+    ///
+    /// If no ordering is given, results are ordered by key.
+    pub fn order_by(mut self, field: &str, direction: Direction) -> Self {
+        self.order_by.push(Order {
+            field: Some(FieldReference {
+                field_path: field.to_string(),
+            }),
+            direction: direction.to_proto(),
+        });
+        self
+    }
+
+    /// Skip the first `offset` matching documents, in the query's order.
+    ///
+    /// Firestore still reads the skipped documents, and bills them as reads, so paging
+    /// through a large result set with increasing offsets is expensive; filtering from the
+    /// last result returned (e.g. with [Query::where_name]) is usually better. An offset is
+    /// occasionally the simplest tool, for example to jump a short distance ahead.
+    pub fn offset(self, offset: u32) -> Self {
+        Query { offset, ..self }
+    }
+
+    /// Return at most `limit` matching documents.
+    pub fn limit(self, limit: u32) -> Self {
+        Query {
+            limit: Some(limit),
+            ..self
+        }
+    }
+
+    /// Only return results after the given position, which must come from a query with the
+    /// same filters and ordering (such as the `next_cursor` of a [CountedPage]).
+    ///
+    /// Unlike [Query::offset], the skipped documents are not read, so this is the cheap way to
+    /// page through a large result set.
+    ///
+    /// Cursors are positions in the query's ordering, so a query with cursors should be
+    /// ordered explicitly with [Query::order_by], by the fields whose values the cursors
+    /// give. Only the last of `start_at` and `start_after` applies.
+    pub fn start_after(self, cursor: QueryCursor) -> Self {
+        Query {
+            start_at: Some(cursor.to_proto(false)),
+            ..self
+        }
+    }
+
+    /// Only return results at or after the given position, which like one passed to
+    /// [Query::start_after] must match the query's ordering.
+    pub fn start_at(self, cursor: QueryCursor) -> Self {
+        Query {
+            start_at: Some(cursor.to_proto(true)),
+            ..self
+        }
+    }
+
+    /// Only return results at or before the given position, which like one passed to
+    /// [Query::start_after] must match the query's ordering. Only the last of `end_at` and
+    /// `end_before` applies.
+    pub fn end_at(self, cursor: QueryCursor) -> Self {
+        Query {
+            end_at: Some(cursor.to_proto(false)),
+            ..self
+        }
+    }
+
+    /// Only return results before the given position, which like one passed to
+    /// [Query::start_after] must match the query's ordering.
+    pub fn end_before(self, cursor: QueryCursor) -> Self {
+        Query {
+            end_at: Some(cursor.to_proto(true)),
+            ..self
+        }
+    }
+
+    pub(crate) fn with_all_descendants(self) -> Self {
+        Query {
+            all_descendants: true,
+            ..self
+        }
+    }
+
+    /// Use the given policy to retry requests that fail with a transient error. Queries made
+    /// with [crate::Collection::query] use the collection's policy.
+    pub fn with_retry_policy(self, retry: RetryPolicy) -> Self {
+        Query { retry, ..self }
+    }
+
+    pub(crate) fn with_cache(self, cache: Option<Arc<DocumentCache<T>>>) -> Self {
+        Query { cache, ..self }
+    }
+
+    pub(crate) fn with_count_cache(self, cache: CountCache, clock: Arc<dyn Clock>) -> Self {
+        Query {
+            count_cache: Some((cache, clock)),
+            ..self
+        }
+    }
+
+    /// Returns the number of documents matching the query.
+    ///
+    /// Firestore's aggregation API is not part of the protocol version this crate is built
+    /// on, so documents are counted on the client: the query is run returning only the name
+    /// of each document. Every matching document is billed as a read, and counting a large
+    /// result set is slow; see [Query::count_cached] for counts that are polled frequently.
+    pub async fn count(mut self) -> anyhow::Result<i64> {
+        if let Some(error) = self.error.take() {
+            return Err(error);
+        }
+
+        self.count_matching(self.structured_query()).await
+    }
+
+    /// Returns one page of up to `page_size` documents matching the query, along with the
+    /// total number of documents matching it and a cursor from which the next page starts.
+    ///
+    /// This makes two requests concurrently: one for the page, and one which counts matching
+    /// documents as [Query::count] does, so every matching document is billed as a read. They
+    /// are not made in a transaction, so if documents are written in between, the total may
+    /// not agree with the page (e.g. a document may appear on the page but not in the total).
+    ///
+    /// The total ignores the query's offset, limit and starting position (see
+    /// [Query::start_after]), so it stays the same from page to page.
+    pub async fn list_with_count(mut self, page_size: u32) -> anyhow::Result<CountedPage<T>> {
+        if let Some(error) = self.error.take() {
+            return Err(error);
+        }
+        if page_size == 0 || page_size >= i32::MAX as u32 {
+            return Err(anyhow::anyhow!("Invalid page size {}.", page_size));
+        }
+
+        let mut count_query = self.structured_query();
+        count_query.offset = 0;
+        count_query.limit = None;
+        count_query.start_at = None;
+
+        // One extra document is fetched to tell whether there is another page.
+        let mut page_query = self.structured_query();
+        let fetch = self
+            .limit
+            .map_or(page_size + 1, |limit| limit.min(page_size + 1));
+        page_query.limit = Some(fetch as i32);
+
+        let (total, documents) = tokio::join!(
+            self.count_matching(count_query),
+            self.fetch_documents(page_query)
+        );
+        let total = total?;
+        let mut documents = documents?;
+
+        let next_cursor = if documents.len() > page_size as usize {
+            documents.truncate(page_size as usize);
+            let last = documents.last().expect("Expected a page of documents.");
+            Some(self.cursor_after(last)?)
+        } else {
+            None
+        };
+        let page = documents
+            .into_iter()
+            .map(Self::to_named_document)
+            .collect::<anyhow::Result<_>>()?;
+
+        Ok(CountedPage {
+            total,
+            page,
+            next_cursor,
+        })
+    }
+
+    /// Returns the number of documents matched by the given query.
+    async fn count_matching(&self, query: StructuredQuery) -> anyhow::Result<i64> {
+        let mut stream = self.run_names_only(query).await?;
+
+        let mut count = 0;
+        while let Some(response) = stream
+            .message()
+            .await
+            .map_err(|status| MissingIndexError::from_status(status, self.shape()))?
+        {
+            if response.document.is_some() {
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    /// Delete every document matching the query, returning the number deleted.
+    ///
+    /// The query is run returning only the name of each document, and the documents are
+    /// deleted in batches as their names arrive. This is not atomic across the whole set:
+    /// if an error occurs part-way through, the batches already committed stay deleted, and
+    /// documents written while the deletion runs may or may not be deleted. Deleted documents
+    /// are evicted from the collection's cache (see [crate::Collection::with_cache]).
+    pub async fn delete_matching(mut self) -> anyhow::Result<usize> {
+        if let Some(error) = self.error.take() {
+            return Err(error);
+        }
+
+        let mut stream = self.run_names_only(self.structured_query()).await?;
+
+        let mut deleted = 0;
+        let mut batch = Vec::with_capacity(MAX_WRITES_PER_COMMIT);
+        loop {
+            let response = stream
+                .message()
+                .await
+                .map_err(|status| MissingIndexError::from_status(status, self.shape()))?;
+            let done = response.is_none();
+            if let Some(document) = response.and_then(|response| response.document) {
+                batch.push(document.name);
+            }
+
+            if batch.len() == MAX_WRITES_PER_COMMIT || (done && !batch.is_empty()) {
+                deleted += batch.len();
+                self.delete_batch(std::mem::take(&mut batch)).await?;
+            }
+            if done {
+                return Ok(deleted);
+            }
+        }
+    }
+
+    /// Delete the documents with the given names in a single commit, evicting them from the
+    /// collection's cache.
+    async fn delete_batch(&self, names: Vec<String>) -> anyhow::Result<()> {
+        if let Some(cache) = &self.cache {
+            for name in &names {
+                cache.remove(&DocumentName::parse(name)?);
+            }
+        }
+
+        let request = &CommitRequest {
+            database: self.collection.database_name(),
+            writes: names
+                .into_iter()
+                .map(|name| Write {
+                    operation: Some(Operation::Delete(name)),
+                    ..Write::default()
+                })
+                .collect(),
+            ..CommitRequest::default()
+        };
+        // Unconditional deletes have the same effect however many times they are applied.
+        let db = &self.db;
+        self.retry
+            .run(Idempotency::Idempotent, || async move {
+                db.clone().commit(request.clone()).await
+            })
+            .await?;
+        Ok(())
+    }
+
+    /// Run the given query, returning every document it matches.
+    async fn fetch_documents(&self, query: StructuredQuery) -> anyhow::Result<Vec<Document>> {
+        let mut stream = self.run(query).await?;
+
+        let mut documents = Vec::new();
+        while let Some(response) = stream
+            .message()
+            .await
+            .map_err(|status| MissingIndexError::from_status(status, self.shape()))?
+        {
+            documents.extend(response.document);
+        }
+        Ok(documents)
+    }
+
+    /// Run the given query, returning only the name of each matching document.
+    async fn run_names_only(
+        &self,
+        mut query: StructuredQuery,
+    ) -> anyhow::Result<Streaming<RunQueryResponse>> {
+        query.select = Some(Projection {
+            fields: vec![FieldReference {
+                field_path: NAME_FIELD.to_string(),
+            }],
+        });
+        self.run(query).await
+    }
+
+    async fn run(&self, query: StructuredQuery) -> anyhow::Result<Streaming<RunQueryResponse>> {
+        let request = &RunQueryRequest {
+            parent: self.collection.parent().name(),
+            query_type: Some(QueryType::StructuredQuery(query)),
+            ..RunQueryRequest::default()
+        };
+
+        let db = &self.db;
+        let result = self
+            .retry
+            .run(Idempotency::Idempotent, || async move {
+                db.clone().run_query(request.clone()).await
+            })
+            .await;
+        Ok(result
+            .map_err(|status| MissingIndexError::from_status(status, self.shape()))?
+            .into_inner())
+    }
+
+    /// Returns the position just after the given result of this query.
+    fn cursor_after(&self, document: &Document) -> anyhow::Result<QueryCursor> {
+        let name = Value {
+            value_type: Some(ValueType::ReferenceValue(document.name.clone())),
+        };
+
+        let mut values = Vec::new();
+        for field in self.ordered_fields() {
+            if field == NAME_FIELD {
+                values.push(name);
+                return Ok(QueryCursor { values });
+            }
+
+            let value = field_value(&document.fields, &field).ok_or_else(|| {
+                anyhow::anyhow!("Expected {} to have field {}.", document.name, field)
+            })?;
+            values.push(value.clone());
+        }
+
+        // Results with equal values are ordered by name.
+        values.push(name);
+        Ok(QueryCursor { values })
+    }
+
+    /// Returns the fields results are ordered by on the server, before their names: those
+    /// passed to [Query::order_by], followed by any fields with range filters which are not
+    /// among them, in lexicographic order, as Firestore orders by them implicitly.
+    fn ordered_fields(&self) -> Vec<String> {
+        let mut fields: Vec<String> = self
+            .order_by
+            .iter()
+            .map(|order| {
+                let field = order.field.as_ref().map(|f| f.field_path.as_str());
+                field.unwrap_or_default().to_string()
+            })
+            .collect();
+
+        let mut implicit = Vec::new();
+        for filter in &self.filters {
+            inequality_fields(filter, &mut implicit);
+        }
+        implicit.sort();
+        implicit.dedup();
+        implicit.retain(|field| !fields.contains(field));
+        fields.extend(implicit);
+        fields
+    }
+
+    /// Returns the number of documents matching the query, reusing the result of an identical
+    /// query made through the same collection within the last `ttl`.
+    ///
+    /// The count may therefore be up to `ttl` out of date, which suits dashboards that poll a
+    /// count frequently. Each distinct query counted keeps an entry in the collection's
+    /// cache. Queries not obtained from [crate::Collection::query] are never cached.
+    pub async fn count_cached(self, ttl: Duration) -> anyhow::Result<i64> {
+        let (cache, clock) = match &self.count_cache {
+            Some((cache, clock)) if self.error.is_none() => (cache.clone(), clock.clone()),
+            _ => return self.count().await,
+        };
+
+        let key = self.structured_query().encode_to_vec();
+        let now = clock.now();
+        if let Some((counted_at, count)) = cache.lock().unwrap().get(&key) {
+            let fresh = now.duration_since(*counted_at).is_ok_and(|age| age < ttl);
+            if fresh {
+                return Ok(*count);
+            }
+        }
+
+        let count = self.count().await?;
+        cache.lock().unwrap().insert(key, (now, count));
+        Ok(count)
+    }
+
+    /// Record an error to be returned when the query is run, keeping the first one.
+    fn with_error(mut self, error: anyhow::Error) -> Self {
+        self.error.get_or_insert(error);
+        self
+    }
+
+    /// Build the `StructuredQuery` that will be sent to the server.
+    fn structured_query(&self) -> StructuredQuery {
+        let filter = match self.filters.len() {
+            0 => None,
+            1 => Some(self.filters[0].clone()),
+            _ => Some(Filter {
+                filter_type: Some(FilterType::CompositeFilter(CompositeFilter {
+                    op: composite_filter::Operator::And as i32,
+                    filters: self.filters.clone(),
+                })),
+            }),
+        };
+
+        StructuredQuery {
+            from: vec![CollectionSelector {
+                collection_id: self.collection.leaf_name(),
+                all_descendants: self.all_descendants,
+            }],
+            r#where: filter,
+            order_by: self.order_by.clone(),
+            offset: self.offset as i32,
+            limit: self.limit.map(|limit| limit.min(i32::MAX as u32) as i32),
+            start_at: self.start_at.clone(),
+            end_at: self.end_at.clone(),
+            ..StructuredQuery::default()
+        }
+    }
+
+    /// Describe the fields this query filters and orders on, for error messages
+    /// (e.g. `a ==, b >, b asc`).
+    fn shape(&self) -> String {
+        let mut parts = Vec::new();
+        for filter in &self.filters {
+            describe_filter(filter, &mut parts);
+        }
+        for order in &self.order_by {
+            let field = order.field.as_ref().map(|f| f.field_path.as_str());
+            let direction = if order.direction == Direction::Descending.to_proto() {
+                "desc"
+            } else {
+                "asc"
+            };
+            parts.push(format!("{} {}", field.unwrap_or_default(), direction));
+        }
+        parts.join(", ")
+    }
+
+    fn to_named_document(
+        document: firestore_serde::firestore::Document,
+    ) -> anyhow::Result<NamedDocument<T>> {
+        let name = DocumentName::parse(&document.name)?;
+        Ok(NamedDocument::from_proto(name, document)?)
+    }
+}
+
+/// Returns the value at the given dot-separated path in `fields`, if there is one.
+fn field_value<'a>(fields: &'a HashMap<String, Value>, path: &str) -> Option<&'a Value> {
+    let (field, rest) = match path.split_once('.') {
+        Some((field, rest)) => (field, Some(rest)),
+        None => (path, None),
+    };
+
+    let value = fields.get(field)?;
+    match (rest, &value.value_type) {
+        (None, _) => Some(value),
+        (Some(rest), Some(ValueType::MapValue(map))) => field_value(&map.fields, rest),
+        (Some(_), _) => None,
+    }
+}
+
+/// Construct the value Firestore uses to refer to a document, e.g. when comparing against
+/// the `__name__` field.
+fn reference(name: &DocumentName) -> Value {
+    Value {
+        value_type: Some(ValueType::ReferenceValue(name.name())),
+    }
+}
+
+/// Append a description of each field comparison in `filter` to `parts`.
+fn describe_filter(filter: &Filter, parts: &mut Vec<String>) {
+    match &filter.filter_type {
+        Some(FilterType::CompositeFilter(composite)) => {
+            for filter in &composite.filters {
+                describe_filter(filter, parts);
+            }
+        }
+        Some(FilterType::FieldFilter(filter)) => {
+            use field_filter::Operator;
+
+            let op = match Operator::from_i32(filter.op) {
+                Some(Operator::LessThan) => "<",
+                Some(Operator::LessThanOrEqual) => "<=",
+                Some(Operator::GreaterThan) => ">",
+                Some(Operator::GreaterThanOrEqual) => ">=",
+                Some(Operator::Equal) => "==",
+                Some(Operator::NotEqual) => "!=",
+                Some(Operator::ArrayContains) => "array-contains",
+                Some(Operator::In) => "in",
+                Some(Operator::ArrayContainsAny) => "array-contains-any",
+                Some(Operator::NotIn) => "not-in",
+                Some(Operator::Unspecified) | None => "?",
+            };
+            let field = filter.field.as_ref().map(|f| f.field_path.as_str());
+            parts.push(format!("{} {}", field.unwrap_or_default(), op));
+        }
+        _ => {}
+    }
+}
+
+/// Append the path of each field compared in `filter` with a range (inequality) operator to
+/// `fields`.
+fn inequality_fields(filter: &Filter, fields: &mut Vec<String>) {
+    match &filter.filter_type {
+        Some(FilterType::CompositeFilter(composite)) => {
+            for filter in &composite.filters {
+                inequality_fields(filter, fields);
+            }
+        }
+        Some(FilterType::FieldFilter(filter)) => {
+            use field_filter::Operator;
+
+            let inequality = matches!(
+                Operator::from_i32(filter.op),
+                Some(Operator::LessThan)
+                    | Some(Operator::LessThanOrEqual)
+                    | Some(Operator::GreaterThan)
+                    | Some(Operator::GreaterThanOrEqual)
+                    | Some(Operator::NotEqual)
+                    | Some(Operator::NotIn)
+            );
+            if let (true, Some(field)) = (inequality, &filter.field) {
+                fields.push(field.field_path.clone());
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Construct a filter comparing a field against a value.
+fn field_comparison(field: &str, op: field_filter::Operator, value: Value) -> Filter {
+    Filter {
+        filter_type: Some(FilterType::FieldFilter(FieldFilter {
+            field: Some(FieldReference {
+                field_path: field.to_string(),
+            }),
+            op: op as i32,
+            value: Some(value),
+        })),
+    }
+}
+
+impl<T> Stream for Query<T>
+where
+    T: Serialize + DeserializeOwned + Unpin + 'static,
+{
+    type Item = anyhow::Result<NamedDocument<T>>;
+
+    fn poll_next(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Option<Self::Item>> {
+        let self_mut = self.get_mut();
+
+        // Loop because some actions cause a state change that allow us to make progress.
+        loop {
+            match &mut self_mut.state {
+                QueryState::Pending => {
+                    if let Some(error) = self_mut.error.take() {
+                        self_mut.state = QueryState::Done;
+                        return Poll::Ready(Some(Err(error)));
+                    }
+
+                    let request = RunQueryRequest {
+                        parent: self_mut.collection.parent().name(),
+                        query_type: Some(QueryType::StructuredQuery(self_mut.structured_query())),
+                        ..RunQueryRequest::default()
+                    };
+                    let db = self_mut.db.clone();
+                    let retry = self_mut.retry.clone();
+
+                    // Only opening the stream is retried; a failure once documents have been
+                    // returned ends it.
+                    self_mut.state = QueryState::Connecting(Box::pin(async move {
+                        let response = retry
+                            .run(Idempotency::Idempotent, || {
+                                let mut db = db.clone();
+                                let request = request.clone();
+                                async move { db.run_query(request).await }
+                            })
+                            .await?;
+                        Ok(response.into_inner())
+                    }));
+                }
+                QueryState::Connecting(fut) => match fut.as_mut().poll(cx) {
+                    Poll::Pending => return Poll::Pending,
+                    Poll::Ready(Ok(stream)) => self_mut.state = QueryState::Streaming(stream),
+                    Poll::Ready(Err(status)) => {
+                        self_mut.state = QueryState::Done;
+                        let shape = self_mut.shape();
+                        return Poll::Ready(Some(Err(MissingIndexError::from_status(
+                            status, shape,
+                        ))));
+                    }
+                },
+                QueryState::Streaming(stream) => match Pin::new(stream).poll_next(cx) {
+                    Poll::Pending => return Poll::Pending,
+                    Poll::Ready(Some(Ok(response))) => {
+                        // Some responses only report progress and carry no document.
+                        if let Some(document) = response.document {
+                            return Poll::Ready(Some(Self::to_named_document(document)));
+                        }
+                    }
+                    Poll::Ready(Some(Err(status))) => {
+                        self_mut.state = QueryState::Done;
+                        let shape = self_mut.shape();
+                        return Poll::Ready(Some(Err(MissingIndexError::from_status(
+                            status, shape,
+                        ))));
+                    }
+                    Poll::Ready(None) => {
+                        self_mut.state = QueryState::Done;
+                        return Poll::Ready(None);
+                    }
+                },
+                QueryState::Done => return Poll::Ready(None),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::dynamic_firestore_client::test_client;
+    use crate::{FirestoreTimestamp, MockClock, Timestamp};
+    use tokio_stream::StreamExt;
+
+    fn reference(name: &str) -> Value {
+        Value {
+            value_type: Some(ValueType::ReferenceValue(name.to_string())),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_empty_query() {
+        let collection = CollectionName::new("my-project", "things");
+        let query: Query<()> = Query::new(collection, test_client());
+
+        assert_eq!(
+            StructuredQuery {
+                from: vec![CollectionSelector {
+                    collection_id: "things".to_string(),
+                    all_descendants: false,
+                }],
+                ..StructuredQuery::default()
+            },
+            query.structured_query()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_where_id_in() {
+        let collection = CollectionName::new("my-project", "things");
+        let query: Query<()> = Query::new(collection.clone(), test_client())
+            .where_id_in(vec!["a", "b"])
+            .order_by("size", Direction::Descending);
+
+        let structured_query = query.structured_query();
+
+        assert_eq!(
+            Some(field_comparison(
+                "__name__",
+                field_filter::Operator::In,
+                Value {
+                    value_type: Some(ValueType::ArrayValue(ArrayValue {
+                        values: vec![
+                            reference("projects/my-project/databases/(default)/documents/things/a"),
+                            reference("projects/my-project/databases/(default)/documents/things/b"),
+                        ]
+                    }))
+                }
+            )),
+            structured_query.r#where
+        );
+        assert_eq!(
+            vec![Order {
+                field: Some(FieldReference {
+                    field_path: "size".to_string()
+                }),
+                direction: Direction::Descending.to_proto(),
+            }],
+            structured_query.order_by
+        );
+    }
+
+    #[tokio::test]
+    async fn test_where_name() {
+        let collection = CollectionName::new("my-project", "things");
+        let query: Query<()> = Query::new(collection.clone(), test_client())
+            .where_name(FieldOp::GreaterThan, "b")
+            .order_by_name(Direction::Ascending);
+
+        let structured_query = query.structured_query();
+        assert_eq!(
+            Some(field_comparison(
+                "__name__",
+                field_filter::Operator::GreaterThan,
+                reference("projects/my-project/databases/(default)/documents/things/b"),
+            )),
+            structured_query.r#where
+        );
+        assert_eq!(
+            Some("__name__".to_string()),
+            structured_query.order_by[0]
+                .field
+                .as_ref()
+                .map(|field| field.field_path.clone())
+        );
+
+        let query: Query<()> = Query::new(collection, test_client()).newest_first();
+        assert_eq!("__name__ desc", query.shape());
+    }
+
+    #[tokio::test]
+    async fn test_where_timestamp() {
+        let collection = CollectionName::new("my-project", "things");
+        let timestamp = Timestamp {
+            seconds: 1_600_000_000,
+            nanos: 0,
+        };
+        let query: Query<()> = Query::new(collection, test_client())
+            .where_gt("created", FirestoreTimestamp(timestamp.clone()));
+
+        assert_eq!(
+            Some(field_comparison(
+                "created",
+                field_filter::Operator::GreaterThan,
+                Value {
+                    value_type: Some(ValueType::TimestampValue(timestamp)),
+                },
+            )),
+            query.structured_query().r#where
+        );
+    }
+
+    #[tokio::test]
+    async fn test_shape() {
+        let collection = CollectionName::new("my-project", "things");
+        let query: Query<()> = Query::new(collection, test_client())
+            .where_eq("a", 1)
+            .where_gt("b", 2)
+            .order_by("b", Direction::Ascending)
+            .order_by("c", Direction::Descending);
+
+        assert_eq!("a ==, b >, b asc, c desc", query.shape());
+    }
+
+    #[tokio::test]
+    async fn test_count_cached() {
+        let collection = CollectionName::new("my-project", "things");
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000);
+        let clock = MockClock::new(start);
+        let cache = CountCache::default();
+        let query = || {
+            Query::<()>::new(collection.clone(), test_client())
+                .where_eq("a", 1)
+                .with_count_cache(cache.clone(), Arc::new(clock.clone()))
+        };
+
+        cache
+            .lock()
+            .unwrap()
+            .insert(query().structured_query().encode_to_vec(), (start, 7));
+
+        // Served from the cache, without contacting the (unconnected) server.
+        clock.advance(Duration::from_secs(9));
+        assert_eq!(
+            7,
+            query().count_cached(Duration::from_secs(10)).await.unwrap()
+        );
+
+        // A different query is not.
+        let other = Query::<()>::new(collection.clone(), test_client())
+            .where_eq("a", 2)
+            .with_count_cache(cache.clone(), Arc::new(clock.clone()));
+        assert!(other.count_cached(Duration::from_secs(10)).await.is_err());
+
+        // Once the entry expires, the query is run again.
+        clock.advance(Duration::from_secs(1));
+        assert!(query().count_cached(Duration::from_secs(10)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_offset() {
+        let collection = CollectionName::new("my-project", "things");
+        let query: Query<()> = Query::new(collection.clone(), test_client());
+        assert_eq!(0, query.structured_query().offset);
+
+        let query: Query<()> = Query::new(collection, test_client())
+            .order_by("size", Direction::Ascending)
+            .offset(20);
+        assert_eq!(20, query.structured_query().offset);
+    }
+
+    #[tokio::test]
+    async fn test_where_id_in_combines_filters() {
+        let collection = CollectionName::new("my-project", "things");
+        let query: Query<()> = Query::new(collection, test_client())
+            .where_id_in(vec!["a"])
+            .where_id_in(vec!["b"]);
+
+        match query.structured_query().r#where.unwrap().filter_type {
+            Some(FilterType::CompositeFilter(CompositeFilter { op, filters })) => {
+                assert_eq!(composite_filter::Operator::And as i32, op);
+                assert_eq!(2, filters.len());
+            }
+            _ => panic!("Expected a composite filter."),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_range_filters_on_multiple_fields() {
+        let collection = CollectionName::new("my-project", "things");
+        let query: Query<()> = Query::new(collection, test_client())
+            .where_gt("a", 1)
+            .where_lt("b", "five");
+
+        let integer = |v| Value {
+            value_type: Some(ValueType::IntegerValue(v)),
+        };
+        let string = |v: &str| Value {
+            value_type: Some(ValueType::StringValue(v.to_string())),
+        };
+
+        assert_eq!(
+            Some(Filter {
+                filter_type: Some(FilterType::CompositeFilter(CompositeFilter {
+                    op: composite_filter::Operator::And as i32,
+                    filters: vec![
+                        field_comparison("a", field_filter::Operator::GreaterThan, integer(1)),
+                        field_comparison("b", field_filter::Operator::LessThan, string("five")),
+                    ],
+                })),
+            }),
+            query.structured_query().r#where
+        );
+    }
+
+    #[tokio::test]
+    async fn test_filter() {
+        let collection = CollectionName::new("my-project", "things");
+        let query = |op| Query::<()>::new(collection.clone(), test_client()).filter("a", op, 1);
+
+        assert_eq!(
+            Query::<()>::new(collection.clone(), test_client())
+                .where_lte("a", 1)
+                .structured_query(),
+            query(FieldOp::LessThanOrEqual).structured_query()
+        );
+        assert_eq!(
+            Query::<()>::new(collection.clone(), test_client())
+                .where_ne("a", 1)
+                .structured_query(),
+            query(FieldOp::NotEqual).structured_query()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_collection_group() {
+        let collection = CollectionName::new("my-project", "devices");
+        let query: Query<()> = Query::new(collection, test_client())
+            .with_all_descendants()
+            .where_eq("active", true)
+            .order_by("last_seen", Direction::Descending)
+            .limit(10);
+        let query = query.structured_query();
+
+        assert_eq!(
+            vec![CollectionSelector {
+                collection_id: "devices".to_string(),
+                all_descendants: true,
+            }],
+            query.from
+        );
+        assert_eq!(Some(10), query.limit);
+        assert_eq!(1, query.order_by.len());
+    }
+
+    #[tokio::test]
+    async fn test_where_id_in_too_many_keys() {
+        let collection = CollectionName::new("my-project", "things");
+        let keys: Vec<String> = (0..31).map(|i| i.to_string()).collect();
+        let mut query: Query<()> =
+            Query::new(collection, test_client()).where_id_in(keys.iter().map(String::as_str));
+
+        // The error is reported without contacting the server.
+        assert!(query.next().await.unwrap().is_err());
+        assert!(query.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_cursor_after() {
+        let collection = CollectionName::new("my-project", "things");
+        let document = Document {
+            name: "projects/my-project/databases/(default)/documents/things/a".to_string(),
+            fields: firestore_serde::to_document(&HashMap::from([(
+                "size",
+                HashMap::from([("width", 3)]),
+            )]))
+            .unwrap()
+            .fields,
+            ..Document::default()
+        };
+        let integer = |v| Value {
+            value_type: Some(ValueType::IntegerValue(v)),
+        };
+        let name = reference("projects/my-project/databases/(default)/documents/things/a");
+
+        // Results are ordered by name after the fields of the query's ordering.
+        let query: Query<()> = Query::new(collection.clone(), test_client())
+            .order_by("size.width", Direction::Descending);
+        let cursor = query.cursor_after(&document).unwrap();
+        assert_eq!(vec![integer(3), name.clone()], cursor.values);
+
+        let query: Query<()> = Query::new(collection.clone(), test_client()).newest_first();
+        assert_eq!(
+            vec![name.clone()],
+            query.cursor_after(&document).unwrap().values
+        );
+
+        let query: Query<()> =
+            Query::new(collection.clone(), test_client()).order_by("colour", Direction::Ascending);
+        assert!(query.cursor_after(&document).is_err());
+
+        // Fields with range filters are ordered by implicitly, after the explicit ordering.
+        let query: Query<()> = Query::new(collection.clone(), test_client())
+            .where_gt("size.width", 1)
+            .where_lt("size.width", 5);
+        assert_eq!(
+            vec![integer(3), name.clone()],
+            query.cursor_after(&document).unwrap().values
+        );
+        let query: Query<()> = Query::new(collection.clone(), test_client())
+            .where_ne("size.width", 1)
+            .where_gt("colour", "red")
+            .order_by("size.width", Direction::Descending);
+        assert_eq!(
+            vec!["size.width".to_string(), "colour".to_string()],
+            query.ordered_fields()
+        );
+
+        let query: Query<()> = Query::new(collection, test_client()).start_after(cursor.clone());
+        assert_eq!(
+            Some(Cursor {
+                values: cursor.values,
+                before: false
+            }),
+            query.structured_query().start_at
+        );
+    }
+
+    #[tokio::test]
+    async fn test_cursor_from_values() {
+        let collection = CollectionName::new("my-project", "things");
+        let cursor = QueryCursor::from_values(("Paris", 3)).unwrap();
+        assert_eq!(
+            vec![
+                Value {
+                    value_type: Some(ValueType::StringValue("Paris".to_string()))
+                },
+                Value {
+                    value_type: Some(ValueType::IntegerValue(3))
+                }
+            ],
+            cursor.values
+        );
+        assert!(QueryCursor::from_values("Paris").is_err());
+
+        let query: Query<()> = Query::new(collection, test_client())
+            .order_by("city", Direction::Ascending)
+            .order_by("rank", Direction::Ascending)
+            .start_at(cursor.clone())
+            .end_before(QueryCursor::from_values(["Rome"]).unwrap());
+        let structured_query = query.structured_query();
+        assert_eq!(Some(cursor.to_proto(true)), structured_query.start_at);
+        assert_eq!(
+            Some(Cursor {
+                values: vec![Value {
+                    value_type: Some(ValueType::StringValue("Rome".to_string()))
+                }],
+                before: true
+            }),
+            structured_query.end_at
+        );
+    }
+
+    #[tokio::test]
+    async fn test_list_with_count_page_size() {
+        let collection = CollectionName::new("my-project", "things");
+        let query: Query<()> = Query::new(collection, test_client());
+
+        // The error is reported without contacting the server.
+        assert!(query.list_with_count(0).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_delete_matching_invalid_query() {
+        let collection = CollectionName::new("my-project", "things");
+        let query: Query<()> =
+            Query::new(collection, test_client()).where_id_in(Vec::<&str>::new());
+
+        // Nothing is deleted when the query cannot be run.
+        let error = query.delete_matching().await.unwrap_err();
+        assert!(error.to_string().contains("where_id_in requires"));
+    }
+}
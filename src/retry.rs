@@ -0,0 +1,475 @@
+use crate::auth::TokenRefresher;
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::future::Future;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tonic::{Code, Status};
+
+/// Whether an operation can safely be repeated after an ambiguous failure. See [RetryPolicy]
+/// for how operations are classified.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Idempotency {
+    Idempotent,
+    NotIdempotent,
+}
+
+/// A token bucket limiting the rate of retries across every request that shares it.
+///
+/// Without a budget, every failing request is retried up to its policy's maximum number of
+/// attempts, so during an outage retries multiply the load on the server. With a budget, each
+/// retry withdraws a token; once the bucket is empty, failed requests are returned
+/// immediately instead of being retried, until tokens are refilled over time.
+///
+/// Clones of a budget share the same bucket.
+#[derive(Clone, Debug)]
+pub struct RetryBudget {
+    state: Arc<Mutex<BudgetState>>,
+    capacity: f64,
+    refill_per_second: f64,
+}
+
+#[derive(Debug)]
+struct BudgetState {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl RetryBudget {
+    /// Construct a full budget which allows bursts of up to `capacity` retries, and refills
+    /// at `refill_per_second` retries per second.
+    pub fn new(capacity: u32, refill_per_second: f64) -> Self {
+        RetryBudget {
+            state: Arc::new(Mutex::new(BudgetState {
+                tokens: capacity as f64,
+                last_refill: Instant::now(),
+            })),
+            capacity: capacity as f64,
+            refill_per_second,
+        }
+    }
+
+    /// Take a token from the bucket if one is available, returning `true` if the caller may
+    /// retry.
+    fn try_withdraw(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+
+        let now = Instant::now();
+        let elapsed = now.duration_since(state.last_refill).as_secs_f64();
+        state.tokens = (state.tokens + elapsed * self.refill_per_second).min(self.capacity);
+        state.last_refill = now;
+
+        if state.tokens >= 1. {
+            state.tokens -= 1.;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Decides whether a request that failed with a given status should be retried, in place of
+/// the default classification. Set with [RetryPolicy::with_retry_on].
+#[derive(Clone)]
+struct RetryPredicate(Arc<dyn Fn(&Status) -> bool + Send + Sync>);
+
+impl fmt::Debug for RetryPredicate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("RetryPredicate")
+    }
+}
+
+/// Configuration for automatically retrying requests that fail with a transient error.
+///
+/// By default, only idempotent operations (see below) are retried. Requests that fail with
+/// `UNAVAILABLE`, `ABORTED` or `DEADLINE_EXCEEDED` are retried, waiting `base_delay` before
+/// the first retry and doubling the delay before each subsequent one. Each wait is reduced by
+/// a random amount of up to half, so that clients failing together do not all retry at the
+/// same moment. All other errors are returned immediately. Which errors are retried can be
+/// changed with [RetryPolicy::with_retry_on].
+///
+/// When a request fails with a transient error, it is not always possible to tell whether the
+/// server applied it first, so operations are classified by whether repeating them is safe:
+///
+/// - Idempotent: `get`, `get_many`, `upsert`, `update`, `update_fields`, `delete`,
+///   `delete_recursive`, `apply` (and `apply_with_results` and `apply_or_queue`) without an
+///   increment or a create, `array_union`, `array_remove`, and `apply_transforms` without an
+///   increment. A retried `update`, `update_fields`, `delete` or `apply` may report a
+///   precondition failure (e.g. "not found") caused by its own first attempt having
+///   succeeded.
+/// - Not idempotent: `create` (which assigns a random key, so may create a duplicate if
+///   retried) and increments, including `increment`, `increment_double`, `increment_many`,
+///   and `apply` and `apply_transforms` with an increment (which may be applied twice).
+///   Also writes whose precondition a retry would fail against the first attempt, reporting
+///   a write that happened as rejected: `create_with_key` (and `create_with_key_returning`
+///   and `create_with_transforms`), `try_create`, `update_if_unchanged`, `update_diff`,
+///   `apply` with an [crate::Op::CreateIfAbsent], and [crate::WriteBatch::commit].
+///   These are only retried if `with_force_retry(true)` is set.
+///
+/// Queries (including `delete_matching`'s) are idempotent: a failure to open a query's stream
+/// is retried, but a failure once documents have been returned ends the stream. Listing
+/// (`list`) is not retried.
+///
+/// Independently of the policy, a request which fails with `UNAUTHENTICATED` is retried once
+/// with a fresh access token, unless disabled with
+/// [crate::client::ClientOptions::with_refresh_on_unauthenticated].
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    max_attempts: u32,
+    base_delay: Duration,
+    jitter: bool,
+    force_retry: bool,
+    budget: Option<RetryBudget>,
+    /// Overrides which errors are retried, if set.
+    retry_on: Option<RetryPredicate>,
+    /// Used to fetch a fresh access token when a request is unauthenticated, if set.
+    token_refresher: Option<TokenRefresher>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 5,
+            base_delay: Duration::from_millis(100),
+            jitter: true,
+            force_retry: false,
+            budget: None,
+            retry_on: None,
+            token_refresher: None,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// A policy which never retries.
+    pub fn never() -> Self {
+        RetryPolicy {
+            max_attempts: 1,
+            ..RetryPolicy::default()
+        }
+    }
+
+    /// Set the maximum number of times a request is attempted, including the first attempt.
+    pub fn with_max_attempts(self, max_attempts: u32) -> Self {
+        Self {
+            max_attempts,
+            ..self
+        }
+    }
+
+    /// Set the delay before the first retry. The delay doubles before each subsequent retry.
+    pub fn with_base_delay(self, base_delay: Duration) -> Self {
+        Self { base_delay, ..self }
+    }
+
+    /// If `false`, wait exactly the backoff delay before each retry, rather than a random
+    /// duration between half of it and all of it. Jitter is enabled by default.
+    pub fn with_jitter(self, jitter: bool) -> Self {
+        Self { jitter, ..self }
+    }
+
+    /// If `true`, operations that are not idempotent are retried too.
+    pub fn with_force_retry(self, force_retry: bool) -> Self {
+        Self {
+            force_retry,
+            ..self
+        }
+    }
+
+    /// Only retry while the given budget has tokens available. Clones of this policy (such as
+    /// those passed from a [crate::Database] to its collections) share the budget.
+    pub fn with_budget(self, budget: RetryBudget) -> Self {
+        Self {
+            budget: Some(budget),
+            ..self
+        }
+    }
+
+    /// Retry requests that fail with a status for which `predicate` returns `true`, instead of
+    /// those failing with `UNAVAILABLE`, `ABORTED` or `DEADLINE_EXCEEDED`. The predicate
+    /// replaces the default classification entirely, so should return `true` for those codes
+    /// too if they are still to be retried.
+    ///
+    /// Whether an operation may be retried at all still depends on its idempotency, and
+    /// `UNAUTHENTICATED` errors are handled separately (see [RetryPolicy]).
+    pub fn with_retry_on<F>(self, predicate: F) -> Self
+    where
+        F: Fn(&Status) -> bool + Send + Sync + 'static,
+    {
+        Self {
+            retry_on: Some(RetryPredicate(Arc::new(predicate))),
+            ..self
+        }
+    }
+
+    pub(crate) fn with_token_refresher(self, token_refresher: Option<TokenRefresher>) -> Self {
+        Self {
+            token_refresher,
+            ..self
+        }
+    }
+
+    /// Keep the token refresher of `previous`, if this policy does not have one, so that
+    /// replacing a collection's policy does not stop it refreshing tokens.
+    pub(crate) fn inherit_token_refresher(self, previous: &RetryPolicy) -> Self {
+        let token_refresher = self
+            .token_refresher
+            .or_else(|| previous.token_refresher.clone());
+        Self {
+            token_refresher,
+            ..self
+        }
+    }
+
+    /// Returns `true` if a request that failed with the given status should be retried.
+    fn is_retryable(&self, status: &Status) -> bool {
+        match &self.retry_on {
+            Some(RetryPredicate(predicate)) => predicate(status),
+            None => matches!(
+                status.code(),
+                Code::Unavailable | Code::Aborted | Code::DeadlineExceeded
+            ),
+        }
+    }
+
+    /// Run `request`, retrying it according to this policy.
+    pub(crate) async fn run<F, Fut, R>(
+        &self,
+        idempotency: Idempotency,
+        mut request: F,
+    ) -> Result<R, Status>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<R, Status>>,
+    {
+        let max_attempts = if idempotency == Idempotency::Idempotent || self.force_retry {
+            self.max_attempts.max(1)
+        } else {
+            1
+        };
+
+        let mut delay = self.base_delay;
+        let mut attempt = 1;
+        let mut refreshed = false;
+        loop {
+            match request().await {
+                // The request was rejected before being applied, so it is safe to retry
+                // regardless of idempotency; this does not count as an attempt.
+                Err(status) if status.code() == Code::Unauthenticated && !refreshed => {
+                    match &self.token_refresher {
+                        Some(token_refresher) => token_refresher.invalidate(),
+                        None => return Err(status),
+                    }
+                    refreshed = true;
+                }
+                Err(status)
+                    if attempt < max_attempts
+                        && self.is_retryable(&status)
+                        && self.budget.as_ref().is_none_or(RetryBudget::try_withdraw) =>
+                {
+                    let wait = if self.jitter { jittered(delay) } else { delay };
+                    tokio::time::sleep(wait).await;
+                    delay *= 2;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+/// Returns a random duration between half of `delay` and all of it.
+fn jittered(delay: Duration) -> Duration {
+    let fraction = RandomState::new().build_hasher().finish() as f64 / u64::MAX as f64;
+    delay.mul_f64(1.0 - fraction / 2.0)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    fn fast_policy() -> RetryPolicy {
+        RetryPolicy::default().with_base_delay(Duration::from_millis(1))
+    }
+
+    /// Run a request that fails with `code` the first `failures` times, returning the result
+    /// and the number of attempts made.
+    async fn run_failing(
+        policy: &RetryPolicy,
+        idempotency: Idempotency,
+        code: Code,
+        failures: u32,
+    ) -> (Result<u32, Status>, u32) {
+        let attempts = AtomicU32::new(0);
+        let attempts = &attempts;
+        let result = policy
+            .run(idempotency, || async move {
+                let attempt = attempts.fetch_add(1, Ordering::SeqCst) + 1;
+                if attempt <= failures {
+                    Err(Status::new(code, "failed"))
+                } else {
+                    Ok(attempt)
+                }
+            })
+            .await;
+
+        (result, attempts.load(Ordering::SeqCst))
+    }
+
+    #[tokio::test]
+    async fn test_retries_transient_errors() {
+        let (result, attempts) = run_failing(
+            &fast_policy(),
+            Idempotency::Idempotent,
+            Code::Unavailable,
+            2,
+        )
+        .await;
+
+        assert_eq!(3, result.unwrap());
+        assert_eq!(3, attempts);
+    }
+
+    #[tokio::test]
+    async fn test_gives_up_after_max_attempts() {
+        let policy = fast_policy().with_max_attempts(3);
+        let (result, attempts) =
+            run_failing(&policy, Idempotency::Idempotent, Code::Aborted, 10).await;
+
+        assert_eq!(Code::Aborted, result.unwrap_err().code());
+        assert_eq!(3, attempts);
+    }
+
+    #[tokio::test]
+    async fn test_does_not_retry_permanent_errors() {
+        let (result, attempts) =
+            run_failing(&fast_policy(), Idempotency::Idempotent, Code::NotFound, 1).await;
+
+        assert_eq!(Code::NotFound, result.unwrap_err().code());
+        assert_eq!(1, attempts);
+    }
+
+    #[tokio::test]
+    async fn test_budget_limits_retries() {
+        let policy = fast_policy().with_budget(RetryBudget::new(2, 0.));
+
+        let (result, attempts) =
+            run_failing(&policy, Idempotency::Idempotent, Code::Unavailable, 10).await;
+        assert!(result.is_err());
+        assert_eq!(3, attempts);
+
+        // The budget is shared with clones, and is now exhausted.
+        let (result, attempts) = run_failing(
+            &policy.clone(),
+            Idempotency::Idempotent,
+            Code::Unavailable,
+            1,
+        )
+        .await;
+        assert!(result.is_err());
+        assert_eq!(1, attempts);
+    }
+
+    #[tokio::test]
+    async fn test_retry_on_overrides_default_classification() {
+        let policy = fast_policy().with_retry_on(|status| status.code() == Code::Internal);
+
+        let (result, attempts) =
+            run_failing(&policy, Idempotency::Idempotent, Code::Internal, 2).await;
+        assert_eq!(3, result.unwrap());
+        assert_eq!(3, attempts);
+
+        // Codes retried by default are not, unless the predicate accepts them.
+        let (result, attempts) =
+            run_failing(&policy, Idempotency::Idempotent, Code::Unavailable, 1).await;
+        assert_eq!(Code::Unavailable, result.unwrap_err().code());
+        assert_eq!(1, attempts);
+
+        // Non-idempotent operations are still not retried.
+        let (result, attempts) =
+            run_failing(&policy, Idempotency::NotIdempotent, Code::Internal, 1).await;
+        assert_eq!(Code::Internal, result.unwrap_err().code());
+        assert_eq!(1, attempts);
+    }
+
+    #[test]
+    fn test_budget_refills() {
+        let budget = RetryBudget::new(1, 1000.);
+        assert!(budget.try_withdraw());
+        std::thread::sleep(Duration::from_millis(10));
+        assert!(budget.try_withdraw());
+
+        let budget = RetryBudget::new(1, 0.);
+        assert!(budget.try_withdraw());
+        assert!(!budget.try_withdraw());
+    }
+
+    #[test]
+    fn test_jittered() {
+        let delay = Duration::from_millis(100);
+        for _ in 0..100 {
+            let wait = jittered(delay);
+            assert!(wait >= delay / 2 && wait <= delay);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_does_not_retry_non_idempotent_by_default() {
+        let (result, attempts) = run_failing(
+            &fast_policy(),
+            Idempotency::NotIdempotent,
+            Code::Unavailable,
+            1,
+        )
+        .await;
+
+        assert_eq!(Code::Unavailable, result.unwrap_err().code());
+        assert_eq!(1, attempts);
+
+        let policy = fast_policy().with_force_retry(true);
+        let (result, attempts) =
+            run_failing(&policy, Idempotency::NotIdempotent, Code::Unavailable, 1).await;
+
+        assert_eq!(2, result.unwrap());
+        assert_eq!(2, attempts);
+    }
+
+    #[tokio::test]
+    async fn test_refreshes_token_once_when_unauthenticated() {
+        let (result, attempts) = run_failing(
+            &RetryPolicy::never(),
+            Idempotency::NotIdempotent,
+            Code::Unauthenticated,
+            1,
+        )
+        .await;
+        assert_eq!(Code::Unauthenticated, result.unwrap_err().code());
+        assert_eq!(1, attempts);
+
+        // Retried once with a fresh token, even if the policy would not otherwise retry.
+        let refresher = TokenRefresher::test_refresher();
+        let policy = RetryPolicy::never().with_token_refresher(Some(refresher.clone()));
+        let (result, attempts) = run_failing(
+            &policy,
+            Idempotency::NotIdempotent,
+            Code::Unauthenticated,
+            1,
+        )
+        .await;
+        assert_eq!(2, result.unwrap());
+        assert_eq!(2, attempts);
+        assert!(!refresher.has_token());
+
+        let (result, attempts) = run_failing(
+            &policy,
+            Idempotency::NotIdempotent,
+            Code::Unauthenticated,
+            2,
+        )
+        .await;
+        assert_eq!(Code::Unauthenticated, result.unwrap_err().code());
+        assert_eq!(2, attempts);
+    }
+}
@@ -0,0 +1,324 @@
+use http::header::HeaderName;
+use http::{HeaderMap, HeaderValue, Request, Response};
+use hyper::body::{Bytes, HttpBody, SizeHint};
+use hyper::Body;
+use opentelemetry::global::{self, BoxedSpan};
+use opentelemetry::propagation::Injector;
+use opentelemetry::trace::{Span, SpanKind, StatusCode, TraceContextExt, Tracer};
+use opentelemetry::{Context, KeyValue};
+use std::convert::TryFrom;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::Poll;
+use tonic::body::BoxBody;
+use tonic::{Code, Status};
+
+/// Name of the tracer spans are created with.
+const TRACER_NAME: &str = "tiny-firestore-odm";
+
+/// Future of a traced response. Boxed because it awaits the untraced one.
+pub type TracedFuture =
+    Pin<Box<dyn Future<Output = Result<Response<TracedBody>, tonic::transport::Error>> + Send>>;
+
+/// The span of an RPC, shared between its request body, which records the database it is
+/// made to, and its response, which ends it. `None` once the span has ended.
+type SharedSpan = Arc<Mutex<Option<BoxedSpan>>>;
+
+/// Start a client span for the RPC made by `request`, as a child of the current context, and
+/// propagate it to Firestore through the request's headers.
+pub fn start_span(mut request: Request<BoxBody>) -> (Request<BoxBody>, SharedSpan) {
+    // The path of a gRPC request is `/{package}.{service}/{method}`.
+    let path = request.uri().path().trim_start_matches('/').to_string();
+    let (service, method) = path.split_once('/').unwrap_or((path.as_str(), ""));
+    let attributes = vec![
+        KeyValue::new("rpc.system", "grpc"),
+        KeyValue::new("rpc.service", service.to_string()),
+        KeyValue::new("rpc.method", method.to_string()),
+        KeyValue::new("db.system", "firestore"),
+    ];
+
+    let tracer = global::tracer(TRACER_NAME);
+    let builder = tracer
+        .span_builder(path.clone())
+        .with_kind(SpanKind::Client)
+        .with_attributes(attributes)
+        .with_parent_context(Context::current());
+    let span = tracer.build(builder);
+
+    let context = Context::current().with_remote_span_context(span.span_context().clone());
+    global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(&context, &mut HeaderInjector(request.headers_mut()))
+    });
+
+    let span = Arc::new(Mutex::new(Some(span)));
+    let request = request.map(|body| {
+        DatabaseNameBody {
+            body,
+            span: Some(span.clone()),
+        }
+        .boxed()
+    });
+    (request, span)
+}
+
+/// Await the response to a request traced by `span`, ending the span once the response's
+/// status is known.
+pub async fn trace_response<F>(
+    response: F,
+    span: SharedSpan,
+) -> Result<Response<TracedBody>, tonic::transport::Error>
+where
+    F: Future<Output = Result<Response<Body>, tonic::transport::Error>>,
+{
+    match response.await {
+        Ok(response) => {
+            // Errors are usually reported without a body, in the headers; otherwise the
+            // status is in the trailers, and the span ends when they arrive.
+            if grpc_code(response.headers()).is_some() {
+                end_span(&span, Some(response.headers()));
+            }
+            Ok(response.map(|body| TracedBody { body, span }))
+        }
+        Err(error) => {
+            fail_span(&span, error.to_string());
+            Err(error)
+        }
+    }
+}
+
+/// Record the gRPC status in `headers` (if there is one) on `span`, and end it, unless it
+/// has already ended.
+fn end_span(span: &SharedSpan, headers: Option<&HeaderMap>) {
+    if let Some(mut span) = span.lock().unwrap().take() {
+        if let Some(code) = headers.and_then(grpc_code) {
+            span.set_attribute(KeyValue::new("rpc.grpc.status_code", code as i64));
+            if code != Code::Ok {
+                let message = headers
+                    .and_then(|headers| headers.get("grpc-message"))
+                    .and_then(|message| message.to_str().ok())
+                    .unwrap_or_else(|| code.description());
+                span.set_status(StatusCode::Error, message.to_string());
+            }
+        }
+        span.end();
+    }
+}
+
+/// Mark `span` as failed with the given message and end it, unless it has already ended.
+fn fail_span(span: &SharedSpan, message: String) {
+    if let Some(mut span) = span.lock().unwrap().take() {
+        span.set_status(StatusCode::Error, message);
+        span.end();
+    }
+}
+
+/// Returns the gRPC status code in the given headers or trailers, if there is one.
+fn grpc_code(headers: &HeaderMap) -> Option<Code> {
+    let code = headers.get("grpc-status")?.to_str().ok()?.parse().ok()?;
+    Some(Code::from_i32(code))
+}
+
+/// Returns the ID of the database a Firestore request is made to, given the first frame of
+/// its body.
+///
+/// The first field of every Firestore request message is the name of the database or of a
+/// resource in it, except for `UpdateDocument`'s, which is the document, whose own first
+/// field is its name. Fields are encoded in order, so the name starts the message.
+fn database_id(frame: &[u8]) -> Option<String> {
+    // A gRPC frame is a compression flag and a four-byte length, followed by the message.
+    if frame.first() != Some(&0) {
+        return None;
+    }
+    let mut message = frame.get(5..)?;
+
+    for _ in 0..2 {
+        let field = first_field(message)?;
+        if let Some(name) = field.strip_prefix(b"projects/") {
+            let mut parts = std::str::from_utf8(name).ok()?.split('/');
+            parts.next()?;
+            if parts.next()? != "databases" {
+                return None;
+            }
+            return parts.next().map(str::to_string);
+        }
+        message = field;
+    }
+    None
+}
+
+/// Returns the contents of field 1 of an encoded message, if it is length-delimited (a
+/// string or a message) and is the first field.
+fn first_field(message: &[u8]) -> Option<&[u8]> {
+    // The key of field 1 with the length-delimited wire type.
+    let mut rest = message.strip_prefix(&[0x0a])?;
+    let length = prost::encoding::decode_varint(&mut rest).ok()?;
+    rest.get(..usize::try_from(length).ok()?)
+}
+
+/// Writes propagated context into the headers of a request.
+struct HeaderInjector<'a>(&'a mut HeaderMap);
+
+impl<'a> Injector for HeaderInjector<'a> {
+    fn set(&mut self, key: &str, value: String) {
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(key.as_bytes()),
+            HeaderValue::from_str(&value),
+        ) {
+            self.0.insert(name, value);
+        }
+    }
+}
+
+/// A request body which records the database its RPC is made to on the RPC's span, as the
+/// first frame is sent.
+struct DatabaseNameBody {
+    body: BoxBody,
+
+    /// The span of the RPC, until the first frame is sent.
+    span: Option<SharedSpan>,
+}
+
+impl HttpBody for DatabaseNameBody {
+    type Data = Bytes;
+
+    type Error = Status;
+
+    fn poll_data(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        let this = self.get_mut();
+        let result = Pin::new(&mut this.body).poll_data(cx);
+        if let Poll::Ready(Some(Ok(frame))) = &result {
+            if let (Some(span), Some(database_id)) = (this.span.take(), database_id(frame)) {
+                if let Some(span) = span.lock().unwrap().as_mut() {
+                    span.set_attribute(KeyValue::new("db.name", database_id));
+                }
+            }
+        }
+        result
+    }
+
+    fn poll_trailers(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Result<Option<HeaderMap>, Self::Error>> {
+        Pin::new(&mut self.get_mut().body).poll_trailers(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.body.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.body.size_hint()
+    }
+}
+
+/// A response body which ends the span of its RPC when its trailers are received, or when it
+/// is dropped before then.
+pub struct TracedBody {
+    body: Body,
+    span: SharedSpan,
+}
+
+impl HttpBody for TracedBody {
+    type Data = Bytes;
+
+    type Error = hyper::Error;
+
+    fn poll_data(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        let this = self.get_mut();
+        let result = Pin::new(&mut this.body).poll_data(cx);
+        if let Poll::Ready(Some(Err(error))) = &result {
+            fail_span(&this.span, error.to_string());
+        }
+        result
+    }
+
+    fn poll_trailers(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Result<Option<HeaderMap>, Self::Error>> {
+        let this = self.get_mut();
+        let result = Pin::new(&mut this.body).poll_trailers(cx);
+        match &result {
+            Poll::Ready(Ok(trailers)) => end_span(&this.span, trailers.as_ref()),
+            Poll::Ready(Err(error)) => fail_span(&this.span, error.to_string()),
+            Poll::Pending => {}
+        }
+        result
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.body.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.body.size_hint()
+    }
+}
+
+impl Drop for TracedBody {
+    fn drop(&mut self) {
+        end_span(&self.span, None);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use firestore_serde::firestore::{Document, GetDocumentRequest, UpdateDocumentRequest};
+    use prost::Message;
+
+    /// Encode `message` as an uncompressed gRPC frame.
+    fn frame(message: impl Message) -> Vec<u8> {
+        let message = message.encode_to_vec();
+        let mut frame = vec![0];
+        frame.extend_from_slice(&(message.len() as u32).to_be_bytes());
+        frame.extend(message);
+        frame
+    }
+
+    #[test]
+    fn test_database_id() {
+        let name = "projects/my-project/databases/my-db/documents/people/john";
+        let get = GetDocumentRequest {
+            name: name.to_string(),
+            ..GetDocumentRequest::default()
+        };
+        assert_eq!(Some("my-db".to_string()), database_id(&frame(get)));
+
+        let update = UpdateDocumentRequest {
+            document: Some(Document {
+                name: name.to_string(),
+                ..Document::default()
+            }),
+            ..UpdateDocumentRequest::default()
+        };
+        assert_eq!(Some("my-db".to_string()), database_id(&frame(update)));
+
+        assert_eq!(None, database_id(&frame(GetDocumentRequest::default())));
+        assert_eq!(None, database_id(&[0, 0]));
+    }
+
+    #[test]
+    fn test_grpc_code() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(None, grpc_code(&headers));
+
+        headers.insert("grpc-status", HeaderValue::from_static("5"));
+        assert_eq!(Some(Code::NotFound), grpc_code(&headers));
+    }
+
+    #[test]
+    fn test_inject_headers() {
+        let mut headers = HeaderMap::new();
+        HeaderInjector(&mut headers).set("traceparent", "00-abc-def-01".to_string());
+        assert_eq!("00-abc-def-01", headers.get("traceparent").unwrap());
+    }
+}
@@ -0,0 +1,107 @@
+use crate::Timestamp;
+use prost::Message;
+use serde::de::{Deserialize, Deserializer, Error, Visitor};
+use serde::ser::{Serialize, Serializer};
+use std::fmt;
+use std::time::SystemTime;
+
+/// A [Timestamp] that `firestore_serde` stores as a Firestore timestamp, rather than as a map
+/// of seconds and nanoseconds.
+///
+/// Use it for timestamp fields of documents, and as the value of query filters on them (e.g.
+/// `where_gt("created", FirestoreTimestamp::from(time))`): a filter compares values of the
+/// same type only, so comparing a timestamp field against any other representation silently
+/// matches nothing.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FirestoreTimestamp(pub Timestamp);
+
+impl From<Timestamp> for FirestoreTimestamp {
+    fn from(timestamp: Timestamp) -> Self {
+        FirestoreTimestamp(timestamp)
+    }
+}
+
+impl From<SystemTime> for FirestoreTimestamp {
+    fn from(time: SystemTime) -> Self {
+        FirestoreTimestamp(time.into())
+    }
+}
+
+impl From<FirestoreTimestamp> for Timestamp {
+    fn from(timestamp: FirestoreTimestamp) -> Self {
+        timestamp.0
+    }
+}
+
+/// The encoded timestamp, which `firestore_serde` expects as bytes.
+struct EncodedTimestamp(Vec<u8>);
+
+impl Serialize for EncodedTimestamp {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(&self.0)
+    }
+}
+
+impl Serialize for FirestoreTimestamp {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        // `firestore_serde` recognizes this newtype name and emits a `timestamp_value`.
+        serializer.serialize_newtype_struct(
+            firestore_serde::DATE_MAGIC,
+            &EncodedTimestamp(self.0.encode_to_vec()),
+        )
+    }
+}
+
+struct TimestampVisitor;
+
+impl<'de> Visitor<'de> for TimestampVisitor {
+    type Value = FirestoreTimestamp;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a timestamp")
+    }
+
+    fn visit_newtype_struct<D: Deserializer<'de>>(
+        self,
+        deserializer: D,
+    ) -> Result<Self::Value, D::Error> {
+        deserializer.deserialize_byte_buf(self)
+    }
+
+    fn visit_bytes<E: Error>(self, bytes: &[u8]) -> Result<Self::Value, E> {
+        Timestamp::decode(bytes)
+            .map(FirestoreTimestamp)
+            .map_err(E::custom)
+    }
+}
+
+impl<'de> Deserialize<'de> for FirestoreTimestamp {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_newtype_struct(firestore_serde::DATE_MAGIC, TimestampVisitor)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use firestore_serde::firestore::{value::ValueType, Value};
+
+    #[test]
+    fn test_timestamp_value() {
+        let timestamp = FirestoreTimestamp(Timestamp {
+            seconds: 1_600_000_000,
+            nanos: 500,
+        });
+
+        let value = firestore_serde::to_grpc_value(&timestamp).unwrap();
+        assert_eq!(
+            Value {
+                value_type: Some(ValueType::TimestampValue(timestamp.0.clone()))
+            },
+            value
+        );
+
+        let round_tripped: FirestoreTimestamp = firestore_serde::from_grpc_value(&value).unwrap();
+        assert_eq!(timestamp, round_tripped);
+    }
+}
@@ -0,0 +1,406 @@
+use crate::dynamic_firestore_client::SharedFirestoreClient;
+use crate::error::{from_document, grpc_status, to_document};
+use crate::identifiers::{DocumentName, QualifyDocumentName};
+use crate::{Collection, WriteResult};
+use firestore_serde::firestore::{
+    get_document_request::ConsistencySelector, precondition::ConditionType, transaction_options,
+    write::Operation, BeginTransactionRequest, CommitRequest, Document, GetDocumentRequest,
+    Precondition, RollbackRequest, TransactionOptions, Write,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use tonic::Code;
+
+/// The number of times [crate::Database::transaction] attempts a transaction before returning
+/// the error of the last attempt.
+const MAX_TRANSACTION_ATTEMPTS: u32 = 5;
+
+/// Writes buffered by a transaction, and the overlay they form over the server's documents.
+#[derive(Default)]
+struct TransactionState {
+    /// Writes to send when the transaction is committed, in the order they were made.
+    writes: Vec<Write>,
+
+    /// The document each written name will have after the transaction commits, keyed by
+    /// fully-qualified name, or `None` if it will have been deleted.
+    overlay: HashMap<String, Option<Document>>,
+}
+
+/// A Firestore transaction, started with [crate::Database::begin_transaction], or run with
+/// [crate::Database::transaction].
+///
+/// Reads are made on the server within the transaction, so the transaction fails to commit
+/// if any document it read has changed since. Writes are buffered locally and sent in a
+/// single commit, so they are applied atomically or not at all.
+///
+/// Firestore transactions do not see their own uncommitted writes, so the transaction keeps
+/// an overlay of them: reading a document that was written earlier in the same transaction
+/// returns the written value (or `None`, if it was deleted) without contacting the server.
+///
+/// Firestore requires every read from the server to happen before the first write, so
+/// reading a document that has not been written after any write has been buffered returns
+/// an error.
+///
+/// Clones of a transaction are handles to the same transaction, sharing its buffered writes.
+#[derive(Clone)]
+pub struct Transaction {
+    db: SharedFirestoreClient,
+    database: String,
+    id: Vec<u8>,
+    state: Arc<Mutex<TransactionState>>,
+}
+
+impl Transaction {
+    /// Begin a transaction. If `retry_transaction` is not empty, it is the ID of an aborted
+    /// transaction which this one retries, which keeps its place in the queue for locks.
+    pub(crate) async fn begin(
+        db: SharedFirestoreClient,
+        database: String,
+        retry_transaction: Vec<u8>,
+    ) -> anyhow::Result<Self> {
+        let options = if retry_transaction.is_empty() {
+            None
+        } else {
+            Some(TransactionOptions {
+                mode: Some(transaction_options::Mode::ReadWrite(
+                    transaction_options::ReadWrite { retry_transaction },
+                )),
+            })
+        };
+
+        let id = db
+            .clone()
+            .begin_transaction(BeginTransactionRequest {
+                database: database.clone(),
+                options,
+            })
+            .await?
+            .into_inner()
+            .transaction;
+
+        Ok(Transaction {
+            db,
+            database,
+            id,
+            state: Arc::default(),
+        })
+    }
+
+    /// Get the document with the given key in the given collection, or `None` if it does not
+    /// exist.
+    ///
+    /// If the document was written earlier in this transaction, the written value is returned.
+    pub async fn get<T>(
+        &self,
+        collection: &Collection<T>,
+        key: impl QualifyDocumentName,
+    ) -> anyhow::Result<Option<T>>
+    where
+        T: Serialize + DeserializeOwned + Unpin,
+    {
+        let name = key.qualify(&collection.name())?.name();
+        match self.get_document(name).await? {
+            Some(document) => Ok(Some(from_document(document)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Get the document with the given fully-qualified name without deserializing it, or
+    /// `None` if it does not exist, as `get` does.
+    pub(crate) async fn get_document(&self, name: String) -> anyhow::Result<Option<Document>> {
+        {
+            let state = self.state.lock().unwrap();
+            if let Some(document) = state.overlay.get(&name) {
+                return Ok(document.clone());
+            }
+            if !state.writes.is_empty() {
+                return Err(anyhow::anyhow!(
+                    "Transactions must read {} before making any writes.",
+                    name
+                ));
+            }
+        }
+
+        let result = self
+            .db
+            .clone()
+            .get_document(GetDocumentRequest {
+                name,
+                consistency_selector: Some(ConsistencySelector::Transaction(self.id.clone())),
+                ..GetDocumentRequest::default()
+            })
+            .await;
+
+        match result {
+            Ok(document) => Ok(Some(document.into_inner())),
+            Err(e) if e.code() == Code::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Create a document with the given key in the given collection when the transaction is
+    /// committed. The commit fails if the document already exists.
+    pub fn create<T>(
+        &self,
+        collection: &Collection<T>,
+        key: impl QualifyDocumentName,
+        ob: &T,
+    ) -> anyhow::Result<()>
+    where
+        T: Serialize + DeserializeOwned + Unpin,
+    {
+        let name = key.qualify(&collection.name())?;
+        self.write(name, Some(ob), Some(false))
+    }
+
+    /// Overwrite a document with the given key in the given collection when the transaction
+    /// is committed, creating it if it does not exist.
+    pub fn upsert<T>(
+        &self,
+        collection: &Collection<T>,
+        key: impl QualifyDocumentName,
+        ob: &T,
+    ) -> anyhow::Result<()>
+    where
+        T: Serialize + DeserializeOwned + Unpin,
+    {
+        let name = key.qualify(&collection.name())?;
+        self.write(name, Some(ob), None)
+    }
+
+    /// Overwrite a document with the given key in the given collection when the transaction
+    /// is committed. The commit fails if the document does not exist.
+    pub fn update<T>(
+        &self,
+        collection: &Collection<T>,
+        key: impl QualifyDocumentName,
+        ob: &T,
+    ) -> anyhow::Result<()>
+    where
+        T: Serialize + DeserializeOwned + Unpin,
+    {
+        let name = key.qualify(&collection.name())?;
+        self.write(name, Some(ob), Some(true))
+    }
+
+    /// Delete a document with the given key in the given collection when the transaction is
+    /// committed. The commit fails if the document does not exist.
+    pub fn delete<T>(
+        &self,
+        collection: &Collection<T>,
+        key: impl QualifyDocumentName,
+    ) -> anyhow::Result<()>
+    where
+        T: Serialize + DeserializeOwned + Unpin,
+    {
+        let name = key.qualify(&collection.name())?;
+        self.write::<T>(name, None, Some(true))
+    }
+
+    /// Buffer a write of `ob` (or a delete, if `None`) to the given document, with an
+    /// optional existence precondition.
+    fn write<T>(
+        &self,
+        name: DocumentName,
+        ob: Option<&T>,
+        exists: Option<bool>,
+    ) -> anyhow::Result<()>
+    where
+        T: Serialize,
+    {
+        let name = name.name();
+        let document = match ob {
+            Some(ob) => {
+                let mut document = to_document(ob)?;
+                document.name = name.clone();
+                Some(document)
+            }
+            None => None,
+        };
+        self.write_document(name, document, exists);
+        Ok(())
+    }
+
+    /// Buffer a write of `document` (or a delete, if `None`) to the document with the given
+    /// fully-qualified name, with an optional existence precondition.
+    pub(crate) fn write_document(
+        &self,
+        name: String,
+        document: Option<Document>,
+        exists: Option<bool>,
+    ) {
+        let operation = match &document {
+            Some(document) => Operation::Update(document.clone()),
+            None => Operation::Delete(name.clone()),
+        };
+
+        let mut state = self.state.lock().unwrap();
+        state.writes.push(Write {
+            operation: Some(operation),
+            current_document: exists.map(|exists| Precondition {
+                condition_type: Some(ConditionType::Exists(exists)),
+            }),
+            ..Write::default()
+        });
+        state.overlay.insert(name, document);
+    }
+
+    /// Commit the transaction, applying every buffered write atomically. Returns the time at
+    /// which the writes took effect.
+    ///
+    /// Fails with an `ABORTED` status if a document read in the transaction was changed by
+    /// someone else before the commit; in that case, the whole transaction should be retried.
+    pub async fn commit(self) -> anyhow::Result<WriteResult> {
+        let writes = std::mem::take(&mut self.state.lock().unwrap().writes);
+
+        let result = self
+            .db
+            .clone()
+            .commit(CommitRequest {
+                database: self.database,
+                writes,
+                transaction: self.id,
+            })
+            .await?
+            .into_inner();
+
+        WriteResult::new(result.commit_time)
+    }
+
+    /// Abandon the transaction, discarding every buffered write and releasing any locks it
+    /// holds on the server.
+    pub async fn rollback(self) -> anyhow::Result<()> {
+        self.db
+            .clone()
+            .rollback(RollbackRequest {
+                database: self.database,
+                transaction: self.id,
+            })
+            .await?;
+
+        Ok(())
+    }
+
+    /// Run `body` in a transaction and commit its writes, retrying the whole transaction if
+    /// it is aborted. See [crate::Database::transaction].
+    pub(crate) async fn run<F, Fut, R>(
+        db: SharedFirestoreClient,
+        database: String,
+        mut body: F,
+    ) -> anyhow::Result<R>
+    where
+        F: FnMut(Transaction) -> Fut,
+        Fut: Future<Output = anyhow::Result<R>>,
+    {
+        let mut retry_transaction = Vec::new();
+        let mut attempt = 1;
+        loop {
+            let transaction =
+                Transaction::begin(db.clone(), database.clone(), retry_transaction).await?;
+            retry_transaction = transaction.id.clone();
+
+            let result = match body(transaction.clone()).await {
+                Ok(value) => transaction.commit().await.map(|_| value),
+                Err(error) => {
+                    // The error of the body is more useful than any from rolling back.
+                    let _ = transaction.rollback().await;
+                    Err(error)
+                }
+            };
+
+            match result {
+                Err(error) if attempt < MAX_TRANSACTION_ATTEMPTS && is_aborted(&error) => {
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+/// Returns `true` if `error` was caused by the transaction being aborted, e.g. because a
+/// document it read was changed by someone else.
+fn is_aborted(error: &anyhow::Error) -> bool {
+    grpc_status(error).map(|status| status.code()) == Some(Code::Aborted)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::dynamic_firestore_client::test_client;
+    use crate::CollectionName;
+    use serde::Deserialize;
+    use tonic::Status;
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Account {
+        balance: i64,
+    }
+
+    fn transaction() -> Transaction {
+        Transaction {
+            db: test_client(),
+            database: "projects/my-project/databases/(default)".to_string(),
+            id: vec![1, 2, 3],
+            state: Arc::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_read_your_writes() {
+        let accounts: Collection<Account> =
+            Collection::new(test_client(), CollectionName::new("my-project", "accounts"));
+        let transaction = transaction();
+
+        transaction
+            .upsert(&accounts, "a", &Account { balance: 10 })
+            .unwrap();
+        transaction.delete(&accounts, "b").unwrap();
+
+        // Served from the overlay, without contacting the server.
+        assert_eq!(
+            Some(Account { balance: 10 }),
+            transaction.get(&accounts, "a").await.unwrap()
+        );
+        assert_eq!(None, transaction.get(&accounts, "b").await.unwrap());
+
+        // The latest write wins.
+        transaction
+            .update(&accounts, "a", &Account { balance: 20 })
+            .unwrap();
+        assert_eq!(
+            Some(Account { balance: 20 }),
+            transaction.get(&accounts, "a").await.unwrap()
+        );
+
+        let state = transaction.state.lock().unwrap();
+        assert_eq!(3, state.writes.len());
+    }
+
+    #[tokio::test]
+    async fn test_reads_after_writes_are_rejected() {
+        let accounts: Collection<Account> =
+            Collection::new(test_client(), CollectionName::new("my-project", "accounts"));
+        let transaction = transaction();
+
+        transaction
+            .create(&accounts, "a", &Account { balance: 10 })
+            .unwrap();
+
+        assert!(transaction.get(&accounts, "c").await.is_err());
+    }
+
+    #[test]
+    fn test_is_aborted() {
+        let aborted = anyhow::Error::new(Status::new(Code::Aborted, "Contention."));
+        assert!(is_aborted(&aborted));
+        assert!(is_aborted(&aborted.context("Committing.")));
+        assert!(!is_aborted(&anyhow::Error::new(Status::new(
+            Code::NotFound,
+            "Missing."
+        ))));
+        assert!(!is_aborted(&anyhow::anyhow!("Aborted.")));
+    }
+}
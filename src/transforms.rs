@@ -0,0 +1,243 @@
+use crate::dynamic_firestore_client::SharedFirestoreClient;
+use crate::identifiers::DocumentName;
+use crate::retry::{Idempotency, RetryPolicy};
+use firestore_serde::firestore::{
+    document_transform::{
+        field_transform::{ServerValue, TransformType},
+        FieldTransform,
+    },
+    value::ValueType,
+    write::Operation,
+    ArrayValue, CommitRequest, DocumentTransform, Value, Write, WriteResult,
+};
+use prost_types::Timestamp;
+use serde::Serialize;
+
+/// Several field transforms to apply to a document atomically, in a single write, with
+/// [crate::Collection::apply_transforms].
+///
+/// ```
+/// # fn build() -> anyhow::Result<tiny_firestore_odm::Transforms> {
+/// use tiny_firestore_odm::Transforms;
+///
+/// let transforms = Transforms::new()
+///     .increment("visits", 1)
+///     .array_union("visitors", &["ada"])?
+///     .server_timestamp("last_visit");
+/// # Ok(transforms)
+/// # }
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct Transforms {
+    field_transforms: Vec<FieldTransform>,
+}
+
+impl Transforms {
+    pub fn new() -> Self {
+        Transforms::default()
+    }
+
+    /// Add `by` (which may be negative) to the numeric field `field`, treating it as zero if
+    /// it does not exist.
+    pub fn increment(self, field: &str, by: i64) -> Self {
+        self.with(
+            field,
+            TransformType::Increment(Value {
+                value_type: Some(ValueType::IntegerValue(by)),
+            }),
+        )
+    }
+
+    /// Add `by` (which may be negative) to the numeric field `field`, treating it as zero if
+    /// it does not exist. The result is a double, even if the field held an integer.
+    pub fn increment_double(self, field: &str, by: f64) -> Self {
+        self.with(
+            field,
+            TransformType::Increment(Value {
+                value_type: Some(ValueType::DoubleValue(by)),
+            }),
+        )
+    }
+
+    /// Add each of `elements` to the array field `field`, unless an equal element is already
+    /// present. See [crate::Collection::array_union] for how elements are compared.
+    pub fn array_union<V: Serialize>(self, field: &str, elements: &[V]) -> anyhow::Result<Self> {
+        let elements = to_array_value(elements)?;
+        Ok(self.with(field, TransformType::AppendMissingElements(elements)))
+    }
+
+    /// Remove every element equal to one of `elements` from the array field `field`.
+    pub fn array_remove<V: Serialize>(self, field: &str, elements: &[V]) -> anyhow::Result<Self> {
+        let elements = to_array_value(elements)?;
+        Ok(self.with(field, TransformType::RemoveAllFromArray(elements)))
+    }
+
+    /// Set the field `field` to the time at which the server applies the write.
+    pub fn server_timestamp(self, field: &str) -> Self {
+        self.with(
+            field,
+            TransformType::SetToServerValue(ServerValue::RequestTime as i32),
+        )
+    }
+
+    fn with(mut self, field: &str, transform_type: TransformType) -> Self {
+        self.field_transforms.push(FieldTransform {
+            field_path: field.to_string(),
+            transform_type: Some(transform_type),
+        });
+        self
+    }
+
+    /// Increments are the only transforms which change a field again if applied twice.
+    pub(crate) fn idempotency(&self) -> Idempotency {
+        let increments = self
+            .field_transforms
+            .iter()
+            .any(|transform| matches!(transform.transform_type, Some(TransformType::Increment(_))));
+        if increments {
+            Idempotency::NotIdempotent
+        } else {
+            Idempotency::Idempotent
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.field_transforms.is_empty()
+    }
+
+    pub(crate) fn into_field_transforms(self) -> Vec<FieldTransform> {
+        self.field_transforms
+    }
+}
+
+/// The outcome of applying [Transforms] with [crate::Collection::apply_transforms].
+#[derive(Clone, Debug, PartialEq)]
+pub struct TransformResult {
+    /// The server time at which the write took effect.
+    pub update_time: Timestamp,
+
+    /// The result of each transform, in the order they were added: the new value of the
+    /// field for an increment or server timestamp, and null for array transforms. Values can
+    /// be deserialized with `firestore_serde::from_grpc_value`.
+    pub values: Vec<Value>,
+}
+
+/// Serialize each of `elements` into the array operand of an array transform.
+pub(crate) fn to_array_value<V: Serialize>(elements: &[V]) -> anyhow::Result<ArrayValue> {
+    let values = elements
+        .iter()
+        .map(firestore_serde::to_grpc_value)
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(ArrayValue { values })
+}
+
+/// Atomically add `value` to the numeric field `field` of the given document, returning the
+/// field's new value.
+///
+/// If the document or the field does not exist, the field is treated as zero.
+///
+/// Increments are not idempotent, so are only retried if the policy forces it.
+pub(crate) async fn increment(
+    db: &SharedFirestoreClient,
+    retry: &RetryPolicy,
+    document: &DocumentName,
+    field: &str,
+    value: Value,
+) -> anyhow::Result<Value> {
+    transform(
+        db,
+        retry,
+        Idempotency::NotIdempotent,
+        document,
+        field,
+        TransformType::Increment(value),
+    )
+    .await?
+    .transform_results
+    .into_iter()
+    .next()
+    .ok_or_else(|| anyhow::anyhow!("Expected increment to return the new value."))
+}
+
+/// Atomically apply a single transform to the field `field` of the given document, creating
+/// the document if it does not exist.
+pub(crate) async fn transform(
+    db: &SharedFirestoreClient,
+    retry: &RetryPolicy,
+    idempotency: Idempotency,
+    document: &DocumentName,
+    field: &str,
+    transform_type: TransformType,
+) -> anyhow::Result<WriteResult> {
+    let field_transforms = vec![FieldTransform {
+        field_path: field.to_string(),
+        transform_type: Some(transform_type),
+    }];
+    transform_fields(db, retry, idempotency, document, field_transforms).await
+}
+
+/// Atomically apply the given transforms to the given document in a single write, creating
+/// the document if it does not exist.
+pub(crate) async fn transform_fields(
+    db: &SharedFirestoreClient,
+    retry: &RetryPolicy,
+    idempotency: Idempotency,
+    document: &DocumentName,
+    field_transforms: Vec<FieldTransform>,
+) -> anyhow::Result<WriteResult> {
+    let write = Write {
+        operation: Some(Operation::Transform(DocumentTransform {
+            document: document.name(),
+            field_transforms,
+        })),
+        ..Write::default()
+    };
+
+    let request = &CommitRequest {
+        database: document.collection().database_name(),
+        writes: vec![write],
+        ..CommitRequest::default()
+    };
+    let response = retry
+        .run(idempotency, || async move {
+            db.clone().commit(request.clone()).await
+        })
+        .await?
+        .into_inner();
+
+    response
+        .write_results
+        .into_iter()
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("Expected transform to return a result."))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_transforms_idempotency() {
+        let transforms = Transforms::new()
+            .array_union("tags", &["a"])
+            .unwrap()
+            .server_timestamp("updated");
+        assert_eq!(Idempotency::Idempotent, transforms.idempotency());
+
+        let transforms = transforms.increment("count", 1);
+        assert_eq!(Idempotency::NotIdempotent, transforms.idempotency());
+        assert_eq!(
+            Idempotency::NotIdempotent,
+            Transforms::new()
+                .increment_double("score", 0.5)
+                .idempotency()
+        );
+
+        let fields: Vec<String> = transforms
+            .into_field_transforms()
+            .into_iter()
+            .map(|transform| transform.field_path)
+            .collect();
+        assert_eq!(vec!["tags", "updated", "count"], fields);
+    }
+}
@@ -1,7 +1,15 @@
 use anyhow::Result;
+use firestore_serde::firestore::value::ValueType;
 use google_authz::{Credentials, TokenSource};
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
-use tiny_firestore_odm::{Collection, CollectionName, Database, NamedDocument};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tiny_firestore_odm::{
+    field_paths, ApplyOutcome, CacheConfig, Collection, CollectionName, ConnectionState,
+    Consistency, Database, DatabaseType, Direction, DocumentName, FieldOp, FirestoreError,
+    FirestoreTimestamp, MissingDocuments, MissingIndexError, NamedDocument, OfflineQueue, Op,
+    ParseError, Query, QueryCursor, Timestamp, Transforms, WriteOutcome,
+};
 use tokio_stream::StreamExt;
 use uuid::Uuid;
 
@@ -13,6 +21,13 @@ struct User {
     pub city: Option<String>,
 }
 
+field_paths!(User {
+    name,
+    email,
+    id,
+    city
+});
+
 #[derive(Serialize, Deserialize, PartialEq, Debug, Eq, Hash, Clone)]
 struct Device {
     pub id: String,
@@ -31,7 +46,24 @@ where
     Ok(())
 }
 
+/// Clear the timestamps of a document read from Firestore, so that it can be compared with
+/// one constructed in a test.
+fn without_times<T>(document: NamedDocument<T>) -> NamedDocument<T> {
+    NamedDocument::new(document.name, document.value)
+}
+
 async fn get_source_and_project() -> (TokenSource, String) {
+    // The emulator does not check credentials, so a placeholder stands in for them.
+    if tiny_firestore_odm::client::emulator_host().is_some() {
+        let project_id =
+            std::env::var("GCP_PROJECT_ID").unwrap_or_else(|_| "demo-tiny-firestore-odm".into());
+        let credentials = Credentials::from_json(
+            br#"{"client_id": "", "client_secret": "", "refresh_token": ""}"#,
+            &[],
+        );
+        return (credentials.into(), project_id);
+    }
+
     let project_id = std::env::var("GCP_PROJECT_ID").expect(
         "The GCP_PROJECT_ID environment variable should point to a Google Cloud project ID.",
     );
@@ -41,14 +73,22 @@ async fn get_source_and_project() -> (TokenSource, String) {
     (source, project_id)
 }
 
-#[tokio::test]
-async fn do_test() {
-    let unique_id = Uuid::new_v4().to_string();
-
+/// Returns a database and an empty collection in it with a unique name, for a test to use.
+async fn temp_collection<T>() -> (Database, Collection<T>)
+where
+    T: Serialize + DeserializeOwned + Unpin + 'static,
+{
     let (token_source, project_id) = get_source_and_project().await;
     let db = Database::new(token_source, &project_id).await;
-    let collection_id = format!("tmp-{}", unique_id);
-    let users: Collection<User> = db.collection(&collection_id);
+    let collection = db.collection(&format!("tmp-{}", Uuid::new_v4()));
+    (db, collection)
+}
+
+#[tokio::test]
+async fn do_test() {
+    let (_, users) = temp_collection::<User>().await;
+    let collection_id = users.name().leaf_name();
+    let (_, project_id) = get_source_and_project().await;
 
     assert_eq!(
         CollectionName::parse(&format!(
@@ -80,22 +120,19 @@ async fn do_test() {
 
     {
         // Fetch users and check that results match expectations.
-        let mut users_iter = users.list().with_page_size(1).with_order_by("email");
+        let mut users_iter = users
+            .list()
+            .with_page_size(1)
+            .with_order_by(User::FIELD_EMAIL);
 
         assert_eq!(
-            NamedDocument {
-                name: u2_key.clone(),
-                value: u2.clone(),
-            },
-            users_iter.next().await.unwrap()
+            NamedDocument::new(u2_key.clone(), u2.clone()),
+            without_times(users_iter.next().await.unwrap())
         );
 
         assert_eq!(
-            NamedDocument {
-                name: u1_key.clone(),
-                value: u1.clone(),
-            },
-            users_iter.next().await.unwrap()
+            NamedDocument::new(u1_key.clone(), u1.clone()),
+            without_times(users_iter.next().await.unwrap())
         );
     }
 
@@ -104,29 +141,59 @@ async fn do_test() {
         let mut users_iter = users.list().with_order_by("email desc");
 
         assert_eq!(
-            NamedDocument {
-                name: u1_key.clone(),
-                value: u1.clone(),
-            },
-            users_iter.next().await.unwrap()
+            NamedDocument::new(u1_key.clone(), u1.clone()),
+            without_times(users_iter.next().await.unwrap())
         );
 
         assert_eq!(
-            NamedDocument {
-                name: u2_key.clone(),
-                value: u2.clone(),
-            },
-            users_iter.next().await.unwrap()
+            NamedDocument::new(u2_key.clone(), u2.clone()),
+            without_times(users_iter.next().await.unwrap())
+        );
+    }
+
+    {
+        // Track progress while listing one document per page.
+        let progress = Arc::new(Mutex::new(Vec::new()));
+        let progress_handle = progress.clone();
+        let listed: Vec<NamedDocument<User>> = users
+            .list()
+            .with_page_size(1)
+            .with_order_by(User::FIELD_EMAIL)
+            .on_progress(move |processed, last| {
+                progress_handle
+                    .lock()
+                    .unwrap()
+                    .push((processed, last.clone()))
+            })
+            .collect()
+            .await;
+
+        assert_eq!(2, listed.len());
+        assert_eq!(
+            vec![(1, u2_key.clone()), (2, u1_key.clone())],
+            *progress.lock().unwrap()
         );
     }
 
-    let users_page = users.list().get_page().await;
+    let users_page = users.list().get_page().await.unwrap();
     assert_eq!(2, users_page.len());
 
     // Modify Bob's email
     u1.email = "bob.albert@email".to_string();
 
-    users.update(&u1, &u1_key).await.unwrap();
+    let update_result = users.update(&u1, &u1_key).await.unwrap();
+
+    // Writing again should be reported as happening no earlier than the first write.
+    let upsert_result = users.upsert(&u1, &u1_key).await.unwrap();
+    assert!(
+        (
+            upsert_result.update_time.seconds,
+            upsert_result.update_time.nanos
+        ) >= (
+            update_result.update_time.seconds,
+            update_result.update_time.nanos
+        )
+    );
 
     // Fetch updated Bob
     let u1_updated = users.get(&u1_key).await.unwrap();
@@ -145,3 +212,2266 @@ async fn do_test() {
     // Delete existing documents to create fresh start.
     empty_collection(&users).await.unwrap();
 }
+
+#[tokio::test]
+async fn test_delete_recursive() {
+    let (_, users) = temp_collection::<User>().await;
+
+    let user = User {
+        name: "Carol".to_string(),
+        email: "carol@email".to_string(),
+        id: 5,
+        city: None,
+    };
+    users.create_with_key(&user, "carol").await.unwrap();
+
+    let devices: Collection<Device> = users.subcollection("carol", "devices");
+    devices
+        .create_with_key(
+            &Device {
+                id: "phone".to_string(),
+            },
+            "phone",
+        )
+        .await
+        .unwrap();
+
+    // A document nested under a device, which is itself nested under the user.
+    let apps: Collection<Device> = devices.subcollection("phone", "apps");
+    apps.create(&Device {
+        id: "clock".to_string(),
+    })
+    .await
+    .unwrap();
+
+    // Nested documents are too deep for a depth limit of 1, so nothing should be deleted.
+    assert!(users
+        .delete_recursive_with_max_depth("carol", 1)
+        .await
+        .is_err());
+    assert_eq!(user, users.get("carol").await.unwrap());
+
+    assert_eq!(3, users.delete_recursive("carol").await.unwrap());
+
+    assert!(users.get("carol").await.is_err());
+    assert_eq!(0, devices.list().collect::<Vec<_>>().await.len());
+    assert_eq!(0, apps.list().collect::<Vec<_>>().await.len());
+}
+
+#[tokio::test]
+async fn test_walk_tree() {
+    let (_, users) = temp_collection::<User>().await;
+
+    let user = User {
+        name: "Yolanda".to_string(),
+        email: "yolanda@email".to_string(),
+        id: 16,
+        city: None,
+    };
+    users.create_with_key(&user, "yolanda").await.unwrap();
+
+    let devices: Collection<Device> = users.subcollection("yolanda", "devices");
+    devices
+        .create_with_key(
+            &Device {
+                id: "phone".to_string(),
+            },
+            "phone",
+        )
+        .await
+        .unwrap();
+
+    let names = |tree: Vec<(DocumentName, usize)>| -> Vec<(String, usize)> {
+        tree.into_iter()
+            .map(|(name, depth)| (name.leaf_name().to_string(), depth))
+            .collect()
+    };
+
+    assert_eq!(
+        vec![("yolanda".to_string(), 0), ("phone".to_string(), 1)],
+        names(users.walk_tree(5).await.unwrap())
+    );
+    assert_eq!(
+        vec![("yolanda".to_string(), 0)],
+        names(users.walk_tree(0).await.unwrap())
+    );
+
+    users.delete_recursive("yolanda").await.unwrap();
+}
+
+#[tokio::test]
+async fn test_sharded_counter() {
+    let (_, counters) = temp_collection::<User>().await;
+
+    let counter = counters.sharded_counter("visits", 4).unwrap();
+    assert_eq!(0, counter.get().await.unwrap());
+
+    for _ in 0..10 {
+        counter.increment(1).await.unwrap();
+    }
+    counter.increment(-3).await.unwrap();
+
+    assert_eq!(7, counter.get().await.unwrap());
+
+    counters.delete_recursive("visits").await.unwrap();
+}
+
+#[tokio::test]
+async fn test_get_many() {
+    let (db, users) = temp_collection::<User>().await;
+    let collection_id = users.name().leaf_name();
+
+    let user = User {
+        name: "Dave".to_string(),
+        email: "dave@email".to_string(),
+        id: 6,
+        city: Some("Toronto".to_string()),
+    };
+    users.create_with_key(&user, "dave").await.unwrap();
+
+    // Write a document of a different shape into the same collection.
+    let devices: Collection<Device> = db.collection(&collection_id);
+    devices
+        .create_with_key(
+            &Device {
+                id: "laptop".to_string(),
+            },
+            "laptop",
+        )
+        .await
+        .unwrap();
+
+    let result = users
+        .get_many(vec!["laptop", "nobody", "dave"])
+        .await
+        .unwrap();
+
+    assert_eq!(
+        vec![NamedDocument::new(users.name().document("dave"), user)],
+        result
+            .found
+            .into_iter()
+            .map(without_times)
+            .collect::<Vec<_>>()
+    );
+    assert_eq!(vec![users.name().document("nobody")], result.missing);
+    assert_eq!(1, result.errors.len());
+    assert_eq!(users.name().document("laptop"), result.errors[0].0);
+
+    devices.delete("laptop").await.unwrap();
+    empty_collection(&users).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_concurrent_gets() {
+    let (_, users) = temp_collection::<User>().await;
+    let users = Arc::new(users);
+
+    let user = User {
+        name: "Dave".to_string(),
+        email: "dave@email".to_string(),
+        id: 6,
+        city: None,
+    };
+    users.create_with_key(&user, "dave").await.unwrap();
+
+    // Time a few requests made one after the other, for the cost of a round trip.
+    let start = std::time::Instant::now();
+    for _ in 0..5 {
+        users.get("dave").await.unwrap();
+    }
+    let round_trip = start.elapsed() / 5;
+
+    // Requests share the connection rather than waiting on each other, so a hundred of them
+    // take far less than a hundred round trips.
+    let start = std::time::Instant::now();
+    let gets: Vec<_> = (0..100)
+        .map(|_| {
+            let users = users.clone();
+            tokio::spawn(async move { users.get("dave").await })
+        })
+        .collect();
+    for get in gets {
+        assert_eq!(user, get.await.unwrap().unwrap());
+    }
+    assert!(start.elapsed() < round_trip * 20);
+
+    empty_collection(&users).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_get_many_ordered() {
+    let (_, users) = temp_collection::<User>().await;
+
+    let mut created = Vec::new();
+    for (id, name) in ["zed", "amy"].iter().enumerate() {
+        let user = User {
+            name: name.to_string(),
+            email: format!("{}@email", name),
+            id: id as u32,
+            city: None,
+        };
+        users.create_with_key(&user, *name).await.unwrap();
+        created.push(NamedDocument::new(users.name().document(name), user));
+    }
+
+    let result = users
+        .get_many_ordered(vec!["zed", "nobody", "amy", "zed"])
+        .await
+        .unwrap();
+
+    assert_eq!(
+        vec![
+            Some(created[0].clone()),
+            None,
+            Some(created[1].clone()),
+            Some(created[0].clone()),
+        ],
+        result
+            .into_iter()
+            .map(|document| document.map(without_times))
+            .collect::<Vec<_>>()
+    );
+
+    empty_collection(&users).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_get_many_strict() {
+    let (_, users) = temp_collection::<User>().await;
+
+    for (id, name) in ["zed", "amy"].iter().enumerate() {
+        let user = User {
+            name: name.to_string(),
+            email: format!("{}@email", name),
+            id: id as u32,
+            city: None,
+        };
+        users.create_with_key(&user, *name).await.unwrap();
+    }
+
+    let found: Vec<String> = users
+        .get_many_strict(vec!["amy", "zed"])
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|user| user.value.name)
+        .collect();
+    assert_eq!(vec!["amy", "zed"], found);
+
+    // Every missing document is reported, each once.
+    let error = users
+        .get_many_strict(vec!["nobody", "zed", "noone", "nobody"])
+        .await
+        .unwrap_err();
+    assert_eq!(
+        &MissingDocuments {
+            names: vec![
+                users.name().document("nobody"),
+                users.name().document("noone")
+            ]
+        },
+        error.downcast_ref::<MissingDocuments>().unwrap()
+    );
+
+    empty_collection(&users).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_get_if_exists() {
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    struct Count {
+        count: i64,
+    }
+
+    let (db, users) = temp_collection::<User>().await;
+    let counts: Collection<Count> = db.collection(&users.name().leaf_name());
+
+    assert_eq!(None, users.get_if_exists("finn").await.unwrap());
+
+    let user = User {
+        name: "Finn".to_string(),
+        email: "finn@email".to_string(),
+        id: 6,
+        city: None,
+    };
+    users.create_with_key(&user, "finn").await.unwrap();
+    assert_eq!(Some(user), users.get_if_exists("finn").await.unwrap());
+
+    // A document of the wrong shape is an error, not a missing document.
+    let error = counts.get_if_exists("finn").await.unwrap_err();
+    assert!(error.to_string().contains("count"));
+
+    empty_collection(&users).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_get_at() {
+    let (_, users) = temp_collection::<User>().await;
+
+    let mut user = User {
+        name: "Gus".to_string(),
+        email: "gus@email".to_string(),
+        id: 7,
+        city: None,
+    };
+    users.create_with_key(&user, "gus").await.unwrap();
+    let created = users.get_with_metadata("gus").await.unwrap();
+
+    user.city = Some("Lima".to_string());
+    users.upsert(&user, "gus").await.unwrap();
+
+    let read_time = created.update_time.unwrap();
+    assert_eq!(created.value, users.get_at("gus", read_time).await.unwrap());
+    assert_eq!(
+        user,
+        users
+            .get_with_consistency("gus", Consistency::Strong)
+            .await
+            .unwrap()
+    );
+
+    empty_collection(&users).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_get_with_metadata() {
+    let (_, users) = temp_collection::<User>().await;
+
+    let mut user = User {
+        name: "Erin".to_string(),
+        email: "erin@email".to_string(),
+        id: 5,
+        city: None,
+    };
+    users.create_with_key(&user, "erin").await.unwrap();
+
+    let created = users.get_with_metadata("erin").await.unwrap();
+    assert_eq!(users.name().document("erin"), created.name);
+    assert_eq!(user, created.value);
+    assert!(created.create_time.is_some());
+    assert_eq!(created.create_time, created.update_time);
+
+    user.city = Some("Quito".to_string());
+    users.upsert(&user, "erin").await.unwrap();
+
+    let updated = users.get_with_metadata("erin").await.unwrap();
+    assert_eq!(user, updated.value);
+    assert_eq!(created.create_time, updated.create_time);
+    assert_ne!(created.update_time, updated.update_time);
+
+    // Documents read by listing carry their timestamps too.
+    let listed: Vec<NamedDocument<User>> = users.list().collect().await;
+    assert_eq!(vec![updated], listed);
+
+    empty_collection(&users).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_update_if_unchanged() {
+    let (_, users) = temp_collection::<User>().await;
+
+    let mut user = User {
+        name: "Fay".to_string(),
+        email: "fay@email".to_string(),
+        id: 6,
+        city: None,
+    };
+    users.create_with_key(&user, "fay").await.unwrap();
+
+    // The update time read from the server is accepted as a precondition as it is.
+    let read = users.get_with_metadata("fay").await.unwrap();
+    let read_time = read.update_time.unwrap();
+    user.city = Some("Lagos".to_string());
+    let written = users
+        .update_if_unchanged(&user, "fay", &read_time)
+        .await
+        .unwrap();
+    assert_eq!(user, users.get("fay").await.unwrap());
+
+    // The update time of a write can be used for the next one.
+    user.id = 7;
+    users
+        .update_if_unchanged(&user, "fay", &written.update_time)
+        .await
+        .unwrap();
+
+    // The document has changed since it was first read.
+    match users.update_if_unchanged(&user, "fay", &read_time).await {
+        Err(FirestoreError::Stale(name)) => assert_eq!(users.name().document("fay"), name),
+        result => panic!("Expected a stale error, got {:?}.", result),
+    }
+
+    empty_collection(&users).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_apply_or_queue() {
+    let (db, users) = temp_collection::<User>().await;
+    let db = db.with_offline_queue(OfflineQueue::in_memory());
+    let users: Collection<User> = db.collection(&users.name().leaf_name());
+
+    let user = User {
+        name: "Faythe".to_string(),
+        email: "faythe@email".to_string(),
+        id: 6,
+        city: None,
+    };
+
+    // Firestore is reachable, so the writes are applied immediately.
+    let outcome = users
+        .apply_or_queue(vec![Op::Upsert("faythe", user.clone())])
+        .await
+        .unwrap();
+    assert!(matches!(outcome, ApplyOutcome::Applied(_)));
+    assert_eq!(user, users.get("faythe").await.unwrap());
+
+    assert_eq!(0, db.flush_offline_queue().await.unwrap());
+
+    empty_collection(&users).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_get_many_raw() {
+    let (_, users) = temp_collection::<User>().await;
+
+    let user = User {
+        name: "Raw".to_string(),
+        email: "raw@email".to_string(),
+        id: 1,
+        city: None,
+    };
+    users.create_with_key(&user, "raw").await.unwrap();
+
+    let result = users.get_many_raw(vec!["nobody", "raw"]).await.unwrap();
+    assert_eq!(2, result.len());
+
+    let (name, document) = &result[0];
+    assert_eq!(&users.name().document("nobody"), name);
+    assert!(document.is_none());
+
+    // The raw document can be deserialized into a type other than the collection's.
+    #[derive(Deserialize, PartialEq, Debug)]
+    struct NameOnly {
+        name: String,
+    }
+
+    let (name, document) = result.into_iter().nth(1).unwrap();
+    assert_eq!(users.name().document("raw"), name);
+    let name_only: NameOnly = firestore_serde::from_document(document.unwrap()).unwrap();
+    assert_eq!(
+        NameOnly {
+            name: "Raw".to_string()
+        },
+        name_only
+    );
+
+    empty_collection(&users).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_case_insensitive_ids() {
+    let (db, exact) = temp_collection::<User>().await;
+    let collection_id = exact.name().leaf_name();
+    let users: Collection<User> = db
+        .collection::<User>(&collection_id)
+        .with_case_insensitive_ids();
+
+    let user = User {
+        name: "Mallory".to_string(),
+        email: "mallory@email".to_string(),
+        id: 1,
+        city: None,
+    };
+    users.create_with_key(&user, "Mallory").await.unwrap();
+
+    assert_eq!(user, users.get("MALLORY").await.unwrap());
+    assert!(users.exists("mallory").await.unwrap());
+    assert!(exact.exists("mallory").await.unwrap());
+    assert!(!exact.exists("Mallory").await.unwrap());
+    assert!(matches!(
+        users.create_with_key(&user, "mallory").await,
+        Err(FirestoreError::AlreadyExists(_))
+    ));
+
+    // A document written before the mode was enabled is still found by its exact key.
+    exact.create_with_key(&user, "Legacy").await.unwrap();
+    assert_eq!(user, users.get("Legacy").await.unwrap());
+    assert!(users.exists("Legacy").await.unwrap());
+    assert!(!users.exists("LEGACY").await.unwrap());
+
+    empty_collection(&exact).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_cache() {
+    let (db, uncached) = temp_collection::<User>().await;
+    let collection_id = uncached.name().leaf_name();
+    let users: Collection<User> = db
+        .collection::<User>(&collection_id)
+        .with_cache(CacheConfig {
+            max_entries: 100,
+            ttl: Duration::from_secs(600),
+        });
+
+    let mut user = User {
+        name: "Victor".to_string(),
+        email: "victor@email".to_string(),
+        id: 1,
+        city: None,
+    };
+    users.create_with_key(&user, "victor").await.unwrap();
+
+    // A write through another collection does not invalidate the cache.
+    let mut external = user.clone();
+    external.city = Some("Elsewhere".to_string());
+    uncached.upsert(&external, "victor").await.unwrap();
+    assert_eq!(user, users.get("victor").await.unwrap());
+
+    // A write through the same collection does.
+    user.city = Some("Vancouver".to_string());
+    users.update(&user, "victor").await.unwrap();
+    assert_eq!(user, users.get("victor").await.unwrap());
+    assert_eq!(user, uncached.get("victor").await.unwrap());
+
+    users.delete("victor").await.unwrap();
+    assert!(users.get("victor").await.is_err());
+
+    empty_collection(&uncached).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_validate_schema() {
+    let (db, users) = temp_collection::<User>().await;
+    let collection_id = users.name().leaf_name();
+    let devices: Collection<Device> = db.collection(&collection_id);
+
+    for (id, name) in ["wendy", "xavier"].iter().enumerate() {
+        let user = User {
+            name: name.to_string(),
+            email: format!("{}@email", name),
+            id: id as u32,
+            city: None,
+        };
+        users.create_with_key(&user, *name).await.unwrap();
+    }
+    let device = Device {
+        id: "phone".to_string(),
+    };
+    devices.create_with_key(&device, "phone").await.unwrap();
+
+    let report = users.validate_schema().await.unwrap();
+    assert!(!report.is_valid());
+    assert_eq!(3, report.total());
+    assert_eq!(2, report.conforming);
+    assert_eq!(1, report.non_conforming.len());
+    assert_eq!(users.name().document("phone"), report.non_conforming[0].0);
+
+    assert_eq!(1, devices.validate_schema().await.unwrap().conforming);
+
+    empty_collection(&users).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_export_import_binary() {
+    let (db, users) = temp_collection::<User>().await;
+    let restored: Collection<User> =
+        db.collection(&format!("{}-restored", users.name().leaf_name()));
+
+    let mut expected = Vec::new();
+    for (id, name) in ["yara", "zed"].iter().enumerate() {
+        let user = User {
+            name: name.to_string(),
+            email: format!("{}@email", name),
+            id: id as u32,
+            city: Some("Quito".to_string()),
+        };
+        users.create_with_key(&user, *name).await.unwrap();
+        expected.push(user);
+    }
+
+    let mut export = Vec::new();
+    assert_eq!(2, users.export_binary(&mut export).await.unwrap());
+    assert_eq!(2, restored.import_binary(export.as_slice()).await.unwrap());
+
+    // Documents keep their keys in the collection they are imported into.
+    assert_eq!(expected[0], restored.get("yara").await.unwrap());
+    assert_eq!(expected[1], restored.get("zed").await.unwrap());
+
+    empty_collection(&users).await.unwrap();
+    empty_collection(&restored).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_update_fields() {
+    #[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+    struct Address {
+        street: String,
+        city: String,
+    }
+
+    #[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+    struct Contact {
+        name: String,
+        phone: Option<String>,
+        address: Address,
+    }
+
+    let (_, contacts) = temp_collection::<Contact>().await;
+
+    let stored = Contact {
+        name: "Ivy".to_string(),
+        phone: Some("555-0100".to_string()),
+        address: Address {
+            street: "1 Elm St".to_string(),
+            city: "Oslo".to_string(),
+        },
+    };
+    contacts.create_with_key(&stored, "ivy").await.unwrap();
+
+    // Only the listed fields are written, although `phone` is `None` and `street` differs.
+    let changes = Contact {
+        name: "Ivy B.".to_string(),
+        phone: None,
+        address: Address {
+            street: "".to_string(),
+            city: "Bergen".to_string(),
+        },
+    };
+    contacts
+        .update_fields("ivy", &changes, &["name", "address.city"])
+        .await
+        .unwrap();
+
+    let mut expected = stored.clone();
+    expected.name = "Ivy B.".to_string();
+    expected.address.city = "Bergen".to_string();
+    assert_eq!(expected, contacts.get("ivy").await.unwrap());
+
+    // Like `update`, the document must exist.
+    assert!(contacts
+        .update_fields("nobody", &changes, &["name"])
+        .await
+        .is_err());
+
+    empty_collection(&contacts).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_rename_field() {
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    struct Renamed {
+        full_name: String,
+        email: String,
+    }
+
+    let (db, users) = temp_collection::<User>().await;
+    let collection_id = users.name().leaf_name();
+    let devices: Collection<Device> = db.collection(&collection_id);
+
+    let user = User {
+        name: "Alice".to_string(),
+        email: "alice@email".to_string(),
+        id: 1,
+        city: None,
+    };
+    users.create_with_key(&user, "alice").await.unwrap();
+    let device = Device {
+        id: "tablet".to_string(),
+    };
+    devices.create_with_key(&device, "tablet").await.unwrap();
+
+    // Documents without the field are left alone.
+    assert_eq!(
+        1,
+        users
+            .rename_field(User::FIELD_NAME, "full_name")
+            .await
+            .unwrap()
+    );
+
+    let renamed: Collection<Renamed> = db.collection(&collection_id);
+    assert_eq!(
+        Renamed {
+            full_name: "Alice".to_string(),
+            email: "alice@email".to_string(),
+        },
+        renamed.get("alice").await.unwrap()
+    );
+    assert_eq!(device, devices.get("tablet").await.unwrap());
+    assert!(users.get("alice").await.is_err());
+
+    empty_collection(&devices).await.unwrap();
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Page {
+    visits: i64,
+    visitors: Vec<String>,
+}
+
+#[tokio::test]
+async fn test_apply_transforms() {
+    let (_, pages) = temp_collection::<Page>().await;
+
+    let transforms = Transforms::new()
+        .increment("visits", 2)
+        .array_union("visitors", &["ada", "bob"])
+        .unwrap()
+        .server_timestamp("last_visit");
+    let result = pages.apply_transforms("home", transforms).await.unwrap();
+
+    assert_eq!(3, result.values.len());
+    assert_eq!(
+        2i64,
+        firestore_serde::from_grpc_value::<i64>(&result.values[0]).unwrap()
+    );
+    assert!(matches!(
+        result.values[2].value_type,
+        Some(ValueType::TimestampValue(_))
+    ));
+
+    let page = pages.get("home").await.unwrap();
+    assert_eq!(2, page.visits);
+    assert_eq!(vec!["ada".to_string(), "bob".to_string()], page.visitors);
+
+    empty_collection(&pages).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_create_with_transforms() {
+    let (_, users) = temp_collection::<User>().await;
+
+    let user = User {
+        name: "Peggy".to_string(),
+        email: "peggy@email".to_string(),
+        id: 1,
+        city: None,
+    };
+    let result = users
+        .create_with_transforms(
+            &user,
+            "peggy",
+            Transforms::new().server_timestamp("created"),
+        )
+        .await
+        .unwrap();
+    assert_eq!(1, result.values.len());
+
+    // The document is created with the server timestamp already set.
+    let raw = users.get_many_raw(vec!["peggy"]).await.unwrap();
+    let document = raw[0].1.clone().unwrap();
+    assert_eq!(
+        result.values[0].value_type,
+        document.fields["created"].value_type
+    );
+    assert!(matches!(
+        document.fields["created"].value_type,
+        Some(ValueType::TimestampValue(_))
+    ));
+    assert_eq!(user, users.get("peggy").await.unwrap());
+
+    assert!(matches!(
+        users
+            .create_with_transforms(
+                &user,
+                "peggy",
+                Transforms::new().server_timestamp("created")
+            )
+            .await,
+        Err(FirestoreError::AlreadyExists(_))
+    ));
+
+    empty_collection(&users).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_subcollection_checked() {
+    let (_, users) = temp_collection::<User>().await;
+
+    let user = User {
+        name: "Olivia".to_string(),
+        email: "olivia@email".to_string(),
+        id: 1,
+        city: None,
+    };
+    users.create_with_key(&user, "olivia").await.unwrap();
+
+    let devices: Collection<Device> = users
+        .subcollection_checked("olivia", "devices")
+        .await
+        .unwrap();
+    assert_eq!(
+        users.subcollection::<Device>("olivia", "devices").name(),
+        devices.name()
+    );
+
+    assert!(users
+        .subcollection_checked::<Device>("olivai", "devices")
+        .await
+        .is_err());
+
+    empty_collection(&users).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_apply_with_results() {
+    let (_, pages) = temp_collection::<Page>().await;
+
+    pages
+        .upsert(
+            &Page {
+                visits: 10,
+                visitors: vec![],
+            },
+            "about",
+        )
+        .await
+        .unwrap();
+
+    let results = pages
+        .apply_with_results(vec![
+            Op::Upsert(
+                "home",
+                Page {
+                    visits: 1,
+                    visitors: vec![],
+                },
+            ),
+            Op::Transform("about", Transforms::new().increment("visits", 5)),
+            Op::Transform(
+                "contact",
+                Transforms::new()
+                    .increment("visits", 3)
+                    .array_union("visitors", &["ada"])
+                    .unwrap(),
+            ),
+        ])
+        .await
+        .unwrap();
+
+    assert_eq!(3, results.len());
+    assert!(results[0].values.is_empty());
+    let values: Vec<i64> = results[1..]
+        .iter()
+        .map(|result| firestore_serde::from_grpc_value(&result.values[0]).unwrap())
+        .collect();
+    assert_eq!(vec![15, 3], values);
+    assert_eq!(2, results[2].values.len());
+
+    assert_eq!(15, pages.get("about").await.unwrap().visits);
+    assert_eq!(
+        vec!["ada".to_string()],
+        pages.get("contact").await.unwrap().visitors
+    );
+
+    empty_collection(&pages).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_increment() {
+    #[derive(Serialize, Deserialize, PartialEq, Debug, Default)]
+    #[serde(default)]
+    struct Page {
+        views: i64,
+        score: f64,
+    }
+
+    let (_, pages) = temp_collection::<Page>().await;
+
+    pages
+        .create_with_key(&Page::default(), "home")
+        .await
+        .unwrap();
+
+    assert_eq!(3, pages.increment("home", "views", 3).await.unwrap());
+    assert_eq!(2, pages.increment("home", "views", -1).await.unwrap());
+    assert_eq!(
+        0.5,
+        pages.increment_double("home", "score", 0.5).await.unwrap()
+    );
+    assert_eq!(
+        Page {
+            views: 2,
+            score: 0.5
+        },
+        pages.get("home").await.unwrap()
+    );
+
+    // Like an update, an increment does not create the document.
+    assert!(pages.increment("about", "views", 1).await.is_err());
+    assert!(pages.get("about").await.is_err());
+
+    empty_collection(&pages).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_touch_many() {
+    let (_, users) = temp_collection::<User>().await;
+
+    for (key, id) in &[("amy", 1), ("bo", 2)] {
+        let user = User {
+            name: key.to_string(),
+            email: format!("{}@email", key),
+            id: *id,
+            city: None,
+        };
+        users.create_with_key(&user, *key).await.unwrap();
+    }
+    let before = users.get_with_metadata("amy").await.unwrap();
+
+    assert_eq!(2, users.touch_many(vec!["amy", "bo", "amy"]).await.unwrap());
+
+    let after = users.get_with_metadata("amy").await.unwrap();
+    assert_eq!(before.value, after.value);
+    assert_ne!(before.update_time, after.update_time);
+
+    // Touching does not create documents.
+    assert!(users.touch_many(vec!["bo", "cy"]).await.is_err());
+    assert!(!users.exists("cy").await.unwrap());
+
+    empty_collection(&users).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_increment_many() {
+    #[derive(Serialize, Deserialize, PartialEq, Debug, Default)]
+    #[serde(default)]
+    struct Sales {
+        units: i64,
+        returns: i64,
+    }
+
+    let (_, sales) = temp_collection::<Sales>().await;
+
+    sales
+        .upsert(
+            &Sales {
+                units: 10,
+                returns: 1,
+            },
+            "books",
+        )
+        .await
+        .unwrap();
+
+    let values = sales
+        .increment_many(&[
+            ("books", "units", 2),
+            ("music", "units", 5),
+            ("books", "returns", -1),
+        ])
+        .await
+        .unwrap();
+    assert_eq!(vec![12, 5, 0], values);
+
+    assert_eq!(
+        Sales {
+            units: 12,
+            returns: 0
+        },
+        sales.get("books").await.unwrap()
+    );
+    assert_eq!(5, sales.get("music").await.unwrap().units);
+
+    empty_collection(&sales).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_get_with_ancestors() {
+    let (_, apps) = temp_collection::<Device>().await;
+    let users: Collection<User> = apps.subcollection("phone", "users");
+    let settings: Collection<Device> = users.subcollection("john", "settings");
+
+    apps.create_with_key(
+        &Device {
+            id: "phone".to_string(),
+        },
+        "phone",
+    )
+    .await
+    .unwrap();
+    // "john" is left as a missing document, existing only as the parent of "settings".
+    settings
+        .create_with_key(
+            &Device {
+                id: "dark-mode".to_string(),
+            },
+            "dark-mode",
+        )
+        .await
+        .unwrap();
+
+    let documents = settings.get_with_ancestors("dark-mode").await.unwrap();
+    let names: Vec<DocumentName> = documents.iter().map(|(name, _)| name.clone()).collect();
+    assert_eq!(
+        vec![
+            apps.name().document("phone"),
+            settings.name().document("dark-mode")
+        ],
+        names
+    );
+    assert_eq!(
+        Device {
+            id: "dark-mode".to_string()
+        },
+        firestore_serde::from_document(documents[1].1.clone()).unwrap()
+    );
+
+    assert!(settings.get_with_ancestors("light-mode").await.is_err());
+
+    apps.delete_recursive("phone").await.unwrap();
+}
+
+#[tokio::test]
+async fn test_skip_nulls() {
+    let (_, users) = temp_collection::<User>().await;
+    let users = users.with_skip_nulls(true);
+
+    let user = User {
+        name: "Bert".to_string(),
+        email: "bert@email".to_string(),
+        id: 3,
+        city: None,
+    };
+    users.create_with_key(&user, "bert").await.unwrap();
+
+    // The field is absent, rather than null, and still reads back as `None`.
+    let documents = users.get_many_raw(vec!["bert"]).await.unwrap();
+    let document = documents[0].1.as_ref().unwrap();
+    assert!(!document.fields.contains_key(User::FIELD_CITY));
+    assert_eq!(user, users.get("bert").await.unwrap());
+
+    // Clearing a field removes it.
+    let with_city = User {
+        city: Some("Rome".to_string()),
+        ..user.clone()
+    };
+    users.upsert(&with_city, "bert").await.unwrap();
+    assert!(users.update_diff(&user, "bert").await.unwrap());
+    let documents = users.get_many_raw(vec!["bert"]).await.unwrap();
+    let document = documents[0].1.as_ref().unwrap();
+    assert!(!document.fields.contains_key(User::FIELD_CITY));
+
+    empty_collection(&users).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_create_if_newer() {
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    struct Event {
+        version: i64,
+        status: String,
+    }
+
+    let (_, events) = temp_collection::<Event>().await;
+
+    let event = |version: i64, status: &str| Event {
+        version,
+        status: status.to_string(),
+    };
+
+    events
+        .create_if_newer(&event(2, "shipped"), "order", "version")
+        .await
+        .unwrap();
+
+    // Older and equal versions are rejected.
+    for version in &[1, 2] {
+        match events
+            .create_if_newer(&event(*version, "placed"), "order", "version")
+            .await
+        {
+            Err(FirestoreError::Stale(name)) => {
+                assert_eq!(events.name().document("order"), name)
+            }
+            result => panic!("Expected a stale write, got {:?}.", result),
+        }
+    }
+    assert_eq!(event(2, "shipped"), events.get("order").await.unwrap());
+
+    events
+        .create_if_newer(&event(3, "delivered"), "order", "version")
+        .await
+        .unwrap();
+    assert_eq!(event(3, "delivered"), events.get("order").await.unwrap());
+
+    empty_collection(&events).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_upsert_returning_status() {
+    let (_, users) = temp_collection::<User>().await;
+
+    let mut user = User {
+        name: "Sybil".to_string(),
+        email: "sybil@email".to_string(),
+        id: 1,
+        city: None,
+    };
+    assert_eq!(
+        WriteOutcome::Created,
+        users.upsert_returning_status(&user, "sybil").await.unwrap()
+    );
+
+    user.city = Some("Sydney".to_string());
+    assert_eq!(
+        WriteOutcome::Updated,
+        users.upsert_returning_status(&user, "sybil").await.unwrap()
+    );
+    assert_eq!(user, users.get("sybil").await.unwrap());
+
+    empty_collection(&users).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_get_by_path() {
+    let (db, users) = temp_collection::<User>().await;
+    let collection_id = users.name().leaf_name();
+    let devices: Collection<Device> = users.subcollection("erin", "devices");
+
+    let device = Device {
+        id: "tablet".to_string(),
+    };
+    devices.create_with_key(&device, "tablet").await.unwrap();
+
+    let fetched: Device = db
+        .get_by_path(&format!("{}/erin/devices/tablet", collection_id))
+        .await
+        .unwrap();
+    assert_eq!(device, fetched);
+
+    // A path to a collection, rather than a document, is rejected.
+    let error = db
+        .get_by_path::<Device>(&format!("{}/erin/devices", collection_id))
+        .await
+        .unwrap_err();
+    assert!(error.downcast_ref::<ParseError>().is_some());
+
+    users.delete_recursive("erin").await.unwrap();
+}
+
+#[tokio::test]
+async fn test_query_where_id_in() {
+    let (_, users) = temp_collection::<User>().await;
+
+    for (key, id) in &[("frank", 7), ("grace", 8), ("heidi", 9)] {
+        let user = User {
+            name: key.to_string(),
+            email: format!("{}@email", key),
+            id: *id,
+            city: None,
+        };
+        users.create_with_key(&user, *key).await.unwrap();
+    }
+
+    let results: Vec<NamedDocument<User>> = users
+        .query()
+        .where_id_in(vec!["frank", "heidi", "nobody"])
+        .order_by(User::FIELD_ID, Direction::Descending)
+        .collect::<anyhow::Result<_>>()
+        .await
+        .unwrap();
+
+    let names: Vec<&str> = results.iter().map(|doc| doc.name.leaf_name()).collect();
+    assert_eq!(vec!["heidi", "frank"], names);
+
+    empty_collection(&users).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_query_range_on_multiple_fields() {
+    let (_, users) = temp_collection::<User>().await;
+
+    for (key, id) in &[("ivan", 1), ("judy", 2), ("mallory", 3), ("niaj", 4)] {
+        let user = User {
+            name: key.to_string(),
+            email: format!("{}@email", key),
+            id: *id,
+            city: None,
+        };
+        users.create_with_key(&user, *key).await.unwrap();
+    }
+
+    let result: Result<Vec<NamedDocument<User>>> = users
+        .query()
+        .where_gt(User::FIELD_ID, 1)
+        .where_lt(User::FIELD_NAME, "n")
+        .collect()
+        .await;
+
+    match result {
+        Ok(results) => {
+            let mut names: Vec<&str> = results.iter().map(|doc| doc.name.leaf_name()).collect();
+            names.sort_unstable();
+            assert_eq!(vec!["judy", "mallory"], names);
+        }
+        // Each temporary collection would need its own composite index, which is unlikely to
+        // exist; in that case check that the error tells us how to create it.
+        Err(error) => {
+            let error = error.downcast_ref::<MissingIndexError>().unwrap();
+            assert!(error.create_index_url.is_some());
+            assert_eq!("id >, name <", error.query_shape);
+        }
+    }
+
+    empty_collection(&users).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_collection_group() {
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    struct Gadget {
+        active: bool,
+        last_seen: i64,
+    }
+
+    let (db, users) = temp_collection::<User>().await;
+    let gadgets_id = format!("{}-gadgets", users.name().leaf_name());
+
+    for (user, gadget, active, last_seen) in &[
+        ("olga", "phone", true, 3),
+        ("olga", "laptop", false, 4),
+        ("peggy", "phone", true, 1),
+        ("peggy", "watch", true, 2),
+    ] {
+        users
+            .subcollection::<Gadget>(user, &gadgets_id)
+            .create_with_key(
+                &Gadget {
+                    active: *active,
+                    last_seen: *last_seen,
+                },
+                *gadget,
+            )
+            .await
+            .unwrap();
+    }
+
+    let result: Result<Vec<NamedDocument<Gadget>>> = db
+        .collection_group(&gadgets_id)
+        .where_eq("active", true)
+        .order_by("last_seen", Direction::Descending)
+        .limit(2)
+        .collect()
+        .await;
+
+    match result {
+        Ok(results) => {
+            let gadget = |user, key| users.name().subcollection(user, &gadgets_id).document(key);
+            let names: Vec<DocumentName> = results.into_iter().map(|doc| doc.name).collect();
+            assert_eq!(
+                vec![gadget("olga", "phone"), gadget("peggy", "watch")],
+                names
+            );
+        }
+        // Each temporary collection ID would need its own collection group index, which is
+        // unlikely to exist; in that case check that the error tells us how to create it.
+        Err(error) => {
+            let error = error.downcast_ref::<MissingIndexError>().unwrap();
+            assert!(error.create_index_url.is_some());
+            assert_eq!("active ==, last_seen desc", error.query_shape);
+        }
+    }
+
+    for user in &["olga", "peggy"] {
+        users.delete_recursive(*user).await.unwrap();
+    }
+}
+
+#[tokio::test]
+async fn test_query_paginate_by_name() {
+    let (_, users) = temp_collection::<User>().await;
+
+    for (key, id) in &[("ivan", 1), ("judy", 2), ("mallory", 3), ("niaj", 4)] {
+        let user = User {
+            name: key.to_string(),
+            email: format!("{}@email", key),
+            id: *id,
+            city: None,
+        };
+        users.create_with_key(&user, *key).await.unwrap();
+    }
+
+    let page: Result<Vec<NamedDocument<User>>> = users
+        .query()
+        .where_name(FieldOp::GreaterThan, "judy")
+        .order_by_name(Direction::Ascending)
+        .collect()
+        .await;
+    let page = page.unwrap();
+    let names: Vec<&str> = page.iter().map(|doc| doc.name.leaf_name()).collect();
+    assert_eq!(vec!["mallory", "niaj"], names);
+
+    empty_collection(&users).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_newest_first() {
+    let (_, users) = temp_collection::<User>().await;
+
+    // Keys which sort in the order the documents were created, like ULIDs.
+    for (key, id) in &[("01a", 1), ("01b", 2), ("01c", 3)] {
+        let user = User {
+            name: key.to_string(),
+            email: format!("{}@email", key),
+            id: *id,
+            city: None,
+        };
+        users.create_with_key(&user, *key).await.unwrap();
+    }
+
+    let listed: Vec<NamedDocument<User>> = users.list().newest_first().collect().await;
+    let names: Vec<&str> = listed.iter().map(|doc| doc.name.leaf_name()).collect();
+    assert_eq!(vec!["01c", "01b", "01a"], names);
+
+    let queried: Result<Vec<NamedDocument<User>>> = users.query().newest_first().collect().await;
+    let queried = queried.unwrap();
+    let names: Vec<&str> = queried.iter().map(|doc| doc.name.leaf_name()).collect();
+    assert_eq!(vec!["01c", "01b", "01a"], names);
+
+    empty_collection(&users).await.unwrap();
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+struct Event {
+    name: String,
+    created: FirestoreTimestamp,
+}
+
+#[tokio::test]
+async fn test_query_by_timestamp() {
+    let (_, events) = temp_collection::<Event>().await;
+
+    for (key, seconds) in &[("old", 1_000), ("new", 2_000)] {
+        let event = Event {
+            name: key.to_string(),
+            created: FirestoreTimestamp(Timestamp {
+                seconds: *seconds,
+                nanos: 0,
+            }),
+        };
+        events.create_with_key(&event, *key).await.unwrap();
+    }
+
+    let cutoff = FirestoreTimestamp(Timestamp {
+        seconds: 1_500,
+        nanos: 0,
+    });
+    let results: Result<Vec<NamedDocument<Event>>> =
+        events.query().where_gt("created", cutoff).collect().await;
+    let results = results.unwrap();
+
+    assert_eq!(1, results.len());
+    assert_eq!("new", results[0].value.name);
+    assert_eq!(2_000, results[0].value.created.0.seconds);
+
+    empty_collection(&events).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_query_offset() {
+    let (_, users) = temp_collection::<User>().await;
+
+    for (key, id) in &[("ivan", 1), ("judy", 2), ("mallory", 3), ("niaj", 4)] {
+        let user = User {
+            name: key.to_string(),
+            email: format!("{}@email", key),
+            id: *id,
+            city: None,
+        };
+        users.create_with_key(&user, *key).await.unwrap();
+    }
+
+    let results: Result<Vec<NamedDocument<User>>> = users
+        .query()
+        .order_by(User::FIELD_ID, Direction::Descending)
+        .offset(1)
+        .collect()
+        .await;
+    let names: Vec<&str> = results
+        .as_ref()
+        .unwrap()
+        .iter()
+        .map(|doc| doc.name.leaf_name())
+        .collect();
+    assert_eq!(vec!["mallory", "judy", "ivan"], names);
+
+    empty_collection(&users).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_query_cursors() {
+    let (_, users) = temp_collection::<User>().await;
+
+    for (key, id) in &[("ivan", 1), ("judy", 2), ("mallory", 3), ("niaj", 4)] {
+        let user = User {
+            name: key.to_string(),
+            email: format!("{}@email", key),
+            id: *id,
+            city: None,
+        };
+        users.create_with_key(&user, *key).await.unwrap();
+    }
+
+    async fn ids(query: Query<User>) -> Vec<u32> {
+        let results: Result<Vec<NamedDocument<User>>> = query.collect().await;
+        results
+            .unwrap()
+            .into_iter()
+            .map(|doc| doc.value.id)
+            .collect()
+    }
+    let by_id = || users.query().order_by(User::FIELD_ID, Direction::Ascending);
+    let at = |id: u32| QueryCursor::from_values([id]).unwrap();
+
+    assert_eq!(vec![2, 3], ids(by_id().start_at(at(2)).end_at(at(3))).await);
+    assert_eq!(vec![3], ids(by_id().start_after(at(2)).end_at(at(3))).await);
+    assert_eq!(vec![1, 2], ids(by_id().end_before(at(3))).await);
+
+    empty_collection(&users).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_stream_keys_chunked() {
+    let (_, users) = temp_collection::<User>().await;
+
+    for (key, id) in &[
+        ("ivan", 1),
+        ("judy", 2),
+        ("mallory", 3),
+        ("niaj", 4),
+        ("olivia", 5),
+    ] {
+        let user = User {
+            name: key.to_string(),
+            email: format!("{}@email", key),
+            id: *id,
+            city: None,
+        };
+        users.create_with_key(&user, *key).await.unwrap();
+    }
+
+    let chunks: Vec<Vec<DocumentName>> = users.stream_keys_chunked(2).collect().await;
+    let chunks: Vec<Vec<&str>> = chunks
+        .iter()
+        .map(|chunk| chunk.iter().map(|name| name.leaf_name()).collect())
+        .collect();
+    assert_eq!(
+        vec![
+            vec!["ivan", "judy"],
+            vec!["mallory", "niaj"],
+            vec!["olivia"]
+        ],
+        chunks
+    );
+
+    empty_collection(&users).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_list_limit_and_offset() {
+    let (_, users) = temp_collection::<User>().await;
+
+    for id in 0..5 {
+        let user = User {
+            name: format!("user{}", id),
+            email: format!("user{}@email", id),
+            id,
+            city: None,
+        };
+        users.create_with_key(&user, &user.name).await.unwrap();
+    }
+
+    let ids = |list: Vec<NamedDocument<User>>| -> Vec<u32> {
+        list.into_iter().map(|user| user.value.id).collect()
+    };
+
+    let listed = users.list().with_page_size(2).with_limit(3).collect().await;
+    assert_eq!(vec![0, 1, 2], ids(listed));
+
+    let listed = users
+        .list()
+        .with_page_size(2)
+        .with_offset(1)
+        .with_limit(3)
+        .collect()
+        .await;
+    assert_eq!(vec![1, 2, 3], ids(listed));
+
+    let page = users
+        .list()
+        .with_offset(3)
+        .with_limit(5)
+        .get_page()
+        .await
+        .unwrap();
+    assert_eq!(vec![3, 4], ids(page));
+
+    empty_collection(&users).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_list_fold() {
+    #[derive(Serialize, Deserialize)]
+    struct Rating {
+        score: f64,
+        weight: f64,
+    }
+
+    let (db, ratings) = temp_collection::<Rating>().await;
+
+    for (score, weight) in &[(4.0, 1.0), (1.0, 3.0), (5.0, 4.0)] {
+        let rating = Rating {
+            score: *score,
+            weight: *weight,
+        };
+        ratings.create(&rating).await.unwrap();
+    }
+
+    let (total, weights) = ratings
+        .list()
+        .with_page_size(2)
+        .fold((0.0, 0.0), |(total, weights), rating| {
+            (
+                total + rating.value.score * rating.value.weight,
+                weights + rating.value.weight,
+            )
+        })
+        .await
+        .unwrap();
+    assert_eq!(3.375, total / weights);
+
+    // A document that cannot be deserialized is an error, rather than a panic.
+    let users: Collection<User> = db.collection(&ratings.name().leaf_name());
+    assert!(users.list().fold(0, |count, _| count + 1).await.is_err());
+
+    empty_collection(&ratings).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_list_with_prefetch() {
+    let (_, users) = temp_collection::<User>().await;
+
+    for id in 0..6 {
+        let user = User {
+            name: format!("user{}", id),
+            email: format!("user{}@email", id),
+            id,
+            city: None,
+        };
+        users.create_with_key(&user, &user.name).await.unwrap();
+    }
+
+    // Consume a page at a time, slower than a page can be fetched, and total the time spent
+    // waiting on the stream.
+    async fn stalls(users: &Collection<User>, prefetch: bool) -> (Vec<u32>, Duration) {
+        let mut list = users.list().with_page_size(2).with_prefetch(prefetch);
+        let mut ids = Vec::new();
+        let mut stalled = Duration::ZERO;
+        loop {
+            let start = std::time::Instant::now();
+            let user = list.next().await;
+            stalled += start.elapsed();
+
+            match user {
+                Some(user) => ids.push(user.value.id),
+                None => return (ids, stalled),
+            }
+            tokio::time::sleep(Duration::from_millis(250)).await;
+        }
+    }
+
+    let (ids, without_prefetch) = stalls(&users, false).await;
+    let (prefetched_ids, with_prefetch) = stalls(&users, true).await;
+
+    assert_eq!(vec![0, 1, 2, 3, 4, 5], ids);
+    assert_eq!(ids, prefetched_ids);
+
+    // Only the first page is waited on when the following pages are fetched ahead.
+    assert!(
+        with_prefetch < without_prefetch,
+        "Prefetching stalled for {:?}, compared to {:?} without.",
+        with_prefetch,
+        without_prefetch
+    );
+
+    empty_collection(&users).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_query_filter() {
+    let (_, users) = temp_collection::<User>().await;
+
+    for (key, id, city) in &[("ann", 1, "NYC"), ("bo", 2, "NYC"), ("cy", 3, "Oslo")] {
+        let user = User {
+            name: key.to_string(),
+            email: format!("{}@email", key),
+            id: *id,
+            city: Some(city.to_string()),
+        };
+        users.create_with_key(&user, *key).await.unwrap();
+    }
+
+    let in_nyc: Vec<String> = users
+        .query()
+        .filter(User::FIELD_CITY, FieldOp::Equal, "NYC")
+        .map(|user| user.unwrap().value.name)
+        .collect()
+        .await;
+    assert_eq!(vec!["ann", "bo"], in_nyc);
+
+    let later_in_nyc: Vec<String> = users
+        .query()
+        .filter(User::FIELD_CITY, FieldOp::Equal, "NYC")
+        .filter(User::FIELD_ID, FieldOp::GreaterThanOrEqual, 2)
+        .map(|user| user.unwrap().value.name)
+        .collect()
+        .await;
+    assert_eq!(vec!["bo"], later_in_nyc);
+
+    empty_collection(&users).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_find_one() {
+    let (_, users) = temp_collection::<User>().await;
+
+    for (key, id) in &[("gil", 1), ("hal", 2)] {
+        let user = User {
+            name: key.to_string(),
+            email: format!("{}@email", key),
+            id: *id,
+            city: None,
+        };
+        users.create_with_key(&user, *key).await.unwrap();
+    }
+
+    let hal = users
+        .find_one(User::FIELD_EMAIL, "hal@email")
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(users.name().document("hal"), hal.name);
+    assert_eq!(2, hal.value.id);
+
+    assert!(users
+        .find_one(User::FIELD_EMAIL, "ida@email")
+        .await
+        .unwrap()
+        .is_none());
+
+    empty_collection(&users).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_list_prefix() {
+    let (_, users) = temp_collection::<User>().await;
+
+    for (id, key) in [
+        "2021-09-30",
+        "2021-10",
+        "2021-10-01",
+        "2021-10-31",
+        "2021-11-01",
+    ]
+    .iter()
+    .enumerate()
+    {
+        let user = User {
+            name: key.to_string(),
+            email: format!("{}@email", key),
+            id: id as u32,
+            city: None,
+        };
+        users.create_with_key(&user, *key).await.unwrap();
+    }
+
+    let october: Vec<String> = users
+        .list_prefix("2021-10")
+        .map(|user| user.unwrap().value.name)
+        .collect()
+        .await;
+    assert_eq!(vec!["2021-10", "2021-10-01", "2021-10-31"], october);
+
+    let everyone: Vec<String> = users
+        .list_prefix("")
+        .map(|user| user.unwrap().value.name)
+        .collect()
+        .await;
+    assert_eq!(5, everyone.len());
+
+    empty_collection(&users).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_query_delete_matching() {
+    let (_, users) = temp_collection::<User>().await;
+
+    for (key, city) in &[
+        ("pat", Some("Lima")),
+        ("quinn", Some("Oslo")),
+        ("rupert", Some("Lima")),
+        ("sybil", None),
+    ] {
+        let user = User {
+            name: key.to_string(),
+            email: format!("{}@email", key),
+            id: 1,
+            city: city.map(str::to_string),
+        };
+        users.create_with_key(&user, *key).await.unwrap();
+    }
+
+    let deleted = users
+        .query()
+        .where_eq(User::FIELD_CITY, "Lima")
+        .delete_matching()
+        .await
+        .unwrap();
+    assert_eq!(2, deleted);
+
+    let remaining: Vec<String> = users
+        .list()
+        .map(|user| user.name.leaf_name().to_string())
+        .collect()
+        .await;
+    assert_eq!(vec!["quinn", "sybil"], remaining);
+
+    empty_collection(&users).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_query_list_with_count() {
+    let (_, users) = temp_collection::<User>().await;
+
+    for (key, id) in &[("ivan", 4), ("judy", 3), ("mallory", 2), ("niaj", 1)] {
+        let user = User {
+            name: key.to_string(),
+            email: format!("{}@email", key),
+            id: *id,
+            city: None,
+        };
+        users.create_with_key(&user, *key).await.unwrap();
+    }
+
+    let query = || {
+        users
+            .query()
+            .where_gt(User::FIELD_ID, 1)
+            .order_by(User::FIELD_ID, Direction::Ascending)
+    };
+
+    let first = query().list_with_count(2).await.unwrap();
+    assert_eq!(3, first.total);
+    let ids: Vec<u32> = first.page.iter().map(|user| user.value.id).collect();
+    assert_eq!(vec![2, 3], ids);
+
+    let second = query()
+        .start_after(first.next_cursor.unwrap())
+        .list_with_count(2)
+        .await
+        .unwrap();
+    assert_eq!(3, second.total);
+    let ids: Vec<u32> = second.page.iter().map(|user| user.value.id).collect();
+    assert_eq!(vec![4], ids);
+    assert_eq!(None, second.next_cursor);
+
+    empty_collection(&users).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_query_count() {
+    let (_, users) = temp_collection::<User>().await;
+
+    for (key, id) in &[("ivan", 1), ("judy", 2), ("mallory", 3)] {
+        let user = User {
+            name: key.to_string(),
+            email: format!("{}@email", key),
+            id: *id,
+            city: None,
+        };
+        users.create_with_key(&user, *key).await.unwrap();
+    }
+
+    assert_eq!(3, users.query().count().await.unwrap());
+    assert_eq!(
+        2,
+        users
+            .query()
+            .where_gt(User::FIELD_ID, 1)
+            .count_cached(Duration::from_secs(60))
+            .await
+            .unwrap()
+    );
+
+    // Within the TTL, the cached count is returned even though it is now stale.
+    users.delete("judy").await.unwrap();
+    assert_eq!(
+        2,
+        users
+            .query()
+            .where_gt(User::FIELD_ID, 1)
+            .count_cached(Duration::from_secs(60))
+            .await
+            .unwrap()
+    );
+    assert_eq!(
+        1,
+        users
+            .query()
+            .where_gt(User::FIELD_ID, 1)
+            .count()
+            .await
+            .unwrap()
+    );
+
+    empty_collection(&users).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_database_info() {
+    let (token_source, project_id) = get_source_and_project().await;
+    let db = Database::new(token_source, &project_id).await;
+
+    let info = db.info().await.unwrap();
+    assert_eq!(
+        format!("projects/{}/databases/(default)", project_id),
+        info.name
+    );
+    assert!(!info.location_id.is_empty());
+    assert_eq!(DatabaseType::FirestoreNative, info.database_type);
+}
+
+#[tokio::test]
+async fn test_connection_state() {
+    let (token_source, project_id) = get_source_and_project().await;
+    let db = Database::new(token_source, &project_id).await;
+
+    // The database connects when it is constructed.
+    assert_eq!(Some(ConnectionState::Ready), db.connection_state());
+    let mut changes = Box::pin(db.connection_state_changes().unwrap());
+    assert_eq!(Some(ConnectionState::Ready), changes.next().await);
+}
+
+#[tokio::test]
+async fn test_apply() {
+    let (_, users) = temp_collection::<User>().await;
+
+    let user = |name: &str, id: u32| User {
+        name: name.to_string(),
+        email: format!("{}@email", name),
+        id,
+        city: None,
+    };
+
+    users
+        .create_with_key(&user("olivia", 1), "olivia")
+        .await
+        .unwrap();
+    users
+        .create_with_key(&user("peggy", 2), "peggy")
+        .await
+        .unwrap();
+
+    users
+        .apply(vec![
+            Op::CreateIfAbsent("rupert", user("rupert", 3)),
+            Op::UpdateIfPresent("olivia", user("olivia", 10)),
+            Op::Upsert("sybil", user("sybil", 4)),
+            Op::Delete("peggy"),
+        ])
+        .await
+        .unwrap();
+
+    assert_eq!(3, users.get("rupert").await.unwrap().id);
+    assert_eq!(10, users.get("olivia").await.unwrap().id);
+    assert_eq!(4, users.get("sybil").await.unwrap().id);
+    assert!(users.get("peggy").await.is_err());
+
+    // "rupert" already exists, so none of these writes are applied.
+    assert!(users
+        .apply(vec![
+            Op::Upsert("trent", user("trent", 5)),
+            Op::CreateIfAbsent("rupert", user("rupert", 6)),
+        ])
+        .await
+        .is_err());
+    assert!(users.get("trent").await.is_err());
+    assert_eq!(3, users.get("rupert").await.unwrap().id);
+
+    empty_collection(&users).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_list_with_mask() {
+    #[derive(Serialize, Deserialize, PartialEq, Debug, Default)]
+    #[serde(default)]
+    struct UserSummary {
+        name: String,
+        email: String,
+        id: u32,
+    }
+
+    let (db, users) = temp_collection::<User>().await;
+    let collection_id = users.name().leaf_name();
+
+    let user = User {
+        name: "Uma".to_string(),
+        email: "uma@email".to_string(),
+        id: 12,
+        city: Some("Oslo".to_string()),
+    };
+    users.create_with_key(&user, "uma").await.unwrap();
+
+    let summaries: Collection<UserSummary> = db.collection(&collection_id);
+    let results: Vec<NamedDocument<UserSummary>> = summaries
+        .list()
+        .with_mask(&[User::FIELD_NAME])
+        .collect()
+        .await;
+
+    assert_eq!(
+        vec![UserSummary {
+            name: "Uma".to_string(),
+            ..UserSummary::default()
+        }],
+        results.into_iter().map(|doc| doc.value).collect::<Vec<_>>()
+    );
+
+    empty_collection(&users).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_create_with_key_already_exists() {
+    let (_, users) = temp_collection::<User>().await;
+
+    let user = User {
+        name: "Victor".to_string(),
+        email: "victor@email".to_string(),
+        id: 13,
+        city: None,
+    };
+    users.create_with_key(&user, "victor").await.unwrap();
+
+    match users.create_with_key(&user, "victor").await {
+        Err(FirestoreError::AlreadyExists(name)) => {
+            assert_eq!(users.name().document("victor"), name)
+        }
+        result => panic!("Expected AlreadyExists, got {:?}.", result),
+    }
+
+    empty_collection(&users).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_create_with_key_returning() {
+    let (_, users) = temp_collection::<User>().await;
+
+    let user = User {
+        name: "Zed".to_string(),
+        email: "zed@email".to_string(),
+        id: 17,
+        city: None,
+    };
+    let created = users.create_with_key_returning(&user, "zed").await.unwrap();
+    assert_eq!(users.name().document("zed"), created.name);
+    assert_eq!(user, created.value);
+
+    let error = users
+        .create_with_key_returning(&user, "zed")
+        .await
+        .unwrap_err();
+    assert!(matches!(
+        error.downcast_ref::<FirestoreError>(),
+        Some(FirestoreError::AlreadyExists(_))
+    ));
+
+    empty_collection(&users).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_get_or_insert_with() {
+    let (_, users) = temp_collection::<User>().await;
+
+    let user = User {
+        name: "Walter".to_string(),
+        email: "walter@email".to_string(),
+        id: 14,
+        city: None,
+    };
+
+    let result = users
+        .get_or_insert_with("walter", || async { user.clone() })
+        .await
+        .unwrap();
+    assert_eq!(user, result);
+    assert_eq!(user, users.get("walter").await.unwrap());
+
+    // The document now exists, so the closure is not called.
+    let result = users
+        .get_or_insert_with("walter", || async { panic!("Document should exist.") })
+        .await
+        .unwrap();
+    assert_eq!(user, result);
+
+    empty_collection(&users).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_update_diff() {
+    let (_, users) = temp_collection::<User>().await;
+
+    let mut user = User {
+        name: "Xavier".to_string(),
+        email: "xavier@email".to_string(),
+        id: 15,
+        city: None,
+    };
+    users.create_with_key(&user, "xavier").await.unwrap();
+
+    assert!(!users.update_diff(&user, "xavier").await.unwrap());
+
+    user.city = Some("Paris".to_string());
+    assert!(users.update_diff(&user, "xavier").await.unwrap());
+    assert_eq!(user, users.get("xavier").await.unwrap());
+
+    assert!(users.update_diff(&user, "nobody").await.is_err());
+
+    empty_collection(&users).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_array_replace() {
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    struct Post {
+        tags: Vec<String>,
+    }
+
+    let (_, posts) = temp_collection::<Post>().await;
+
+    let tags = |tags: &[&str]| tags.iter().map(|tag| tag.to_string()).collect::<Vec<_>>();
+    posts
+        .create_with_key(
+            &Post {
+                tags: tags(&["draft", "rust", "todo"]),
+            },
+            "p1",
+        )
+        .await
+        .unwrap();
+
+    // An element both removed and added is moved to the end.
+    posts
+        .array_replace("p1", "tags", &["draft", "rust"], &["published", "rust"])
+        .await
+        .unwrap();
+    assert_eq!(
+        tags(&["todo", "published", "rust"]),
+        posts.get("p1").await.unwrap().tags
+    );
+
+    // Concurrent replacements are each applied whole, so neither's removal undoes the
+    // other's addition.
+    let (first, second) = tokio::join!(
+        posts.array_replace("p1", "tags", &["todo"], &["done"]),
+        posts.array_replace("p1", "tags", &["published"], &["archived"]),
+    );
+    first.unwrap();
+    second.unwrap();
+    let mut stored = posts.get("p1").await.unwrap().tags;
+    stored.sort();
+    assert_eq!(tags(&["archived", "done", "rust"]), stored);
+
+    empty_collection(&posts).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_array_union_and_remove_structs() {
+    #[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+    struct LineItem {
+        sku: String,
+        quantity: u32,
+    }
+
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    struct Order {
+        items: Vec<LineItem>,
+    }
+
+    let (_, orders) = temp_collection::<Order>().await;
+
+    let apple = LineItem {
+        sku: "apple".to_string(),
+        quantity: 3,
+    };
+    let pear = LineItem {
+        sku: "pear".to_string(),
+        quantity: 1,
+    };
+    orders
+        .create_with_key(
+            &Order {
+                items: vec![apple.clone()],
+            },
+            "o1",
+        )
+        .await
+        .unwrap();
+
+    // The apple is already present, so only the pear is appended.
+    orders
+        .array_union("o1", "items", &[apple.clone(), pear.clone()])
+        .await
+        .unwrap();
+    assert_eq!(
+        vec![apple.clone(), pear.clone()],
+        orders.get("o1").await.unwrap().items
+    );
+
+    // Only elements equal in every field are removed.
+    let different_apple = LineItem {
+        sku: "apple".to_string(),
+        quantity: 4,
+    };
+    orders
+        .array_remove("o1", "items", &[different_apple])
+        .await
+        .unwrap();
+    assert_eq!(2, orders.get("o1").await.unwrap().items.len());
+
+    orders.array_remove("o1", "items", &[apple]).await.unwrap();
+    assert_eq!(vec![pear], orders.get("o1").await.unwrap().items);
+
+    empty_collection(&orders).await.unwrap();
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+struct Account {
+    pub balance: i64,
+}
+
+/// Move `amount` from account `from` to account `to` in a transaction, failing if `from` has
+/// too little.
+async fn transfer(
+    db: &Database,
+    accounts: &Collection<Account>,
+    from: &str,
+    to: &str,
+    amount: i64,
+) -> Result<()> {
+    db.transaction(|tx| async move {
+        let mut source = tx.get(accounts, from).await?.unwrap();
+        let mut destination = tx.get(accounts, to).await?.unwrap();
+
+        source.balance -= amount;
+        destination.balance += amount;
+        tx.update(accounts, to, &destination)?;
+        tx.update(accounts, from, &source)?;
+
+        if source.balance < 0 {
+            anyhow::bail!("Insufficient funds.");
+        }
+        Ok(())
+    })
+    .await
+}
+
+#[tokio::test]
+async fn test_transaction_transfer() {
+    let (db, accounts) = temp_collection::<Account>().await;
+
+    accounts
+        .create_with_key(&Account { balance: 100 }, "a")
+        .await
+        .unwrap();
+    accounts
+        .create_with_key(&Account { balance: 100 }, "b")
+        .await
+        .unwrap();
+
+    // Concurrent transfers contend for the same documents, so some are aborted and retried;
+    // none of them is lost.
+    let (first, second, third) = tokio::join!(
+        transfer(&db, &accounts, "a", "b", 10),
+        transfer(&db, &accounts, "a", "b", 20),
+        transfer(&db, &accounts, "b", "a", 5),
+    );
+    first.unwrap();
+    second.unwrap();
+    third.unwrap();
+
+    assert_eq!(75, accounts.get("a").await.unwrap().balance);
+    assert_eq!(125, accounts.get("b").await.unwrap().balance);
+
+    // A failed transfer applies neither of its writes, although the credit was buffered.
+    assert!(transfer(&db, &accounts, "a", "b", 1000).await.is_err());
+    assert_eq!(75, accounts.get("a").await.unwrap().balance);
+    assert_eq!(125, accounts.get("b").await.unwrap().balance);
+
+    empty_collection(&accounts).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_write_batch() {
+    let (db, accounts) = temp_collection::<Account>().await;
+    let archive: Collection<Account> =
+        db.collection(&format!("{}-archive", accounts.name().leaf_name()));
+
+    accounts
+        .create_with_key(&Account { balance: 5 }, "closed")
+        .await
+        .unwrap();
+
+    // More writes than fit in one commit, across two collections.
+    let mut batch = db.batch();
+    for i in 0..510 {
+        batch
+            .upsert(&accounts, format!("a{}", i), &Account { balance: i })
+            .unwrap();
+    }
+    batch
+        .create(&archive, "closed", &Account { balance: 5 })
+        .unwrap();
+    batch.delete(&accounts, "closed").unwrap();
+
+    let results = batch.commit().await.unwrap();
+    assert_eq!(512, results.len());
+    assert_eq!(509, accounts.get("a509").await.unwrap().balance);
+    assert_eq!(5, archive.get("closed").await.unwrap().balance);
+    assert!(accounts.get("closed").await.is_err());
+
+    empty_collection(&accounts).await.unwrap();
+    empty_collection(&archive).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_transaction_read_your_writes() {
+    let (db, users) = temp_collection::<User>().await;
+
+    let user = User {
+        name: "Walter".to_string(),
+        email: "walter@email".to_string(),
+        id: 14,
+        city: None,
+    };
+    users.create_with_key(&user, "walter").await.unwrap();
+
+    let transaction = db.begin_transaction().await.unwrap();
+    let mut walter = transaction.get(&users, "walter").await.unwrap().unwrap();
+    assert_eq!(None, transaction.get(&users, "wendy").await.unwrap());
+
+    walter.city = Some("Lima".to_string());
+    transaction.update(&users, "walter", &walter).unwrap();
+    assert_eq!(
+        Some(walter.clone()),
+        transaction.get(&users, "walter").await.unwrap()
+    );
+
+    // Nothing is visible outside the transaction until it is committed.
+    assert_eq!(None, users.get("walter").await.unwrap().city);
+    transaction.commit().await.unwrap();
+    assert_eq!(walter, users.get("walter").await.unwrap());
+
+    empty_collection(&users).await.unwrap();
+}